@@ -0,0 +1,99 @@
+//! Pinning the relay's threads to CPUs, so they stay off the cores the lobby server uses.
+//!
+//! Each thread has a `Role`.  `set_cpus` chooses the CPUs for a role, usually from `--cpus`
+//! options at startup, and each thread calls `pin` with its role when it starts.  Roles with no
+//! CPUs set are left alone.  Only CPUs 0 to 63 can be named.
+use std::io;
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::Error;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    /// Threads that read packets from the tun devices or sockets.
+    Reader = 0,
+    /// Stream reassembly and the message outputs.
+    Processing = 1,
+    /// Threads that write packets out.
+    Writer = 2,
+}
+
+impl FromStr for Role {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Role, Error> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "processing" => Ok(Role::Processing),
+            "writer" => Ok(Role::Writer),
+            _ => Err(Error(format!(
+                "unknown thread role {:?} (expected reader, processing, or writer)", s,
+            ))),
+        }
+    }
+}
+
+/// CPU mask for each role.  Zero means unpinned.
+static CPUS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Parse `role=cpus`, where `cpus` is a comma-separated list of CPU numbers and ranges, such as
+/// `processing=2,4-5`.  Returns the role and a mask with a bit set for each CPU.
+pub fn parse_cpus(s: &str) -> Result<(Role, u64), Error> {
+    let i = s.find('=').ok_or_else(|| Error(format!("expected role=cpus, but got {:?}", s)))?;
+    let role = s[..i].parse()?;
+    let parse = |x: &str| -> Result<u32, Error> {
+        let n = x.parse::<u32>().map_err(|e| Error(format!("{:?}: {}", x, e)))?;
+        if n >= 64 {
+            return Err(Error(format!("CPU {} is out of range (0-63)", n)));
+        }
+        Ok(n)
+    };
+    let mut mask = 0;
+    for item in s[i + 1 ..].split(',') {
+        let (lo, hi) = match item.find('-') {
+            Some(j) => (parse(&item[..j])?, parse(&item[j + 1 ..])?),
+            None => (parse(item)?, parse(item)?),
+        };
+        for n in lo ..= hi {
+            mask |= 1 << n;
+        }
+    }
+    if mask == 0 {
+        return Err(Error(format!("no CPUs given in {:?}", s)));
+    }
+    Ok((role, mask))
+}
+
+pub fn set_cpus(role: Role, mask: u64) {
+    CPUS[role as usize].store(mask, Ordering::Relaxed);
+}
+
+/// Pin the current thread to the CPUs chosen for `role`, if any.
+pub fn pin(role: Role) -> Result<(), Error> {
+    let mask = CPUS[role as usize].load(Ordering::Relaxed);
+    if mask == 0 {
+        return Ok(());
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for n in 0 .. 64 {
+            if mask & (1 << n) != 0 {
+                libc::CPU_SET(n, &mut set);
+            }
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            let e = io::Error::last_os_error();
+            return Err(Error(format!("pinning {:?} thread: {}", role, e)));
+        }
+    }
+    Ok(())
+}
+
+/// Like `pin`, but only logs a warning on failure, for threads that can't report errors.
+pub fn try_pin(role: Role) {
+    if let Err(e) = pin(role) {
+        log!(Relay, Warn, "{}", e);
+    }
+}
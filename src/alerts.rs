@@ -0,0 +1,179 @@
+//! Alerts for connections sending some kind of message unusually fast, such as a client
+//! flooding the lobby server with chat or match requests.
+//!
+//! Each rule sets a limit on messages per second for one major opcode, or for each opcode
+//! separately.  Messages are counted per connection, direction, and opcode in one-second
+//! windows.  A connection that goes over a limit raises an `Alert`, then stays quiet for
+//! `COOLDOWN` so a sustained flood doesn't produce one alert per second.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+use crate::Error;
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::json;
+
+
+/// Length of a counting window, in microseconds.
+const WINDOW: u64 = 1_000_000;
+
+/// Time after an alert before the same connection and opcode can alert again, in microseconds.
+const COOLDOWN: u64 = 60_000_000;
+
+/// Connect, send, and receive timeout for webhook requests.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A limit of `limit` messages per second of major opcode `major`, or of each opcode if `None`.
+#[derive(Clone, Copy, Debug)]
+pub struct Rule {
+    pub major: Option<u8>,
+    pub limit: u32,
+}
+
+/// Parse `major=limit`, where `major` is a hex opcode or `*` for each opcode.
+pub fn parse_rule(s: &str) -> Result<Rule, Error> {
+    let i = s.find('=').ok_or_else(|| Error(format!("expected major=limit, but got {:?}", s)))?;
+    let major = match &s[..i] {
+        "*" => None,
+        x => Some(u8::from_str_radix(x.trim_start_matches("0x"), 16)
+            .map_err(|e| Error(format!("{:?}: {}", x, e)))?),
+    };
+    let limit = s[i + 1 ..].parse().map_err(|e| Error(format!("{:?}: {}", &s[i + 1 ..], e)))?;
+    Ok(Rule { major, limit })
+}
+
+#[derive(Clone, Debug)]
+pub struct Alert {
+    pub time: u64,
+    pub ct: ConnTuple,
+    pub dir: u8,
+    pub major: u8,
+    /// Messages seen in the window, which is more than `limit`.
+    pub count: u32,
+    pub limit: u32,
+}
+
+impl Alert {
+    pub fn to_json(&self) -> String {
+        let mut obj = json::Object::new();
+        self.write_json(&mut obj);
+        obj.finish()
+    }
+
+    /// Add the fields of `to_json` to `obj`, for embedding them in a larger object.
+    pub fn write_json(&self, obj: &mut json::Object) {
+        obj.num("time", self.time)
+            .str("conn", &self.ct.to_string())
+            .num("dir", self.dir)
+            .str("major", &format!("{:02x}", self.major))
+            .num("count", self.count)
+            .num("limit", self.limit);
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt, "{}: dir {} sent more than {} major {:02x} messages in one second",
+            self.ct, self.dir, self.limit, self.major,
+        )
+    }
+}
+
+#[derive(Default)]
+struct Window {
+    start: u64,
+    count: u32,
+    /// No alerts until this time.
+    quiet_until: u64,
+}
+
+pub struct RateAlerts {
+    rules: Vec<Rule>,
+    windows: HashMap<(ConnTuple, u8, u8), Window>,
+}
+
+impl RateAlerts {
+    pub fn new(rules: Vec<Rule>) -> RateAlerts {
+        RateAlerts { rules, windows: HashMap::new() }
+    }
+
+    /// The limit for `major`.  A rule for the opcode itself overrides a `*` rule.
+    fn limit(&self, major: u8) -> Option<u32> {
+        self.rules.iter().find(|r| r.major == Some(major))
+            .or_else(|| self.rules.iter().find(|r| r.major.is_none()))
+            .map(|r| r.limit)
+    }
+
+    /// Count `msg`, returning an alert if it puts its connection over a limit.
+    pub fn check(&mut self, ct: ConnTuple, msg: &Message) -> Option<Alert> {
+        let (dir, major) = (msg.header.dir, msg.header.major);
+        let limit = self.limit(major)?;
+        let now = msg.time;
+        let w = self.windows.entry((ct, dir, major)).or_default();
+        if now.saturating_sub(w.start) >= WINDOW {
+            w.start = now;
+            w.count = 0;
+        }
+        w.count += 1;
+        if w.count <= limit || now < w.quiet_until {
+            return None;
+        }
+        w.quiet_until = now + COOLDOWN;
+        Some(Alert { time: now, ct, dir, major, count: w.count, limit })
+    }
+
+    /// Forget the counts for connection `ct`.
+    pub fn close(&mut self, ct: ConnTuple) {
+        self.windows.retain(|k, _| k.0 != ct);
+    }
+}
+
+/// Check that `url` is a webhook URL `post_webhook` can handle: `http://host[:port][/path]`.
+pub fn check_webhook_url(url: &str) -> Result<(), Error> {
+    split_url(url).map(|_| ())
+}
+
+fn split_url(url: &str) -> Result<(&str, &str), Error> {
+    let rest = url.strip_prefix("http://")
+        .ok_or_else(|| Error(format!("{}: only http:// URLs are supported", url)))?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    })
+}
+
+fn try_post(url: &str, body: &str) -> Result<(), Error> {
+    let (host, path) = split_url(url)?;
+    let addr_str = if host.contains(':') { host.to_owned() } else { format!("{}:80", host) };
+    let addr = addr_str.to_socket_addrs()?.next()
+        .ok_or_else(|| Error(format!("{}: no addresses", host)))?;
+    let mut s = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+    s.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    s.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    write!(
+        s,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host, body.len(), body,
+    )?;
+
+    let mut status = [0; 12];
+    s.read_exact(&mut status)?;
+    if !status.starts_with(b"HTTP/1.") || status[9] != b'2' {
+        return Err(Error(format!("unexpected response {:?}", String::from_utf8_lossy(&status))));
+    }
+    Ok(())
+}
+
+/// POST `body`, an alert as JSON, to `url` from a background thread, logging any failure.
+pub fn post_webhook(url: &str, body: String) {
+    let url = url.to_owned();
+    thread::spawn(move || {
+        if let Err(e) = try_post(&url, &body) {
+            log!(Handler, Warn, "alert: webhook {} failed: {}", url, e);
+        }
+    });
+}
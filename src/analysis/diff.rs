@@ -0,0 +1,108 @@
+//! Comparing the messages of two sessions.
+//!
+//! Messages are grouped by direction and opcode, and the Nth message of each group in one session
+//! is paired with the Nth message of the same group in the other.  For protocols where the same
+//! exchange happens in the same order each session (login, lobby join, etc.), this lines up
+//! corresponding messages, and the differing byte ranges point at fields like player identity or
+//! match settings.
+use std::collections::BTreeMap;
+use std::ops::Range;
+use crate::tfh_stream::Message;
+
+
+/// Direction, major opcode, and minor opcode.
+pub type OpcodeKey = (u8, u8, u8);
+
+pub fn opcode_key(msg: &Message) -> OpcodeKey {
+    (msg.header.dir, msg.header.major, msg.header.minor)
+}
+
+/// One message from each session, in corresponding positions.
+pub struct Pair<'a> {
+    pub key: OpcodeKey,
+    /// Position of this pair within its opcode group.
+    pub index: usize,
+    pub a: &'a Message,
+    pub b: &'a Message,
+    /// Byte ranges where the bodies differ.  If one body is longer, its extra bytes are reported
+    /// as a final range.
+    pub diffs: Vec<Range<usize>>,
+}
+
+pub struct Alignment<'a> {
+    /// Pairs, ordered by opcode and then by position.
+    pub pairs: Vec<Pair<'a>>,
+    /// Messages with no counterpart because the other session had fewer of that opcode.
+    pub only_a: Vec<&'a Message>,
+    pub only_b: Vec<&'a Message>,
+}
+
+/// Find the ranges of `a` and `b` where the bytes differ.
+pub fn diff_bytes(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let common = a.len().min(b.len());
+    let mut start = None;
+    for i in 0 .. common {
+        match (a[i] != b[i], start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                ranges.push(s .. i);
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    if let Some(s) = start {
+        ranges.push(s .. common);
+    }
+
+    let longest = a.len().max(b.len());
+    if longest > common {
+        // Merge with a difference that runs right up to the end of the shorter body.
+        match ranges.last_mut() {
+            Some(r) if r.end == common => r.end = longest,
+            _ => ranges.push(common .. longest),
+        }
+    }
+    ranges
+}
+
+fn group<'a>(msgs: &'a [Message]) -> BTreeMap<OpcodeKey, Vec<&'a Message>> {
+    let mut groups = BTreeMap::new();
+    for msg in msgs {
+        groups.entry(opcode_key(msg)).or_insert_with(Vec::new).push(msg);
+    }
+    groups
+}
+
+pub fn align<'a>(a: &'a [Message], b: &'a [Message]) -> Alignment<'a> {
+    let mut groups_a = group(a);
+    let mut groups_b = group(b);
+
+    let mut alignment = Alignment {
+        pairs: Vec::new(),
+        only_a: Vec::new(),
+        only_b: Vec::new(),
+    };
+
+    for (key, msgs_a) in &mut groups_a {
+        let msgs_b = groups_b.remove(key).unwrap_or_default();
+        let n = msgs_a.len().min(msgs_b.len());
+        for i in 0 .. n {
+            alignment.pairs.push(Pair {
+                key: *key,
+                index: i,
+                a: msgs_a[i],
+                b: msgs_b[i],
+                diffs: diff_bytes(&msgs_a[i].body, &msgs_b[i].body),
+            });
+        }
+        alignment.only_a.extend(msgs_a.drain(n..));
+        alignment.only_b.extend(&msgs_b[n..]);
+    }
+    for (_, msgs_b) in groups_b {
+        alignment.only_b.extend(msgs_b);
+    }
+
+    alignment
+}
@@ -0,0 +1,159 @@
+//! Guessing field layouts from per-offset byte statistics.
+//!
+//! Given many bodies of the same opcode, each byte offset is summarized by how many distinct
+//! values it takes and its Shannon entropy.  Runs of offsets that behave alike are grouped into
+//! regions: constant bytes (magic numbers, reserved fields), text, and variable data.  These are
+//! only hints, but they usually make the rough shape of an unknown message obvious.
+use std::ops::Range;
+use crate::util::dump;
+
+
+#[derive(Clone, Debug)]
+pub struct OffsetStats {
+    /// Number of bodies long enough to include this offset.
+    pub count: usize,
+    /// Number of times each byte value occurred.
+    pub hist: Box<[u32; 256]>,
+}
+
+impl OffsetStats {
+    fn new() -> OffsetStats {
+        OffsetStats { count: 0, hist: Box::new([0; 256]) }
+    }
+
+    pub fn distinct(&self) -> usize {
+        self.hist.iter().filter(|&&n| n > 0).count()
+    }
+
+    /// Shannon entropy of the byte values seen here, in bits (0 to 8).
+    pub fn entropy(&self) -> f64 {
+        if self.count == 0 {
+            return 0.;
+        }
+        let total = self.count as f64;
+        -self.hist.iter()
+            .filter(|&&n| n > 0)
+            .map(|&n| {
+                let p = n as f64 / total;
+                p * p.log2()
+            })
+            .sum::<f64>()
+    }
+
+    pub fn min(&self) -> Option<u8> {
+        self.hist.iter().position(|&n| n > 0).map(|i| i as u8)
+    }
+
+    pub fn max(&self) -> Option<u8> {
+        self.hist.iter().rposition(|&n| n > 0).map(|i| i as u8)
+    }
+
+    /// Whether every value seen here is printable ASCII or NUL, with at least one printable.
+    fn is_text(&self) -> bool {
+        let mut printable = false;
+        for (x, &n) in self.hist.iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            if dump::is_printable_ascii(x as u8) {
+                printable = true;
+            } else if x != 0 {
+                return false;
+            }
+        }
+        printable
+    }
+
+    fn kind(&self) -> RegionKind {
+        if self.distinct() <= 1 {
+            RegionKind::Constant
+        } else if self.is_text() {
+            RegionKind::Text
+        } else {
+            RegionKind::Variable
+        }
+    }
+}
+
+/// Collect per-offset statistics.  The result is as long as the longest body.
+pub fn offset_stats<'a>(bodies: impl IntoIterator<Item = &'a [u8]>) -> Vec<OffsetStats> {
+    let mut stats = Vec::new();
+    for body in bodies {
+        while stats.len() < body.len() {
+            stats.push(OffsetStats::new());
+        }
+        for (s, &x) in stats.iter_mut().zip(body.iter()) {
+            s.count += 1;
+            s.hist[x as usize] += 1;
+        }
+    }
+    stats
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionKind {
+    /// Only one value was ever seen at each offset.
+    Constant,
+    /// Printable ASCII, possibly NUL-padded.
+    Text,
+    Variable,
+}
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub range: Range<usize>,
+    pub kind: RegionKind,
+    /// Average entropy of the offsets in this region, in bits.
+    pub entropy: f64,
+    /// Whether some bodies ended before the end of this region.
+    pub partial: bool,
+}
+
+/// Entropy increase between neighboring variable bytes that suggests a new field starts.  The
+/// low byte of a little-endian integer varies much more than the high byte of the previous one.
+const ENTROPY_STEP: f64 = 2.;
+
+/// Split the offsets into regions of similar behavior.
+pub fn regions(stats: &[OffsetStats]) -> Vec<Region> {
+    let full_count = stats.first().map_or(0, |s| s.count);
+    let mut regions: Vec<Region> = Vec::new();
+    let mut entropy_sum = 0.;
+
+    for (i, s) in stats.iter().enumerate() {
+        let kind = s.kind();
+        let entropy = s.entropy();
+        let partial = s.count < full_count;
+
+        let extend = match regions.last() {
+            Some(r) => {
+                let prev = &stats[i - 1];
+                r.kind == kind && r.partial == partial && match kind {
+                    // Constant runs break wherever the set of bodies covering them changes.
+                    RegionKind::Constant => prev.count == s.count,
+                    RegionKind::Text => true,
+                    RegionKind::Variable => entropy - prev.entropy() < ENTROPY_STEP,
+                }
+            },
+            None => false,
+        };
+
+        if extend {
+            let r = regions.last_mut().unwrap();
+            r.range.end = i + 1;
+            entropy_sum += entropy;
+        } else {
+            finish_region(regions.last_mut(), entropy_sum);
+            regions.push(Region { range: i .. i + 1, kind, entropy: 0., partial });
+            entropy_sum = entropy;
+        }
+    }
+    finish_region(regions.last_mut(), entropy_sum);
+
+    regions
+}
+
+fn finish_region(r: Option<&mut Region>, entropy_sum: f64) {
+    if let Some(r) = r {
+        r.entropy = entropy_sum / r.range.len() as f64;
+    }
+}
@@ -0,0 +1,3 @@
+//! Helpers for reverse engineering message layouts from recorded sessions.
+pub mod diff;
+pub mod fields;
@@ -0,0 +1,115 @@
+//! Privacy mode, for logs and captures that will be shared.
+//!
+//! Client IPs are replaced before anything is written, so log filenames, tfhlog records, the chat,
+//! match, and rating logs, and the console messages about each connection never contain the real
+//! address.  The relay's own console messages (STUN, SDR, rate limiting, and `--proxy` clients)
+//! still do.  Server IPs and all ports are kept.  Optionally, the player name in each login
+//! message is replaced with a pseudonym, which then flows into `status.txt` and everything else
+//! keyed by name.  The names in the lobby roster get the same pseudonyms, so `--check-roster` can
+//! still match them up.
+//!
+//! Hashing uses a key chosen at random when the relay starts, so pseudonyms are consistent
+//! within a run but can't be linked across runs or reversed by trying every address.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::str::FromStr;
+use crate::Error;
+use crate::messages::{self, Login, Presence, Roster};
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IpMode {
+    /// Replace each client IP with an address in 10.0.0.0/8 derived from its hash.
+    Hash,
+    /// Zero the low 16 bits, keeping only the network.
+    Mask,
+}
+
+impl FromStr for IpMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<IpMode, Error> {
+        match s {
+            "hash" => Ok(IpMode::Hash),
+            "mask" => Ok(IpMode::Mask),
+            _ => Err(Error(format!("unknown anonymize mode {:?} (expected hash or mask)", s))),
+        }
+    }
+}
+
+pub struct Anonymizer {
+    ip_mode: Option<IpMode>,
+    redact_names: bool,
+    key: RandomState,
+}
+
+impl Anonymizer {
+    pub fn new(ip_mode: Option<IpMode>, redact_names: bool) -> Anonymizer {
+        Anonymizer {
+            ip_mode,
+            redact_names,
+            key: RandomState::new(),
+        }
+    }
+
+    fn hash(&self, x: impl Hash) -> u64 {
+        let mut h = self.key.build_hasher();
+        x.hash(&mut h);
+        h.finish()
+    }
+
+    pub fn ip(&self, ip: u32) -> u32 {
+        match self.ip_mode {
+            Some(IpMode::Hash) => 0x0a00_0000 | (self.hash(ip) as u32 & 0x00ff_ffff),
+            Some(IpMode::Mask) => ip & 0xffff_0000,
+            None => ip,
+        }
+    }
+
+    /// Anonymize the client side of `ct`.
+    pub fn conn(&self, ct: ConnTuple) -> ConnTuple {
+        match ct {
+            ConnTuple::Ipv4(ci, cp, si, sp) => ConnTuple::Ipv4(self.ip(ci), cp, si, sp),
+        }
+    }
+
+    /// A stable stand-in for the player name `name`.
+    pub fn name(&self, name: &str) -> String {
+        format!("player-{:08x}", self.hash(name) as u32)
+    }
+
+    /// Replace the names in `msg`, if names are being redacted and it's a login message or a
+    /// lobby roster or presence update.
+    pub fn redact(&self, msg: &mut Message) {
+        if !self.redact_names {
+            return;
+        }
+        if Roster::matches(msg) || Presence::matches(msg) {
+            for field in msg.body.chunks_exact_mut(Login::NAME_LEN) {
+                let name = messages::nul_padded_str(field);
+                if !name.is_empty() {
+                    self.replace(field, &name);
+                }
+            }
+            return;
+        }
+        if !Login::matches(msg) {
+            return;
+        }
+        let login = match Login::parse(&msg.body) {
+            Some(x) => x,
+            None => return,
+        };
+        let field = &mut msg.body[Login::NAME_OFFSET .. Login::NAME_OFFSET + Login::NAME_LEN];
+        self.replace(field, &login.name);
+    }
+
+    /// Overwrite the NUL-padded name field `field`, which holds `name`, with its pseudonym.
+    fn replace(&self, field: &mut [u8], name: &str) {
+        let pseudonym = self.name(name);
+        for b in field.iter_mut() {
+            *b = 0;
+        }
+        field[..pseudonym.len()].copy_from_slice(pseudonym.as_bytes());
+    }
+}
@@ -0,0 +1,127 @@
+//! The frames that hold packet data.  Each frame has room for one packet of up to `PACKET_CAP`
+//! bytes and a reference count, so several `Packet`s can share the same bytes: a packet can be
+//! logged, mirrored, and forwarded at once without copying it.  The bytes are only copied when
+//! one of the holders changes them.
+//!
+//! With `std`, freed frames go on a free list for reuse instead of back to the allocator, so a
+//! relay moving thousands of packets a second doesn't allocate for each one.  Without it, there's
+//! no lock to guard the list with, so frames are always freed.
+use alloc::boxed::Box;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+use crate::packet::PACKET_CAP;
+
+
+/// Most free frames to keep for reuse.  Beyond this, freed frames go back to the allocator.
+const MAX_FREE: usize = 8192;
+
+struct Frame {
+    data: MaybeUninit<[u8; PACKET_CAP]>,
+    refs: AtomicUsize,
+}
+
+/// A frame on the free list, which no `FrameRef` points to.
+#[cfg(feature = "std")]
+struct FreeFrame(NonNull<Frame>);
+
+#[cfg(feature = "std")]
+unsafe impl Send for FreeFrame {}
+
+#[cfg(feature = "std")]
+static FREE: std::sync::Mutex<Vec<FreeFrame>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(feature = "std")]
+fn take_free() -> Option<NonNull<Frame>> {
+    FREE.lock().unwrap().pop().map(|f| f.0)
+}
+
+/// Put `frame` on the free list, unless it's full.  Returns whether it was kept.
+#[cfg(feature = "std")]
+fn put_free(frame: NonNull<Frame>) -> bool {
+    let mut free = FREE.lock().unwrap();
+    if free.len() >= MAX_FREE {
+        return false;
+    }
+    free.push(FreeFrame(frame));
+    true
+}
+
+#[cfg(not(feature = "std"))]
+fn take_free() -> Option<NonNull<Frame>> {
+    None
+}
+
+#[cfg(not(feature = "std"))]
+fn put_free(_frame: NonNull<Frame>) -> bool {
+    false
+}
+
+
+/// A counted reference to a frame.  Cloning it shares the frame; the frame is freed when the last
+/// reference is dropped.
+pub struct FrameRef(NonNull<Frame>);
+
+unsafe impl Send for FrameRef {}
+unsafe impl Sync for FrameRef {}
+
+impl FrameRef {
+    /// Take a frame from the free list, or allocate a new one.  Its contents are uninitialized.
+    pub fn alloc() -> FrameRef {
+        let frame = take_free().unwrap_or_else(|| {
+            NonNull::from(Box::leak(Box::new(Frame {
+                data: MaybeUninit::uninit(),
+                refs: AtomicUsize::new(0),
+            })))
+        });
+        let f = FrameRef(frame);
+        f.frame().refs.store(1, Ordering::Relaxed);
+        f
+    }
+
+    fn frame(&self) -> &Frame {
+        unsafe { self.0.as_ref() }
+    }
+
+    /// Whether this is the only reference to the frame.
+    pub fn is_unique(&self) -> bool {
+        self.frame().refs.load(Ordering::Acquire) == 1
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        unsafe { ptr::addr_of!((*self.0.as_ptr()).data) as *const u8 }
+    }
+
+    /// Get a pointer for writing to the frame, first moving to a frame of our own if it's
+    /// shared.  Only the first `len` bytes are copied to the new frame.
+    pub fn make_mut(&mut self, len: usize) -> *mut u8 {
+        assert!(len <= PACKET_CAP);
+        if !self.is_unique() {
+            let new = FrameRef::alloc();
+            let dst = unsafe { ptr::addr_of_mut!((*new.0.as_ptr()).data) as *mut u8 };
+            unsafe { ptr::copy_nonoverlapping(self.as_ptr(), dst, len) };
+            *self = new;
+        }
+        unsafe { ptr::addr_of_mut!((*self.0.as_ptr()).data) as *mut u8 }
+    }
+}
+
+impl Clone for FrameRef {
+    fn clone(&self) -> FrameRef {
+        self.frame().refs.fetch_add(1, Ordering::Relaxed);
+        FrameRef(self.0)
+    }
+}
+
+impl Drop for FrameRef {
+    fn drop(&mut self) {
+        if self.frame().refs.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        // Make sure other holders' reads of the frame are done before it's reused.
+        atomic::fence(Ordering::Acquire);
+        if !put_free(self.0) {
+            drop(unsafe { Box::from_raw(self.0.as_ptr()) });
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use tfh_mitm::Error;
+use tfh_mitm::analysis::diff::{self, Pair};
+use tfh_mitm::filter::MessageFilter;
+use tfh_mitm::tfh_stream::Message;
+use tfh_mitm::tfhlog;
+
+
+const USAGE: &str = "usage: tfh log-diff [options] a.tfhlog b.tfhlog
+
+Pairs up messages with the same direction and opcode from two sessions, in the order they were
+sent, and shows the byte ranges where their bodies differ.
+
+options:
+  --major 0a,14         only these major opcodes (hex)
+  --dir 0|1             only client-to-server (0) or server-to-client (1) messages
+  --all                 also list pairs whose bodies are identical";
+
+const BYTES_PER_LINE: usize = 16;
+
+struct Options {
+    filter: MessageFilter,
+    all: bool,
+    inputs: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut opts = Options {
+        filter: MessageFilter::default(),
+        all: false,
+        inputs: Vec::new(),
+    };
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("-") {
+            opts.inputs.push(arg.clone());
+            continue;
+        }
+
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+
+        match &arg[..] {
+            "--major" => {
+                let majors = value()?.split(',')
+                    .map(|x| u8::from_str_radix(x, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Error(format!("--major: {}", e)))?;
+                opts.filter.majors = Some(majors);
+            },
+            "--dir" => {
+                let dir = value()?.parse().map_err(|e| Error(format!("--dir: {}", e)))?;
+                opts.filter.dir = Some(dir);
+            },
+            "--all" => opts.all = true,
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if opts.inputs.len() != 2 {
+        return Err(USAGE.into());
+    }
+    Ok(opts)
+}
+
+fn read_messages(name: &str, filter: &MessageFilter) -> Result<Vec<Message>, Error> {
+    let reader = tfhlog::open(name)?;
+    let mut msgs = Vec::new();
+    for r in reader {
+        let r = r.map_err(|e| Error(format!("{}: {}", name, e)))?;
+        if filter.matches_msg(&r.msg) {
+            msgs.push(r.msg);
+        }
+    }
+    Ok(msgs)
+}
+
+fn format_ranges(ranges: &[Range<usize>]) -> String {
+    ranges.iter().map(|r| format!("{}..{}", r.start, r.end)).collect::<Vec<_>>().join(", ")
+}
+
+fn hex_row(body: &[u8], line: Range<usize>) -> String {
+    let mut s = String::new();
+    for i in line {
+        match body.get(i) {
+            Some(x) => write!(s, " {:02x}", x).unwrap(),
+            None => s.push_str("   "),
+        }
+    }
+    s
+}
+
+/// Print the lines of both bodies that contain a difference, with a row of carets under the
+/// differing bytes.
+fn print_pair(out: &mut impl Write, pair: &Pair) -> io::Result<()> {
+    let (dir, major, minor) = pair.key;
+    write!(
+        out, "{} {:02x}:{:02x} #{} (messages a#{}, b#{})",
+        dir, major, minor, pair.index, pair.a.index, pair.b.index,
+    )?;
+    if pair.diffs.len() == 0 {
+        return writeln!(out, ": identical ({} bytes)", pair.a.body.len());
+    }
+    writeln!(
+        out, ": {} vs {} bytes, differs at {}",
+        pair.a.body.len(), pair.b.body.len(), format_ranges(&pair.diffs),
+    )?;
+
+    let len = pair.a.body.len().max(pair.b.body.len());
+    for start in (0 .. len).step_by(BYTES_PER_LINE) {
+        let line = start .. (start + BYTES_PER_LINE).min(len);
+        let differs = |i: usize| pair.diffs.iter().any(|r| r.contains(&i));
+        if !line.clone().any(differs) {
+            continue;
+        }
+
+        let marks = line.clone()
+            .map(|i| if differs(i) { " ^^" } else { "   " })
+            .collect::<String>();
+        writeln!(out, "  {:04x}  a:{}", start, hex_row(&pair.a.body, line.clone()))?;
+        writeln!(out, "        b:{}", hex_row(&pair.b.body, line.clone()))?;
+        writeln!(out, "          {}", marks.trim_end())?;
+    }
+    Ok(())
+}
+
+fn print_unpaired(out: &mut impl Write, label: &str, msgs: &[&Message]) -> io::Result<()> {
+    for msg in msgs {
+        let h = &msg.header;
+        writeln!(
+            out, "only in {}: {} {:02x}:{:02x} #{} ({} bytes)",
+            label, h.dir, h.major, h.minor, msg.index, msg.body.len(),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+
+    let msgs_a = read_messages(&opts.inputs[0], &opts.filter)?;
+    let msgs_b = read_messages(&opts.inputs[1], &opts.filter)?;
+    let alignment = diff::align(&msgs_a, &msgs_b);
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut identical = 0;
+    for pair in &alignment.pairs {
+        if pair.diffs.len() == 0 {
+            identical += 1;
+            if !opts.all {
+                continue;
+            }
+        }
+        print_pair(&mut out, pair)?;
+    }
+    print_unpaired(&mut out, "a", &alignment.only_a)?;
+    print_unpaired(&mut out, "b", &alignment.only_b)?;
+    out.flush()?;
+
+    eprintln!(
+        "{} pairs ({} identical), {} only in a, {} only in b",
+        alignment.pairs.len(), identical, alignment.only_a.len(), alignment.only_b.len(),
+    );
+    Ok(())
+}
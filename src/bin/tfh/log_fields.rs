@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufWriter, Write};
+use tfh_mitm::Error;
+use tfh_mitm::analysis::diff::{self, OpcodeKey};
+use tfh_mitm::analysis::fields::{self, RegionKind};
+use tfh_mitm::filter::MessageFilter;
+use tfh_mitm::tfh_stream::Message;
+use tfh_mitm::tfhlog;
+use tfh_mitm::util::dump::{self, DumpOptions};
+
+
+const USAGE: &str = "usage: tfh log-fields [options] in.tfhlog...
+
+Collects byte statistics for each offset of each opcode's messages, and splits the bodies into
+constant, text, and variable regions as a hint at the field layout.
+
+options:
+  --major 0a,14         only these major opcodes (hex)
+  --dir 0|1             only client-to-server (0) or server-to-client (1) messages
+  --min-count n         skip opcodes with fewer than n messages (default 2)
+  --offsets             also print the statistics for every offset";
+
+/// Longest constant region whose bytes are printed in full.
+const MAX_SHOWN: usize = 16;
+
+struct Options {
+    filter: MessageFilter,
+    min_count: usize,
+    offsets: bool,
+    inputs: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut opts = Options {
+        filter: MessageFilter::default(),
+        min_count: 2,
+        offsets: false,
+        inputs: Vec::new(),
+    };
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("-") {
+            opts.inputs.push(arg.clone());
+            continue;
+        }
+
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+
+        match &arg[..] {
+            "--major" => {
+                let majors = value()?.split(',')
+                    .map(|x| u8::from_str_radix(x, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Error(format!("--major: {}", e)))?;
+                opts.filter.majors = Some(majors);
+            },
+            "--dir" => {
+                let dir = value()?.parse().map_err(|e| Error(format!("--dir: {}", e)))?;
+                opts.filter.dir = Some(dir);
+            },
+            "--min-count" => {
+                opts.min_count = value()?.parse()
+                    .map_err(|e| Error(format!("--min-count: {}", e)))?;
+            },
+            "--offsets" => opts.offsets = true,
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if opts.inputs.len() == 0 {
+        return Err(USAGE.into());
+    }
+    Ok(opts)
+}
+
+fn print_group(
+    out: &mut impl Write,
+    opts: &Options,
+    key: OpcodeKey,
+    msgs: &[Message],
+    sent: u64,
+) -> io::Result<()> {
+    let (dir, major, minor) = key;
+    let min_len = msgs.iter().map(|m| m.body.len()).min().unwrap_or(0);
+    let max_len = msgs.iter().map(|m| m.body.len()).max().unwrap_or(0);
+    write!(
+        out, "{} {:02x}:{:02x}: {} messages, {}..{} bytes",
+        dir, major, minor, msgs.len(), min_len, max_len,
+    )?;
+    if sent != msgs.len() as u64 {
+        write!(out, " (sampled from about {})", sent)?;
+    }
+    writeln!(out)?;
+
+    let stats = fields::offset_stats(msgs.iter().map(|m| &m.body[..]));
+    for r in fields::regions(&stats) {
+        let kind = match r.kind {
+            RegionKind::Constant => "constant",
+            RegionKind::Text => "text",
+            RegionKind::Variable => "variable",
+        };
+        let detail = match r.kind {
+            RegionKind::Constant => {
+                let bytes = stats[r.range.clone()].iter()
+                    .map(|s| s.min().unwrap_or(0))
+                    .collect::<Vec<_>>();
+                let opts = DumpOptions { max_len: Some(MAX_SHOWN), .. DumpOptions::default() };
+                dump::hex_with(&bytes, &opts)
+            },
+            RegionKind::Text => {
+                // Show a sample from the first message that covers the whole region.
+                let sample = msgs.iter()
+                    .find(|m| m.body.len() >= r.range.end)
+                    .map_or(&[][..], |m| &m.body[r.range.clone()]);
+                let end = sample.iter().position(|&x| x == 0).unwrap_or(sample.len());
+                format!("{:.2} bits, e.g. {:?}", r.entropy, String::from_utf8_lossy(&sample[..end]))
+            },
+            RegionKind::Variable => format!("{:.2} bits", r.entropy),
+        };
+        writeln!(
+            out, "  {:5}..{:<5} {:8} {}{}",
+            r.range.start, r.range.end, kind, detail,
+            if r.partial { " (not in all messages)" } else { "" },
+        )?;
+    }
+
+    if opts.offsets {
+        writeln!(out, "  offset  count  distinct  entropy  min  max")?;
+        for (i, s) in stats.iter().enumerate() {
+            writeln!(
+                out, "  {:6}  {:5}  {:8}  {:7.2}   {:02x}   {:02x}",
+                i, s.count, s.distinct(), s.entropy(),
+                s.min().unwrap_or(0), s.max().unwrap_or(0),
+            )?;
+        }
+    }
+    writeln!(out)
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+
+    let mut groups = BTreeMap::new();
+    for name in &opts.inputs {
+        let mut reader = tfhlog::open(name)?;
+        while let Some(r) = reader.read().map_err(|e| Error(format!("{}: {}", name, e)))? {
+            if opts.filter.matches_msg(&r.msg) {
+                // Messages of sampled opcodes count for all the ones that weren't logged.
+                let rate = reader.sample_rate(&r.msg) as u64;
+                let group = groups.entry(diff::opcode_key(&r.msg))
+                    .or_insert_with(|| (Vec::new(), 0));
+                group.0.push(r.msg);
+                group.1 += rate;
+            }
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for (key, (msgs, sent)) in &groups {
+        if msgs.len() >= opts.min_count {
+            print_group(&mut out, &opts, *key, msgs, *sent)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
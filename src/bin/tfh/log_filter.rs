@@ -0,0 +1,357 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use tfh_mitm::Error;
+use tfh_mitm::export::{self, Field};
+use tfh_mitm::filter::{self, MessageFilter};
+use tfh_mitm::log_index::{self, Entry};
+use tfh_mitm::pcap;
+use tfh_mitm::session::Players;
+use tfh_mitm::tfh_stream::ConnTuple;
+use tfh_mitm::tfhlog::{self, Record};
+use tfh_mitm::util::dump::{self, DumpOptions};
+use tfh_mitm::util::hex;
+
+
+const USAGE: &str = "usage: tfh log-filter [options] in.tfhlog...
+
+Selects messages from one or more logs.  Matching messages are printed, or written to a new log
+with `-o`.  When a log has an index (see `relay --log-index` and `tfh log-index`), selecting by
+time, opcode, or direction reads only the messages the index shows could match.
+
+options:
+  --major 0a,14         only these major opcodes (hex)
+  --dir 0|1             only client-to-server (0) or server-to-client (1) messages
+  --conn ip[:port]      only connections with this endpoint
+  --player name         only sessions of the player who logged in with this name
+  --contains hex        only messages whose body contains these bytes
+  --index n[-m]         only messages with these indices within their connection and direction
+  --since secs          only messages at or after this Unix time
+  --until secs          only messages before this Unix time
+  --hexdump             print bodies as multi-line hex dumps
+  -o out.tfhlog         write matching messages to a file instead of printing them
+  --format fmt          format for `-o`: tfhlog (default); pcap for a capture with one
+                        message per record, for use with wireshark/tfh_message.lua; csv or
+                        parquet for a table of message metadata
+  --field name=off:type for csv and parquet, also decode this field from each body.  Types
+                        are u8, u16, u32, u64, u16be, u32be, hexN, and strN (NUL-padded)";
+
+#[derive(Clone, Copy)]
+enum Format {
+    Tfhlog,
+    Pcap,
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+enum Output {
+    Log(tfhlog::Writer<BufWriter<File>>),
+    Pcap(pcap::Writer<BufWriter<File>>),
+    Csv(export::csv::Writer<BufWriter<File>>),
+    #[cfg(feature = "parquet")]
+    Parquet(export::parquet::Writer),
+}
+
+impl Output {
+    fn create(
+        name: &str,
+        format: Format,
+        fields: &[Field],
+        sampling: &tfhlog::Sampling,
+    ) -> Result<Output, Error> {
+        let file = File::create(name)?;
+        let fields = fields.to_owned();
+        Ok(match format {
+            Format::Tfhlog => {
+                Output::Log(tfhlog::Writer::with_sampling(BufWriter::new(file), sampling)?)
+            },
+            Format::Pcap => Output::Pcap(
+                pcap::Writer::new(BufWriter::new(file), pcap::LINKTYPE_TFH_MESSAGE)?),
+            Format::Csv => Output::Csv(export::csv::Writer::new(BufWriter::new(file), fields)?),
+            // The parquet writer does its own buffering.
+            #[cfg(feature = "parquet")]
+            Format::Parquet => Output::Parquet(export::parquet::Writer::new(file, fields)?),
+        })
+    }
+
+    fn write(&mut self, r: &Record) -> Result<(), Error> {
+        match *self {
+            Output::Log(ref mut w) => w.write_record(r)?,
+            Output::Pcap(ref mut w) => {
+                let ct = r.conn.ok_or("pcap output needs connection info, which old logs lack")?;
+                w.write_message(r.time, ct, &r.msg)?;
+            },
+            Output::Csv(ref mut w) => w.write_record(r)?,
+            #[cfg(feature = "parquet")]
+            Output::Parquet(ref mut w) => w.write_record(r)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            Output::Log(mut w) => w.flush()?,
+            Output::Pcap(mut w) => w.flush()?,
+            Output::Csv(mut w) => w.flush()?,
+            #[cfg(feature = "parquet")]
+            Output::Parquet(w) => w.finish()?,
+        }
+        Ok(())
+    }
+}
+
+struct Options {
+    filter: MessageFilter,
+    player: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    hexdump: bool,
+    output: Option<String>,
+    format: Format,
+    fields: Vec<Field>,
+    inputs: Vec<String>,
+}
+
+/// Parse a Unix timestamp in seconds, possibly fractional, into microseconds.
+fn parse_time(s: &str) -> Result<u64, Error> {
+    let secs = s.parse::<f64>().map_err(|e| Error(format!("bad time {:?}: {}", s, e)))?;
+    Ok((secs * 1e6) as u64)
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut opts = Options {
+        filter: MessageFilter::default(),
+        player: None,
+        since: None,
+        until: None,
+        hexdump: false,
+        output: None,
+        format: Format::Tfhlog,
+        fields: Vec::new(),
+        inputs: Vec::new(),
+    };
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("-") {
+            opts.inputs.push(arg.clone());
+            continue;
+        }
+
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+
+        match &arg[..] {
+            "--major" => {
+                let majors = value()?.split(',')
+                    .map(|x| u8::from_str_radix(x, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Error(format!("--major: {}", e)))?;
+                opts.filter.majors = Some(majors);
+            },
+            "--dir" => {
+                let dir = value()?.parse().map_err(|e| Error(format!("--dir: {}", e)))?;
+                opts.filter.dir = Some(dir);
+            },
+            "--conn" => {
+                let conn = filter::parse_endpoint(&value()?)
+                    .map_err(|e| Error(format!("--conn: {}", e)))?;
+                opts.filter.conn = Some(conn);
+            },
+            "--player" => opts.player = Some(value()?),
+            "--contains" => {
+                let pat = hex::parse(&value()?).map_err(|e| Error(format!("--contains: {}", e)))?;
+                opts.filter.contains = Some(pat);
+            },
+            "--index" => {
+                let range = filter::parse_index_range(&value()?)
+                    .map_err(|e| Error(format!("--index: {}", e)))?;
+                opts.filter.index = Some(range);
+            },
+            "--since" => opts.since = Some(parse_time(&value()?)?),
+            "--until" => opts.until = Some(parse_time(&value()?)?),
+            "--hexdump" => opts.hexdump = true,
+            "-o" => opts.output = Some(value()?),
+            "--format" => {
+                opts.format = match &value()?[..] {
+                    "tfhlog" => Format::Tfhlog,
+                    "pcap" => Format::Pcap,
+                    "csv" => Format::Csv,
+                    #[cfg(feature = "parquet")]
+                    "parquet" => Format::Parquet,
+                    #[cfg(not(feature = "parquet"))]
+                    "parquet" => return Err(
+                        "--format: parquet support requires the `parquet` feature".into()),
+                    f => return Err(Error(format!("--format: unknown format {:?}", f))),
+                };
+            },
+            "--field" => {
+                let field = Field::parse(&value()?).map_err(|e| Error(format!("--field: {}", e)))?;
+                opts.fields.push(field);
+            },
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if opts.inputs.len() == 0 {
+        return Err(USAGE.into());
+    }
+    Ok(opts)
+}
+
+fn record_matches(opts: &Options, players: &Players, r: &Record) -> bool {
+    if let Some(ref player) = opts.player {
+        if players.name(r) != Some(player) {
+            return false;
+        }
+    }
+    if let Some(since) = opts.since {
+        if r.time < since {
+            return false;
+        }
+    }
+    if let Some(until) = opts.until {
+        if r.time >= until {
+            return false;
+        }
+    }
+    if !opts.filter.matches_msg(&r.msg) {
+        return false;
+    }
+    match r.conn {
+        Some(ct) => opts.filter.matches_conn(ct),
+        // Old logs don't record the connection, so a connection filter can't match them.
+        None => opts.filter.conn.is_none(),
+    }
+}
+
+/// Whether the record of `e` could match, going by what the index records.
+fn entry_matches(opts: &Options, e: &Entry) -> bool {
+    opts.since.map_or(true, |since| e.time >= since) &&
+        opts.until.map_or(true, |until| e.time < until) &&
+        opts.filter.majors.as_ref().map_or(true, |majors| majors.contains(&e.major)) &&
+        opts.filter.dir.map_or(true, |dir| dir == e.dir)
+}
+
+/// The entries of the index of the log `name`, if it has one and the options select by something
+/// it records.
+fn load_index(opts: &Options, name: &str) -> Result<Option<Vec<Entry>>, Error> {
+    if opts.since.is_none() && opts.until.is_none() && opts.filter.majors.is_none() &&
+            opts.filter.dir.is_none() {
+        return Ok(None);
+    }
+    let path = log_index::path(name);
+    if fs::metadata(&path).is_err() {
+        return Ok(None);
+    }
+    let len = fs::metadata(name).map_err(|e| Error(format!("{}: {}", name, e)))?.len();
+    match log_index::load(&path, len) {
+        Ok(x) => Ok(Some(x)),
+        Err(e) => {
+            eprintln!("{}: not using the index: {}", path, e);
+            Ok(None)
+        },
+    }
+}
+
+fn print_record(out: &mut impl Write, opts: &Options, r: &Record) -> io::Result<()> {
+    let mut conn = r.conn.as_ref().map_or_else(|| "?".to_owned(), ConnTuple::to_string);
+    if let Some(session) = r.session {
+        conn = format!("{}/{}", conn, session);
+    }
+    let h = &r.msg.header;
+    write!(
+        out, "{}.{:06} {} #{} {} {:02x}:{:02x} ack={}{} len={}",
+        r.time / 1_000_000, r.time % 1_000_000, conn, r.msg.index, h.dir, h.major, h.minor,
+        h.ack, r.msg.acks_label(), h.len,
+    )?;
+    if opts.hexdump {
+        writeln!(out)?;
+        writeln!(out, "{}", dump::hex_with(&r.msg.body, &DumpOptions::lines()))
+    } else {
+        writeln!(out, " {}", dump::mixed(&r.msg.body))
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+
+    // A tfhlog output carries over the inputs' sampling rates, so they have to agree.
+    let mut sampling = None;
+    if let (Some(_), Format::Tfhlog) = (&opts.output, opts.format) {
+        for name in &opts.inputs {
+            let reader = tfhlog::open(name)?;
+            let s = sampling.get_or_insert_with(|| reader.sampling().to_owned());
+            if &s[..] != reader.sampling() {
+                return Err(Error(format!("{}: sampled differently from {}", name, opts.inputs[0])));
+            }
+        }
+    }
+
+    let mut file_out = match opts.output {
+        Some(ref name) => {
+            let sampling = sampling.as_ref().map_or(&[][..], |s| &s[..]);
+            Some(Output::create(name, opts.format, &opts.fields, sampling)?)
+        },
+        None => None,
+    };
+    let stdout = io::stdout();
+    let mut text_out = BufWriter::new(stdout.lock());
+
+    // The login can be in a different file from the rest of the session, so find every player's
+    // sessions before filtering.
+    let mut players = Players::default();
+    if let Some(ref player) = opts.player {
+        for name in &opts.inputs {
+            let reader = tfhlog::open(name)?;
+            for r in reader {
+                players.add(&r.map_err(|e| Error(format!("{}: {}", name, e)))?);
+            }
+        }
+        let sessions = players.sessions(player);
+        if sessions.len() == 0 {
+            return Err(Error(format!("no login as {:?} in these logs", player)));
+        }
+        for (ct, session) in sessions {
+            eprintln!("{} logged in on {}/{}", player, ct,
+                session.map_or_else(|| "?".to_owned(), |s| s.to_string()));
+        }
+    }
+
+    let mut emit = |r: &Record| -> Result<(), Error> {
+        if record_matches(&opts, &players, r) {
+            match file_out {
+                Some(ref mut w) => w.write(r)?,
+                None => print_record(&mut text_out, &opts, r)?,
+            }
+        }
+        Ok(())
+    };
+    for name in &opts.inputs {
+        let mut reader = tfhlog::open(name)?;
+        // Read just the records the index picks out, then whatever it doesn't cover yet.
+        if let Some(entries) = load_index(&opts, name)? {
+            let end = log_index::end(&entries, reader.position());
+            for e in entries.iter().filter(|e| entry_matches(&opts, e)) {
+                reader.seek(e.offset)?;
+                let r = reader.read().map_err(|e| Error(format!("{}: {}", name, e)))?;
+                match r {
+                    Some(ref r) if r.time == e.time => emit(r)?,
+                    _ => return Err(Error(format!(
+                        "{}: doesn't match its index; rebuild it with `tfh log-index`", name))),
+                }
+            }
+            reader.seek(end)?;
+        }
+        for r in reader {
+            emit(&r.map_err(|e| Error(format!("{}: {}", name, e)))?)?;
+        }
+    }
+
+    if let Some(w) = file_out {
+        w.finish()?;
+    }
+    text_out.flush()?;
+    Ok(())
+}
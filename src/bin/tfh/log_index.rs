@@ -0,0 +1,21 @@
+use tfh_mitm::Error;
+use tfh_mitm::log_index;
+
+
+const USAGE: &str = "usage: tfh log-index in.tfhlog...
+
+Writes an index next to each log, as `in.tfhlog.idx`, as `relay --log-index` does for the logs it
+writes, so `tfh log-filter` can select by time, opcode, or direction without reading the whole
+log.  An existing index is replaced.";
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let names = &args[1..];
+    if names.len() == 0 || names.iter().any(|a| a.starts_with("--")) {
+        return Err(USAGE.into());
+    }
+    for name in names {
+        let count = log_index::build(name).map_err(|e| Error(format!("{}: {}", name, e)))?;
+        println!("{}: indexed {} records", name, count);
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::Ipv4Addr;
+use std::vec;
+use tfh_mitm::{Error, ErrorAt};
+use tfh_mitm::process;
+use tfh_mitm::skew::{self, EventKey};
+use tfh_mitm::tfhlog::{self, Record};
+
+
+const USAGE: &str = "usage: tfh log-merge [options] out.tfhlog in1.tfhlog [in2.tfhlog|in2.pcap...]
+
+Merges the messages of several logs into one, in time order.  Inputs ending in `.pcap` are
+captures, decoded as `replay-pcap` would.
+
+options:
+  --server 192.168.84.2    the server's address, needed to decode captures
+  --offset FILE=OFFSET     add OFFSET to the times from input FILE, as seconds (`-1.5`) or with a
+                           unit of s, ms, or us (`250ms`), to correct for its clock
+  --align                  estimate each input's offset from the first input, using the messages
+                           both contain; inputs with an --offset keep it";
+
+struct Options {
+    server: Option<Ipv4Addr>,
+    offsets: HashMap<String, i64>,
+    align: bool,
+    output: String,
+    inputs: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut server = None;
+    let mut offsets = HashMap::new();
+    let mut align = false;
+    let mut positional = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+        match &arg[..] {
+            "--server" => {
+                let v = value()?;
+                server = Some(v.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?);
+            },
+            "--offset" => {
+                let v = value()?;
+                let i = v.rfind('=')
+                    .ok_or_else(|| Error(format!("{}: expected FILE=OFFSET", arg)))?;
+                let offset = skew::parse_offset(&v[i + 1 ..])
+                    .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                offsets.insert(v[..i].to_owned(), offset);
+            },
+            "--align" => align = true,
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if positional.len() < 2 {
+        return Err(USAGE.into());
+    }
+    let output = positional.remove(0);
+    for name in offsets.keys() {
+        if !positional.contains(name) {
+            return Err(Error(format!("--offset: {} isn't one of the inputs", name)));
+        }
+    }
+    Ok(Options { server, offsets, align, output, inputs: positional })
+}
+
+enum Source {
+    Log(tfhlog::Reader<tfhlog::Input>),
+    /// Messages decoded from a capture, in time order.
+    Decoded(vec::IntoIter<Record>),
+}
+
+struct Input {
+    name: String,
+    source: Source,
+    /// Added to each record's time.
+    offset: i64,
+}
+
+impl Input {
+    fn open(name: &str, server: Option<Ipv4Addr>) -> Result<Input, Error> {
+        let source = if name.ends_with(".pcap") {
+            let server = server.ok_or_else(|| Error(format!("{}: captures need --server", name)))?;
+            let mut records = Vec::new();
+            for (ct, msgs) in process::decode_capture(name, server)? {
+                records.extend(msgs.into_iter().map(|msg| Record {
+                    time: msg.time,
+                    conn: Some(ct),
+                    session: None,
+                    msg,
+                    body_omitted: false,
+                }));
+            }
+            // Sorting is stable, so each connection's messages stay in order.
+            records.sort_by_key(|r| r.time);
+            Source::Decoded(records.into_iter())
+        } else {
+            let reader = tfhlog::open(name).at(name)?;
+            if reader.version() == 0 {
+                return Err(Error(format!(
+                    "{}: log has no timestamps (written by an older version)", name)));
+            }
+            Source::Log(reader)
+        };
+        Ok(Input { name: name.to_owned(), source, offset: 0 })
+    }
+
+    fn sampling(&self) -> &tfhlog::Sampling {
+        match self.source {
+            Source::Log(ref r) => r.sampling(),
+            Source::Decoded(_) => &[],
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Record>, Error> {
+        let r = match self.source {
+            Source::Log(ref mut r) => r.read().map_err(|e| Error(format!("{}: {}", self.name, e)))?,
+            Source::Decoded(ref mut it) => it.next(),
+        };
+        Ok(r.map(|mut r| {
+            r.time = skew::apply(r.time, self.offset);
+            r
+        }))
+    }
+
+    /// Every message's key and time, plus `offset`, reading the input from the start.
+    fn events(
+        name: &str,
+        server: Option<Ipv4Addr>,
+        offset: i64,
+    ) -> Result<Vec<(EventKey, u64)>, Error> {
+        let mut input = Input::open(name, server)?;
+        input.offset = offset;
+        let mut events = Vec::new();
+        while let Some(r) = input.next()? {
+            if let Some(ct) = r.conn {
+                events.push((EventKey::new(ct, &r.msg), r.time));
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Estimate the offsets of the inputs without one given, relative to the first input.
+fn align(opts: &Options, inputs: &mut [Input]) -> Result<(), Error> {
+    let reference = Input::events(&opts.inputs[0], opts.server, inputs[0].offset)?
+        .into_iter().collect();
+    for input in &mut inputs[1..] {
+        if opts.offsets.contains_key(&input.name) {
+            continue;
+        }
+        let events = Input::events(&input.name, opts.server, 0)?;
+        match skew::estimate(&reference, &events) {
+            Some((offset, n)) => {
+                eprintln!("{}: offset {} (from {} messages)",
+                    input.name, skew::format_offset(offset), n);
+                input.offset = offset;
+            },
+            None => eprintln!("{}: no messages in common with {}, not aligned",
+                input.name, opts.inputs[0]),
+        }
+    }
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+
+    let mut inputs = Vec::<Input>::new();
+    for name in &opts.inputs {
+        let mut input = Input::open(name, opts.server)?;
+        // The output has one sampling table for all its messages.
+        if let Some(first) = inputs.first() {
+            if input.sampling() != first.sampling() {
+                return Err(Error(format!(
+                    "{}: sampled differently from {}", name, first.name)));
+            }
+        }
+        input.offset = opts.offsets.get(name).copied().unwrap_or(0);
+        inputs.push(input);
+    }
+    if opts.align {
+        align(&opts, &mut inputs)?;
+    }
+
+    let file = BufWriter::new(File::create(&opts.output)?);
+    let mut out = tfhlog::Writer::with_sampling(file, inputs[0].sampling())?;
+
+    // Holds the next record from each input, ordered by timestamp.  Ties go to the input listed
+    // first, which keeps each file's own records in order.
+    let mut heap = BinaryHeap::new();
+    let mut pending = Vec::with_capacity(inputs.len());
+    for (i, input) in inputs.iter_mut().enumerate() {
+        let r = input.next()?;
+        if let Some(ref r) = r {
+            heap.push(Reverse((r.time, i)));
+        }
+        pending.push(r);
+    }
+
+    let mut count = 0;
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let r = pending[i].take().unwrap();
+        out.write_record(&r)?;
+        count += 1;
+
+        pending[i] = inputs[i].next()?;
+        if let Some(ref r) = pending[i] {
+            heap.push(Reverse((r.time, i)));
+        }
+    }
+
+    out.flush()?;
+    eprintln!("merged {} messages from {} inputs", count, inputs.len());
+    Ok(())
+}
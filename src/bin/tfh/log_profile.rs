@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::fs;
+use tfh_mitm::{Error, ErrorAt};
+use tfh_mitm::canary::Profile;
+use tfh_mitm::messages::{self, Known};
+use tfh_mitm::opcodes::Registry;
+use tfh_mitm::tfhlog;
+
+
+const USAGE: &str = "usage: tfh log-profile [options] out.txt in.tfhlog...
+
+Records the range of body lengths of each opcode's messages, and of the values of each integer
+field named in the opcodes file, as the profile `relay --canary` checks live traffic against.
+Give it logs of traffic from before the game update to watch for, all from one client version.
+Fields are found with the layouts for the version each connection logged in with.
+
+options:
+  --opcodes FILE        field layouts, as for `relay --opcodes`";
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let mut opcodes = None;
+    let mut positional = Vec::new();
+    let mut it = args[1..].iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        match &arg[..] {
+            "--opcodes" => {
+                let path = it.next().ok_or_else(|| Error(format!("{}: missing value", arg)))?;
+                opcodes = Some(Registry::load(path)?);
+            },
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+    if positional.len() < 2 {
+        return Err(USAGE.into());
+    }
+
+    let mut profile = Profile::default();
+    let mut count = 0;
+    // The client version of each connection, from its login.
+    let mut versions = HashMap::new();
+    for name in &positional[1..] {
+        let mut r = tfhlog::open(name).at(name)?;
+        while let Some(rec) = r.read().map_err(|e| Error(format!("{}: {}", name, e)))? {
+            if let (Some(ct), Some(Known::Login(login))) = (rec.conn, messages::decode(&rec.msg)) {
+                versions.insert(ct, login.version);
+            }
+            let version = rec.conn.and_then(|ct| versions.get(&ct).copied());
+            profile.learn(&rec.msg, opcodes.as_ref(), version);
+            count += 1;
+        }
+    }
+
+    fs::write(&positional[0], profile.to_text()).at(&positional[0])?;
+    eprintln!("profiled {} opcodes from {} messages", profile.opcodes.len(), count);
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::thread;
+use std::time::Duration;
+use tfh_mitm::Error;
+use tfh_mitm::filter::{self, MessageFilter};
+use tfh_mitm::session::Players;
+use tfh_mitm::tfh_client::Client;
+use tfh_mitm::tfh_stream::Message;
+use tfh_mitm::tfhlog::{self, Record};
+use tfh_mitm::util::dump;
+
+
+const USAGE: &str = "usage: tfh log-replay [options] in.tfhlog server_ip:port
+
+Re-sends the client-to-server messages of a recorded session to a lobby server, with fresh
+sequence numbers, and prints the messages the server sends back.
+
+options:
+  --conn ip[:port]      replay the connection with this endpoint (default: the first in the log)
+  --player name         replay the first session of the player who logged in with this name
+  --speed x             play back x times faster than recorded (default 1); 0 sends everything
+                        at once
+  --interval ms         wait this long between messages, instead of following the recording
+  --wait secs           keep listening this long after the last message (default 2)";
+
+struct Options {
+    conn: MessageFilter,
+    player: Option<String>,
+    speed: f64,
+    interval: Option<Duration>,
+    wait: Duration,
+    input: String,
+    server: String,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut conn = MessageFilter::default();
+    let mut player = None;
+    let mut speed = 1.;
+    let mut interval = None;
+    let mut wait = Duration::from_secs(2);
+    let mut positional = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("-") {
+            positional.push(arg.clone());
+            continue;
+        }
+
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+
+        match &arg[..] {
+            "--conn" => {
+                let endpoint = filter::parse_endpoint(&value()?)
+                    .map_err(|e| Error(format!("--conn: {}", e)))?;
+                conn.conn = Some(endpoint);
+            },
+            "--player" => player = Some(value()?),
+            "--speed" => {
+                speed = value()?.parse().map_err(|e| Error(format!("--speed: {}", e)))?;
+            },
+            "--interval" => {
+                let ms = value()?.parse().map_err(|e| Error(format!("--interval: {}", e)))?;
+                interval = Some(Duration::from_millis(ms));
+            },
+            "--wait" => {
+                let secs = value()?.parse().map_err(|e| Error(format!("--wait: {}", e)))?;
+                wait = Duration::from_secs_f64(secs);
+            },
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if positional.len() != 2 {
+        return Err(USAGE.into());
+    }
+    let server = positional.pop().unwrap();
+    let input = positional.pop().unwrap();
+    Ok(Options { conn, player, speed, interval, wait, input, server })
+}
+
+fn read_log(opts: &Options) -> Result<Vec<Record>, Error> {
+    let reader = tfhlog::open(&opts.input)?;
+    reader.map(|r| r.map_err(|e| Error(format!("{}: {}", opts.input, e)))).collect()
+}
+
+/// Read the client-to-server messages of one session, with their timestamps.
+fn read_session(opts: &Options) -> Result<Vec<(u64, Message)>, Error> {
+    let records = read_log(opts)?;
+    let mut players = Players::default();
+    if let Some(ref player) = opts.player {
+        records.iter().for_each(|r| players.add(r));
+        if players.sessions(player).len() == 0 {
+            return Err(Error(format!("{}: no login as {:?}", opts.input, player)));
+        }
+    }
+
+    let mut session_conn = None;
+    let mut msgs = Vec::new();
+    for r in records {
+        if let Some(ref player) = opts.player {
+            if players.name(&r) != Some(player) {
+                continue;
+            }
+        }
+        if let Some(ct) = r.conn {
+            if !opts.conn.matches_conn(ct) {
+                continue;
+            }
+            // Stick with the first matching session.
+            if *session_conn.get_or_insert((ct, r.session)) != (ct, r.session) {
+                continue;
+            }
+        }
+        if r.msg.header.dir == 0 {
+            msgs.push((r.time, r.msg));
+        }
+    }
+
+    match session_conn {
+        Some((ct, _)) => eprintln!("replaying {} messages from {}", msgs.len(), ct),
+        None => eprintln!("replaying {} messages", msgs.len()),
+    }
+    Ok(msgs)
+}
+
+fn describe(msg: &Message) -> String {
+    let h = &msg.header;
+    format!("{} {:02x}:{:02x} len={} {}", h.dir, h.major, h.minor, h.len, dump::mixed(&msg.body))
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+    let msgs = read_session(&opts)?;
+
+    let client = Client::connect(&opts.server, |msg| println!("recv {}", describe(&msg)))?;
+
+    let mut prev_time = None;
+    for (i, (time, msg)) in msgs.iter().enumerate() {
+        let delay = match (opts.interval, prev_time) {
+            (Some(interval), Some(_)) => interval,
+            (None, Some(prev)) if opts.speed > 0. => {
+                Duration::from_micros(time.saturating_sub(prev)).div_f64(opts.speed)
+            },
+            _ => Duration::from_secs(0),
+        };
+        thread::sleep(delay);
+        prev_time = Some(*time);
+
+        // Each stream starts with a single unframed byte, which the log records as a one-byte
+        // message with opcode 0.
+        if i == 0 && msg.header.major == 0 && msg.body.len() == 1 {
+            client.send(&msg.body)?;
+        } else {
+            if i == 0 {
+                eprintln!("warning: log doesn't start with the stream preamble");
+            }
+            client.send_message(msg)?;
+        }
+        println!("sent {}", describe(msg));
+    }
+
+    thread::sleep(opts.wait);
+    eprintln!("server sent {} bytes", client.received());
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::fs::{File, OpenOptions};
+use tfh_mitm::{Error, ErrorAt};
+use tfh_mitm::tfhlog;
+
+
+const USAGE: &str = "usage: tfh log-verify [options] in.tfhlog...
+
+Reads each log to the end, checking the CRC of every record, and reports the first record that's
+damaged or cut short, as a crash leaves the last one.  Logs from before tfhlog version 2 have no
+CRCs, so in those only a record cut short is found.  Compressed logs (.tfhlog.gz) are checked
+too, but not cut short.
+
+options:
+  --truncate            cut each damaged log off before its first bad record, dropping everything
+                        from there on";
+
+/// What's wrong with a log, if anything.
+enum Outcome {
+    Ok,
+    /// The first bad record starts at `pos`.
+    Damaged { pos: u64, error: String },
+}
+
+/// Read the log at `name`.  Returns its version, the number of good records, and the outcome.
+fn verify(name: &str) -> Result<(u32, u64, Outcome), Error> {
+    let mut r = tfhlog::open(name).at(name)?;
+    let mut good = 0;
+    loop {
+        match r.read() {
+            Ok(Some(_)) => good += 1,
+            Ok(None) => return Ok((r.version(), good, Outcome::Ok)),
+            Err(e) => {
+                let outcome = Outcome::Damaged { pos: r.position(), error: e.to_string() };
+                return Ok((r.version(), good, outcome));
+            },
+        }
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let mut truncate = false;
+    let mut names = Vec::new();
+    for arg in &args[1..] {
+        if !arg.starts_with("--") {
+            names.push(arg.clone());
+            continue;
+        }
+        match &arg[..] {
+            "--truncate" => truncate = true,
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+    if names.len() == 0 {
+        return Err(USAGE.into());
+    }
+
+    let mut damaged = 0;
+    for name in &names {
+        let (version, records, outcome) = match verify(name) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("{}", e);
+                damaged += 1;
+                continue;
+            },
+        };
+        let (pos, error) = match outcome {
+            Outcome::Ok => {
+                let crcs = if version >= 2 { "" } else { ", without CRCs" };
+                println!("{}: ok, {} records (version {}{})", name, records, version, crcs);
+                continue;
+            },
+            Outcome::Damaged { pos, error } => (pos, error),
+        };
+        // A compressed log can't be cut short in place.
+        if name.ends_with(".gz") {
+            println!("{}: bad record at byte {} of the uncompressed log, after {} good records: {}",
+                name, pos, records, error);
+            damaged += 1;
+            continue;
+        }
+        let len = File::open(name).and_then(|f| f.metadata()).at(name)?.len();
+        println!("{}: bad record at byte {}, after {} good records: {} ({} bytes from there on)",
+            name, pos, records, error, len - pos);
+        if truncate {
+            let f = OpenOptions::new().write(true).open(name).at(name)?;
+            f.set_len(pos).at(name)?;
+            println!("{}: cut off at byte {}", name, pos);
+        } else {
+            damaged += 1;
+        }
+    }
+
+    if damaged > 0 {
+        return Err(Error(format!("{} of {} logs are damaged", damaged, names.len())));
+    }
+    Ok(())
+}
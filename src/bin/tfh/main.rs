@@ -0,0 +1,132 @@
+//! All the tools in one binary, so a deployment is a single file.  Run a tool as `tfh TOOL
+//! [args...]`, or through a link named after the tool's old binary (`tfh-relay`, `tun-server`,
+//! and so on), which runs the tool with the arguments as given, so existing scripts and unit
+//! files keep working.
+use std::env;
+use std::path::Path;
+use std::process;
+use tfh_mitm::Error;
+use tfh_mitm::config::Config;
+
+mod log_diff;
+mod log_fields;
+mod log_filter;
+mod log_index;
+mod log_merge;
+mod log_profile;
+mod log_replay;
+mod log_verify;
+mod relay;
+mod replay_check;
+mod replay_pcap;
+mod sandbox;
+mod sanitize;
+mod self_test;
+mod tun_server;
+
+
+const USAGE: &str = "usage: tfh [-v|-q|--log spec]... TOOL [args...]
+
+tools:
+  relay         relay between tun devices, or proxy UDP to a server (`relay self-test` checks
+                this host)
+  tun-server    hand out tun devices over a Unix socket
+  sandbox       set up or tear down the lobby server's network namespace
+  replay-pcap   run a capture, or live traffic, through message processing
+  replay-check  replay a capture several times and compare the logs, to catch nondeterminism
+  sanitize      hide player names and IDs in a capture
+  log-filter    select and export messages from tfhlogs
+  log-merge     merge tfhlogs into one timeline
+  log-diff      compare the messages of two sessions
+  log-fields    guess the field layout of each opcode
+  log-profile   record what each opcode's messages look like, for `relay --canary`
+  log-replay    re-send a recorded session to a server
+  log-verify    check tfhlogs for damaged or cut-short records
+  log-index     write index files for tfhlogs, for faster `log-filter`
+
+Run a tool without arguments for its usage.  The options before the tool set the log levels, as
+for `relay`.  A link to this binary named after a tool's old binary, like `tfh-relay` or
+`tfhlog-filter`, runs that tool.";
+
+struct Tool {
+    name: &'static str,
+    /// Name of the tool's binary from before they were combined, for running it through a link.
+    /// Tools added since then have none.
+    old_name: Option<&'static str>,
+    /// Whether the tool reads its options with `Config::from_args`, so it gets the log options
+    /// too.  It sets up logging itself from them.
+    config: bool,
+    run: fn(&[String]) -> Result<(), Error>,
+}
+
+const TOOLS: &[Tool] = &[
+    Tool { name: "relay", old_name: Some("tfh-relay"), config: true, run: relay::run },
+    Tool { name: "tun-server", old_name: Some("tun-server"), config: false, run: tun_server::run },
+    Tool { name: "sandbox", old_name: Some("tfh-sandbox"), config: false, run: sandbox::run },
+    Tool {
+        name: "replay-pcap", old_name: Some("replay-pcap"), config: true, run: replay_pcap::run,
+    },
+    Tool { name: "replay-check", old_name: None, config: false, run: replay_check::run },
+    Tool { name: "sanitize", old_name: Some("tfh-sanitize"), config: false, run: sanitize::run },
+    Tool {
+        name: "log-filter", old_name: Some("tfhlog-filter"), config: false, run: log_filter::run,
+    },
+    Tool { name: "log-merge", old_name: Some("tfhlog-merge"), config: false, run: log_merge::run },
+    Tool { name: "log-diff", old_name: Some("tfhlog-diff"), config: false, run: log_diff::run },
+    Tool {
+        name: "log-fields", old_name: Some("tfhlog-fields"), config: false, run: log_fields::run,
+    },
+    Tool { name: "log-profile", old_name: None, config: false, run: log_profile::run },
+    Tool {
+        name: "log-replay", old_name: Some("tfhlog-replay"), config: false, run: log_replay::run,
+    },
+    Tool { name: "log-verify", old_name: None, config: false, run: log_verify::run },
+    Tool { name: "log-index", old_name: None, config: false, run: log_index::run },
+];
+
+fn real_main() -> Result<(), Error> {
+    let args = env::args().collect::<Vec<_>>();
+    let called_as = args.get(0).map_or("", |s| {
+        Path::new(s).file_name().and_then(|x| x.to_str()).unwrap_or("")
+    });
+    if let Some(tool) = TOOLS.iter().find(|t| t.old_name == Some(called_as)) {
+        return (tool.run)(&args);
+    }
+
+    // Log options come before the tool's name.
+    let mut log_args = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        match &args[i][..] {
+            "-v" | "-q" => log_args.push(args[i].clone()),
+            "--log" if i + 1 < args.len() => {
+                log_args.extend_from_slice(&args[i .. i + 2]);
+                i += 1;
+            },
+            _ => break,
+        }
+        i += 1;
+    }
+    let name = args.get(i).ok_or(USAGE)?;
+    let tool = TOOLS.iter().find(|t| t.name == name)
+        .ok_or_else(|| Error(format!("unknown tool {}\n{}", name, USAGE)))?;
+
+    let (cfg, _) = Config::from_args(&log_args)?;
+    cfg.init_logging();
+    let mut tool_args = vec![format!("tfh {}", tool.name)];
+    if tool.config {
+        tool_args.extend(log_args);
+    }
+    tool_args.extend_from_slice(&args[i + 1 ..]);
+    (tool.run)(&tool_args)
+}
+
+fn main() {
+    match real_main() {
+        Ok(()) => {},
+        Err(e) => {
+            println!("error: {}", e);
+            process::exit(1);
+        },
+    }
+}
@@ -0,0 +1,326 @@
+use std;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(feature = "xdp")]
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use nix::sys::signal::{SigSet, Signal};
+use tfh_mitm::{Error, ErrorAt, log};
+use tfh_mitm::channel::{DropCounter, HighWater, Receiver, Sender};
+use tfh_mitm::config::Config;
+use tfh_mitm::failover;
+use tfh_mitm::health;
+use tfh_mitm::process::{self, Input, Output};
+use tfh_mitm::relay;
+use tfh_mitm::stats::{self, RelayStats};
+use tfh_mitm::supervise::Supervisor;
+#[cfg(feature = "xdp")]
+use tfh_mitm::transport::PacketSink;
+use tfh_mitm::transport::TunDevice;
+use tfh_mitm::tun_socket::{self, Request};
+use tfh_mitm::tuntap;
+use tfh_mitm::udp_proxy;
+use tfh_mitm::util::clock;
+#[cfg(feature = "xdp")]
+use tfh_mitm::xdp::XdpSocket;
+
+
+/// How often to check the queues for dropped packets.
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for the workers to save the snapshot on shutdown.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to rewrite `--health-file`.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default for `--stats-interval`, in seconds.
+const DEFAULT_STATS_INTERVAL: u64 = 60;
+
+/// Print the traffic counters and high-water marks every `interval`, and write their totals to
+/// `path` if set.
+fn report_stats(
+    stats: Arc<RelayStats>,
+    queues: Vec<(&'static str, HighWater)>,
+    interval: Duration,
+    path: Option<String>,
+) {
+    let mut prev = stats.snapshot();
+    loop {
+        thread::sleep(interval);
+        let snap = stats.snapshot();
+        let depths = queues.iter().map(|&(name, ref hw)| (name, hw.get())).collect::<Vec<_>>();
+        let secs = interval.as_secs_f64();
+        log!(Relay, Info, "stats: A->B: {}", snap.a_to_b.describe_since(&prev.a_to_b, secs));
+        log!(Relay, Info, "stats: B->A: {}", snap.b_to_a.describe_since(&prev.b_to_a, secs));
+        log!(Relay, Info, "stats: connections: {}", snap.describe_conns_since(&prev));
+        log!(Relay, Info, "stats: high water: {}", snap.describe_high_water(&depths));
+        let latency = stats.latency.lock().unwrap();
+        log!(Relay, Info, "stats: rtt to client: {}", latency.total.client.describe());
+        log!(Relay, Info, "stats: rtt to server: {}", latency.total.server.describe());
+        if let Some(ref path) = path {
+            stats::write_json(path, clock::now(), &snap, &depths, &latency)
+                .unwrap_or_else(|e| log!(Relay, Error, "failed to write {}: {}", path, e));
+        }
+        prev = snap;
+    }
+}
+
+/// Rewrite the heartbeat file at `path` every `HEALTH_INTERVAL`.
+fn write_health(stats: Arc<RelayStats>, path: String) {
+    loop {
+        health::write_json(&path, clock::now(), &stats.snapshot())
+            .unwrap_or_else(|e| log!(Relay, Error, "failed to write {}: {}", path, e));
+        thread::sleep(HEALTH_INTERVAL);
+    }
+}
+
+/// Periodically print how many packets each queue has dropped, whenever the count goes up.
+fn report_drops(queues: Vec<(&'static str, DropCounter)>) {
+    let mut last = vec![0; queues.len()];
+    loop {
+        thread::sleep(DROP_REPORT_INTERVAL);
+        for (&(name, ref counter), last) in queues.iter().zip(last.iter_mut()) {
+            let n = counter.get();
+            if n > *last {
+                log!(Relay, Warn, "{} queue full: dropped {} packets ({} total)",
+                    name, n - *last, n);
+                *last = n;
+            }
+        }
+    }
+}
+
+/// Wait for SIGINT or SIGTERM, which `signals` has blocked, then save the connections to the
+/// `--snapshot` file before shutting down, so they're as fresh as they can be for the next run or
+/// for the standby relays fed by `feed`.
+fn save_on_signal(
+    cfg: &Config,
+    sup: &Supervisor,
+    signals: SigSet,
+    input: Sender<Input>,
+    feed: Option<failover::Feed>,
+) {
+    let cfg = cfg.clone();
+    let shutdown = sup.shutdown_handle();
+    thread::Builder::new().name("signals".into()).spawn(move || {
+        let sig = match signals.wait() {
+            Ok(x) => x,
+            Err(e) => {
+                log!(Relay, Error, "failed to wait for signals: {}", e);
+                return;
+            },
+        };
+        log!(Relay, Info, "got {:?}, saving snapshot", sig);
+        if !process::checkpoint(&cfg, &input, SNAPSHOT_TIMEOUT) {
+            log!(Relay, Warn, "snapshot: not every worker finished saving");
+        }
+        if let Some(feed) = feed {
+            feed.push();
+        }
+        let _ = shutdown.send(format!("got {:?}", sig));
+    }).unwrap();
+}
+
+/// Get a tun device.  `name` is either the socket of a `tun-server` to request the default
+/// device from, `socket:device` to request a particular one, or the name of a new tun interface
+/// to create.
+fn open_or_get_tun(name: &str) -> Result<RawFd, Error> {
+    // `fd:N[:dev]` and `systemd:NAME[:dev]` name an inherited fd, holding either the device or
+    // a socket connected to a tun server.
+    for &prefix in &["fd:", "systemd:"] {
+        if !name.starts_with(prefix) {
+            continue;
+        }
+        let rest = &name[prefix.len() ..];
+        let (src, dev) = match rest.find(':') {
+            Some(i) => (&rest[..i], &rest[i + 1 ..]),
+            None => (rest, ""),
+        };
+        let fd = if prefix == "fd:" {
+            src.parse().map_err(|e| Error(format!("{}: {}", name, e)))?
+        } else {
+            tun_socket::listen_fd(src).at(name)?
+        };
+        log!(Relay, Info, "using inherited fd {} ({})", fd, name);
+        return tun_socket::from_fd(fd, dev);
+    }
+    if let Some(i) = name.rfind(':') {
+        let (path, dev) = (&name[..i], &name[i + 1 ..]);
+        if Path::new(path).exists() {
+            log!(Relay, Info, "receiving tun fd {:?} from socket {:?}", dev, path);
+            return tun_socket::request(path, &Request::Get(dev.to_owned()));
+        }
+    }
+    if Path::new(name).exists() {
+        log!(Relay, Info, "receiving tun fd from socket {:?}", name);
+        tun_socket::request(name, &Request::Get(String::new()))
+    } else {
+        log!(Relay, Info, "creating tun device {:?}", name);
+        tuntap::open_tun(name)
+    }
+}
+
+/// Relay between an AF_XDP socket on the outside interface `spec` (`ifname` or `ifname:queue`)
+/// and the tun device `inside`.  TFH packets go through the relay as usual, but everything else
+/// the XDP program redirects is written straight to the inside device.
+#[cfg(feature = "xdp")]
+fn start_xdp(
+    cfg: &Config,
+    sup: &Supervisor,
+    stats: Arc<RelayStats>,
+    spec: &str,
+    inside: &str,
+    inp_send: Sender<Input>,
+    out_recv: Receiver<Output>,
+) -> Result<(), Error> {
+    let map = cfg.xdp_map.as_ref().ok_or("xdp: interfaces need --xdp-map")?;
+    let (ifname, queue) = match spec.rfind(':') {
+        Some(i) => {
+            let queue = spec[i + 1 ..].parse().map_err(|e| Error(format!("{}: {}", spec, e)))?;
+            (&spec[..i], queue)
+        },
+        None => (spec, 0),
+    };
+    let mut xdp = XdpSocket::open(ifname, queue, map).at(spec)?;
+    log!(Relay, Info, "receiving from {} queue {} with AF_XDP", ifname, queue);
+    let b = TunDevice::new(open_or_get_tun(inside)?);
+
+    let stats2 = stats.clone();
+    xdp.set_bypass(Box::new(move |data| {
+        let counters = &stats2.a_to_b;
+        counters.count_packet(data.len());
+        if b.write(data).is_err() {
+            counters.write_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }));
+    let sink = xdp.try_clone()?;
+    relay::start(cfg, sup, stats, (xdp, sink), (b, b), inp_send, out_recv)
+}
+
+#[cfg(not(feature = "xdp"))]
+fn start_xdp(
+    _cfg: &Config,
+    _sup: &Supervisor,
+    _stats: Arc<RelayStats>,
+    _spec: &str,
+    _inside: &str,
+    _inp_send: Sender<Input>,
+    _out_recv: Receiver<Output>,
+) -> Result<(), Error> {
+    Err("xdp: interfaces require building with `--features xdp`".into())
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let (cfg, pos) = Config::from_args(&args[1..])?;
+    cfg.init_logging();
+    if pos.first().map(|s| &s[..]) == Some("self-test") {
+        return crate::self_test::run(&pos[1..]);
+    }
+    cfg.init_affinity();
+    if cfg.proxy.is_some() && cfg.tproxy.is_some() {
+        return Err("--proxy and --tproxy can't be used together".into());
+    }
+    if cfg.gateway.is_some() && (cfg.proxy.is_some() || cfg.tproxy.is_some()) {
+        return Err("--gateway only works when relaying between tun devices".into());
+    }
+    if cfg.dedup_ms.is_some() && (cfg.proxy.is_some() || cfg.tproxy.is_some()) {
+        // Proxied datagrams come from sockets, with no real IP ident to tell duplicates by.
+        return Err("--dedup only works when relaying between tun devices".into());
+    }
+    if cfg.write_batch.is_some() && cfg.proxy.is_none() && cfg.tproxy.is_none() {
+        // A tun device takes exactly one packet per write, so there's nothing to coalesce.
+        return Err("--write-batch only works with --proxy or --tproxy".into());
+    }
+    if cfg.proxy.is_some() {
+        assert!(pos.len() == 1, "usage: {} [options] --proxy listen_addr server_addr", args[0]);
+    } else if cfg.tproxy.is_some() {
+        assert!(pos.len() == 0, "usage: {} [options] --tproxy listen_addr", args[0]);
+    } else {
+        assert!(pos.len() == 2, "usage: {} [options] outside inside", args[0]);
+    }
+    if cfg.snapshot.is_none() && (cfg.failover_listen.is_some() || cfg.standby.is_some()) {
+        return Err("--failover-listen and --standby need --snapshot".into());
+    }
+
+    // Held for as long as this relay runs, to fence off its standbys.
+    let _lock = if let Some(ref active) = cfg.standby {
+        log!(Relay, Info, "failover: standing by for {}", active);
+        let lock = failover::standby(active, cfg.snapshot.as_ref().unwrap())?;
+        log!(Relay, Warn, "failover: lost the active relay at {}, taking over", active);
+        Some(lock)
+    } else if cfg.failover_listen.is_some() {
+        let path = cfg.snapshot.as_ref().unwrap();
+        let lock = failover::Lock::try_acquire(path)?;
+        let held = || Error(format!("failover: another relay holds {}", failover::lock_path(path)));
+        Some(lock.ok_or_else(held)?)
+    } else {
+        None
+    };
+
+    // Signals have to be blocked before any threads start, since the threads inherit the mask.
+    let signals = if cfg.snapshot.is_some() {
+        let mut set = SigSet::empty();
+        set.add(Signal::SIGINT);
+        set.add(Signal::SIGTERM);
+        set.thread_block()?;
+        Some(set)
+    } else {
+        None
+    };
+
+    let stats = Arc::new(RelayStats::default());
+    let sup = Supervisor::new();
+    let (inp_send, out_recv) =
+        process::start_supervised_processing_thread(&cfg, &sup, stats.clone())?;
+    let feed = match cfg.failover_listen {
+        Some(ref addr) => Some(failover::Feed::start(addr, cfg.snapshot.as_ref().unwrap())?),
+        None => None,
+    };
+    if let Some(set) = signals {
+        save_on_signal(&cfg, &sup, set, inp_send.clone(), feed);
+    }
+    let queues = vec![("input", inp_send.drop_counter()), ("output", out_recv.drop_counter())];
+    thread::Builder::new().name("drop report".into()).spawn(move || report_drops(queues))?;
+
+    let interval = Duration::from_secs(cfg.stats_interval.unwrap_or(DEFAULT_STATS_INTERVAL));
+    let stats_file = cfg.stats_file.clone();
+    let stats2 = stats.clone();
+    let queues = vec![("input", inp_send.high_water()), ("output", out_recv.high_water())];
+    thread::Builder::new().name("stats".into())
+        .spawn(move || report_stats(stats2, queues, interval, stats_file))?;
+
+    if let Some(path) = cfg.health_file.clone() {
+        let stats2 = stats.clone();
+        thread::Builder::new().name("health".into()).spawn(move || write_health(stats2, path))?;
+    }
+
+    let limiter = cfg.rate_limiter();
+    if let Some(ref listen) = cfg.proxy {
+        let (pacer, batch) = (cfg.pacer(), cfg.write_batch.unwrap_or(1));
+        let server = Some(&pos[0] as &str);
+        udp_proxy::start(&sup, listen, server, limiter, pacer, batch, stats, inp_send, out_recv)?;
+        return Err(Error(format!("shutting down: {}", sup.wait())));
+    }
+    if let Some(ref listen) = cfg.tproxy {
+        let (pacer, batch) = (cfg.pacer(), cfg.write_batch.unwrap_or(1));
+        udp_proxy::start(&sup, listen, None, limiter, pacer, batch, stats, inp_send, out_recv)?;
+        return Err(Error(format!("shutting down: {}", sup.wait())));
+    }
+
+    if pos[0].starts_with("xdp:") {
+        start_xdp(&cfg, &sup, stats, &pos[0]["xdp:".len() ..], &pos[1], inp_send, out_recv)?;
+        return Err(Error(format!("shutting down: {}", sup.wait())));
+    }
+
+    let fd_a = open_or_get_tun(&pos[0])?;
+    let fd_b = open_or_get_tun(&pos[1])?;
+
+    println!("got tun devices {}, {}", fd_a, fd_b);
+
+    let (a, b) = (TunDevice::new(fd_a), TunDevice::new(fd_b));
+    relay::start(&cfg, &sup, stats, (a, a), (b, b), inp_send, out_recv)?;
+    Err(Error(format!("shutting down: {}", sup.wait())))
+}
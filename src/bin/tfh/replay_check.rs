@@ -0,0 +1,233 @@
+//! `tfh replay-check`: runs a capture through `replay-pcap` more than once and compares what the
+//! runs logged, to catch processing whose output depends on thread timing rather than on the
+//! packets.  Session IDs and message times come from the capture, so the logs of two good runs
+//! are identical, down to their file names.  `--with` makes the last run with another build,
+//! to check that a refactor didn't change the output.
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::Instant;
+use tfh_mitm::{Error, ErrorAt};
+use tfh_mitm::tfhlog::{self, Record};
+
+
+const USAGE: &str = "usage: tfh replay-check [options] in.pcap [server_ip] [-- replay options...]
+
+Runs a capture through `replay-pcap` several times, each in a new directory, and compares the
+logs each run writes, message by message.  Options after `--` are passed to `replay-pcap`, such
+as `--workers 4` to check that spreading the work over threads doesn't change the result.
+Options that depend on the wall clock, like `--log-rotate`, make the runs differ.
+
+options:
+  --runs N              number of runs (default 2)
+  --with PATH           make the last run with the tfh binary at PATH, such as an earlier build
+  --keep                keep the run directories, which are otherwise removed if the runs match";
+
+/// Differences listed before the rest are only counted.
+const MAX_SHOWN: usize = 20;
+
+struct Options {
+    runs: usize,
+    with: Option<PathBuf>,
+    keep: bool,
+    /// The capture, and the server's IP if given.
+    inputs: Vec<String>,
+    replay_args: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut opts = Options {
+        runs: 2,
+        with: None,
+        keep: false,
+        inputs: Vec::new(),
+        replay_args: Vec::new(),
+    };
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "--" {
+            opts.replay_args.extend(it.by_ref().cloned());
+            break;
+        }
+        if !arg.starts_with("--") {
+            opts.inputs.push(arg.clone());
+            continue;
+        }
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+        match &arg[..] {
+            "--runs" => {
+                opts.runs = value()?.parse().map_err(|e| Error(format!("--runs: {}", e)))?;
+            },
+            "--with" => opts.with = Some(PathBuf::from(value()?)),
+            "--keep" => opts.keep = true,
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if opts.inputs.len() == 0 || opts.inputs.len() > 2 {
+        return Err(USAGE.into());
+    }
+    if opts.runs < 2 {
+        return Err("--runs: need at least 2 runs to compare".into());
+    }
+    Ok(opts)
+}
+
+/// The logs of one run, keyed by file name.
+struct Run {
+    label: String,
+    logs: BTreeMap<String, Vec<Record>>,
+}
+
+impl Run {
+    fn summary(&self) -> String {
+        let mut dirs = [0; 2];
+        for r in self.logs.values().flatten() {
+            dirs[r.msg.header.dir as usize & 1] += 1;
+        }
+        format!("{} logs, {} messages ({} from clients, {} from servers)",
+            self.logs.len(), dirs[0] + dirs[1], dirs[0], dirs[1])
+    }
+}
+
+/// Run `exe replay-pcap` in `dir`, with its output going to `dir/output.txt`.
+fn replay(exe: &Path, dir: &Path, opts: &Options) -> Result<(), Error> {
+    fs::create_dir_all(dir).at(&dir.display().to_string())?;
+    let output = dir.join("output.txt");
+    let out = File::create(&output).at(&output.display().to_string())?;
+    let err = out.try_clone().at(&output.display().to_string())?;
+
+    let mut cmd = Command::new(exe);
+    // An older build may predate the combined binary, and have `replay-pcap` on its own.
+    if exe.file_name().map_or(true, |n| n != "replay-pcap") {
+        cmd.arg("replay-pcap");
+    }
+    let pcap = fs::canonicalize(&opts.inputs[0]).at(&opts.inputs[0])?;
+    cmd.args(&opts.replay_args).arg(pcap).args(&opts.inputs[1..])
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(out)
+        .stderr(err);
+    let status = cmd.status().at(&format!("running {}", exe.display()))?;
+    if !status.success() {
+        return Err(Error(format!("{} replay-pcap failed ({}); see {}",
+            exe.display(), status, output.display())));
+    }
+    Ok(())
+}
+
+fn read_logs(dir: &Path) -> Result<BTreeMap<String, Vec<Record>>, Error> {
+    let logs_dir = dir.join("logs");
+    let mut logs = BTreeMap::new();
+    let entries = match fs::read_dir(&logs_dir) {
+        Ok(x) => x,
+        // Nothing was logged.
+        Err(_) => return Ok(logs),
+    };
+    for e in entries {
+        let path = e.at(&logs_dir.display().to_string())?.path();
+        // Skip the indexes `--log-index` writes alongside.
+        if path.extension().map_or(true, |x| x != "tfhlog") {
+            continue;
+        }
+        let name = path.display().to_string();
+        let mut r = tfhlog::Reader::new(BufReader::new(File::open(&path).at(&name)?)).at(&name)?;
+        let mut records = Vec::new();
+        while let Some(rec) = r.read().map_err(|e| Error(format!("{}: {}", name, e)))? {
+            records.push(rec);
+        }
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        logs.insert(file_name, records);
+    }
+    Ok(logs)
+}
+
+fn same_record(a: &Record, b: &Record) -> bool {
+    let (x, y) = (&a.msg, &b.msg);
+    a.time == b.time && a.conn == b.conn && a.session == b.session &&
+        x.header.dir == y.header.dir && x.header.major == y.header.major &&
+        x.header.minor == y.header.minor && x.header.ack == y.header.ack &&
+        x.body == y.body && x.index == y.index && x.acks == y.acks
+}
+
+fn describe(r: &Record) -> String {
+    let h = &r.msg.header;
+    format!("dir {} #{} {:02x}:{:02x}, {} bytes, at {}{}",
+        h.dir, r.msg.index, h.major, h.minor, r.msg.body.len(), r.time, r.msg.acks_label())
+}
+
+/// The ways `b` differs from `a`: logs only one has, and the first differing message of each
+/// log both have.
+fn compare(a: &Run, b: &Run) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for name in a.logs.keys().filter(|n| !b.logs.contains_key(*n)) {
+        diffs.push(format!("{} is only in {}", name, a.label));
+    }
+    for name in b.logs.keys().filter(|n| !a.logs.contains_key(*n)) {
+        diffs.push(format!("{} is only in {}", name, b.label));
+    }
+    for (name, xs) in &a.logs {
+        let ys = match b.logs.get(name) {
+            Some(x) => x,
+            None => continue,
+        };
+        match xs.iter().zip(ys).position(|(x, y)| !same_record(x, y)) {
+            Some(i) => diffs.push(format!("{}: record {} differs: {} in {}, {} in {}",
+                name, i, describe(&xs[i]), a.label, describe(&ys[i]), b.label)),
+            None if xs.len() != ys.len() => diffs.push(format!(
+                "{}: {} has {} records, {} has {}", name, a.label, xs.len(), b.label, ys.len())),
+            None => {},
+        }
+    }
+    diffs
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+    let this = env::current_exe().at("finding this binary")?;
+
+    let base = env::temp_dir().join(format!("tfh-replay-check-{}", process::id()));
+    let mut runs = Vec::new();
+    for i in 0 .. opts.runs {
+        let exe = match opts.with {
+            Some(ref other) if i + 1 == opts.runs => other,
+            _ => &this,
+        };
+        let dir = base.join(format!("run{}", i + 1));
+        let start = Instant::now();
+        replay(exe, &dir, &opts)?;
+        let label = format!("run {}", i + 1);
+        let run = Run { label, logs: read_logs(&dir)? };
+        println!("{} ({}, {:.1}s): {}", run.label, exe.display(),
+            start.elapsed().as_secs_f64(), run.summary());
+        runs.push(run);
+    }
+
+    let mut diffs = Vec::new();
+    for run in &runs[1..] {
+        diffs.extend(compare(&runs[0], run));
+    }
+    for d in diffs.iter().take(MAX_SHOWN) {
+        println!("  {}", d);
+    }
+    if diffs.len() > MAX_SHOWN {
+        println!("  ... and {} more differences", diffs.len() - MAX_SHOWN);
+    }
+
+    if diffs.len() > 0 {
+        return Err(Error(format!("runs differ; their output is in {}", base.display())));
+    }
+    if opts.keep {
+        println!("runs match; their output is in {}", base.display());
+    } else {
+        let _ = fs::remove_dir_all(&base);
+        println!("runs match");
+    }
+    Ok(())
+}
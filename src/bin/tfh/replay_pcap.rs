@@ -0,0 +1,112 @@
+use std::env;
+use std::fs::File;
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use tfh_mitm::{Error, ErrorAt};
+use tfh_mitm::config::Config;
+use tfh_mitm::packet::{Packet, PACKET_CAP};
+use tfh_mitm::packet_text;
+use tfh_mitm::pcap::{Direction, Pcap};
+use tfh_mitm::process::{self, Input, Output};
+use tfh_mitm::transport::{PacketSource, RawSocket};
+use tfh_mitm::util::clock;
+
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let (cfg, pos) = Config::from_args(&args[1..])?;
+    cfg.init_logging();
+    assert!(pos.len() == 1 || pos.len() == 2,
+        "usage: {} [options] file.pcap|raw:ifname|text:file [server_ip]", args[0]);
+    // `raw:eth0` watches the live traffic on `eth0` instead of reading a capture, and
+    // `text:file` reads packets written out in the `packet_text` format.  Only captures can have
+    // direction tags, so the others always need `server_ip`.
+    let mut src = if pos[0].starts_with("raw:") {
+        let ifname = &pos[0]["raw:".len() ..];
+        Source::Untagged(Box::new(RawSocket::open(ifname).at(&pos[0])?))
+    } else if pos[0].starts_with("text:") {
+        Source::Untagged(Box::new(packet_text::load(&pos[0]["text:".len() ..])?.into_iter()))
+    } else {
+        Source::Pcap(Pcap::new(File::open(&pos[0])?)?)
+    };
+    let server_ip = match pos.get(1) {
+        Some(s) => {
+            let ip = Ipv4Addr::from_str(s).map_err(|e| Error(format!("{}: {}", s, e)))?;
+            Some(u32::from_be_bytes(ip.octets()))
+        },
+        None => None,
+    };
+    if server_ip.is_none() {
+        if let Source::Untagged(_) = src {
+            return Err(Error(format!("{}: needs the server's IP", pos[0])));
+        }
+    }
+
+    let (inp_send, out_recv, proc) = process::start_processing_thread(&cfg, Arc::default())?;
+    let mut dedup = cfg.dedup();
+
+    thread::spawn(move || {
+        for _ in out_recv.iter() {
+            // Ignore
+        }
+    });
+
+    let res = loop {
+        let (p, tag) = match src.recv() {
+            Ok(Some(x)) => x,
+            Ok(None) => break Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        if !p.is_ipv4() {
+            continue;
+        }
+        if let Some(ref mut d) = dedup {
+            // Live packets have no capture time.
+            if !d.check(&p, p.time().unwrap_or_else(clock::now_us)) {
+                continue;
+            }
+        }
+
+        // `A` is the outside of the sandbox and `B` is the inside.  So packets destined for the
+        // server are traveling from A to B.  A direction tag, when the capture has them, is used
+        // over the addresses.
+        let dir = match (tag, server_ip) {
+            (Some(dir), _) => dir,
+            (None, Some(ip)) if p.ipv4().dest_ip() == ip => Direction::AToB,
+            (None, Some(ip)) if p.ipv4().source_ip() == ip => Direction::BToA,
+            (None, Some(_)) => continue,
+            (None, None) => {
+                break Err(io::Error::new(io::ErrorKind::InvalidData,
+                    "packet without a direction tag; give the server's IP"));
+            },
+        };
+        let inp = match dir {
+            Direction::AToB => Input::FromA(p),
+            Direction::BToA => Input::FromB(p),
+        };
+
+        inp_send.send(inp).unwrap();
+    };
+
+    drop(inp_send);
+    proc.join();
+    Ok(res?)
+}
+
+enum Source {
+    /// A capture, whose packets may be tagged with their direction.
+    Pcap(Pcap<File>),
+    Untagged(Box<dyn PacketSource>),
+}
+
+impl Source {
+    fn recv(&mut self) -> io::Result<Option<(Packet, Option<Direction>)>> {
+        match *self {
+            Source::Pcap(ref mut pcap) => pcap.recv_tagged(),
+            Source::Untagged(ref mut src) => Ok(src.recv()?.map(|p| (p, None))),
+        }
+    }
+}
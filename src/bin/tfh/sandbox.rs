@@ -0,0 +1,66 @@
+use std::env;
+use tfh_mitm::Error;
+use tfh_mitm::sandbox::Sandbox;
+
+
+const USAGE: &str = "usage: tfh sandbox [options] setup|teardown
+
+Creates (or removes) a network namespace for the lobby server, with the inside tun device in it
+and the outside tun device in the current namespace, addressed and routed so that tfh-relay can
+connect the two.  Must be run as root.
+
+  --netns tfh                       namespace name
+  --user NAME                       owner of the tun devices (default $SUDO_USER)
+  --inside tun-tfh-inside           inside device name
+  --outside tun-tfh-outside         outside device name
+  --inside-addr 192.168.84.2/24     inside device address
+  --outside-addr 192.168.84.1/24    outside device address, also the sandbox's gateway";
+
+fn parse_args(args: &[String]) -> Result<(Sandbox, String), Error> {
+    let mut sb = Sandbox::default();
+    sb.user = env::var("SUDO_USER").ok();
+    let mut positional = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+        match &arg[..] {
+            "--netns" => sb.netns = value()?,
+            "--user" => sb.user = Some(value()?),
+            "--inside" => sb.inside = value()?,
+            "--outside" => sb.outside = value()?,
+            "--inside-addr" => sb.inside_addr = value()?,
+            "--outside-addr" => sb.outside_addr = value()?,
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    match positional.len() {
+        1 => Ok((sb, positional.pop().unwrap())),
+        _ => Err(USAGE.into()),
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let (sb, cmd) = parse_args(&args[1..])?;
+    match &cmd[..] {
+        "setup" => {
+            sb.setup()?;
+            let user = sb.user.as_ref().map_or(String::new(), |u| format!(" sudo -u {}", u));
+            println!("sandbox {:?} is ready.  Next, run:", sb.netns);
+            println!("  sudo ip netns exec {}{} ./tun-server {} tun &", sb.netns, user, sb.inside);
+            println!("  ./tfh-relay {} tun", sb.outside);
+            println!("and start the lobby server from a shell in the sandbox:");
+            println!("  sudo ip netns exec {}{} bash", sb.netns, user);
+        },
+        "teardown" => sb.teardown()?,
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{BufReader, BufWriter};
+use std::net::Ipv4Addr;
+use tfh_mitm::Error;
+use tfh_mitm::anonymize::Anonymizer;
+use tfh_mitm::messages::{self, Chat, Known, Login};
+use tfh_mitm::packet::Packet;
+use tfh_mitm::pcap::{self, Direction, Pcap};
+use tfh_mitm::rewrite::{Mutator, NameRewriter};
+use tfh_mitm::tfh_stream::{ConnTuple, Message, StreamHandler, TfhStreamConns};
+
+
+const USAGE: &str = "usage: tfh sanitize [options] in.pcap out.pcap server_ip
+
+Copies the TFH traffic in a capture, hiding player names, account and match IDs, and chat
+text, so the capture can be shared.  IP and UDP headers and the TFH framing are kept, so the
+result still replays and reassembles like the original.  Other traffic is dropped, and MAC
+addresses are zeroed.
+
+Names are replaced with stable pseudonyms, and IDs with hashes of themselves, so messages that
+refer to the same player or match still match up.  A name is also replaced in later messages
+that carry it, such as the lobby roster, once its player's login has been seen.
+
+options:
+  --zero                zero the fields instead of replacing them
+  --chat-major xx       major opcode of chat messages (hex; default 14)";
+
+/// Packets held back in case a message they start is rewritten once a later packet completes
+/// it.
+const HOLD_PACKETS: usize = 256;
+
+struct Options {
+    zero: bool,
+    chat_major: u8,
+    input: String,
+    output: String,
+    server_ip: u32,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut zero = false;
+    let mut chat_major = messages::MAJOR_CHAT;
+    let mut positional = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("-") {
+            positional.push(arg.clone());
+            continue;
+        }
+
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+
+        match &arg[..] {
+            "--zero" => zero = true,
+            "--chat-major" => {
+                let v = value()?;
+                chat_major = u8::from_str_radix(v.trim_start_matches("0x"), 16)
+                    .map_err(|e| Error(format!("--chat-major: {}", e)))?;
+            },
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if positional.len() != 3 {
+        return Err(USAGE.into());
+    }
+    let server_ip = positional[2].parse::<Ipv4Addr>()
+        .map_err(|e| Error(format!("{}: {}", positional[2], e)))?;
+    Ok(Options {
+        zero,
+        chat_major,
+        input: positional[0].clone(),
+        output: positional[1].clone(),
+        server_ip: u32::from(server_ip),
+    })
+}
+
+struct Sanitizer {
+    zero: bool,
+    chat_major: u8,
+    anon: Anonymizer,
+    key: RandomState,
+    /// Rewriters for the names seen in login messages so far.
+    names: Vec<NameRewriter>,
+    known_names: Vec<String>,
+    /// Set when a message is rewritten, so held packets get patched.
+    changed: bool,
+    count: u64,
+}
+
+impl Sanitizer {
+    fn hash(&self, x: impl Hash) -> u64 {
+        let mut h = self.key.build_hasher();
+        x.hash(&mut h);
+        h.finish()
+    }
+
+    fn sanitize_login(&mut self, msg: &mut Message) {
+        let login = match Login::parse(&msg.body) {
+            Some(x) => x,
+            None => return,
+        };
+        let account_id = if self.zero { 0 } else { self.hash(login.account_id) };
+        msg.body[..8].copy_from_slice(&account_id.to_le_bytes());
+        let new_name = if self.zero {
+            let field = &mut msg.body[Login::NAME_OFFSET .. Login::NAME_OFFSET + Login::NAME_LEN];
+            for b in field.iter_mut() {
+                *b = 0;
+            }
+            String::new()
+        } else {
+            self.anon.redact(msg);
+            self.anon.name(&login.name)
+        };
+        if login.name.len() > 0 && !self.known_names.contains(&login.name) {
+            self.names.push(NameRewriter::new(&login.name, &new_name));
+            self.known_names.push(login.name);
+        }
+    }
+
+    fn sanitize(&mut self, ct: ConnTuple, msg: &mut Message) {
+        if Login::matches(msg) {
+            return self.sanitize_login(msg);
+        }
+        if Chat::matches(msg, self.chat_major) {
+            for b in msg.body.iter_mut() {
+                if *b != 0 {
+                    *b = if self.zero { 0 } else { b'x' };
+                }
+            }
+            return;
+        }
+        let match_id = match messages::decode(msg) {
+            Some(Known::MatchStart(m)) => Some(m.match_id),
+            Some(Known::MatchEnd(m)) => Some(m.match_id),
+            _ => None,
+        };
+        if let Some(id) = match_id {
+            let id = if self.zero { 0 } else { self.hash(id) as u32 };
+            msg.body[..4].copy_from_slice(&id.to_le_bytes());
+            return;
+        }
+        for n in &self.names {
+            n.rewrite(ct, msg);
+        }
+    }
+}
+
+impl StreamHandler for Sanitizer {
+    fn rewrite(&mut self, ct: ConnTuple, msg: &mut Message) -> bool {
+        let old = msg.body.clone();
+        self.sanitize(ct, msg);
+        if msg.body == old {
+            return false;
+        }
+        self.changed = true;
+        self.count += 1;
+        true
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+
+    let mut pcap = Pcap::new(BufReader::new(File::open(&opts.input)?))?;
+    let mut out = pcap::Writer::new(
+        BufWriter::new(File::create(&opts.output)?), pcap::LINKTYPE_ETHERNET)?;
+    let mut conns = TfhStreamConns::new(Sanitizer {
+        zero: opts.zero,
+        chat_major: opts.chat_major,
+        anon: Anonymizer::new(None, true),
+        key: RandomState::new(),
+        names: Vec::new(),
+        known_names: Vec::new(),
+        changed: false,
+        count: 0,
+    });
+
+    let mut held: VecDeque<(Packet, bool)> = VecDeque::with_capacity(HOLD_PACKETS);
+    let mut written = 0;
+    loop {
+        let mut p = match pcap.recv_tagged()? {
+            Some((p, _)) => p,
+            None => break,
+        };
+        if !p.is_tfh_stream() {
+            continue;
+        }
+        let flip = if p.ipv4().dest_ip() == opts.server_ip {
+            false
+        } else if p.ipv4().source_ip() == opts.server_ip {
+            true
+        } else {
+            continue;
+        };
+
+        conns.handle_mut(&mut p, flip);
+        if conns.handler().changed {
+            for &mut (ref mut q, q_flip) in &mut held {
+                conns.apply_patches(q, q_flip);
+            }
+            conns.handler_mut().changed = false;
+        }
+
+        if held.len() == HOLD_PACKETS {
+            let (q, q_flip) = held.pop_front().unwrap();
+            out.write_tagged_packet(&q, Direction::from_flip(q_flip))?;
+            written += 1;
+        }
+        held.push_back((p, flip));
+    }
+    for (q, q_flip) in held {
+        out.write_tagged_packet(&q, Direction::from_flip(q_flip))?;
+        written += 1;
+    }
+    out.flush()?;
+
+    eprintln!("wrote {} packets, sanitized {} messages", written, conns.handler().count);
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! `tfh relay self-test`: a quick check that the relay works on this host, for running after
+//! deploying to a new one.  It starts a relay with the default options between two in-memory
+//! links, plays a synthetic session through it, and checks that every packet comes out the other
+//! side unchanged with valid checksums, and that the session's messages are logged.  `self-test
+//! tun` also checks that tun devices can be created here.
+//!
+//! The relay logs to a temporary directory, so its logs don't mix with real ones.  Unless `-v`
+//! asks for more, the processing output for each packet is turned down to warnings, so the
+//! results stand out.
+use std::fs::{self, File};
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tfh_mitm::Error;
+use tfh_mitm::channel::Receiver;
+use tfh_mitm::config::Config;
+use tfh_mitm::logging::{self, Level, Subsystem};
+use tfh_mitm::packet::Packet;
+use tfh_mitm::process as processing;
+use tfh_mitm::relay;
+use tfh_mitm::stats::RelayStats;
+use tfh_mitm::supervise::Supervisor;
+use tfh_mitm::testing::{self, Delivery, Rng};
+use tfh_mitm::tfh_stream::Message;
+use tfh_mitm::tfhlog;
+use tfh_mitm::transport;
+use tfh_mitm::tuntap;
+
+
+/// How long to wait for the relay to pass on the packets and log the messages.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages sent in each direction.
+const MESSAGES: usize = 200;
+
+/// The outcome of each check, printed as it's made.
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    fn check(&mut self, what: &str, res: Result<String, String>) {
+        match res {
+            Ok(detail) => println!("ok      {}: {}", what, detail),
+            Err(detail) => {
+                println!("FAILED  {}: {}", what, detail);
+                self.failed += 1;
+            },
+        }
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let tun = match args {
+        [] => false,
+        [x] if x == "tun" => true,
+        _ => return Err("usage: tfh relay self-test [tun]".into()),
+    };
+    if !logging::enabled(Subsystem::Handler, Level::Debug) {
+        logging::set_level(Subsystem::Handler, Level::Warn);
+    }
+    let mut report = Report { failed: 0 };
+    if tun {
+        report.check("tun devices", check_tun());
+    }
+
+    let dir = testing::temp_dir("self-test");
+    let res = check_pipeline(&mut report, &dir.join("logs"));
+    let _ = fs::remove_dir_all(&dir);
+    res?;
+
+    if report.failed > 0 {
+        return Err(Error(format!("self-test: {} checks failed", report.failed)));
+    }
+    println!("self-test passed");
+    Ok(())
+}
+
+/// Create a pair of tun devices, which go away again once closed.
+fn check_tun() -> Result<String, String> {
+    let names = ["a", "b"].iter()
+        .map(|side| format!("tfhtest{}{}", process::id() % 100_000, side))
+        .collect::<Vec<_>>();
+    let mut fds = Vec::new();
+    let res = names.iter().map(|name| {
+        fds.push(tuntap::open_tun(name).map_err(|e| e.0)?);
+        Ok(())
+    }).collect::<Result<(), String>>();
+    for fd in fds {
+        let _ = nix::unistd::close(fd);
+    }
+    res.map(|()| format!("created {}", names.join(" and ")))
+}
+
+/// Run a session through a relay on `loopback` links, as `tests/relay.rs` does, logging to
+/// `log_dir`.
+fn check_pipeline(report: &mut Report, log_dir: &Path) -> Result<(), Error> {
+    let client = testing::conn().client();
+    // The server's port is outside 27010-27030, where replies from the server are edited as
+    // server status, so every packet should come through unchanged.
+    let server = SocketAddrV4::new(*testing::conn().server().ip(), 27000);
+    let mut rng = Rng::new(1);
+    let delivery = Delivery::in_order(1000);
+    let msgs_to_server = testing::random_messages(&mut rng, MESSAGES, 500);
+    let msgs_to_client = testing::random_messages(&mut rng, MESSAGES, 2000);
+    let to_server =
+        testing::packetize(&mut rng, &msgs_to_server, &delivery, client, server, 0);
+    let to_client =
+        testing::packetize(&mut rng, &msgs_to_client, &delivery, server, client, 0);
+
+    let (mut cfg, _) = Config::from_args(&[])?;
+    let log_dir_str = log_dir.to_str().ok_or("self-test: temporary directory isn't UTF-8")?;
+    cfg.log_dir = Some(log_dir_str.to_owned());
+    let sup = Supervisor::new();
+    let stats = Arc::new(RelayStats::default());
+    let (inp_send, out_recv) =
+        processing::start_supervised_processing_thread(&cfg, &sup, stats.clone())?;
+    let (outside, side_a) = transport::loopback(64);
+    let (inside, side_b) = transport::loopback(64);
+    relay::start(&cfg, &sup, stats, side_a, side_b, inp_send, out_recv)?;
+
+    // Alternate between the directions, as a real session would.
+    let deadline = Instant::now() + TIMEOUT;
+    let (mut a, mut b) = (to_server.iter(), to_client.iter());
+    let (mut got_server, mut got_client) = (Vec::new(), Vec::new());
+    loop {
+        let (p, q) = (a.next(), b.next());
+        if p.is_none() && q.is_none() {
+            break;
+        }
+        if let Some(p) = p {
+            outside.1.send(p.clone()).map_err(|_| "self-test: relay stopped reading")?;
+            let n = got_server.len() + 1;
+            collect(&inside.0, &mut got_server, n, deadline);
+        }
+        if let Some(q) = q {
+            inside.1.send(q.clone()).map_err(|_| "self-test: relay stopped reading")?;
+            let n = got_client.len() + 1;
+            collect(&outside.0, &mut got_client, n, deadline);
+        }
+    }
+    collect(&inside.0, &mut got_server, to_server.len(), deadline);
+    collect(&outside.0, &mut got_client, to_client.len(), deadline);
+
+    report.check("forwarding to server", same_packets(&got_server, &to_server));
+    report.check("forwarding to client", same_packets(&got_client, &to_client));
+    let mut got = got_server;
+    got.extend(got_client);
+    report.check("checksums", checksums(&got));
+    report.check("logging", logged(log_dir, [&msgs_to_server, &msgs_to_client], deadline));
+    Ok(())
+}
+
+/// Receive packets into `out` until it has `count`, or until `deadline`.
+fn collect(recv: &Receiver<Packet>, out: &mut Vec<Packet>, count: usize, deadline: Instant) {
+    while out.len() < count {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match recv.recv_timeout(left) {
+            Ok(p) => out.push(p),
+            Err(_) => break,
+        }
+    }
+}
+
+fn same_packets(got: &[Packet], want: &[Packet]) -> Result<String, String> {
+    if got.len() != want.len() {
+        return Err(format!("got {} of {} packets", got.len(), want.len()));
+    }
+    match got.iter().zip(want).position(|(a, b)| a.as_slice() != b.as_slice()) {
+        Some(i) => Err(format!("packet {} differs", i)),
+        None => Ok(format!("{} packets", got.len())),
+    }
+}
+
+fn checksums(got: &[Packet]) -> Result<String, String> {
+    for (i, p) in got.iter().enumerate() {
+        if p.ipv4().checksum() != p.compute_ipv4_checksum() {
+            return Err(format!("packet {}: bad IPv4 header checksum", i));
+        }
+        let udp = p.udp().checksum();
+        // Zero means the sender didn't compute one.
+        if udp != 0 && udp != p.compute_udp_checksum(p.udp_payload()) {
+            return Err(format!("packet {}: bad UDP checksum", i));
+        }
+    }
+    Ok(format!("{} packets", got.len()))
+}
+
+/// Check that the session's log in `dir` holds `want`, the messages of each direction, waiting
+/// until `deadline` for processing to catch up.
+fn logged(dir: &Path, want: [&[Message]; 2], deadline: Instant) -> Result<String, String> {
+    loop {
+        let res = read_logs(dir).and_then(|got| {
+            for (dir, want) in want.iter().enumerate() {
+                let got = &got[dir];
+                if got.len() != want.len() {
+                    return Err(format!("direction {}: logged {} of {} messages",
+                        dir, got.len(), want.len()));
+                }
+                if let Some(i) = got.iter().zip(want.iter())
+                    .position(|(a, b)| !testing::same_message(a, b)) {
+                    return Err(format!("direction {}: message {} differs", dir, i));
+                }
+            }
+            Ok(format!("{} messages", got[0].len() + got[1].len()))
+        });
+        if res.is_ok() || Instant::now() >= deadline {
+            return res;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// The messages of each direction in the one session log.
+fn read_logs(dir: &Path) -> Result<[Vec<Message>; 2], String> {
+    let logs = fs::read_dir(dir).and_then(|d| d.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let logs = logs.into_iter().map(|e| e.path())
+        .filter(|p| p.extension().map_or(false, |x| x == "tfhlog"))
+        .collect::<Vec<_>>();
+    if logs.len() != 1 {
+        return Err(format!("expected one session log, found {}", logs.len()));
+    }
+    let path = &logs[0];
+    let err = |e| format!("{}: {}", path.display(), e);
+    let mut r = tfhlog::Reader::new(File::open(&path).map_err(err)?).map_err(err)?;
+    let mut got = [Vec::new(), Vec::new()];
+    while let Some(rec) = r.read().map_err(err)? {
+        got[rec.msg.header.dir as usize & 1].push(rec.msg);
+    }
+    Ok(got)
+}
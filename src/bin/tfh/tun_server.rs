@@ -0,0 +1,194 @@
+use std::env;
+use std::fs;
+use std::mem::{self, MaybeUninit};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::slice;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use libc;
+use nix::errno::Errno;
+use nix::sys::socket::sockopt::PeerCredentials;
+use nix::sys::stat::Mode;
+use tfh_mitm::Error;
+use tfh_mitm::tun_socket::{self, Request};
+use tfh_mitm::tuntap::{self, TunOptions};
+
+
+const USAGE: &str = "usage: tfh tun-server [options] [name=]tunXX... socket
+
+Opens the tun devices and hands them out to clients of the Unix socket.  Clients ask for a
+device by name, which is the interface name unless given as `name=tunXX`.  The first device is
+the default, for clients that don't ask for one in particular.
+
+Only processes running as the same user as tun-server may have a device, unless allowed by:
+  --allow-uid 1000,1001     these users
+  --allow-gid 27            users whose primary group is one of these
+Either option also makes the socket itself accessible to all users, leaving it to this check.
+
+  --allow-create            let clients create new tun devices, with `create` requests";
+
+/// How long to wait for a client's request before giving up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Device {
+    name: String,
+    fd: RawFd,
+}
+
+struct Options {
+    allow_uids: Vec<u32>,
+    allow_gids: Vec<u32>,
+    allow_create: bool,
+    devices: Vec<String>,
+    socket: String,
+}
+
+fn parse_ids(arg: &str, s: &str) -> Result<Vec<u32>, Error> {
+    s.split(',').map(|x| x.parse().map_err(|e| Error(format!("{}: {}", arg, e)))).collect()
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Error> {
+    let mut allow_uids = Vec::new();
+    let mut allow_gids = Vec::new();
+    let mut allow_create = false;
+    let mut positional = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let mut value = || {
+            it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+        };
+        match &arg[..] {
+            "--allow-uid" => allow_uids.extend(parse_ids(arg, &value()?)?),
+            "--allow-gid" => allow_gids.extend(parse_ids(arg, &value()?)?),
+            "--allow-create" => allow_create = true,
+            _ => return Err(Error(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    let socket = match positional.pop() {
+        Some(x) if positional.len() > 0 => x,
+        _ => return Err(USAGE.into()),
+    };
+    Ok(Options { allow_uids, allow_gids, allow_create, devices: positional, socket })
+}
+
+/// Check the credentials of the process on the other end of `socket`.  Returns a description
+/// of the client for logging.
+fn check_peer(socket: &UnixStream, opts: &Options) -> Result<String, String> {
+    let cred = nix::sys::socket::getsockopt(socket.as_raw_fd(), PeerCredentials)
+        .map_err(|e| format!("failed to get peer credentials: {}", e))?;
+    let desc = format!("pid {} (uid {}, gid {})", cred.pid(), cred.uid(), cred.gid());
+    let allowed = cred.uid() == nix::unistd::geteuid().as_raw() ||
+        opts.allow_uids.contains(&cred.uid()) ||
+        opts.allow_gids.contains(&cred.gid());
+    if allowed {
+        Ok(desc)
+    } else {
+        Err(desc)
+    }
+}
+
+fn handle_client(socket: &mut UnixStream, opts: &Options, devices: &[Device]) -> Result<(), Error> {
+    // Read the request even from clients that will be rejected, so they see the error reply
+    // instead of a broken pipe.
+    let req = tun_socket::read_request(socket)?;
+    let client = match check_peer(socket, opts) {
+        Ok(x) => x,
+        Err(client) => {
+            eprintln!("rejected client {}", client);
+            return tun_socket::send_error(socket, "permission denied");
+        },
+    };
+
+    let found = match req {
+        Request::Get(ref name) if name.len() == 0 => Ok(&devices[0]),
+        Request::Get(name) => devices.iter().find(|d| d.name == name)
+            .ok_or_else(|| format!("no device named {:?}", name)),
+        Request::Create(name, tun_opts) => {
+            if !opts.allow_create {
+                return tun_socket::send_error(socket, "creating devices is not allowed");
+            }
+            return create_device(socket, &client, &name, tun_opts);
+        },
+    };
+    match found {
+        Ok(dev) => {
+            eprintln!("sending device {:?} to {}", dev.name, client);
+            tun_socket::send_fd(socket, dev.fd)
+        },
+        Err(msg) => tun_socket::send_error(socket, &msg),
+    }
+}
+
+/// Open a new device for the client.  The server doesn't keep it, so unless it's persistent,
+/// it goes away once the client closes it.
+fn create_device(
+    socket: &mut UnixStream,
+    client: &str,
+    name: &str,
+    tun_opts: TunOptions,
+) -> Result<(), Error> {
+    let fd = match tuntap::open_tun_with(name, tun_opts) {
+        Ok(x) => x,
+        Err(e) => return tun_socket::send_error(socket, &e.to_string()),
+    };
+    eprintln!("created device {:?} ({:?}) for {}", name, tun_opts, client);
+    let res = tun_socket::send_fd(socket, fd);
+    nix::unistd::close(fd)?;
+    res
+}
+
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let opts = parse_args(&args[1..])?;
+
+    let mut devices = Vec::new();
+    for arg in &opts.devices {
+        let (name, if_name) = match arg.find('=') {
+            Some(i) => (&arg[..i], &arg[i + 1 ..]),
+            None => (&arg[..], &arg[..]),
+        };
+        let fd = tuntap::open_tun(if_name)?;
+        devices.push(Device { name: name.to_owned(), fd });
+    }
+
+
+    // `bind` will fail if the socket already exists from a previous run.
+    let socket_path = Path::new(&opts.socket);
+    match socket_path.symlink_metadata() {
+        Ok(m) => {
+            // For safety, we only remove if it's really a socket.  Other files are left intact
+            // (and will cause `bind` to fail later on).
+            if m.file_type().is_socket() {
+                fs::remove_file(socket_path)?;
+            }
+        },
+        // Ignore errors, particularly "not found".
+        Err(_) => {},
+    }
+
+    nix::sys::stat::umask(Mode::S_IRWXG | Mode::S_IRWXO);
+    let listener = UnixListener::bind(socket_path)?;
+    if opts.allow_uids.len() > 0 || opts.allow_gids.len() > 0 {
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(0o777))?;
+    }
+    for socket in listener.incoming() {
+        let mut socket = socket?;
+        // A client that never sends its request shouldn't hold up everyone else.
+        socket.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        match handle_client(&mut socket, &opts, &devices) {
+            Ok(()) => {},
+            Err(e) => eprintln!("client error: {}", e),
+        }
+    }
+
+    Ok(())
+}
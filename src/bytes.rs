@@ -1,4 +1,4 @@
-use std::convert::TryInto;
+use core::convert::TryInto;
 
 pub trait Bytes {
     fn u8_be(&self, i: usize) -> u8;
@@ -0,0 +1,290 @@
+//! The protocol-change canary, with `--canary PROFILE`: live traffic is checked against a profile
+//! of what each opcode's messages looked like before, and when they stop matching, as after a
+//! game patch, an alert says which opcode changed, so the decoders can be fixed before much is
+//! exported wrong.
+//!
+//! `tfh log-profile` writes the profile from logs of known-good traffic.  It's text, with a line
+//! per opcode giving its direction, opcodes, the number of messages it was learned from, and the
+//! shortest and longest body.  Indented lines after it give the smallest and largest value seen
+//! of each integer field named in the opcodes file (see `opcodes`), as `OFFSET TYPE NAME MIN MAX`:
+//!
+//! ```text
+//! 0 0a:00 1520 76 140
+//!     8 u32 version 1180 1180
+//! 1 20:05 310 24 24
+//!     0 u32 match_id 1 40211
+//! ```
+//!
+//! A message matches its opcode's profile if its body length and fields are in range.  Messages
+//! are counted per opcode over a minute, across all connections.  Once at least `MIN_MESSAGES`
+//! of an opcode have been seen in a minute and more than `MAX_MISMATCH_PERCENT` of them don't
+//! match, or the opcode isn't in the profile at all, the canary raises an alert, then stays quiet
+//! about that opcode for `COOLDOWN`.  Opcodes learned from fewer than `MIN_MESSAGES` messages
+//! are only checked for being in the profile, since their ranges are too narrow to go by.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write as _};
+use std::fs;
+use crate::{Error, ErrorAt};
+use crate::opcodes::{self, Field, FieldType, Registry};
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::json;
+
+
+/// Length of a counting window, in microseconds.
+const WINDOW: u64 = 60_000_000;
+
+/// Messages of an opcode needed in a window before it can alert, and in the profile before its
+/// ranges are checked.
+pub const MIN_MESSAGES: u64 = 20;
+
+/// Share of an opcode's messages in a window that can fail to match before it alerts.
+pub const MAX_MISMATCH_PERCENT: u64 = 10;
+
+/// Time after an alert before the same opcode can alert again, in microseconds.
+const COOLDOWN: u64 = 600_000_000;
+
+/// The values seen of one integer field.
+#[derive(Clone, Debug)]
+pub struct FieldRange {
+    pub field: Field,
+    pub min: u64,
+    pub max: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct OpcodeProfile {
+    /// Messages the profile was learned from.
+    pub count: u64,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub fields: Vec<FieldRange>,
+}
+
+impl OpcodeProfile {
+    /// How `body` doesn't match, if it doesn't.
+    pub fn mismatch(&self, body: &[u8]) -> Option<String> {
+        if body.len() < self.min_len || body.len() > self.max_len {
+            return Some(format!("body is {} bytes, expected {}..{}",
+                body.len(), self.min_len, self.max_len));
+        }
+        for f in &self.fields {
+            match f.field.uint(body) {
+                Some(v) if v < f.min || v > f.max => {
+                    let name = &f.field.name;
+                    return Some(format!("{} is {}, expected {}..{}", name, v, f.min, f.max));
+                },
+                _ => {},
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    /// Keyed by direction, major, and minor opcode.
+    pub opcodes: BTreeMap<(u8, u8, u8), OpcodeProfile>,
+}
+
+impl Profile {
+    /// Add `msg` to the profile, with ranges for the integer fields `opcodes` lists for it in the
+    /// layout for client version `version` (see `Registry::for_version`).  The fields are picked
+    /// by the first message of each opcode, so a profile should be learned from one version.
+    pub fn learn(&mut self, msg: &Message, opcodes: Option<&Registry>, version: Option<u32>) {
+        let h = &msg.header;
+        let len = msg.body.len();
+        let p = self.opcodes.entry((h.dir, h.major, h.minor)).or_insert_with(|| {
+            let op = opcodes.and_then(|r| r.for_version(version).get(h.major, h.minor));
+            let fields = op.map_or(Vec::new(), |op| {
+                op.fields.iter()
+                    .filter(|f| f.ty.is_integer())
+                    .map(|f| FieldRange { field: f.clone(), min: u64::MAX, max: 0 })
+                    .collect()
+            });
+            OpcodeProfile { count: 0, min_len: len, max_len: len, fields }
+        });
+        p.count += 1;
+        p.min_len = p.min_len.min(len);
+        p.max_len = p.max_len.max(len);
+        for f in &mut p.fields {
+            if let Some(v) = f.field.uint(&msg.body) {
+                f.min = f.min.min(v);
+                f.max = f.max.max(v);
+            }
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Profile, Error> {
+        let mut profile = Profile::default();
+        let mut cur = None;
+        for (i, line) in s.lines().enumerate() {
+            let err = |e: String| Error(format!("line {}: {}", i + 1, e));
+            let text = line.find('#').map_or(line, |j| &line[..j]);
+            let words = text.split_whitespace().collect::<Vec<_>>();
+            if words.len() == 0 {
+                continue;
+            }
+            let num = |w: &str| w.parse::<u64>().map_err(|e| err(format!("{}: {}", w, e)));
+
+            if !line.starts_with(char::is_whitespace) {
+                if words.len() != 5 {
+                    return Err(err("expected DIR MAJOR:MINOR COUNT MIN_LEN MAX_LEN".into()));
+                }
+                let dir = match words[0] {
+                    "0" => 0,
+                    "1" => 1,
+                    x => return Err(err(format!("bad direction {:?}", x))),
+                };
+                let (major, minor) = match opcodes::parse_opcode(words[1]).map_err(err)? {
+                    (major, Some(minor)) => (major, minor),
+                    (_, None) => return Err(err(format!("{}: expected MAJOR:MINOR", words[1]))),
+                };
+                let key = (dir, major, minor);
+                if profile.opcodes.contains_key(&key) {
+                    return Err(err(format!("{} {} is listed twice", words[0], words[1])));
+                }
+                profile.opcodes.insert(key, OpcodeProfile {
+                    count: num(words[2])?,
+                    min_len: num(words[3])? as usize,
+                    max_len: num(words[4])? as usize,
+                    fields: Vec::new(),
+                });
+                cur = Some(key);
+                continue;
+            }
+
+            let key = cur.ok_or_else(|| err("field comes before any opcode".into()))?;
+            if words.len() != 5 {
+                return Err(err("expected OFFSET TYPE NAME MIN MAX".into()));
+            }
+            let field = Field {
+                offset: num(words[0])? as usize,
+                ty: FieldType::parse(words[1]).map_err(err)?,
+                name: words[2].to_owned(),
+            };
+            let range = FieldRange { field, min: num(words[3])?, max: num(words[4])? };
+            profile.opcodes.get_mut(&key).unwrap().fields.push(range);
+        }
+        Ok(profile)
+    }
+
+    pub fn load(path: &str) -> Result<Profile, Error> {
+        let s = fs::read_to_string(path).at(path)?;
+        Profile::parse(&s).map_err(|e| Error(format!("{}: {}", path, e)))
+    }
+
+    /// The profile in the format `parse` reads.  Fields that were never seen are left out.
+    pub fn to_text(&self) -> String {
+        let mut s = String::new();
+        for (&(dir, major, minor), p) in &self.opcodes {
+            writeln!(s, "{} {:02x}:{:02x} {} {} {}",
+                dir, major, minor, p.count, p.min_len, p.max_len).unwrap();
+            for f in p.fields.iter().filter(|f| f.min <= f.max) {
+                writeln!(s, "    {} {} {} {} {}",
+                    f.field.offset, f.field.ty, f.field.name, f.min, f.max).unwrap();
+            }
+        }
+        s
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CanaryAlert {
+    pub time: u64,
+    /// The connection of the message that set off the alert.
+    pub ct: ConnTuple,
+    pub dir: u8,
+    pub major: u8,
+    pub minor: u8,
+    /// Messages of the opcode seen in the window, and how many of them didn't match.
+    pub checked: u64,
+    pub mismatched: u64,
+    /// How the first of them didn't match.
+    pub example: String,
+}
+
+impl CanaryAlert {
+    pub fn to_json(&self) -> String {
+        let mut obj = json::Object::new();
+        self.write_json(&mut obj);
+        obj.finish()
+    }
+
+    /// Add the fields of `to_json` to `obj`, for embedding them in a larger object.
+    pub fn write_json(&self, obj: &mut json::Object) {
+        obj.num("time", self.time)
+            .str("conn", &self.ct.to_string())
+            .num("dir", self.dir)
+            .str("opcodes", &format!("{:02x}:{:02x}", self.major, self.minor))
+            .num("checked", self.checked)
+            .num("mismatched", self.mismatched)
+            .str("example", &self.example);
+    }
+}
+
+impl fmt::Display for CanaryAlert {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt, "dir {} {:02x}:{:02x}: {} of {} messages in the last minute don't match the \
+                profile (e.g. on {}: {})",
+            self.dir, self.major, self.minor, self.mismatched, self.checked, self.ct, self.example,
+        )
+    }
+}
+
+#[derive(Default)]
+struct Window {
+    start: u64,
+    checked: u64,
+    mismatched: u64,
+    example: Option<String>,
+    /// No alerts until this time.
+    quiet_until: u64,
+}
+
+pub struct Canary {
+    profile: Profile,
+    windows: HashMap<(u8, u8, u8), Window>,
+}
+
+impl Canary {
+    pub fn new(profile: Profile) -> Canary {
+        Canary { profile, windows: HashMap::new() }
+    }
+
+    /// Check `msg` against the profile, returning an alert if its opcode has stopped matching.
+    pub fn check(&mut self, ct: ConnTuple, msg: &Message) -> Option<CanaryAlert> {
+        let h = &msg.header;
+        let key = (h.dir, h.major, h.minor);
+        let mismatch = match self.profile.opcodes.get(&key) {
+            None => Some("opcode isn't in the profile".to_owned()),
+            Some(p) if p.count < MIN_MESSAGES => None,
+            Some(p) => p.mismatch(&msg.body),
+        };
+        let now = msg.time;
+        let w = self.windows.entry(key).or_default();
+        if now.saturating_sub(w.start) >= WINDOW {
+            *w = Window { start: now, quiet_until: w.quiet_until, .. Window::default() };
+        }
+        w.checked += 1;
+        if let Some(m) = mismatch {
+            w.mismatched += 1;
+            w.example.get_or_insert(m);
+        }
+        if w.checked < MIN_MESSAGES || w.mismatched * 100 <= w.checked * MAX_MISMATCH_PERCENT ||
+                now < w.quiet_until {
+            return None;
+        }
+        w.quiet_until = now + COOLDOWN;
+        Some(CanaryAlert {
+            time: now,
+            ct,
+            dir: h.dir,
+            major: h.major,
+            minor: h.minor,
+            checked: w.checked,
+            mismatched: w.mismatched,
+            example: w.example.clone().unwrap_or_default(),
+        })
+    }
+}
@@ -0,0 +1,90 @@
+//! Recording the packets the relay processes to a pcap file.  With a filter, only connections
+//! that carry a matching message are recorded: their packets are held back until a message
+//! matches, then written along with everything after it, and dropped if the connection ends
+//! without a match.
+//!
+//! Each packet's direction is recorded in its MAC addresses (see `pcap::MAC_A`), so the capture
+//! can be replayed without knowing the server's address.
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use crate::filter::MessageFilter;
+use crate::packet::Packet;
+use crate::pcap::{self, Direction};
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+/// Most packets to hold back for each connection that hasn't matched yet.  Older ones are
+/// dropped, so a long connection that matches late is recorded from partway through.
+const MAX_HELD: usize = 1024;
+
+#[derive(Default)]
+struct ConnState {
+    matched: bool,
+    held: VecDeque<(Packet, Direction)>,
+}
+
+pub struct Capture {
+    out: pcap::Writer<BufWriter<File>>,
+    filter: Option<MessageFilter>,
+    conns: HashMap<ConnTuple, ConnState>,
+}
+
+impl Capture {
+    pub fn create(path: &str, filter: Option<MessageFilter>) -> io::Result<Capture> {
+        let out = pcap::Writer::new(BufWriter::new(File::create(path)?), pcap::LINKTYPE_ETHERNET)?;
+        Ok(Capture { out, filter, conns: HashMap::new() })
+    }
+
+    /// Check a decoded message against the filter.  Call this for each message, before
+    /// `record` for the packet that completed it.
+    pub fn on_message(&mut self, ct: ConnTuple, msg: &Message) {
+        let filter = match self.filter {
+            Some(ref x) => x,
+            None => return,
+        };
+        let state = self.conns.entry(ct).or_default();
+        if !state.matched && filter.matches(ct, msg) {
+            log!(Handler, Info, "{:?}: capturing connection", ct);
+            state.matched = true;
+        }
+    }
+
+    /// Record `p`, or hold it back if its connection hasn't matched the filter yet.  `flip` and
+    /// `now` are as for `TfhStreamConns::handle`; packets without a capture timestamp are
+    /// recorded with time `now`.
+    pub fn record(&mut self, p: &Packet, flip: bool, now: u64) -> io::Result<()> {
+        let mut p = p.clone();
+        if p.time().is_none() {
+            p.set_time(Some(now));
+        }
+        let dir = Direction::from_flip(flip);
+        if self.filter.is_none() {
+            return self.out.write_tagged_packet(&p, dir);
+        }
+        if !p.is_tfh_stream() {
+            return Ok(());
+        }
+        let state = self.conns.entry(ConnTuple::from_udp_packet(&p, flip)).or_default();
+        if !state.matched {
+            if state.held.len() == MAX_HELD {
+                state.held.pop_front();
+            }
+            state.held.push_back((p, dir));
+            return Ok(());
+        }
+        for (q, q_dir) in state.held.drain(..) {
+            self.out.write_tagged_packet(&q, q_dir)?;
+        }
+        self.out.write_tagged_packet(&p, dir)
+    }
+
+    /// Forget connection `ct`, discarding its held packets if it never matched.
+    pub fn close(&mut self, ct: ConnTuple) {
+        self.conns.remove(&ct);
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
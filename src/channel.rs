@@ -0,0 +1,266 @@
+//! Bounded multi-producer, single-consumer channel with a configurable overflow policy.
+//!
+//! The relay's threads are connected by these rather than unbounded `std::sync::mpsc` channels,
+//! so a stalled consumer costs dropped packets (counted, and reported by the relay) instead of
+//! unbounded memory growth.
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::Error;
+
+
+/// Default channel capacity.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// What `Sender::send` does when the channel is full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    /// Wait for the receiver to make room.  Nothing is dropped.
+    Block,
+    /// Discard the item being sent.
+    DropNewest,
+    /// Discard the oldest queued item to make room.  This keeps latency bounded, since what
+    /// eventually gets through is the most recent traffic.
+    DropOldest,
+}
+
+impl FromStr for Overflow {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Overflow, Error> {
+        match s {
+            "block" => Ok(Overflow::Block),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            _ => Err(Error(format!(
+                "unknown overflow policy {:?} (expected block, drop-newest, or drop-oldest)", s))),
+        }
+    }
+}
+
+/// Error from `Sender::send` when the receiver is gone.  Holds the item that wasn't sent.
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("SendError { .. }")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("sending on a closed channel")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecvTimeoutError {
+    Timeout,
+    /// The channel is empty and all senders are gone.
+    Disconnected,
+}
+
+/// Handle on a channel's count of dropped items, usable without keeping the channel open.
+#[derive(Clone)]
+pub struct DropCounter(Arc<AtomicU64>);
+
+impl DropCounter {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Handle on the most items a channel has held at once, usable without keeping the channel open.
+#[derive(Clone)]
+pub struct HighWater(Arc<AtomicU64>);
+
+impl HighWater {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+    dropped: DropCounter,
+    high_water: HighWater,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn bounded<T>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be nonzero");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity,
+        overflow,
+        dropped: DropCounter(Arc::new(AtomicU64::new(0))),
+        high_water: HighWater(Arc::new(AtomicU64::new(0))),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Queue `x`, applying the overflow policy if the channel is full.  Fails only if the
+    /// receiver is gone.  A dropped item still counts as sent.
+    pub fn send(&self, x: T) -> Result<(), SendError<T>> {
+        let sh = &self.shared;
+        let mut state = sh.state.lock().unwrap();
+        loop {
+            if !state.receiver_alive {
+                return Err(SendError(x));
+            }
+            if state.items.len() < sh.capacity {
+                break;
+            }
+            match sh.overflow {
+                Overflow::Block => {
+                    state = sh.not_full.wait(state).unwrap();
+                },
+                Overflow::DropNewest => {
+                    sh.dropped.0.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                },
+                Overflow::DropOldest => {
+                    state.items.pop_front();
+                    sh.dropped.0.fetch_add(1, Ordering::Relaxed);
+                    break;
+                },
+            }
+        }
+        state.items.push_back(x);
+        sh.high_water.0.fetch_max(state.items.len() as u64, Ordering::Relaxed);
+        sh.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Number of items discarded so far because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.get()
+    }
+
+    pub fn drop_counter(&self) -> DropCounter {
+        self.shared.dropped.clone()
+    }
+
+    pub fn high_water(&self) -> HighWater {
+        self.shared.high_water.clone()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Wait for the next item.  Returns `None` once the channel is empty and all senders are
+    /// gone.
+    pub fn recv(&self) -> Option<T> {
+        let sh = &self.shared;
+        let mut state = sh.state.lock().unwrap();
+        loop {
+            if let Some(x) = state.items.pop_front() {
+                sh.not_full.notify_one();
+                return Some(x);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = sh.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Like `recv`, but give up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let sh = &self.shared;
+        let mut state = sh.state.lock().unwrap();
+        loop {
+            if let Some(x) = state.items.pop_front() {
+                sh.not_full.notify_one();
+                return Ok(x);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = sh.not_empty.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Take the next item if there is one, without waiting.
+    pub fn try_recv(&self) -> Option<T> {
+        let sh = &self.shared;
+        let x = sh.state.lock().unwrap().items.pop_front();
+        if x.is_some() {
+            sh.not_full.notify_one();
+        }
+        x
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.recv())
+    }
+
+    /// Number of items discarded so far because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.get()
+    }
+
+    pub fn drop_counter(&self) -> DropCounter {
+        self.shared.dropped.clone()
+    }
+
+    pub fn high_water(&self) -> HighWater {
+        self.shared.high_water.clone()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_alive = false;
+        state.items.clear();
+        self.shared.not_full.notify_all();
+    }
+}
@@ -0,0 +1,48 @@
+//! Human-readable chat transcripts, one file per UTC day.
+//!
+//! Each line is `HH:MM:SS name (conn): text`.  The files go in their own directory, named
+//! `YYYY-MM-DD.txt`, and are appended to, so restarting the relay continues the day's transcript.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use crate::tfh_stream::ConnTuple;
+use crate::util::clock;
+
+
+pub struct ChatLog {
+    dir: PathBuf,
+    /// The open transcript, and the date it's for.
+    file: Option<(String, File)>,
+}
+
+impl ChatLog {
+    pub fn new(dir: &str) -> io::Result<ChatLog> {
+        fs::create_dir_all(dir)?;
+        Ok(ChatLog {
+            dir: dir.into(),
+            file: None,
+        })
+    }
+
+    /// Append a line to the transcript for the day of `time_us`.  `name` is the sender's login
+    /// name, if known.
+    pub fn write(
+        &mut self,
+        time_us: u64,
+        ct: ConnTuple,
+        name: Option<&str>,
+        text: &str,
+    ) -> io::Result<()> {
+        let secs = (time_us / 1_000_000) as i64;
+        let date = clock::utc_date(secs);
+        if self.file.as_ref().map_or(true, |&(ref d, _)| *d != date) {
+            let path = self.dir.join(format!("{}.txt", date));
+            let f = OpenOptions::new().create(true).append(true).open(path)?;
+            self.file = Some((date, f));
+        }
+        let f = &mut self.file.as_mut().unwrap().1;
+        // Keep each message on one line, whatever the player typed.
+        let text = text.replace(|c: char| c.is_control(), " ");
+        writeln!(f, "{} {} ({}): {}", clock::utc_time(secs), name.unwrap_or("?"), ct, text)
+    }
+}
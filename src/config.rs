@@ -0,0 +1,419 @@
+use std::net::Ipv4Addr;
+use crate::Error;
+use crate::affinity::{self, Role};
+use crate::alerts::{self, Rule};
+use crate::anonymize::IpMode;
+use crate::channel::Overflow;
+use crate::dedup::Dedup;
+use crate::filter::{self, MessageFilter};
+use crate::framing;
+use crate::log_layout::{self, LogLayout};
+use crate::logging::{self, Level, Subsystem};
+use crate::pacing::{self, Pacer};
+use crate::ratelimit::{Limits, RateLimiter};
+use crate::sendmmsg;
+
+
+/// Optional settings for the relay and replay tools, given as `--name value` command-line
+/// options ahead of or mixed in with the usual positional arguments.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Serve decoded messages as JSON over WebSocket on this address (`host:port`).  Requires
+    /// the `websocket` feature, which is on by default.
+    pub websocket: Option<String>,
+    /// Serve the gRPC interface on this address.  Requires the `grpc` feature.
+    pub grpc: Option<String>,
+    /// Publish decoded messages on a ZeroMQ-compatible PUB endpoint at this address.
+    pub zmq_pub: Option<String>,
+    /// Send messages to these Kafka brokers (comma-separated).  Requires the `kafka` feature.
+    pub kafka: Option<String>,
+    /// Kafka topic for messages.  Session events go to `<topic>-events`.  Defaults to `tfh`.
+    pub kafka_topic: Option<String>,
+    /// `json` (the default) or `binary`.
+    pub kafka_format: Option<String>,
+    /// Path of the Unix socket for control commands.  Also enables the in-memory message store
+    /// that backs them.
+    pub control: Option<String>,
+    /// Capacity of the queues between the reader, processing, and writer threads.
+    pub queue_len: Option<usize>,
+    /// What to do when a queue is full.  Defaults to `drop-oldest` for the live relay and
+    /// `block` when replaying captures, where nothing should be lost.
+    pub overflow: Option<Overflow>,
+    /// Number of threads to spread TFH stream processing across.  Defaults to 1.
+    pub workers: Option<usize>,
+    /// How often the relay prints traffic counters, in seconds.  Defaults to 60.
+    pub stats_interval: Option<u64>,
+    /// Also write the counters as JSON to this file at each interval.
+    pub stats_file: Option<String>,
+    /// Write thread liveness and the latest packet times as JSON to this file every second.
+    pub health_file: Option<String>,
+    /// Instead of relaying between tun devices, listen for UDP on this address and proxy to the
+    /// server given as the positional argument.
+    pub proxy: Option<String>,
+    /// Like `proxy`, but run as a transparent proxy behind a `TPROXY` rule, forwarding each
+    /// datagram to its original destination.
+    pub tproxy: Option<String>,
+    /// Pinned `XSKMAP` for an `xdp:` outside interface to add its socket to.  Requires the `xdp`
+    /// feature.
+    pub xdp_map: Option<String>,
+    /// Message rate limits that raise an alert when a connection exceeds them.
+    pub alert_rates: Vec<Rule>,
+    /// POST each alert as JSON to this `http://` URL.
+    pub alert_webhook: Option<String>,
+    /// On each alert, write the connection's recent messages from the message store to a
+    /// tfhlog in this directory.
+    pub alert_dump: Option<String>,
+    /// Check live traffic against the opcode profile in this file, and alert when an opcode
+    /// stops matching it.  See `canary`.
+    pub canary: Option<String>,
+    /// Record processed packets to this pcap file.
+    pub capture: Option<String>,
+    /// Only record connections that carry a message matching this filter.
+    pub capture_filter: Option<MessageFilter>,
+    /// Which opcodes go in separate log files, from `--log-class`, are left out of the logs, from
+    /// `--log-skip`, or are sampled, from `--log-sample`, the caps from `--log-max-bytes` and
+    /// `--log-max-messages`, and whether to index the logs, from `--log-index`.
+    pub log_layout: LogLayout,
+    /// Where to store each session's messages, by name.  See `sink::open`.
+    pub log_sink: Option<String>,
+    /// The directory for the logs, instead of `logs`.
+    pub log_dir: Option<String>,
+    /// Start new log files every this many seconds.
+    pub log_rotate: Option<u64>,
+    /// Most bytes the logs directory may take before old logs are deleted.  See
+    /// `disk_watchdog`.
+    pub log_budget: Option<u64>,
+    /// Delete old logs to keep this many bytes free on the disk holding them.
+    pub log_min_free: Option<u64>,
+    /// Compress old logs before deleting any to stay within `log_budget` and `log_min_free`.
+    pub log_compress: bool,
+    /// Write chat transcripts to this directory.
+    pub chat_log: Option<String>,
+    /// Opcode names and field layouts to add to the WebSocket feed and control socket events.
+    /// The file is reloaded when it changes.  See `opcodes` for the format.
+    pub opcodes: Option<String>,
+    /// Major opcode of chat messages, if not `messages::MAJOR_CHAT`.
+    pub chat_major: Option<u8>,
+    /// Append a JSON record of each finished match to this file.
+    pub match_log: Option<String>,
+    /// Append each player's rating changes to this CSV file.
+    pub rating_log: Option<String>,
+    /// Cross-check login names against the lobby roster.  See `roster`.
+    pub check_roster: bool,
+    /// Opcodes of keepalive messages, whose spacing is tracked.  See `keepalive`.
+    pub keepalive: Vec<log_layout::OpcodeSpec>,
+    /// Rewrite this file with the keepalive figures of every connection.
+    pub keepalive_stats: Option<String>,
+    /// Append each decoding anomaly and stall to this file as JSON lines.  See `parse_warnings`.
+    pub parse_warnings: Option<String>,
+    /// Save connection state to this file, and resume the connections in it at startup.  See
+    /// `snapshot`.
+    pub snapshot: Option<String>,
+    /// Send the snapshot to standby relays connecting to this address.  See `failover`.
+    pub failover_listen: Option<String>,
+    /// Stand by, copying the snapshot of the active relay at this address, and take over once
+    /// it's gone.
+    pub standby: Option<String>,
+    /// Player names to rewrite in passing traffic, as `(old, new)` pairs.
+    pub rename: Vec<(String, String)>,
+    /// Hide client IPs in everything the handler writes.
+    pub anonymize: Option<IpMode>,
+    /// Replace player names in login messages with pseudonyms before they're logged.
+    pub redact_names: bool,
+    /// Packets per second to accept from each source IP outside the sandbox.
+    pub rate_limit: Option<f64>,
+    /// New connections per second to accept from each source IP outside the sandbox.
+    pub conn_rate_limit: Option<f64>,
+    /// Ban sources that go over a rate limit for this many seconds.
+    pub ban_secs: Option<u64>,
+    /// Most processed packets to write toward the server per pacing tick.
+    pub pace: Option<u32>,
+    /// Length of a pacing tick in milliseconds.  Defaults to 1.
+    pub pace_tick_ms: Option<u64>,
+    /// In proxy mode, most datagrams for one flow to send with a single system call.
+    pub write_batch: Option<usize>,
+    /// Drop connections that haven't finished the TFH handshake after this many seconds.
+    pub handshake_timeout: Option<u64>,
+    /// Print how each packet of connections with this endpoint (address and optional port) was
+    /// reassembled.  See `TfhStreamConns::set_trace`.
+    pub trace: Option<(u32, Option<u16>)>,
+    /// Longest TFH message to accept, in bytes.  A longer length field makes the stream skip
+    /// ahead to the next plausible message.  Defaults to `tfh_stream::DEFAULT_MAX_MESSAGE_LEN`.
+    pub max_message_len: Option<usize>,
+    /// Drop packets that duplicate one seen within this many milliseconds.
+    pub dedup_ms: Option<u64>,
+    /// Answer pings to this address that arrive on the inside tun device, standing in for the
+    /// sandbox's default gateway.
+    pub gateway: Option<Ipv4Addr>,
+    /// CPUs to pin each kind of thread to, from `--cpus`, as masks.
+    pub cpus: Vec<(Role, u64)>,
+    /// Net count of `-v` minus `-q` flags, applied to every subsystem's log level.
+    pub verbosity: i32,
+    /// Per-subsystem overrides from `--log`, applied after `verbosity`.
+    pub log_levels: Vec<(Subsystem, Level)>,
+}
+
+impl Config {
+    /// Parse options out of `args` (not including the program name).  Returns the config along
+    /// with the remaining positional arguments.
+    pub fn from_args(args: &[String]) -> Result<(Config, Vec<String>), Error> {
+        let mut cfg = Config::default();
+        let mut positional = Vec::new();
+
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            if arg == "-v" || arg == "-q" {
+                cfg.verbosity += if arg == "-v" { 1 } else { -1 };
+                continue;
+            }
+            if !arg.starts_with("--") {
+                positional.push(arg.clone());
+                continue;
+            }
+
+            let mut value = || {
+                it.next().cloned().ok_or_else(|| Error(format!("{}: missing value", arg)))
+            };
+
+            match &arg[2..] {
+                "websocket" => cfg.websocket = Some(value()?),
+                "grpc" => cfg.grpc = Some(value()?),
+                "zmq-pub" => cfg.zmq_pub = Some(value()?),
+                "kafka" => cfg.kafka = Some(value()?),
+                "kafka-topic" => cfg.kafka_topic = Some(value()?),
+                "kafka-format" => cfg.kafka_format = Some(value()?),
+                "control" => cfg.control = Some(value()?),
+                "queue-len" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 {
+                        return Err(Error(format!("{}: must be at least 1", arg)));
+                    }
+                    cfg.queue_len = Some(n);
+                },
+                "overflow" => cfg.overflow = Some(value()?.parse()?),
+                "workers" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 {
+                        return Err(Error(format!("{}: must be at least 1", arg)));
+                    }
+                    cfg.workers = Some(n);
+                },
+                "stats-interval" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 {
+                        return Err(Error(format!("{}: must be at least 1", arg)));
+                    }
+                    cfg.stats_interval = Some(n);
+                },
+                "stats-file" => cfg.stats_file = Some(value()?),
+                "health-file" => cfg.health_file = Some(value()?),
+                "proxy" => cfg.proxy = Some(value()?),
+                "tproxy" => cfg.tproxy = Some(value()?),
+                "xdp-map" => cfg.xdp_map = Some(value()?),
+                "chat-log" => cfg.chat_log = Some(value()?),
+                "capture" => cfg.capture = Some(value()?),
+                "alert-rate" => cfg.alert_rates.push(alerts::parse_rule(&value()?)?),
+                "alert-webhook" => {
+                    let v = value()?;
+                    alerts::check_webhook_url(&v)?;
+                    cfg.alert_webhook = Some(v);
+                },
+                "alert-dump" => cfg.alert_dump = Some(value()?),
+                "canary" => cfg.canary = Some(value()?),
+                "capture-filter" => {
+                    let f = MessageFilter::parse_query(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.capture_filter = Some(f);
+                },
+                "chat-major" => {
+                    let v = value()?;
+                    let major = u8::from_str_radix(v.trim_start_matches("0x"), 16)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.chat_major = Some(major);
+                },
+                "opcodes" => cfg.opcodes = Some(value()?),
+                "log-class" => {
+                    cfg.log_layout.add_class(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                },
+                "log-skip" => {
+                    let list = log_layout::parse_opcode_list(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.log_layout.skip.extend(list);
+                },
+                "log-sample" => {
+                    cfg.log_layout.add_sampling(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                },
+                "log-max-bytes" => {
+                    let size = log_layout::parse_size(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.log_layout.max_bytes = Some(size);
+                },
+                "log-max-messages" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.log_layout.max_messages = Some(n);
+                },
+                "log-index" => cfg.log_layout.index = true,
+                "log-sink" => cfg.log_sink = Some(value()?),
+                "log-dir" => cfg.log_dir = Some(value()?),
+                "log-rotate" => {
+                    let secs = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.log_rotate = Some(secs);
+                },
+                "log-budget" => {
+                    let size = log_layout::parse_size(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.log_budget = Some(size);
+                },
+                "log-min-free" => {
+                    let size = log_layout::parse_size(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.log_min_free = Some(size);
+                },
+                "log-compress" => cfg.log_compress = true,
+                "match-log" => cfg.match_log = Some(value()?),
+                "rating-log" => cfg.rating_log = Some(value()?),
+                "check-roster" => cfg.check_roster = true,
+                "keepalive" => {
+                    let list = log_layout::parse_opcode_list(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.keepalive.extend(list);
+                },
+                "keepalive-stats" => cfg.keepalive_stats = Some(value()?),
+                "parse-warnings" => cfg.parse_warnings = Some(value()?),
+                "snapshot" => cfg.snapshot = Some(value()?),
+                "failover-listen" => cfg.failover_listen = Some(value()?),
+                "standby" => cfg.standby = Some(value()?),
+                "rename" => {
+                    let v = value()?;
+                    let (old, new) = match v.find('=') {
+                        Some(i) => (&v[..i], &v[i + 1..]),
+                        None => return Err(Error(format!("{}: expected OLD=NEW", arg))),
+                    };
+                    if old.is_empty() {
+                        return Err(Error(format!("{}: name is empty", arg)));
+                    }
+                    cfg.rename.push((old.to_owned(), new.to_owned()));
+                },
+                "anonymize" => cfg.anonymize = Some(value()?.parse()?),
+                "redact-names" => cfg.redact_names = true,
+                "rate-limit" | "conn-rate-limit" => {
+                    let n: f64 = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if !(n > 0.) {
+                        return Err(Error(format!("{}: must be positive", arg)));
+                    }
+                    if arg == "--rate-limit" {
+                        cfg.rate_limit = Some(n);
+                    } else {
+                        cfg.conn_rate_limit = Some(n);
+                    }
+                },
+                "ban" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.ban_secs = Some(n);
+                },
+                "pace" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 {
+                        return Err(Error(format!("{}: must be at least 1", arg)));
+                    }
+                    cfg.pace = Some(n);
+                },
+                "pace-tick" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 {
+                        return Err(Error(format!("{}: must be at least 1", arg)));
+                    }
+                    cfg.pace_tick_ms = Some(n);
+                },
+                "write-batch" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 || n > sendmmsg::MAX_BATCH {
+                        return Err(Error(format!(
+                            "{}: must be between 1 and {}", arg, sendmmsg::MAX_BATCH,
+                        )));
+                    }
+                    cfg.write_batch = Some(n);
+                },
+                "handshake-timeout" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 {
+                        return Err(Error(format!("{}: must be at least 1", arg)));
+                    }
+                    cfg.handshake_timeout = Some(n);
+                },
+                "trace" => {
+                    let conn = filter::parse_endpoint(&value()?)
+                        .map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    cfg.trace = Some(conn);
+                },
+                "max-message-len" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n < framing::MAX_HEADER_LEN {
+                        return Err(Error(format!(
+                            "{}: must be at least {}", arg, framing::MAX_HEADER_LEN,
+                        )));
+                    }
+                    cfg.max_message_len = Some(n);
+                },
+                "dedup" => {
+                    let n = value()?.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?;
+                    if n == 0 {
+                        return Err(Error(format!("{}: must be at least 1", arg)));
+                    }
+                    cfg.dedup_ms = Some(n);
+                },
+                "gateway" => {
+                    let v = value()?;
+                    cfg.gateway = Some(v.parse().map_err(|e| Error(format!("{}: {}", arg, e)))?);
+                },
+                "cpus" => cfg.cpus.push(affinity::parse_cpus(&value()?)?),
+                "log" => cfg.log_levels.extend(logging::parse_levels(&value()?)?),
+                _ => return Err(Error(format!("unknown option {}", arg))),
+            }
+        }
+
+        Ok((cfg, positional))
+    }
+
+    /// The rate limiter described by the options, if any limit is set.
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        if self.rate_limit.is_none() && self.conn_rate_limit.is_none() {
+            return None;
+        }
+        Some(RateLimiter::new(Limits {
+            packets: self.rate_limit,
+            conns: self.conn_rate_limit,
+            ban_secs: self.ban_secs,
+        }))
+    }
+
+    /// The pacer for writes toward the server, if `--pace` is set.
+    pub fn pacer(&self) -> Option<Pacer> {
+        let tick = self.pace_tick_ms.map_or(pacing::DEFAULT_TICK, |ms| ms * 1000);
+        self.pace.map(|n| Pacer::new(n, tick))
+    }
+
+    pub fn dedup(&self) -> Option<Dedup> {
+        self.dedup_ms.map(|ms| Dedup::new(ms * 1000))
+    }
+
+    /// Record the CPUs from `cpus` for `affinity::pin`.
+    pub fn init_affinity(&self) {
+        for &(role, mask) in &self.cpus {
+            affinity::set_cpus(role, mask);
+        }
+    }
+
+    /// Set the global log levels from `verbosity` and `log_levels`.
+    pub fn init_logging(&self) {
+        let level = Level::from_verbosity(self.verbosity);
+        for &sub in &logging::SUBSYSTEMS {
+            logging::set_level(sub, level);
+        }
+        for &(sub, level) in &self.log_levels {
+            logging::set_level(sub, level);
+        }
+    }
+}
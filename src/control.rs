@@ -0,0 +1,477 @@
+//! Control socket for inspecting a running relay.  Clients connect to a Unix socket and send
+//! one command per line, e.g. with `socat - UNIX-CONNECT:control.sock`.  Each response ends with
+//! a line containing only `.`.
+//!
+//! Commands:
+//!
+//!  - `conns`: list connections with recent messages, and their session IDs and player names
+//!  - `messages [key=value...]`: show recent messages, oldest first.  Keys are `player` (login
+//!    name), `session`, `conn`, `major`, `dir`, `index`, and `contains` (as in
+//!    `MessageFilter::parse_query`), `since` and `until` (Unix time in seconds), and `limit`
+//!    (default 50)
+//!  - `say [player=NAME] [major=XX] text`: send `text` to the player as an announcement from the
+//!    server, or to every open connection if no player is given.  `major` (hex) overrides
+//!    `messages::MAJOR_ANNOUNCE`.
+//!  - `subscribe TOPIC... [key=value...]`: switch the connection to a live feed of events, one
+//!    JSON object per line after the usual `.`, until the client disconnects.  Topics are `conns`
+//!    (connects, new sessions, logins, and timeouts), `messages`, and `warnings` (rate and canary
+//!    alerts).
+//!    Keys are as for `messages`, except `since`, `until`, and `limit`; `player`, `session`, and
+//!    `conn` apply to every topic, and the rest to `messages` only.
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use crate::{Error, ErrorAt};
+use crate::alerts::Alert;
+use crate::canary::CanaryAlert;
+use crate::filter::MessageFilter;
+use crate::inject::Injector;
+use crate::messages::{self, Announce};
+use crate::opcodes::Registry;
+use crate::session::SessionId;
+use crate::store::{MessageStore, Query};
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::{dump, json};
+
+
+/// Number of events that can be queued for a slow subscriber before further events are dropped.
+const SUBSCRIBER_QUEUE_LEN: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Topic {
+    Conns,
+    Messages,
+    Warnings,
+}
+
+impl Topic {
+    fn parse(s: &str) -> Option<Topic> {
+        match s {
+            "conns" => Some(Topic::Conns),
+            "messages" => Some(Topic::Messages),
+            "warnings" => Some(Topic::Warnings),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Topic::Conns => "conns",
+            Topic::Messages => "messages",
+            Topic::Warnings => "warnings",
+        }
+    }
+}
+
+struct Subscriber {
+    topics: Vec<Topic>,
+    filter: MessageFilter,
+    player: Option<String>,
+    session: Option<SessionId>,
+    send: SyncSender<Arc<String>>,
+    /// Set once the client has gone, so the next `publish` drops it whether or not it wants the
+    /// event.
+    closed: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    fn wants(
+        &self,
+        topic: Topic,
+        ct: ConnTuple,
+        session: Option<SessionId>,
+        player: Option<&str>,
+    ) -> bool {
+        self.topics.contains(&topic) &&
+            self.filter.matches_conn(ct) &&
+            self.session.map_or(true, |s| session == Some(s)) &&
+            self.player.as_ref().map_or(true, |p| player == Some(p))
+    }
+}
+
+/// Handle for publishing events to control clients that have run `subscribe`.
+#[derive(Clone, Default)]
+pub struct Subscribers {
+    clients: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Subscribers {
+    /// Whether nobody is subscribed, so the caller can skip gathering details for the events.
+    pub fn is_empty(&self) -> bool {
+        self.clients.lock().unwrap().len() == 0
+    }
+
+    /// Publish a change in the state of connection `ct`: `connect`, `session`, `login`, `seeded`
+    /// (named from the lobby roster; see `roster`), or `timeout`.
+    pub fn conn_event(
+        &self,
+        event: &str,
+        ct: ConnTuple,
+        session: Option<SessionId>,
+        player: Option<&str>,
+    ) {
+        self.publish(Topic::Conns, ct, session, player, None, |obj| {
+            obj.str("event", event).str("conn", &ct.to_string());
+        });
+    }
+
+    pub fn message(
+        &self,
+        ct: ConnTuple,
+        session: Option<SessionId>,
+        player: Option<&str>,
+        msg: &Message,
+        opcodes: Option<&Registry>,
+    ) {
+        self.publish(Topic::Messages, ct, session, player, Some(msg), |obj| {
+            msg.write_json(ct, obj);
+            if let Some(opcodes) = opcodes {
+                opcodes.write_json(msg, obj);
+            }
+        });
+    }
+
+    pub fn alert(&self, alert: &Alert, session: Option<SessionId>, player: Option<&str>) {
+        self.publish(Topic::Warnings, alert.ct, session, player, None, |obj| {
+            obj.str("kind", "alert");
+            alert.write_json(obj);
+        });
+    }
+
+    pub fn canary(&self, alert: &CanaryAlert, session: Option<SessionId>, player: Option<&str>) {
+        self.publish(Topic::Warnings, alert.ct, session, player, None, |obj| {
+            obj.str("kind", "canary");
+            alert.write_json(obj);
+        });
+    }
+
+    /// Send an event to each subscriber that wants it.  The JSON is built only if someone does,
+    /// starting with the topic, session, and player, followed by whatever `fields` adds.
+    fn publish(
+        &self,
+        topic: Topic,
+        ct: ConnTuple,
+        session: Option<SessionId>,
+        player: Option<&str>,
+        msg: Option<&Message>,
+        fields: impl FnOnce(&mut json::Object),
+    ) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|c| !c.closed.load(Ordering::Relaxed));
+        if clients.len() == 0 {
+            return;
+        }
+
+        let mut fields = Some(fields);
+        let mut line = None;
+        clients.retain(|c| {
+            if !c.wants(topic, ct, session, player) ||
+                    msg.map_or(false, |m| !c.filter.matches_msg(m)) {
+                return true;
+            }
+            let line = line.get_or_insert_with(|| {
+                let mut obj = json::Object::new();
+                obj.str("topic", topic.name());
+                if let Some(session) = session {
+                    obj.str("session", &session.to_string());
+                }
+                if let Some(player) = player {
+                    obj.str("player", player);
+                }
+                (fields.take().unwrap())(&mut obj);
+                let mut s = obj.finish();
+                s.push('\n');
+                Arc::new(s)
+            }).clone();
+            match c.send.try_send(line) {
+                Ok(()) => true,
+                // The client is falling behind.  Drop this event, but keep the client.
+                Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Parse the arguments of `subscribe` and add a subscriber.  Returns the topics, the
+    /// receiving end of its queue, and the flag that marks it closed.
+    fn subscribe(
+        &self,
+        args: &[&str],
+    ) -> Result<(Vec<Topic>, Receiver<Arc<String>>, Arc<AtomicBool>), String> {
+        let mut topics = Vec::new();
+        let mut player = None;
+        let mut session = None;
+        let mut filter_parts = Vec::new();
+        for arg in args {
+            let (k, v) = match arg.find('=') {
+                Some(i) => (&arg[..i], &arg[i + 1 ..]),
+                None => {
+                    let topic = Topic::parse(arg)
+                        .ok_or_else(|| format!("unknown topic {:?}", arg))?;
+                    if !topics.contains(&topic) {
+                        topics.push(topic);
+                    }
+                    continue;
+                },
+            };
+            match k {
+                "player" => player = Some(v.to_owned()),
+                "session" => session = Some(SessionId::parse(v)?),
+                _ => filter_parts.push(*arg),
+            }
+        }
+        if topics.len() == 0 {
+            return Err("usage: subscribe conns|messages|warnings... [key=value...]".into());
+        }
+        let filter = MessageFilter::parse_query(&filter_parts.join("&"))?;
+
+        let (send, recv) = mpsc::sync_channel(SUBSCRIBER_QUEUE_LEN);
+        let closed = Arc::new(AtomicBool::new(false));
+        let sub = Subscriber {
+            topics: topics.clone(),
+            filter,
+            player,
+            session,
+            send,
+            closed: closed.clone(),
+        };
+        self.clients.lock().unwrap().push(sub);
+        Ok((topics, recv, closed))
+    }
+}
+
+/// Listen on the Unix socket at `path` and serve commands from a background thread.  Returns the
+/// handle for publishing events to subscribed clients.
+pub fn start(
+    path: &str,
+    store: Arc<Mutex<MessageStore>>,
+    injector: Arc<Injector>,
+) -> Result<Subscribers, Error> {
+    // Clear out the socket from a previous run.  Only sockets are removed; anything else is left
+    // for `bind` to complain about.
+    if let Ok(m) = Path::new(path).symlink_metadata() {
+        if m.file_type().is_socket() {
+            fs::remove_file(path).at("control: removing old socket")?;
+        }
+    }
+    let listener = UnixListener::bind(path).at("control: bind")?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).at("control: chmod")?;
+
+    let subs = Subscribers::default();
+    let subs2 = subs.clone();
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let socket = match socket {
+                Ok(x) => x,
+                Err(e) => {
+                    log!(Handler, Error, "control: accept failed: {}", e);
+                    continue;
+                },
+            };
+            let store = store.clone();
+            let injector = injector.clone();
+            let subs = subs2.clone();
+            thread::spawn(move || {
+                match serve_client(socket, &store, &injector, &subs) {
+                    Ok(()) => {},
+                    Err(e) => log!(Handler, Warn, "control: {}", e),
+                }
+            });
+        }
+    });
+
+    Ok(subs)
+}
+
+fn serve_client(
+    socket: UnixStream,
+    store: &Mutex<MessageStore>,
+    injector: &Injector,
+    subs: &Subscribers,
+) -> Result<(), Error> {
+    let mut out = socket.try_clone()?;
+    for line in BufReader::new(socket).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.len() == 0 {
+            continue;
+        }
+        if line.split_whitespace().next() == Some("subscribe") {
+            let args = line.split_whitespace().skip(1).collect::<Vec<_>>();
+            match subs.subscribe(&args) {
+                Ok((topics, recv, closed)) => {
+                    let names = topics.iter().map(|t| t.name()).collect::<Vec<_>>();
+                    writeln!(out, "subscribed to {}", names.join(", "))?;
+                    out.write_all(b".\n")?;
+                    // Anything else the client sends is ignored, but reading it notices when the
+                    // client goes even if none of its events have come up lately.
+                    let mut incoming = out.try_clone()?;
+                    let closed2 = closed.clone();
+                    thread::spawn(move || {
+                        let mut buf = [0; 512];
+                        while let Ok(n) = incoming.read(&mut buf) {
+                            if n == 0 {
+                                break;
+                            }
+                        }
+                        closed2.store(true, Ordering::Relaxed);
+                    });
+
+                    // Once the client is marked closed, the next `publish` removes its
+                    // `Subscriber` entry, which ends this loop.
+                    for line in recv.iter() {
+                        if let Err(e) = out.write_all(line.as_bytes()) {
+                            closed.store(true, Ordering::Relaxed);
+                            return Err(e.into());
+                        }
+                    }
+                    return Ok(());
+                },
+                Err(e) => {
+                    writeln!(out, "error: {}", e)?;
+                    out.write_all(b".\n")?;
+                    continue;
+                },
+            }
+        }
+        let resp = match run_command(line, store, injector) {
+            Ok(s) => s,
+            Err(e) => format!("error: {}\n", e),
+        };
+        out.write_all(resp.as_bytes())?;
+        out.write_all(b".\n")?;
+    }
+    Ok(())
+}
+
+fn run_command(
+    line: &str,
+    store: &Mutex<MessageStore>,
+    injector: &Injector,
+) -> Result<String, String> {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap_or("");
+    let args = words.collect::<Vec<_>>();
+    match cmd {
+        "conns" => Ok(list_conns(&store.lock().unwrap())),
+        "messages" => list_messages(&args, &store.lock().unwrap()),
+        "say" => say(line[cmd.len()..].trim_start(), &store.lock().unwrap(), injector),
+        _ => Err(format!("unknown command {:?}", cmd)),
+    }
+}
+
+fn list_conns(store: &MessageStore) -> String {
+    let mut s = String::new();
+    for (ct, session, name, count, open) in store.conns() {
+        writeln!(
+            s, "{} {} {} {} messages{}",
+            ct, session.map_or_else(|| "-".to_owned(), |s| s.to_string()), name.unwrap_or("-"),
+            count, if open { "" } else { " (closed)" },
+        ).unwrap();
+    }
+    s
+}
+
+fn parse_secs(k: &str, v: &str) -> Result<u64, String> {
+    let secs = v.parse::<f64>().map_err(|e| format!("{}: {}", k, e))?;
+    Ok((secs * 1e6) as u64)
+}
+
+fn list_messages(args: &[&str], store: &MessageStore) -> Result<String, String> {
+    let mut q = Query::default();
+    let mut player = None;
+    let mut session = None;
+    let mut filter_parts = Vec::new();
+    for arg in args {
+        let (k, v) = match arg.find('=') {
+            Some(i) => (&arg[..i], &arg[i + 1 ..]),
+            None => return Err(format!("expected key=value, got {:?}", arg)),
+        };
+        match k {
+            "player" => player = Some(v),
+            "session" => session = Some(SessionId::parse(v)?),
+            "since" => q.since = Some(parse_secs(k, v)?),
+            "until" => q.until = Some(parse_secs(k, v)?),
+            "limit" => q.limit = v.parse().map_err(|e| format!("limit: {}", e))?,
+            _ => filter_parts.push(*arg),
+        }
+    }
+    q.filter = MessageFilter::parse_query(&filter_parts.join("&"))?;
+    if let Some(name) = player {
+        let conns = store.find_name(name);
+        if conns.len() == 0 {
+            return Err(format!("no connection for player {:?}", name));
+        }
+        q.conns = Some(conns);
+    }
+    if let Some(session) = session {
+        let ct = store.find_session(session)
+            .ok_or_else(|| format!("no connection with session {}", session))?;
+        q.conns = Some(q.conns.map_or(vec![ct], |v| v.into_iter().filter(|&x| x == ct).collect()));
+    }
+
+    let mut s = String::new();
+    for (ct, e) in store.query(&q) {
+        let h = &e.msg.header;
+        writeln!(
+            s, "{}.{:06} {} #{} {} {:02x}:{:02x} ack={}{} len={} {}",
+            e.time / 1_000_000, e.time % 1_000_000, ct, e.msg.index, h.dir, h.major, h.minor,
+            h.ack, e.msg.acks_label(), h.len,
+            dump::mixed(&e.msg.body),
+        ).unwrap();
+    }
+    Ok(s)
+}
+
+/// Longest text `say` accepts, in bytes, so the message fits in one packet.
+const MAX_SAY_LEN: usize = 1000;
+
+fn say(mut rest: &str, store: &MessageStore, injector: &Injector) -> Result<String, String> {
+    let mut player = None;
+    let mut major = messages::MAJOR_ANNOUNCE;
+    // Options come first; the text is everything after them, with its spacing intact.
+    while let Some(word) = rest.split_whitespace().next() {
+        if let Some(v) = word.strip_prefix("player=") {
+            player = Some(v);
+        } else if let Some(v) = word.strip_prefix("major=") {
+            major = u8::from_str_radix(v.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("major: {}", e))?;
+        } else {
+            break;
+        }
+        rest = rest[word.len()..].trim_start();
+    }
+    if rest.len() == 0 {
+        return Err("usage: say [player=NAME] [major=XX] text".into());
+    }
+    if rest.len() > MAX_SAY_LEN {
+        return Err(format!("text is too long (max {} bytes)", MAX_SAY_LEN));
+    }
+
+    let conns = store.conns().into_iter()
+        .filter(|&(_, _, name, _, open)| open && player.map_or(true, |p| name == Some(p)))
+        .map(|(ct, ..)| ct)
+        .collect::<Vec<_>>();
+    if conns.len() == 0 {
+        return Err(match player {
+            Some(name) => format!("no open connection for player {:?}", name),
+            None => "no open connections".into(),
+        });
+    }
+
+    let msg = Announce { text: rest.to_owned() }.to_message(major);
+    for &ct in &conns {
+        injector.push(ct, msg.clone());
+    }
+    Ok(format!(
+        "queued for {} connection{}\n",
+        conns.len(), if conns.len() != 1 { "s" } else { "" },
+    ))
+}
@@ -0,0 +1,146 @@
+//! Known-answer tests: captured sessions paired with the messages they should decode to, so a
+//! change to reassembly can be checked against real traffic.  `tests/corpus.rs` runs every
+//! fixture in `tests/corpus`.
+//!
+//! A fixture is a capture, `NAME.pcap`, and the messages it carries, `NAME.txt`:
+//!
+//! ```text
+//! # A comment.
+//! server 192.168.84.2
+//! conn 10.0.0.5:5001 -> 192.168.84.2:27016
+//! 0 00:00 3f
+//! 1 00:00 c2
+//! 0 0a:00 00000000000000000000000061
+//! 1 20:05 -
+//! ```
+//!
+//! The `server` line gives the server's address, which tells which way each packet is going, as
+//! for `replay-pcap`.  Each `conn` line starts a connection, client first, in the order they're
+//! first seen, and the lines after it are that connection's messages in the order they're
+//! decoded: the direction (0 for client to server), the opcodes in hex, and the body in hex, or
+//! `-` if it's empty.  Only the order within each direction is checked.
+//!
+//! To add a capture, write a `.txt` with just the `server` line and run the tests with
+//! `TFH_CORPUS_BLESS=1`, which rewrites every fixture's messages from what its capture decodes
+//! to now (dropping comments).  Check the result by hand before committing it.  Fixtures named
+//! `synthetic-*` aren't captures; `tests/corpus.rs` generates them, and checks they still match.
+use std::fmt::Write as _;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use crate::{Error, ErrorAt};
+use crate::process;
+use crate::testing;
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::hex;
+
+
+#[derive(Clone, Debug)]
+pub struct Fixture {
+    pub server: Ipv4Addr,
+    /// Each connection's messages, both directions together, in the order they were decoded.
+    pub conns: Vec<(ConnTuple, Vec<Message>)>,
+}
+
+impl Fixture {
+    pub fn parse(s: &str) -> Result<Fixture, Error> {
+        let mut server = None;
+        let mut conns: Vec<(ConnTuple, Vec<Message>)> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let err = |e: String| Error(format!("line {}: {}", i + 1, e));
+            let line = line.find('#').map_or(line, |j| &line[..j]).trim();
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.get(0).copied() {
+                None => {},
+                Some("server") if words.len() == 2 => {
+                    server = Some(words[1].parse().map_err(|e| err(format!("{}", e)))?);
+                },
+                Some("conn") if words.len() == 4 && words[2] == "->" => {
+                    let addr = |s: &str| s.parse::<SocketAddrV4>()
+                        .map_err(|e| err(format!("{}: {}", s, e)));
+                    let (client, server) = (addr(words[1])?, addr(words[3])?);
+                    let ct = ConnTuple::Ipv4(
+                        (*client.ip()).into(), client.port(), (*server.ip()).into(), server.port(),
+                    );
+                    conns.push((ct, Vec::new()));
+                },
+                Some(_) if words.len() == 3 => {
+                    let msg = parse_message(&words).map_err(err)?;
+                    let msgs = &mut conns.last_mut()
+                        .ok_or_else(|| err("message comes before any conn".into()))?.1;
+                    msgs.push(msg);
+                },
+                Some(_) => return Err(err(format!("can't parse {:?}", line))),
+            }
+        }
+        let server = server.ok_or("missing server line")?;
+        Ok(Fixture { server, conns })
+    }
+
+    pub fn load(path: &str) -> Result<Fixture, Error> {
+        let s = fs::read_to_string(path).at(path)?;
+        Fixture::parse(&s).map_err(|e| Error(format!("{}: {}", path, e)))
+    }
+
+    /// What the capture at `path` decodes to now.  See `process::decode_capture`.
+    pub fn decode(path: &str, server: Ipv4Addr) -> Result<Fixture, Error> {
+        Ok(Fixture { server, conns: process::decode_capture(path, server)? })
+    }
+
+    /// Describe the first difference from `got`, if any.
+    pub fn check(&self, got: &Fixture) -> Result<(), String> {
+        let cts = |f: &Fixture| f.conns.iter().map(|c| c.0.to_string()).collect::<Vec<_>>();
+        if cts(self) != cts(got) {
+            return Err(format!("expected connections {:?}, got {:?}", cts(self), cts(got)));
+        }
+        for ((ct, want), (_, got)) in self.conns.iter().zip(&got.conns) {
+            for dir in 0 .. 2 {
+                let want = want.iter().filter(|m| m.header.dir == dir).collect::<Vec<_>>();
+                let got = got.iter().filter(|m| m.header.dir == dir).collect::<Vec<_>>();
+                for (i, (a, b)) in want.iter().zip(&got).enumerate() {
+                    if !testing::same_message(a, b) {
+                        return Err(format!("{}: direction {}: message {}: expected {}, got {}",
+                            ct, dir, i, format_message(a), format_message(b)));
+                    }
+                }
+                if want.len() != got.len() {
+                    return Err(format!("{}: direction {}: expected {} messages, got {}",
+                        ct, dir, want.len(), got.len()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The fixture in the format `parse` reads.
+    pub fn to_text(&self) -> String {
+        let mut s = format!("server {}\n", self.server);
+        for (ct, msgs) in &self.conns {
+            writeln!(s, "conn {}", ct).unwrap();
+            for msg in msgs {
+                writeln!(s, "{}", format_message(msg)).unwrap();
+            }
+        }
+        s
+    }
+}
+
+/// `DIR MAJOR:MINOR BODY`, as in a fixture.
+fn format_message(msg: &Message) -> String {
+    let body = if msg.body.len() == 0 { "-".to_owned() } else { hex::encode(&msg.body) };
+    format!("{} {:02x}:{:02x} {}", msg.header.dir, msg.header.major, msg.header.minor, body)
+}
+
+fn parse_message(words: &[&str]) -> Result<Message, String> {
+    let dir = match words[0] {
+        "0" => 0,
+        "1" => 1,
+        x => return Err(format!("bad direction {:?}", x)),
+    };
+    let opcode = |x: &str| u8::from_str_radix(x, 16).map_err(|e| format!("{}: {}", words[1], e));
+    let i = words[1].find(':').ok_or_else(|| format!("expected MAJOR:MINOR, not {}", words[1]))?;
+    let (major, minor) = (opcode(&words[1][..i])?, opcode(&words[1][i + 1 ..])?);
+    let body = if words[2] == "-" { Vec::new() } else { hex::parse(words[2])? };
+    let mut msg = testing::message(major, minor, body);
+    msg.header.dir = dir;
+    Ok(msg)
+}
@@ -0,0 +1,59 @@
+//! Dropping duplicated packets, for networks that deliver some packets twice, such as bonded
+//! links or misbehaving bridges.  Duplicates would otherwise be counted twice in the stats and
+//! look like retransmissions to the stream parser.
+//!
+//! Two IPv4 packets are duplicates if they have the same addresses, IP ident, length, and UDP
+//! checksum, and arrive within the window of each other.  The sender picks a fresh ident for
+//! each packet, so a real retransmission doesn't match.
+use std::collections::{HashMap, VecDeque};
+use crate::packet::Packet;
+
+
+/// Source IP, destination IP, IP ident, UDP checksum (zero for other protocols), and length.
+type Key = (u32, u32, u16, u16, usize);
+
+pub struct Dedup {
+    /// How long to remember a packet, in microseconds.
+    window: u64,
+    /// Arrival time of each packet seen within the window.
+    seen: HashMap<Key, u64>,
+    /// The same packets, oldest first, for expiring them.
+    order: VecDeque<(u64, Key)>,
+}
+
+impl Dedup {
+    pub fn new(window: u64) -> Dedup {
+        Dedup {
+            window,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Decide whether `p` is new.  Returns `false` for a duplicate of a packet seen within the
+    /// window.  Packets other than IPv4 are always new.
+    pub fn check(&mut self, p: &Packet, now: u64) -> bool {
+        if p.len() < 20 || !p.is_ipv4() {
+            return true;
+        }
+        while let Some(&(t, key)) = self.order.front() {
+            if now.saturating_sub(t) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            if self.seen.get(&key) == Some(&t) {
+                self.seen.remove(&key);
+            }
+        }
+
+        let ip = p.ipv4();
+        let checksum = if p.is_udp() && p.len() >= p.udp_end() { p.udp().checksum() } else { 0 };
+        let key = (ip.source_ip(), ip.dest_ip(), ip.ident(), checksum, p.len());
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+        self.seen.insert(key, now);
+        self.order.push_back((now, key));
+        true
+    }
+}
@@ -0,0 +1,265 @@
+//! The disk watchdog, with `--log-budget SIZE` or `--log-min-free SIZE`: a background thread
+//! that keeps the session logs within a size budget, and keeps some space free on the disk they're
+//! on, so a busy week doesn't stop the relay with a full disk.
+//!
+//! Every `CHECK_INTERVAL`, it adds up the logs directory and checks the free space.  Getting near
+//! a limit logs a warning.  Past one, it frees space from the logs no worker has open, oldest
+//! first: with `--log-compress` it first compresses them with `gzip`, and then, if that isn't
+//! enough, deletes them, logging a warning before each deletion.  Each worker reports the logs it
+//! has open (see `MessageSink::locations`) with `set_open`.  If only open logs are left, it has
+//! the workers rotate their logs (see `MessageSink::rotate`), so the old files can go at a later
+//! check.  A log's index (see `log_index`) goes when the log is compressed or deleted.
+//! Compressed logs can still be read, with `tfhlog::open`.  Only logs and their indexes count;
+//! anything else in the directory is left alone.
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::{Error, ErrorAt};
+
+
+/// How often to check the logs directory.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Logs written to within this long may have been opened since their worker last reported, so
+/// they're left alone.
+pub const ACTIVE: Duration = Duration::from_secs(120);
+
+/// Share of a limit, in percent, past which the watchdog warns that it will soon act.
+const WARN_PERCENT: u64 = 90;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    /// Most bytes the logs directory may take.
+    pub max_bytes: Option<u64>,
+    /// Fewest bytes to leave free on the disk holding the logs.
+    pub min_free: Option<u64>,
+    /// Compress old logs before deleting any.
+    pub compress: bool,
+}
+
+impl Budget {
+    /// Bytes that have to go to get back within the budget, when the logs take `used` bytes and
+    /// `free` are free on the disk.
+    pub fn excess(&self, used: u64, free: u64) -> u64 {
+        let over = self.max_bytes.map_or(0, |max| used.saturating_sub(max));
+        let short = self.min_free.map_or(0, |min| min.saturating_sub(free));
+        over.max(short)
+    }
+
+    /// Whether `used` and `free` are close to a limit.
+    fn near(&self, used: u64, free: u64) -> bool {
+        self.max_bytes.map_or(false, |max| used >= max / 100 * WARN_PERCENT) ||
+            self.min_free.map_or(false, |min| free <= min / WARN_PERCENT * 100)
+    }
+}
+
+/// Handle to the watchdog thread.
+#[derive(Clone)]
+pub struct Watchdog {
+    rotations: Arc<AtomicU64>,
+    /// The logs each registered worker has open, as of its last report.
+    open: Arc<Mutex<Vec<HashSet<PathBuf>>>>,
+}
+
+impl Watchdog {
+    /// Start watching `dir`.
+    pub fn start(dir: &str, budget: Budget) -> Watchdog {
+        let w = Watchdog {
+            rotations: Arc::new(AtomicU64::new(0)),
+            open: Arc::new(Mutex::new(Vec::new())),
+        };
+        let w2 = w.clone();
+        let dir = PathBuf::from(dir);
+        thread::Builder::new().name("disk watchdog".into()).spawn(move || {
+            let mut state = State { warned: false, last_rotation: None };
+            loop {
+                if let Err(e) = w2.check(&dir, &budget, &mut state) {
+                    log!(Handler, Error, "disk: checking {}: {}", dir.display(), e);
+                }
+                thread::sleep(CHECK_INTERVAL);
+            }
+        }).expect("failed to spawn thread");
+        w
+    }
+
+    /// Add a worker, which reports its open logs under the returned number.
+    pub fn register(&self) -> usize {
+        let mut open = self.open.lock().unwrap();
+        open.push(HashSet::new());
+        open.len() - 1
+    }
+
+    /// Worker `worker` has the logs at `paths` open, and no others.
+    pub fn set_open(&self, worker: usize, paths: impl IntoIterator<Item = String>) {
+        self.open.lock().unwrap()[worker] = paths.into_iter().map(PathBuf::from).collect();
+    }
+
+    /// Number of times the watchdog has asked for the logs to be rotated.  Each worker rotates its
+    /// logs when this goes up.
+    pub fn rotations(&self) -> u64 {
+        self.rotations.load(Ordering::Relaxed)
+    }
+
+    fn check(&self, dir: &Path, budget: &Budget, state: &mut State) -> Result<(), Error> {
+        let used = scan(dir).at(&dir.display().to_string())?.iter().map(|f| f.len).sum();
+        let free = free_space(dir)?;
+        let excess = budget.excess(used, free);
+        if excess == 0 {
+            if budget.near(used, free) && !state.warned {
+                log!(Handler, Warn, "disk: logs take {} bytes with {} free, close to the limits; \
+                    old logs will be {} past them",
+                    used, free, if budget.compress { "compressed and deleted" } else { "deleted" });
+            }
+            state.warned = budget.near(used, free);
+            return Ok(());
+        }
+
+        log!(Handler, Warn, "disk: logs take {} bytes with {} free, {} bytes over the limits",
+            used, free, excess);
+        let now = SystemTime::now();
+        let open = self.open.lock().unwrap().iter().flatten().cloned().collect();
+        let left = free_up(dir, budget.compress, excess, now, &open)
+            .at(&dir.display().to_string())?;
+        let rotated_lately = state.last_rotation
+            .map_or(false, |t| now.duration_since(t).unwrap_or_default() < ACTIVE);
+        if left > 0 && !rotated_lately {
+            log!(Handler, Warn, "disk: only logs in use are left; rotating them to free {} bytes",
+                left);
+            self.rotations.fetch_add(1, Ordering::Relaxed);
+            state.last_rotation = Some(now);
+        }
+        Ok(())
+    }
+}
+
+struct State {
+    /// Whether the last check warned of being near a limit.
+    warned: bool,
+    last_rotation: Option<SystemTime>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+}
+
+/// Whether `path` is a log, compressed or not, or a log's index.  Nothing else in the directory
+/// counts toward the budget or gets deleted: with `--log-dir .` it holds the snapshot, the status
+/// file, and the like.
+fn is_log(path: &Path) -> bool {
+    let name = path.file_name().map_or(Default::default(), |n| n.to_string_lossy());
+    [".tfhlog", ".tfhlog.gz", ".tfhlog.idx"].iter().any(|ext| name.ends_with(ext))
+}
+
+/// The logs and indexes in `dir`, oldest first.  A directory that doesn't exist yet is empty.
+fn scan(dir: &Path) -> io::Result<Vec<LogFile>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for e in entries {
+        let e = e?;
+        let meta = e.metadata()?;
+        if meta.is_file() && is_log(&e.path()) {
+            files.push(LogFile { path: e.path(), len: meta.len(), modified: meta.modified()? });
+        }
+    }
+    files.sort_by(|a, b| (a.modified, &a.path).cmp(&(b.modified, &b.path)));
+    Ok(files)
+}
+
+/// Free bytes on the disk holding `dir`, or on the current directory's if `dir` isn't there yet.
+fn free_space(dir: &Path) -> Result<u64, Error> {
+    let path = if dir.exists() { dir } else { Path::new(".") };
+    let st = nix::sys::statvfs::statvfs(path)?;
+    Ok(st.blocks_available() as u64 * st.fragment_size() as u64)
+}
+
+/// Free `excess` bytes from the logs in `dir` that aren't in `open` and haven't been written to
+/// in the `ACTIVE` before `now`, oldest first: by compressing them if `compress` is set, and then
+/// by deleting them.  Returns how many bytes are still to be freed.
+pub fn free_up(
+    dir: &Path,
+    compress: bool,
+    mut excess: u64,
+    now: SystemTime,
+    open: &HashSet<PathBuf>,
+) -> io::Result<u64> {
+    let closed = |f: &LogFile| {
+        !open.contains(&f.path) &&
+            now.duration_since(f.modified).map_or(false, |age| age >= ACTIVE)
+    };
+    if compress {
+        for f in scan(dir)?.iter().filter(|f| closed(f)) {
+            if excess == 0 {
+                return Ok(0);
+            }
+            if f.path.extension().map_or(true, |x| x != "tfhlog") {
+                continue;
+            }
+            match gzip(&f.path) {
+                Ok(len) => {
+                    log!(Handler, Info, "disk: compressed {} from {} to {} bytes",
+                        f.path.display(), f.len, len);
+                    // Offsets into the log mean nothing once it's compressed.
+                    let freed = f.len.saturating_sub(len) + remove_index(&f.path)?;
+                    excess = excess.saturating_sub(freed);
+                },
+                Err(e) => {
+                    log!(Handler, Warn, "disk: can't compress {}: {}", f.path.display(), e);
+                    break;
+                },
+            }
+        }
+    }
+
+    for f in scan(dir)?.iter().filter(|f| closed(f)) {
+        if excess == 0 {
+            break;
+        }
+        // An index goes with its log, and may have gone already.
+        let is_index = f.path.extension().map_or(false, |x| x == "idx");
+        if !f.path.exists() || is_index && f.path.with_extension("").exists() {
+            continue;
+        }
+        log!(Handler, Warn, "disk: deleting {} ({} bytes) to stay within the limits",
+            f.path.display(), f.len);
+        fs::remove_file(&f.path)?;
+        excess = excess.saturating_sub(f.len + remove_index(&f.path)?);
+    }
+    Ok(excess)
+}
+
+/// Remove the index of the log at `path`, if it has one.  Returns the index's size.
+fn remove_index(path: &Path) -> io::Result<u64> {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".idx");
+    match fs::metadata(&index) {
+        Ok(meta) => {
+            fs::remove_file(&index)?;
+            Ok(meta.len())
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compress `path` to `path.gz`, which replaces it.  Returns the compressed size.
+fn gzip(path: &Path) -> io::Result<u64> {
+    let status = Command::new("gzip").arg("--").arg(path).status()?;
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("gzip failed ({})", status)));
+    }
+    let mut gz = path.as_os_str().to_owned();
+    gz.push(".gz");
+    Ok(fs::metadata(gz)?.len())
+}
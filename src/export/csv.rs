@@ -0,0 +1,42 @@
+use std::io::{self, Write};
+use crate::tfhlog::Record;
+use super::{Field, Value};
+
+
+pub struct Writer<W> {
+    w: W,
+    fields: Vec<Field>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a new CSV file, writing the header row to `w`.
+    pub fn new(mut w: W, fields: Vec<Field>) -> io::Result<Writer<W>> {
+        let names = super::columns(&fields).into_iter()
+            .map(|(name, _)| quote(&name))
+            .collect::<Vec<_>>();
+        writeln!(w, "{}", names.join(","))?;
+        Ok(Writer { w, fields })
+    }
+
+    pub fn write_record(&mut self, r: &Record) -> io::Result<()> {
+        let cells = super::row(r, &self.fields).into_iter().map(|v| match v {
+            Value::Int(x) => x.to_string(),
+            Value::Text(s) => quote(&s),
+            Value::Null => String::new(),
+        }).collect::<Vec<_>>();
+        writeln!(self.w, "{}", cells.join(","))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Quote a cell if it contains anything that would confuse a CSV parser.
+pub fn quote(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
@@ -0,0 +1,157 @@
+//! Tabular export of message metadata, for loading logs into pandas, DuckDB, etc.
+//!
+//! Each message becomes one row with the columns in `BASE_COLUMNS`, followed by one column for
+//! each `Field` the user asked to decode from the body.
+use crate::tfhlog::Record;
+use crate::util::hex;
+
+pub mod csv;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FieldType {
+    /// Little-endian unsigned integers.
+    U8,
+    U16,
+    U32,
+    U64,
+    /// Big-endian unsigned integers.
+    U16Be,
+    U32Be,
+    /// This many raw bytes, as a hex string.
+    Hex(usize),
+    /// A NUL-padded string of this many bytes.
+    Str(usize),
+}
+
+impl FieldType {
+    pub fn size(self) -> usize {
+        match self {
+            FieldType::U8 => 1,
+            FieldType::U16 | FieldType::U16Be => 2,
+            FieldType::U32 | FieldType::U32Be => 4,
+            FieldType::U64 => 8,
+            FieldType::Hex(n) | FieldType::Str(n) => n,
+        }
+    }
+
+    pub fn is_text(self) -> bool {
+        match self {
+            FieldType::Hex(_) | FieldType::Str(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A value to decode from each message body.
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: String,
+    pub offset: usize,
+    pub ty: FieldType,
+}
+
+impl Field {
+    /// Parse `name=offset:type`, like `hp=0x10:u16` or `player=12:str64`.  The types are `u8`,
+    /// `u16`, `u32`, `u64`, `u16be`, `u32be`, `hexN`, and `strN`.
+    pub fn parse(s: &str) -> Result<Field, String> {
+        let eq = s.find('=').ok_or_else(|| format!("expected name=offset:type, got {:?}", s))?;
+        let (name, rest) = (&s[..eq], &s[eq + 1 ..]);
+        let colon = rest.find(':')
+            .ok_or_else(|| format!("expected name=offset:type, got {:?}", s))?;
+        let (offset, ty) = (&rest[..colon], &rest[colon + 1 ..]);
+
+        let offset = if offset.starts_with("0x") {
+            usize::from_str_radix(&offset[2..], 16)
+        } else {
+            offset.parse()
+        }.map_err(|e| format!("{}: bad offset {:?}: {}", name, offset, e))?;
+
+        let parse_len = |n: &str| n.parse::<usize>()
+            .map_err(|e| format!("{}: bad length in {:?}: {}", name, ty, e));
+        let ty = match ty {
+            "u8" => FieldType::U8,
+            "u16" => FieldType::U16,
+            "u32" => FieldType::U32,
+            "u64" => FieldType::U64,
+            "u16be" => FieldType::U16Be,
+            "u32be" => FieldType::U32Be,
+            _ if ty.starts_with("hex") => FieldType::Hex(parse_len(&ty[3..])?),
+            _ if ty.starts_with("str") => FieldType::Str(parse_len(&ty[3..])?),
+            _ => return Err(format!("{}: unknown type {:?}", name, ty)),
+        };
+
+        Ok(Field { name: name.to_owned(), offset, ty })
+    }
+
+    /// Decode this field from `body`.  Gives `Null` if the body is too short.
+    pub fn extract(&self, body: &[u8]) -> Value {
+        let b = match body.get(self.offset .. self.offset + self.ty.size()) {
+            Some(x) => x,
+            None => return Value::Null,
+        };
+        let le = |b: &[u8]| b.iter().rev().fold(0_u64, |acc, &x| acc << 8 | x as u64);
+        let be = |b: &[u8]| b.iter().fold(0_u64, |acc, &x| acc << 8 | x as u64);
+        match self.ty {
+            FieldType::U8 | FieldType::U16 | FieldType::U32 | FieldType::U64 =>
+                Value::Int(le(b) as i64),
+            FieldType::U16Be | FieldType::U32Be => Value::Int(be(b) as i64),
+            FieldType::Hex(_) => Value::Text(hex::encode(b)),
+            FieldType::Str(_) => {
+                let end = b.iter().position(|&x| x == 0).unwrap_or(b.len());
+                Value::Text(String::from_utf8_lossy(&b[..end]).into_owned())
+            },
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Value {
+    Int(i64),
+    Text(String),
+    Null,
+}
+
+/// Columns present in every export, and whether each holds text.  `time_us` is microseconds
+/// since the Unix epoch.
+pub const BASE_COLUMNS: [(&str, bool); 11] = [
+    ("time_us", false),
+    ("conn", true),
+    ("session", true),
+    ("index", false),
+    ("dir", false),
+    ("major", false),
+    ("minor", false),
+    ("len", false),
+    ("ack", false),
+    ("acks_start", false),
+    ("acks_end", false),
+];
+
+/// Names of all columns, and whether each holds text.
+pub fn columns(fields: &[Field]) -> Vec<(String, bool)> {
+    BASE_COLUMNS.iter().map(|&(name, text)| (name.to_owned(), text))
+        .chain(fields.iter().map(|f| (f.name.clone(), f.ty.is_text())))
+        .collect()
+}
+
+pub fn row(r: &Record, fields: &[Field]) -> Vec<Value> {
+    let h = &r.msg.header;
+    let mut row = vec![
+        Value::Int(r.time as i64),
+        r.conn.map_or(Value::Null, |ct| Value::Text(ct.to_string())),
+        r.session.map_or(Value::Null, |s| Value::Text(s.to_string())),
+        Value::Int(r.msg.index as i64),
+        Value::Int(h.dir as i64),
+        Value::Int(h.major as i64),
+        Value::Int(h.minor as i64),
+        Value::Int(h.len as i64),
+        Value::Int(h.ack as i64),
+        Value::Int(r.msg.acks.start as i64),
+        Value::Int(r.msg.acks.end as i64),
+    ];
+    row.extend(fields.iter().map(|f| f.extract(&r.msg.body)));
+    row
+}
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::sync::Arc;
+use parquet_crate::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet_crate::file::properties::WriterProperties;
+use parquet_crate::file::writer::SerializedFileWriter;
+use parquet_crate::schema::parser::parse_message_type;
+use crate::Error;
+use crate::tfhlog::Record;
+use super::{Field, Value};
+
+
+/// Number of rows buffered before they're written out as a row group.
+const ROW_GROUP_LEN: usize = 65536;
+
+enum Column {
+    Int(Vec<i64>),
+    Text(Vec<ByteArray>),
+}
+
+pub struct Writer {
+    w: SerializedFileWriter<File>,
+    fields: Vec<Field>,
+    columns: Vec<Column>,
+    /// Definition levels for each column: 1 for present values, 0 for nulls.
+    defs: Vec<Vec<i16>>,
+    rows: usize,
+}
+
+fn pq_err(e: parquet_crate::errors::ParquetError) -> Error {
+    Error(format!("parquet: {}", e))
+}
+
+impl Writer {
+    pub fn new(file: File, fields: Vec<Field>) -> Result<Writer, Error> {
+        let cols = super::columns(&fields);
+        let mut schema = String::from("message tfh_message {\n");
+        for (name, text) in &cols {
+            if name == "time_us" {
+                schema.push_str("  required int64 time_us (TIMESTAMP(MICROS,true));\n");
+            } else if *text {
+                schema.push_str(&format!("  optional binary {} (UTF8);\n", name));
+            } else {
+                schema.push_str(&format!("  optional int64 {};\n", name));
+            }
+        }
+        schema.push('}');
+
+        let schema = parse_message_type(&schema).map_err(pq_err)?;
+        let props = WriterProperties::builder().build();
+        let w = SerializedFileWriter::new(file, Arc::new(schema), Arc::new(props))
+            .map_err(pq_err)?;
+
+        let columns = cols.iter()
+            .map(|&(_, text)| if text { Column::Text(Vec::new()) } else { Column::Int(Vec::new()) })
+            .collect();
+        Ok(Writer {
+            w,
+            fields,
+            columns,
+            defs: vec![Vec::new(); cols.len()],
+            rows: 0,
+        })
+    }
+
+    pub fn write_record(&mut self, r: &Record) -> Result<(), Error> {
+        let row = super::row(r, &self.fields);
+        for (i, v) in row.into_iter().enumerate() {
+            match (&mut self.columns[i], v) {
+                (Column::Int(vals), Value::Int(x)) => vals.push(x),
+                (Column::Text(vals), Value::Text(s)) => vals.push(ByteArray::from(s.into_bytes())),
+                (_, Value::Null) => {
+                    self.defs[i].push(0);
+                    continue;
+                },
+                _ => unreachable!("column {} has the wrong type", i),
+            }
+            self.defs[i].push(1);
+        }
+
+        self.rows += 1;
+        if self.rows >= ROW_GROUP_LEN {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn write_row_group(&mut self) -> Result<(), Error> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+
+        let mut rg = self.w.next_row_group().map_err(pq_err)?;
+        let mut i = 0;
+        while let Some(mut col) = rg.next_column().map_err(pq_err)? {
+            // `time_us` is required, so it takes no definition levels.
+            let defs = if i == 0 { None } else { Some(&self.defs[i][..]) };
+            match self.columns[i] {
+                Column::Int(ref mut vals) => {
+                    col.typed::<Int64Type>().write_batch(vals, defs, None).map_err(pq_err)?;
+                    vals.clear();
+                },
+                Column::Text(ref mut vals) => {
+                    col.typed::<ByteArrayType>().write_batch(vals, defs, None).map_err(pq_err)?;
+                    vals.clear();
+                },
+            }
+            col.close().map_err(pq_err)?;
+            self.defs[i].clear();
+            i += 1;
+        }
+        rg.close().map_err(pq_err)?;
+
+        self.rows = 0;
+        Ok(())
+    }
+
+    /// Write any buffered rows and the file footer.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.write_row_group()?;
+        self.w.close().map_err(pq_err)?;
+        Ok(())
+    }
+}
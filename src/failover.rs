@@ -0,0 +1,204 @@
+//! Active/standby failover between two relays.  The active relay, run with `--failover-listen
+//! ADDR`, sends its `--snapshot` file to each standby that connects, every `PUSH_INTERVAL` and
+//! once more as it shuts down.  A relay run with `--standby ADDR` connects to it and keeps its
+//! own snapshot file up to date with what it receives, without touching any traffic.  Once the
+//! active relay stops sending for `MAX_MISSES` attempts in a row, the standby starts up as usual:
+//! it takes the tun devices (normally from a `tun-server`, which still has them) and resumes the
+//! connections from the copied snapshot.
+//!
+//! The active relay holds an exclusive lock on `failover.lock` in its snapshot's directory (see
+//! `Lock`) for as long as it runs, and a standby that has lost touch with it only takes over once
+//! it can take the lock itself, so the two never both read from the same devices, even if the
+//! active relay is alive but too busy to send.  This needs the relays on the same host, with
+//! their snapshots in the same directory; passing tun devices over a Unix socket needs the first
+//! anyway.
+//!
+//! Each snapshot is sent as a line `snapshot LEN`, then `LEN` bytes of the file.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use nix::errno::Errno;
+use nix::fcntl::{self, FlockArg};
+use crate::{Error, ErrorAt};
+use crate::snapshot;
+
+
+/// How often the active relay sends its snapshot to standbys.
+pub const PUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How many connection attempts or push intervals in a row can go by with no snapshot before
+/// the standby takes over.
+pub const MAX_MISSES: u32 = 3;
+/// How long to wait on a standby before dropping it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The lock on a snapshot file that makes a relay the active one, held until it's dropped.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock for relays keeping their snapshots beside the one at `path`, or return
+    /// `None` if another relay has it.
+    pub fn try_acquire(path: &str) -> Result<Option<Lock>, Error> {
+        let lock_path = lock_path(path);
+        let file = OpenOptions::new().write(true).create(true).open(&lock_path).at(&lock_path)?;
+        match fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(Some(Lock { _file: file })),
+            Err(e) if e.as_errno() == Some(Errno::EAGAIN) => Ok(None),
+            Err(e) => Err(Error(format!("{}: {}", lock_path, e))),
+        }
+    }
+}
+
+/// The lock file for the relay with its snapshot at `path`.
+pub fn lock_path(path: &str) -> String {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    dir.join("failover.lock").to_string_lossy().into_owned()
+}
+
+/// The active relay's side: sends the snapshot to connected standbys.
+#[derive(Clone)]
+pub struct Feed {
+    path: String,
+    addr: SocketAddr,
+    standbys: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Feed {
+    /// Accept standbys on `addr` and send them the snapshot at `path` every `PUSH_INTERVAL`, from
+    /// background threads.
+    pub fn start(addr: &str, path: &str) -> Result<Feed, Error> {
+        let listener = TcpListener::bind(addr).at("failover: bind")?;
+        let feed = Feed {
+            path: path.to_owned(),
+            addr: listener.local_addr().at("failover: bind")?,
+            standbys: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let feed2 = feed.clone();
+        thread::Builder::new().name("failover accept".into()).spawn(move || {
+            for socket in listener.incoming() {
+                let socket = match socket.and_then(|s| {
+                    s.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    Ok(s)
+                }) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log!(Relay, Error, "failover: accept failed: {}", e);
+                        continue;
+                    },
+                };
+                log!(Relay, Info, "failover: standby connected from {:?}", socket.peer_addr().ok());
+                feed2.standbys.lock().unwrap().push(socket);
+                feed2.push();
+            }
+        })?;
+
+        let feed2 = feed.clone();
+        thread::Builder::new().name("failover push".into()).spawn(move || loop {
+            thread::sleep(PUSH_INTERVAL);
+            feed2.push();
+        })?;
+
+        Ok(feed)
+    }
+
+    /// The address standbys connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send the current snapshot to every standby now, dropping any that can't take it.
+    pub fn push(&self) {
+        let data = match fs::read(&self.path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log!(Relay, Error, "failover: failed to read {}: {}", self.path, e);
+                return;
+            },
+        };
+        let mut msg = format!("snapshot {}\n", data.len()).into_bytes();
+        msg.extend_from_slice(&data);
+        self.standbys.lock().unwrap().retain(|mut s| match s.write_all(&msg) {
+            Ok(()) => true,
+            Err(e) => {
+                log!(Relay, Warn, "failover: dropping standby {:?}: {}", s.peer_addr().ok(), e);
+                false
+            },
+        });
+    }
+}
+
+/// The standby's side: copy each snapshot sent by the active relay at `addr` into the file at
+/// `path`.  Once `MAX_MISSES` attempts in a row have failed to get one, it's time to take over,
+/// as soon as the active relay lets go of the lock, and this returns the lock.
+pub fn standby(addr: &str, path: &str) -> Result<Lock, Error> {
+    let mut misses = 0;
+    let mut fenced = false;
+    loop {
+        let res = follow(addr, path, &mut misses);
+        if misses == 0 {
+            fenced = false;
+        }
+        misses += 1;
+        match res {
+            Err(e) if misses <= MAX_MISSES => {
+                log!(Relay, Warn, "failover: {} ({} of {})", e, misses, MAX_MISSES);
+            },
+            _ => {},
+        }
+        if misses >= MAX_MISSES {
+            if let Some(lock) = Lock::try_acquire(path)? {
+                return Ok(lock);
+            }
+            if !fenced {
+                log!(Relay, Warn, "failover: lost the active relay, but it still holds {}, so \
+                    not taking over yet", lock_path(path));
+                fenced = true;
+            }
+        }
+        thread::sleep(PUSH_INTERVAL);
+    }
+}
+
+/// Connect to the active relay and copy snapshots until it goes quiet, clearing `misses` with each
+/// one.
+fn follow(addr: &str, path: &str, misses: &mut u32) -> Result<(), Error> {
+    let sa = addr.to_socket_addrs().at(addr)?.next()
+        .ok_or_else(|| Error(format!("{}: no address", addr)))?;
+    let socket = TcpStream::connect_timeout(&sa, PUSH_INTERVAL).at(addr)?;
+    socket.set_read_timeout(Some(PUSH_INTERVAL * MAX_MISSES)).at(addr)?;
+    log!(Relay, Info, "failover: following active relay at {}", addr);
+    let mut r = BufReader::new(socket);
+    loop {
+        let mut line = String::new();
+        let len = match r.read_line(&mut line) {
+            Ok(0) => {
+                log!(Relay, Warn, "failover: {} closed the connection", addr);
+                return Ok(());
+            },
+            Ok(_) => line.trim().strip_prefix("snapshot ").and_then(|x| x.parse::<usize>().ok())
+                .ok_or_else(|| Error(format!("{}: bad header {:?}", addr, line)))?,
+            Err(e) => return Err(Error(format!("{}: {}", addr, e))),
+        };
+        let mut data = vec![0; len];
+        r.read_exact(&mut data).at(addr)?;
+        *misses = 0;
+        let text = String::from_utf8(data).map_err(|e| Error(format!("{}: {}", addr, e)))?;
+        if let Err(e) = snapshot::parse(&text) {
+            log!(Relay, Warn, "failover: ignoring bad snapshot from {}: {}", addr, e);
+            continue;
+        }
+        // As in `SnapshotFile::update`, never leave a partly written file behind.
+        let tmp = format!("{}.tmp", path);
+        if let Err(e) = fs::write(&tmp, &text).and_then(|()| fs::rename(&tmp, path)) {
+            log!(Relay, Error, "failover: failed to write {}: {}", path, e);
+        }
+    }
+}
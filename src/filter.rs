@@ -0,0 +1,114 @@
+use std::net::Ipv4Addr;
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::hex;
+
+
+/// Selects messages by opcode, direction, connection endpoint, index, and body contents.  `None`
+/// fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct MessageFilter {
+    pub majors: Option<Vec<u8>>,
+    pub dir: Option<u8>,
+    /// IP address and optional port.  Matches if either end of the connection is this endpoint.
+    pub conn: Option<(u32, Option<u16>)>,
+    /// Byte string that must appear somewhere in the message body.
+    pub contains: Option<Vec<u8>>,
+    /// Inclusive range of `Message::index`.
+    pub index: Option<(u64, u64)>,
+}
+
+impl MessageFilter {
+    /// Parse a URL-style query string, like `major=0a,14&dir=0&conn=1.2.3.4:5678&contains=ff00`.
+    /// Opcodes and byte strings are in hex.  `index` takes a number or a range like `10-20`.
+    pub fn parse_query(query: &str) -> Result<MessageFilter, String> {
+        let mut f = MessageFilter::default();
+        for part in query.split('&').filter(|s| s.len() > 0) {
+            let (k, v) = match part.find('=') {
+                Some(i) => (&part[..i], &part[i + 1 ..]),
+                None => (part, ""),
+            };
+            match k {
+                "major" => {
+                    let majors = v.split(',')
+                        .map(|x| u8::from_str_radix(x, 16))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| format!("major: {}", e))?;
+                    f.majors = Some(majors);
+                },
+                "dir" => {
+                    f.dir = Some(v.parse().map_err(|e| format!("dir: {}", e))?);
+                },
+                "conn" => {
+                    f.conn = Some(parse_endpoint(v).map_err(|e| format!("conn: {}", e))?);
+                },
+                "contains" => {
+                    f.contains = Some(hex::parse(v).map_err(|e| format!("contains: {}", e))?);
+                },
+                "index" => {
+                    f.index = Some(parse_index_range(v).map_err(|e| format!("index: {}", e))?);
+                },
+                _ => return Err(format!("unknown filter {:?}", k)),
+            }
+        }
+        Ok(f)
+    }
+
+    pub fn matches(&self, ct: ConnTuple, msg: &Message) -> bool {
+        self.matches_msg(msg) && self.matches_conn(ct)
+    }
+
+    /// Check everything except the connection.
+    pub fn matches_msg(&self, msg: &Message) -> bool {
+        if let Some(ref majors) = self.majors {
+            if !majors.contains(&msg.header.major) {
+                return false;
+            }
+        }
+        if let Some(dir) = self.dir {
+            if msg.header.dir != dir {
+                return false;
+            }
+        }
+        if let Some((lo, hi)) = self.index {
+            if msg.index < lo || msg.index > hi {
+                return false;
+            }
+        }
+        if let Some(ref pat) = self.contains {
+            if pat.len() > 0 && !msg.body.windows(pat.len()).any(|w| w == &pat[..]) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn matches_conn(&self, ct: ConnTuple) -> bool {
+        self.conn.map_or(true, |(ip, port)| ct.has_endpoint(ip, port))
+    }
+}
+
+/// Parse `N` or `N-M` as an inclusive range.
+pub fn parse_index_range(s: &str) -> Result<(u64, u64), String> {
+    let parse = |x: &str| x.parse::<u64>().map_err(|e| e.to_string());
+    match s.find('-') {
+        Some(i) => Ok((parse(&s[..i])?, parse(&s[i + 1 ..])?)),
+        None => {
+            let n = parse(s)?;
+            Ok((n, n))
+        },
+    }
+}
+
+/// Parse `1.2.3.4` or `1.2.3.4:5678`.
+pub fn parse_endpoint(s: &str) -> Result<(u32, Option<u16>), String> {
+    let (ip, port) = match s.find(':') {
+        Some(i) => (&s[..i], Some(&s[i + 1 ..])),
+        None => (s, None),
+    };
+    let ip = ip.parse::<Ipv4Addr>().map_err(|e| e.to_string())?;
+    let port = match port {
+        Some(p) => Some(p.parse().map_err(|e: std::num::ParseIntError| e.to_string())?),
+        None => None,
+    };
+    Ok((u32::from(ip), port))
+}
@@ -0,0 +1,91 @@
+//! Framing of the messages in a TFH stream, without any of the stream reassembly.  Each message
+//! is a big-endian length, two bytes of unknown purpose, the big-endian major opcode, the
+//! little-endian minor opcode (for major 0x20 only), and the body.  The length counts everything
+//! after itself.
+//!
+//! This only needs `core` and `alloc`, so it's available without the `std` feature.
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp;
+use crate::bytes::Bytes;
+
+
+/// Length of the length field at the start of each message.
+pub const LEN_PREFIX: usize = 4;
+/// Length of the longest header, including the length field.
+pub const MAX_HEADER_LEN: usize = 14;
+
+/// The major opcode whose messages also carry a minor opcode.
+const MAJOR_WITH_MINOR: u32 = 0x20;
+
+/// Length of the header of a message with major opcode `major`, including the length field.
+pub fn header_len(major: u32) -> usize {
+    10 + if major == MAJOR_WITH_MINOR { 4 } else { 0 }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    /// Value of the length field: the length of the message after it.
+    pub len: u32,
+    /// Opcodes as they appear in the stream.  The protocol only uses values below 0x100, but
+    /// the fields are wider, so out-of-range values are reported rather than truncated.
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Header {
+    /// Parse the header from `buf`, which holds the start of a message: its first
+    /// `MAX_HEADER_LEN` bytes, or fewer if the message is shorter.  Returns `None` if `buf`
+    /// doesn't hold the whole length field.  A message too short to hold the opcodes reads as if
+    /// it were padded with zeros.
+    pub fn parse(buf: &[u8]) -> Option<Header> {
+        if buf.len() < LEN_PREFIX {
+            return None;
+        }
+        let len = buf.u32_be(0);
+        let mut raw = [0; MAX_HEADER_LEN];
+        let raw_len = cmp::min(cmp::min(raw.len(), buf.len()), LEN_PREFIX + len as usize);
+        raw[..raw_len].copy_from_slice(&buf[..raw_len]);
+        let major = raw.u32_be(6);
+        let minor = if major == MAJOR_WITH_MINOR { raw.u32_le(10) } else { 0 };
+        Some(Header { len, major, minor })
+    }
+
+    /// Length of the whole message, including the length field.
+    pub fn frame_len(&self) -> usize {
+        LEN_PREFIX + self.len as usize
+    }
+
+    pub fn header_len(&self) -> usize {
+        header_len(self.major)
+    }
+
+    pub fn body_len(&self) -> usize {
+        self.frame_len().saturating_sub(self.header_len())
+    }
+}
+
+/// Split the message at the start of `buf` into its header and body, or return `None` if `buf`
+/// doesn't hold all of it.
+pub fn split(buf: &[u8]) -> Option<(Header, &[u8])> {
+    let header = Header::parse(buf)?;
+    if buf.len() < header.frame_len() {
+        return None;
+    }
+    let body_start = cmp::min(header.header_len(), header.frame_len());
+    Some((header, &buf[body_start .. header.frame_len()]))
+}
+
+/// Encode a message with the given opcodes and body.  The unknown bytes are written as zeros.
+pub fn encode(major: u8, minor: u8, body: &[u8]) -> Vec<u8> {
+    let major = major as u32;
+    let header_len = header_len(major);
+    let mut buf = vec![0; header_len];
+    buf.put_u32_be(0, (header_len - LEN_PREFIX + body.len()) as u32);
+    buf.put_u32_be(6, major);
+    if major == MAJOR_WITH_MINOR {
+        buf.put_u32_le(10, minor as u32);
+    }
+    buf.extend_from_slice(body);
+    buf
+}
@@ -0,0 +1,217 @@
+//! gRPC service for consuming live messages and connection state from other programs.  The
+//! interface is defined in `proto/tfh.proto`.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use crate::{Error, ErrorAt};
+use crate::filter::{self, MessageFilter};
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, Message};
+
+mod pb {
+    tonic::include_proto!("tfh");
+}
+
+use self::pb::relay_server::{Relay, RelayServer};
+
+
+/// Number of messages that can be queued for a slow subscriber before further messages are
+/// dropped.
+const SUBSCRIBER_QUEUE_LEN: usize = 1024;
+
+type EventSender = mpsc::Sender<Result<pb::MessageEvent, Status>>;
+
+struct Subscriber {
+    filter: MessageFilter,
+    send: EventSender,
+}
+
+#[derive(Default)]
+struct ConnInfo {
+    name: String,
+    messages: u64,
+}
+
+#[derive(Default)]
+struct State {
+    subscribers: HashMap<String, Subscriber>,
+    conns: HashMap<ConnTuple, ConnInfo>,
+    /// Counter for naming subscriptions that didn't provide a name.
+    anon_count: u64,
+}
+
+/// Handle shared between the processing thread, which reports messages and connection changes,
+/// and the gRPC server thread.
+#[derive(Clone)]
+pub struct Service {
+    state: Arc<Mutex<State>>,
+}
+
+impl Service {
+    /// Listen on `addr` and serve requests from a background thread.
+    pub fn start(addr: &str) -> Result<Service, Error> {
+        let addr = addr.parse::<SocketAddr>()
+            .map_err(|e| Error(format!("grpc: bad address {:?}: {}", addr, e)))?;
+        let rt = tokio::runtime::Runtime::new().at("grpc: starting runtime")?;
+        // Bind here rather than in the server thread, so errors are reported at startup.
+        let listener = rt.block_on(tokio::net::TcpListener::bind(addr)).at("grpc: bind")?;
+
+        let svc = Service { state: Arc::new(Mutex::new(State::default())) };
+        let server = RelayServer::new(svc.clone());
+        thread::spawn(move || {
+            let res = rt.block_on(
+                tonic::transport::Server::builder()
+                    .add_service(server)
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            if let Err(e) = res {
+                log!(Handler, Error, "grpc: server failed: {}", e);
+            }
+        });
+
+        Ok(svc)
+    }
+
+    pub fn publish(&self, ct: ConnTuple, session: SessionId, msg: &Message) {
+        let mut state = self.state.lock().unwrap();
+        state.conns.entry(ct).or_default().messages += 1;
+
+        let mut event = None;
+        state.subscribers.retain(|_, sub| {
+            if sub.send.is_closed() {
+                return false;
+            }
+            if !sub.filter.matches(ct, msg) {
+                return true;
+            }
+            let event = event.get_or_insert_with(|| pb::MessageEvent {
+                conn: ct.to_string(),
+                dir: msg.header.dir as u32,
+                major: msg.header.major as u32,
+                minor: msg.header.minor as u32,
+                ack: msg.header.ack,
+                body: msg.body.to_vec(),
+                time_us: msg.time,
+                index: msg.index,
+                acks_start: msg.acks.start,
+                acks_end: msg.acks.end,
+                session: session.to_string(),
+            }).clone();
+            match sub.send.try_send(Ok(event)) {
+                Ok(()) => true,
+                // The subscriber is falling behind.  Drop this message, but keep the stream.
+                Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+
+    pub fn set_name(&self, ct: ConnTuple, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.conns.entry(ct).or_default().name = name.to_owned();
+    }
+
+    pub fn remove_conn(&self, ct: ConnTuple) {
+        self.state.lock().unwrap().conns.remove(&ct);
+    }
+}
+
+fn convert_filter(f: Option<pb::Filter>) -> Result<MessageFilter, Status> {
+    let f = match f {
+        Some(x) => x,
+        None => return Ok(MessageFilter::default()),
+    };
+
+    let majors = if f.majors.len() > 0 {
+        let majors = f.majors.iter()
+            .map(|&m| u8::try_from(m))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Status::invalid_argument("major opcodes go up to 255"))?;
+        Some(majors)
+    } else {
+        None
+    };
+    let conn = if f.conn.len() > 0 {
+        Some(filter::parse_endpoint(&f.conn).map_err(Status::invalid_argument)?)
+    } else {
+        None
+    };
+
+    let contains = if f.contains.len() > 0 { Some(f.contains) } else { None };
+
+    let dir = match f.dir {
+        Some(d) if d > 1 => return Err(Status::invalid_argument("dir is 0 or 1")),
+        d => d.map(|d| d as u8),
+    };
+
+    Ok(MessageFilter {
+        majors,
+        dir,
+        conn,
+        contains,
+        index: None,
+    })
+}
+
+#[tonic::async_trait]
+impl Relay for Service {
+    type StreamMessagesStream = ReceiverStream<Result<pb::MessageEvent, Status>>;
+
+    async fn stream_messages(
+        &self,
+        req: Request<pb::StreamMessagesRequest>,
+    ) -> Result<Response<Self::StreamMessagesStream>, Status> {
+        let req = req.into_inner();
+        let filter = convert_filter(req.filter)?;
+        let (send, recv) = mpsc::channel(SUBSCRIBER_QUEUE_LEN);
+
+        let mut state = self.state.lock().unwrap();
+        let name = if req.subscription.len() > 0 {
+            req.subscription
+        } else {
+            state.anon_count += 1;
+            format!("anon-{}", state.anon_count)
+        };
+        // Reusing a name replaces the old subscription, which ends its stream.
+        state.subscribers.insert(name, Subscriber { filter, send });
+
+        Ok(Response::new(ReceiverStream::new(recv)))
+    }
+
+    async fn list_connections(
+        &self,
+        _req: Request<pb::ListConnectionsRequest>,
+    ) -> Result<Response<pb::ListConnectionsResponse>, Status> {
+        let state = self.state.lock().unwrap();
+        let mut connections = state.conns.iter().map(|(ct, info)| pb::Connection {
+            conn: ct.to_string(),
+            name: info.name.clone(),
+            messages: info.messages,
+        }).collect::<Vec<_>>();
+        connections.sort_by(|a, b| a.conn.cmp(&b.conn));
+        Ok(Response::new(pb::ListConnectionsResponse { connections }))
+    }
+
+    async fn apply_filter(
+        &self,
+        req: Request<pb::ApplyFilterRequest>,
+    ) -> Result<Response<pb::ApplyFilterResponse>, Status> {
+        let req = req.into_inner();
+        let filter = convert_filter(req.filter)?;
+
+        let mut state = self.state.lock().unwrap();
+        let found = match state.subscribers.get_mut(&req.subscription) {
+            Some(sub) => {
+                sub.filter = filter;
+                true
+            },
+            None => false,
+        };
+        Ok(Response::new(pb::ApplyFilterResponse { found }))
+    }
+}
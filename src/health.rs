@@ -0,0 +1,124 @@
+//! Liveness tracking, for spotting a relay that is still running but has stopped doing anything.
+//!
+//! Threads call `register` when they start and `beat` whenever they finish a unit of work, such
+//! as handling a packet.  `Supervisor` registers its threads itself, and records when one is
+//! restarting or has stopped.  A thread waiting for input doesn't beat, so an old `last_active`
+//! only means trouble if packets arrived since then; `write_json` puts the time of each side's
+//! latest packet alongside for comparison.
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use crate::stats::RelaySnapshot;
+use crate::util::clock;
+use crate::util::json;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    Running = 0,
+    /// Failed, and waiting to be restarted.
+    Restarting = 1,
+    /// Exited or gave up, and won't be restarted.
+    Stopped = 2,
+}
+
+impl State {
+    fn from_u8(x: u8) -> State {
+        match x {
+            0 => State::Running,
+            1 => State::Restarting,
+            _ => State::Stopped,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Restarting => "restarting",
+            State::Stopped => "stopped",
+        }
+    }
+}
+
+struct ThreadHealth {
+    name: String,
+    state: AtomicU8,
+    /// Time of the latest `beat`, in microseconds since the Unix epoch, or zero if none.
+    last_active: AtomicU64,
+}
+
+static THREADS: Mutex<Vec<Arc<ThreadHealth>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<ThreadHealth>>> = RefCell::new(None);
+}
+
+/// Start tracking the current thread as `name`, in the `Running` state.  A thread restarted
+/// under the same name takes over the old entry.
+pub fn register(name: &str) {
+    let th = {
+        let mut threads = THREADS.lock().unwrap();
+        match threads.iter().find(|th| th.name == name) {
+            Some(th) => th.clone(),
+            None => {
+                let th = Arc::new(ThreadHealth {
+                    name: name.to_owned(),
+                    state: AtomicU8::new(State::Running as u8),
+                    last_active: AtomicU64::new(0),
+                });
+                threads.push(th.clone());
+                th
+            },
+        }
+    };
+    th.state.store(State::Running as u8, Ordering::Relaxed);
+    CURRENT.with(|c| *c.borrow_mut() = Some(th));
+}
+
+/// Record that the current thread is making progress.  Does nothing on unregistered threads.
+pub fn beat() {
+    CURRENT.with(|c| {
+        if let Some(ref th) = *c.borrow() {
+            th.last_active.store(clock::now_us(), Ordering::Relaxed);
+        }
+    });
+}
+
+/// Set the state of the current thread.
+pub fn set_state(state: State) {
+    CURRENT.with(|c| {
+        if let Some(ref th) = *c.borrow() {
+            th.state.store(state as u8, Ordering::Relaxed);
+        }
+    });
+}
+
+fn secs_or_null(us: u64) -> String {
+    if us == 0 { "null".to_owned() } else { (us / 1_000_000).to_string() }
+}
+
+/// Write the time of the latest packet from each side, and the state and latest activity of each
+/// registered thread, as JSON to `path`.  Times are in seconds since the Unix epoch, or `null` if
+/// there hasn't been one yet.  The file is replaced atomically.
+pub fn write_json(path: &str, time: i64, snap: &RelaySnapshot) -> io::Result<()> {
+    let mut threads = json::Object::new();
+    for th in THREADS.lock().unwrap().iter() {
+        let state = State::from_u8(th.state.load(Ordering::Relaxed));
+        let entry = json::Object::new()
+            .str("state", state.name())
+            .raw("last_active", &secs_or_null(th.last_active.load(Ordering::Relaxed)))
+            .finish();
+        threads.raw(&th.name, &entry);
+    }
+    let s = json::Object::new()
+        .num("time", time)
+        .raw("a_last_packet", &secs_or_null(snap.a_to_b.last_packet))
+        .raw("b_last_packet", &secs_or_null(snap.b_to_a.last_packet))
+        .raw("threads", &threads.finish())
+        .finish();
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, s + "\n")?;
+    fs::rename(&tmp, path)
+}
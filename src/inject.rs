@@ -0,0 +1,37 @@
+//! Messages waiting to be injected into connections.
+//!
+//! The control socket queues messages here, and the processing worker that owns each connection
+//! picks them up through `StreamHandler::next_injection`, which `TfhStreamConns::handle_mut` calls
+//! when the server-to-client stream is between messages.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+#[derive(Default)]
+pub struct Injector {
+    pending: Mutex<HashMap<ConnTuple, VecDeque<Message>>>,
+}
+
+impl Injector {
+    /// Queue `msg` to be sent to the client on `ct`.
+    pub fn push(&self, ct: ConnTuple, msg: Message) {
+        self.pending.lock().unwrap().entry(ct).or_insert_with(VecDeque::new).push_back(msg);
+    }
+
+    /// Take the next message queued for `ct`, if any.
+    pub fn take(&self, ct: ConnTuple) -> Option<Message> {
+        let mut pending = self.pending.lock().unwrap();
+        let q = pending.get_mut(&ct)?;
+        let msg = q.pop_front();
+        if q.len() == 0 {
+            pending.remove(&ct);
+        }
+        msg
+    }
+
+    /// Discard anything still queued for `ct`, which has closed.
+    pub fn clear(&self, ct: ConnTuple) {
+        self.pending.lock().unwrap().remove(&ct);
+    }
+}
@@ -0,0 +1,182 @@
+//! Producer that copies decoded messages and session events to Kafka, for storage and stream
+//! processing off the relay host.
+//!
+//! Messages go to the configured topic, keyed by connection and session, like
+//! `1.2.3.4:5678 -> 5.6.7.8:27016 0badf00d`.  Session events (connect, timeout) go to
+//! `<topic>-events` as JSON objects like `{"event":"connect","conn":"..."}`, with a `session`
+//! field and the session in the key once the session is known.
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use kafka_client::producer::{Producer, Record, RequiredAcks};
+use crate::Error;
+use crate::session::SessionId;
+use crate::sink::MessageSink;
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::json;
+
+
+/// Number of records that can be waiting for the broker before further records are dropped.
+const QUEUE_LEN: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    /// The output of `Message::to_json`, with a `session` field.
+    Json,
+    /// 12-byte `ConnTuple`, 4-byte `SessionId`, then the 12-byte message header and the body, as
+    /// in tfhlog records.
+    Binary,
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Encoding, Error> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "binary" => Ok(Encoding::Binary),
+            _ => Err(Error(format!("unknown kafka encoding {:?} (expected json or binary)", s))),
+        }
+    }
+}
+
+enum Topic {
+    Messages,
+    Events,
+}
+
+struct Item {
+    topic: Topic,
+    key: String,
+    value: Vec<u8>,
+}
+
+/// Sends to the producer thread.  Forks share the thread, so every worker's records go out in
+/// one stream.
+pub struct Sink {
+    send: SyncSender<Item>,
+    encoding: Encoding,
+    /// Records dropped so far, by this sink and its forks.
+    dropped: Arc<AtomicU64>,
+}
+
+impl Sink {
+    /// Connect to `hosts` (comma-separated `host:port` list) and start the producer thread.
+    pub fn start(hosts: &str, topic: &str, encoding: Encoding) -> Result<Sink, Error> {
+        let hosts = hosts.split(',').map(|h| h.to_owned()).collect();
+        let mut producer = Producer::from_hosts(hosts)
+            .with_ack_timeout(Duration::from_secs(1))
+            .with_required_acks(RequiredAcks::One)
+            .create()
+            .map_err(|e| Error(format!("kafka: connecting: {}", e)))?;
+
+        let (send, recv) = mpsc::sync_channel::<Item>(QUEUE_LEN);
+        let msg_topic = topic.to_owned();
+        let event_topic = format!("{}-events", topic);
+        thread::spawn(move || {
+            let mut failing = false;
+            for item in recv.iter() {
+                let topic = match item.topic {
+                    Topic::Messages => &msg_topic,
+                    Topic::Events => &event_topic,
+                };
+                let record = Record::from_key_value(topic, item.key.as_bytes(), &item.value[..]);
+                match producer.send(&record) {
+                    Ok(()) => {
+                        if failing {
+                            log!(Handler, Info, "kafka: sending works again");
+                            failing = false;
+                        }
+                    },
+                    Err(e) => {
+                        // Only report the first failure, to avoid flooding the console while the
+                        // broker is down.
+                        if !failing {
+                            log!(Handler, Error,
+                                "kafka: send failed, dropping records until it recovers: {}", e);
+                            failing = true;
+                        }
+                    },
+                }
+            }
+        });
+
+        Ok(Sink { send, encoding, dropped: Arc::new(AtomicU64::new(0)) })
+    }
+
+    fn push(&mut self, item: Item) {
+        match self.send.try_send(item) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    log!(Handler, Warn,
+                        "kafka: producer is falling behind; {} records dropped so far", dropped);
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+
+    pub fn message(&mut self, ct: ConnTuple, session: SessionId, msg: &Message) {
+        let value = match self.encoding {
+            Encoding::Json => {
+                let mut obj = json::Object::new();
+                obj.str("session", &session.to_string());
+                msg.write_json(ct, &mut obj);
+                obj.finish().into_bytes()
+            },
+            Encoding::Binary => {
+                let mut v = Vec::with_capacity(28 + msg.body.len());
+                v.extend_from_slice(&ct.as_bytes());
+                v.extend_from_slice(&session.0.to_be_bytes());
+                v.extend_from_slice(&msg.header.as_bytes());
+                v.extend_from_slice(&msg.body);
+                v
+            },
+        };
+        self.push(Item { topic: Topic::Messages, key: key(ct, Some(session)), value });
+    }
+
+    /// Report a session event, such as `"connect"` or `"timeout"`.  A connection has no session
+    /// until its first message.
+    pub fn event(&mut self, ct: ConnTuple, session: Option<SessionId>, event: &str) {
+        let mut obj = json::Object::new();
+        obj.str("event", event).str("conn", &ct.to_string());
+        if let Some(session) = session {
+            obj.str("session", &session.to_string());
+        }
+        let value = obj.finish().into_bytes();
+        self.push(Item { topic: Topic::Events, key: key(ct, session), value });
+    }
+}
+
+/// The record key for `ct`'s session `session`.
+fn key(ct: ConnTuple, session: Option<SessionId>) -> String {
+    match session {
+        Some(session) => format!("{} {}", ct, session),
+        None => ct.to_string(),
+    }
+}
+
+impl MessageSink for Sink {
+    fn append(&mut self, ct: ConnTuple, session: SessionId, msg: &Message) -> io::Result<()> {
+        self.message(ct, session, msg);
+        Ok(())
+    }
+
+    fn event(&mut self, ct: ConnTuple, session: Option<SessionId>, event: &str) {
+        Sink::event(self, ct, session, event);
+    }
+
+    fn fork(&self) -> Box<dyn MessageSink> {
+        Box::new(Sink {
+            send: self.send.clone(),
+            encoding: self.encoding,
+            dropped: self.dropped.clone(),
+        })
+    }
+}
@@ -0,0 +1,198 @@
+//! Keepalive spacing, with `--keepalive OPCODES`.  Clients and servers send keepalives at a
+//! steady pace, so a gap much longer than usual is an early sign of a connection about to time
+//! out.  This keeps the recent intervals between keepalives in each direction of each
+//! connection, warns once the current gap is far outside them, and with `--keepalive-stats
+//! FILE`, writes the figures for every connection to a JSON file.
+//!
+//! The protocol's keepalive opcodes aren't known, so they have to be given.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use crate::log_layout::{self, OpcodeSpec};
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::json;
+
+
+/// Number of recent intervals kept for each stream.
+pub const WINDOW: usize = 32;
+/// Number of intervals needed before a gap can count as late.
+pub const MIN_INTERVALS: usize = 5;
+/// A gap is late once it's this many standard deviations over the mean interval.
+pub const LATE_DEVIATIONS: f64 = 4.0;
+/// Lower bound on the standard deviation used for `deviation`, as a fraction of the mean, so
+/// that perfectly regular keepalives don't make every small delay look alarming.
+const MIN_SPREAD: f64 = 0.1;
+
+/// Figures for the keepalives in one direction of one connection.  Times are in microseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    /// Keepalives seen in total.
+    pub count: u64,
+    /// Mean, standard deviation, and maximum of the recent intervals.
+    pub mean: f64,
+    pub stddev: f64,
+    pub max: u64,
+    /// The most recent interval.
+    pub last_interval: u64,
+    /// Time since the last keepalive.
+    pub gap: u64,
+    /// How far `gap` is above `mean`, in standard deviations.  Zero until there are
+    /// `MIN_INTERVALS` intervals to compare against.
+    pub deviation: f64,
+    /// Intervals that were late.
+    pub late_count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// No keepalive has arrived in `gap` microseconds, where `mean` is usual.
+    Late { ct: ConnTuple, dir: u8, gap: u64, mean: u64 },
+    /// A keepalive arrived after being late.
+    Recovered { ct: ConnTuple, dir: u8, gap: u64 },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Late { ct, dir, gap, mean } => write!(
+                fmt, "{}: dir {}: no keepalive for {} ms (usually {} ms)",
+                ct, dir, gap / 1000, mean / 1000,
+            ),
+            Event::Recovered { ct, dir, gap } => write!(
+                fmt, "{}: dir {}: keepalive again after {} ms", ct, dir, gap / 1000,
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Intervals {
+    count: u64,
+    last: u64,
+    recent: VecDeque<u64>,
+    late: bool,
+    late_count: u64,
+}
+
+impl Intervals {
+    fn mean_stddev(&self) -> (f64, f64) {
+        let n = self.recent.len() as f64;
+        let mean = self.recent.iter().sum::<u64>() as f64 / n;
+        let var = self.recent.iter().map(|&x| (x as f64 - mean).powi(2)).sum::<f64>() / n;
+        (mean, var.sqrt())
+    }
+
+    fn deviation(&self, gap: u64) -> f64 {
+        if self.recent.len() < MIN_INTERVALS {
+            return 0.;
+        }
+        let (mean, stddev) = self.mean_stddev();
+        (gap as f64 - mean) / stddev.max(mean * MIN_SPREAD).max(1.)
+    }
+
+    fn summary(&self, now: u64) -> Summary {
+        let (mean, stddev) = if self.recent.len() > 0 { self.mean_stddev() } else { (0., 0.) };
+        let gap = now.saturating_sub(self.last);
+        Summary {
+            count: self.count,
+            mean,
+            stddev,
+            max: self.recent.iter().copied().max().unwrap_or(0),
+            last_interval: self.recent.back().copied().unwrap_or(0),
+            gap,
+            deviation: self.deviation(gap),
+            late_count: self.late_count,
+        }
+    }
+}
+
+pub struct KeepaliveTracker {
+    opcodes: Vec<OpcodeSpec>,
+    streams: HashMap<(ConnTuple, u8), Intervals>,
+}
+
+impl KeepaliveTracker {
+    pub fn new(opcodes: Vec<OpcodeSpec>) -> KeepaliveTracker {
+        KeepaliveTracker { opcodes, streams: HashMap::new() }
+    }
+
+    pub fn matches(&self, msg: &Message) -> bool {
+        log_layout::list_matches(&self.opcodes, msg)
+    }
+
+    /// Record a keepalive on `ct` in direction `dir` at `time`.  Returns `Recovered` if the
+    /// stream was late.
+    pub fn record(&mut self, ct: ConnTuple, dir: u8, time: u64) -> Option<Event> {
+        let s = self.streams.entry((ct, dir)).or_default();
+        s.count += 1;
+        if s.count == 1 {
+            s.last = time;
+            return None;
+        }
+        let gap = time.saturating_sub(s.last);
+        s.last = time;
+        if s.recent.len() == WINDOW {
+            s.recent.pop_front();
+        }
+        s.recent.push_back(gap);
+        if s.late {
+            s.late = false;
+            return Some(Event::Recovered { ct, dir, gap });
+        }
+        None
+    }
+
+    /// Check for streams whose current gap has become late at `now`.  Each late gap is reported
+    /// once.
+    pub fn check(&mut self, now: u64) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (&(ct, dir), s) in &mut self.streams {
+            let gap = now.saturating_sub(s.last);
+            if !s.late && s.deviation(gap) >= LATE_DEVIATIONS {
+                s.late = true;
+                s.late_count += 1;
+                events.push(Event::Late { ct, dir, gap, mean: s.mean_stddev().0 as u64 });
+            }
+        }
+        events.sort_by_key(|e| e.to_string());
+        events
+    }
+
+    pub fn close(&mut self, ct: ConnTuple) {
+        self.streams.retain(|&(c, _), _| c != ct);
+    }
+
+    pub fn summary(&self, ct: ConnTuple, dir: u8, now: u64) -> Option<Summary> {
+        self.streams.get(&(ct, dir)).map(|s| s.summary(now))
+    }
+
+    /// The figures for every stream at `now`, as a JSON array of objects.
+    pub fn to_json(&self, now: u64) -> String {
+        let mut keys = self.streams.keys().copied().collect::<Vec<_>>();
+        keys.sort_by_key(|&(ct, dir)| (ct.to_string(), dir));
+        let items = keys.into_iter().map(|(ct, dir)| {
+            let s = self.streams[&(ct, dir)].summary(now);
+            json::Object::new()
+                .str("conn", &ct.to_string())
+                .num("dir", dir)
+                .num("count", s.count)
+                .num("mean_ms", format!("{:.1}", s.mean / 1000.))
+                .num("stddev_ms", format!("{:.1}", s.stddev / 1000.))
+                .num("max_ms", s.max / 1000)
+                .num("last_interval_ms", s.last_interval / 1000)
+                .num("gap_ms", s.gap / 1000)
+                .num("deviation", format!("{:.2}", s.deviation))
+                .num("late_count", s.late_count)
+                .finish()
+        }).collect::<Vec<_>>();
+        format!("[{}]", items.join(","))
+    }
+
+    /// Write `to_json` to the file at `path`, replacing it.
+    pub fn write_json(&self, path: &str, now: u64) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, self.to_json(now) + "\n")?;
+        fs::rename(&tmp, path)
+    }
+}
@@ -0,0 +1,175 @@
+//! Latency histograms, for putting numbers on complaints that the relay adds lag.
+//!
+//! `Histogram` uses HDR-style log-linear buckets: values below 16 are counted exactly, and each
+//! power of two above that is split into 16 buckets, so any recorded value is within about 6% of
+//! the bucket it's reported as.  Buckets are allocated only up to the largest value seen.
+//!
+//! The relay estimates round-trip times from the TFH streams' acknowledgements (see
+//! `TfhStreamConns`), separately for its two legs: to the client and back, and to the server and
+//! back.  Each sample also includes however long the far end waited before acknowledging.
+use std::cmp;
+use std::fmt::Write as _;
+use crate::util::json;
+
+
+/// log2 of the number of buckets per power of two.
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+fn bucket(v: u64) -> usize {
+    if v < SUB_BUCKETS as u64 {
+        return v as usize;
+    }
+    let exp = 63 - v.leading_zeros();
+    let sub = (v >> (exp - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exp - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+/// The smallest value that falls in bucket `i`, and the width of the bucket.
+fn bucket_range(i: usize) -> (u64, u64) {
+    if i < SUB_BUCKETS {
+        return (i as u64, 1);
+    }
+    let shift = (i / SUB_BUCKETS - 1) as u32;
+    let sub = (i % SUB_BUCKETS) as u64;
+    ((SUB_BUCKETS as u64 + sub) << shift, 1 << shift)
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram::default()
+    }
+
+    pub fn record(&mut self, v: u64) {
+        let i = bucket(v);
+        if i >= self.counts.len() {
+            self.counts.resize(i + 1, 0);
+        }
+        self.counts[i] += 1;
+        self.min = if self.total == 0 { v } else { cmp::min(self.min, v) };
+        self.max = cmp::max(self.max, v);
+        self.total += 1;
+        self.sum = self.sum.saturating_add(v);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.total == 0 {
+            return;
+        }
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (a, &b) in self.counts.iter_mut().zip(&other.counts) {
+            *a += b;
+        }
+        self.min = if self.total == 0 { other.min } else { cmp::min(self.min, other.min) };
+        self.max = cmp::max(self.max, other.max);
+        self.total += other.total;
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> u64 {
+        if self.total == 0 { 0 } else { self.sum / self.total }
+    }
+
+    /// The value below which `percent` of the recorded values fall, rounded up to the top of its
+    /// bucket.  Zero if nothing has been recorded.
+    pub fn percentile(&self, percent: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let rank = cmp::max(1, (percent / 100. * self.total as f64).ceil() as u64);
+        let mut seen = 0;
+        for (i, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let (lo, width) = bucket_range(i);
+                return cmp::min(lo + width - 1, self.max);
+            }
+        }
+        self.max
+    }
+
+    /// The count, mean, and percentiles as a JSON object.  With `buckets`, also include the
+    /// nonzero buckets, as `[lowest value, count]` pairs, for tools that want to merge or
+    /// re-bucket them.
+    pub fn to_json(&self, buckets: bool) -> String {
+        let mut obj = json::Object::new();
+        obj.num("count", self.total)
+            .num("min", self.min)
+            .num("mean", self.mean())
+            .num("p50", self.percentile(50.))
+            .num("p90", self.percentile(90.))
+            .num("p99", self.percentile(99.))
+            .num("p999", self.percentile(99.9))
+            .num("max", self.max);
+        if buckets {
+            let mut s = String::from("[");
+            for (i, &n) in self.counts.iter().enumerate().filter(|&(_, &n)| n > 0) {
+                if s.len() > 1 {
+                    s.push(',');
+                }
+                write!(s, "[{},{}]", bucket_range(i).0, n).unwrap();
+            }
+            s.push(']');
+            obj.raw("buckets", &s);
+        }
+        obj.finish()
+    }
+
+    /// Short human-readable summary, like `p50 12.3 ms, p99 40.1 ms (500 samples)`.
+    pub fn describe(&self) -> String {
+        if self.total == 0 {
+            return "no samples".into();
+        }
+        format!(
+            "p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms ({} samples)",
+            self.percentile(50.) as f64 / 1000., self.percentile(90.) as f64 / 1000.,
+            self.percentile(99.) as f64 / 1000., self.max as f64 / 1000., self.total,
+        )
+    }
+}
+
+/// Round-trip times in microseconds for each leg of a relayed connection.
+#[derive(Clone, Debug, Default)]
+pub struct Rtt {
+    /// From the relay forwarding server data to the client's acknowledgement of it.
+    pub client: Histogram,
+    /// From the relay forwarding client data to the server's acknowledgement of it.
+    pub server: Histogram,
+}
+
+impl Rtt {
+    pub fn merge(&mut self, other: &Rtt) {
+        self.client.merge(&other.client);
+        self.server.merge(&other.server);
+    }
+
+    pub fn to_json(&self, buckets: bool) -> String {
+        json::Object::new()
+            .raw("client", &self.client.to_json(buckets))
+            .raw("server", &self.server.to_json(buckets))
+            .finish()
+    }
+}
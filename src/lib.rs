@@ -1,16 +1,148 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(map_first_last)]
-#![feature(untagged_unions)]
-use std::fmt;
+extern crate alloc;
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::io;
-use nix;
 
 
-mod bytes;
+#[cfg(feature = "std")]
+#[macro_use]
+pub mod logging;
+
+#[cfg(feature = "relay")]
+pub mod affinity;
+#[cfg(feature = "std")]
+pub mod alerts;
+#[cfg(feature = "std")]
+pub mod analysis;
+#[cfg(feature = "std")]
+pub mod anonymize;
+pub mod arena;
+pub mod bytes;
+#[cfg(feature = "std")]
+pub mod canary;
+#[cfg(feature = "pcap")]
+pub mod capture;
+#[cfg(feature = "std")]
+pub mod channel;
+#[cfg(feature = "std")]
+pub mod chat_log;
+#[cfg(feature = "relay")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod control;
+#[cfg(all(feature = "relay", feature = "testing"))]
+pub mod corpus;
+#[cfg(feature = "std")]
+pub mod dedup;
+#[cfg(feature = "relay")]
+pub mod disk_watchdog;
+#[cfg(feature = "std")]
+pub mod export;
+#[cfg(feature = "relay")]
+pub mod failover;
+#[cfg(feature = "std")]
+pub mod filter;
+pub mod framing;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "std")]
+pub mod health;
+#[cfg(feature = "std")]
+pub mod inject;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "std")]
+pub mod keepalive;
+#[cfg(feature = "std")]
+pub mod latency;
+#[cfg(feature = "std")]
+pub mod log_index;
+#[cfg(feature = "std")]
+pub mod log_layout;
+#[cfg(feature = "std")]
+pub mod matches;
+#[cfg(feature = "std")]
+pub mod messages;
 pub mod packet;
+#[cfg(feature = "std")]
+pub mod packet_text;
+#[cfg(feature = "std")]
+pub mod opcodes;
+#[cfg(feature = "std")]
+pub mod pacing;
+#[cfg(feature = "std")]
+pub mod parse_warnings;
+#[cfg(feature = "pcap")]
 pub mod pcap;
+pub mod prelude;
+#[cfg(feature = "relay")]
 pub mod process;
+#[cfg(feature = "std")]
+pub mod ratelimit;
+#[cfg(feature = "std")]
+pub mod ratings;
+#[cfg(feature = "relay")]
+pub mod relay;
+#[cfg(feature = "std")]
+pub mod rewrite;
+#[cfg(feature = "std")]
+pub mod roster;
+#[cfg(feature = "std")]
+pub mod sandbox;
+pub mod sdr;
+#[cfg(feature = "relay")]
+pub mod sendmmsg;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "relay")]
+pub mod sim;
+#[cfg(feature = "std")]
+pub mod sink;
+#[cfg(feature = "std")]
+pub mod skew;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod store;
+pub mod stun;
+#[cfg(feature = "std")]
+pub mod supervise;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod tfh_client;
+#[cfg(feature = "std")]
 pub mod tfh_stream;
+#[cfg(feature = "std")]
+pub mod tfhlog;
+#[cfg(feature = "relay")]
+pub mod tproxy;
+#[cfg(feature = "relay")]
+pub mod transport;
+#[cfg(feature = "tun")]
+pub mod tun_socket;
+#[cfg(feature = "tun")]
 pub mod tuntap;
+#[cfg(feature = "relay")]
+pub mod udp_proxy;
+#[cfg(feature = "std")]
+pub mod util;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+#[cfg(feature = "xdp")]
+pub mod xdp;
+#[cfg(feature = "std")]
+pub mod zmtp;
 
 
 #[derive(Clone, Debug)]
@@ -22,10 +154,12 @@ impl fmt::Display for Error {
     }
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for Error {
     fn from(x: io::Error) -> Error { Error(x.to_string()) }
 }
 
+#[cfg(feature = "nix")]
 impl From<nix::Error> for Error {
     fn from(x: nix::Error) -> Error { Error(x.to_string()) }
 }
@@ -0,0 +1,193 @@
+//! Index sidecars for tfhlogs, with `--log-index`: next to each `NAME.tfhlog`, `NAME.tfhlog.idx`
+//! lists where each record starts, with its time and opcodes, so tools can pick a time range or
+//! an opcode out of a multi-gigabyte log by reading the much smaller index and seeking, instead
+//! of reading the whole log.  `tfh log-index` writes indexes for existing logs.
+//!
+//! An index starts with the magic bytes `TFHI`, then a big-endian u32 format version.  Then for
+//! each record of the log, in order, 24 bytes:
+//!
+//!  - offset: u64, where the record starts in the log
+//!  - time: u64, as in the record
+//!  - len: u32, the record's length in bytes
+//!  - the message's major opcode, minor opcode, and direction, and a zero byte
+//!
+//! The index is written through a buffer, so it can lag behind its log, and after a crash it can
+//! end partway through an entry.  `load` leaves out the entries that don't fit in the log, and
+//! readers read the log on from where the last entry ends (see `end`).
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use crate::bytes::Bytes;
+use crate::tfh_stream::MessageHeader;
+use crate::tfhlog;
+
+
+pub const MAGIC: [u8; 4] = *b"TFHI";
+pub const VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 24;
+
+/// Path of the index of the log at `log`.
+pub fn path(log: &str) -> String {
+    format!("{}.idx", log)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub offset: u64,
+    /// Microseconds since the Unix epoch.
+    pub time: u64,
+    pub len: u32,
+    pub major: u8,
+    pub minor: u8,
+    pub dir: u8,
+}
+
+impl Entry {
+    /// The entry of a record that starts at `offset` and ends at `end`.
+    pub fn new(offset: u64, end: u64, time: u64, header: &MessageHeader) -> Entry {
+        Entry {
+            offset,
+            time,
+            len: (end - offset) as u32,
+            major: header.major,
+            minor: header.minor,
+            dir: header.dir,
+        }
+    }
+
+    /// Offset of the end of the record.
+    pub fn end(&self) -> u64 {
+        self.offset + self.len as u64
+    }
+
+    fn as_bytes(&self) -> [u8; ENTRY_LEN] {
+        let mut buf = [0; ENTRY_LEN];
+        buf[0 .. 8].copy_from_slice(&self.offset.to_be_bytes());
+        buf[8 .. 16].copy_from_slice(&self.time.to_be_bytes());
+        buf[16 .. 20].copy_from_slice(&self.len.to_be_bytes());
+        buf[20] = self.major;
+        buf[21] = self.minor;
+        buf[22] = self.dir;
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; ENTRY_LEN]) -> Entry {
+        Entry {
+            offset: buf.u64_be(0),
+            time: buf.u64_be(8),
+            len: buf.u32_be(16),
+            major: buf[20],
+            minor: buf[21],
+            dir: buf[22],
+        }
+    }
+}
+
+pub struct Writer<W> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a new index, writing its header to `w`.
+    pub fn new(mut w: W) -> io::Result<Writer<W>> {
+        let mut hdr = [0; HEADER_LEN];
+        hdr[0 .. 4].copy_from_slice(&MAGIC);
+        hdr[4 .. 8].copy_from_slice(&VERSION.to_be_bytes());
+        w.write_all(&hdr)?;
+        Ok(Writer { w })
+    }
+
+    pub fn write(&mut self, e: &Entry) -> io::Result<()> {
+        self.w.write_all(&e.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Read the index at `path` of a log `log_len` bytes long.  Entries past the end of the log, as
+/// left when a damaged log is cut short, are left out, as is an entry cut short.
+pub fn load(path: &str, log_len: u64) -> io::Result<Vec<Entry>> {
+    let data = fs::read(path)?;
+    check_header(&data)?;
+    Ok(data[HEADER_LEN ..].chunks_exact(ENTRY_LEN)
+        .map(|c| Entry::from_bytes(c.try_into().unwrap()))
+        .take_while(|e| e.end() <= log_len)
+        .collect())
+}
+
+/// Open the index at `path` of a log `log_len` bytes long to add to it, cutting off the entries
+/// `load` would leave out.  Only the entries a binary search lands on are read, so this is quick
+/// however long the log.  Returns the index and where to read the log on from, as `end` does.
+pub fn reopen(
+    path: &str,
+    log_len: u64,
+    first: u64,
+) -> io::Result<(Writer<BufWriter<File>>, u64)> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut hdr = [0; HEADER_LEN];
+    f.read_exact(&mut hdr)?;
+    check_header(&hdr)?;
+    let count = (f.metadata()?.len() - HEADER_LEN as u64) / ENTRY_LEN as u64;
+    let mut entry = |i: u64| -> io::Result<Entry> {
+        let mut buf = [0; ENTRY_LEN];
+        f.seek(SeekFrom::Start(HEADER_LEN as u64 + i * ENTRY_LEN as u64))?;
+        f.read_exact(&mut buf)?;
+        Ok(Entry::from_bytes(&buf))
+    };
+    // The entries that fit in the log come first, since they're in the order of the records.
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if entry(mid)?.end() <= log_len {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let end = if lo == 0 { first } else { entry(lo - 1)?.end() };
+    f.set_len(HEADER_LEN as u64 + lo * ENTRY_LEN as u64)?;
+    f.seek(SeekFrom::End(0))?;
+    Ok((Writer { w: BufWriter::new(f) }, end))
+}
+
+fn check_header(data: &[u8]) -> io::Result<()> {
+    if data.len() < HEADER_LEN || data[0 .. 4] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a tfhlog index"));
+    }
+    let version = data.u32_be(4);
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported index version {}", version),
+        ));
+    }
+    Ok(())
+}
+
+/// Where to read the log on from after the entries of its index: the end of the last entry, or
+/// `first` if there are none, which should be the start of the first record.
+pub fn end(entries: &[Entry], first: u64) -> u64 {
+    entries.last().map_or(first, |e| e.end())
+}
+
+/// Write the index of the log at `log` from scratch.  Returns the number of records.
+pub fn build(log: &str) -> io::Result<u64> {
+    let mut r = tfhlog::Reader::new(BufReader::new(File::open(log)?))?;
+    let mut w = Writer::new(BufWriter::new(File::create(path(log))?))?;
+    let mut count = 0;
+    loop {
+        let offset = r.position();
+        let rec = match r.read()? {
+            Some(x) => x,
+            None => break,
+        };
+        w.write(&Entry::new(offset, r.position(), rec.time, &rec.msg.header))?;
+        count += 1;
+    }
+    w.flush()?;
+    Ok(count)
+}
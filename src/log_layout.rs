@@ -0,0 +1,126 @@
+//! How the processing handler divides each session's messages between log files.  By default
+//! they all go in one tfhlog per session.  A log class (`--log-class NAME=OPCODES`) moves the
+//! messages with those opcodes to a file of their own next to it, named after the class, so
+//! frequent opcodes like position updates and keepalives can be kept apart from the rare
+//! messages worth keeping, or deleted sooner.  `--log-skip OPCODES` leaves opcodes out of the
+//! logs entirely, and `--log-sample OPCODES=N` logs only one in `N` of them, counting
+//! separately for each session, direction, and opcode.  The rates are recorded in the header of
+//! each log, so tools that count messages can scale the counts back up.
+//!
+//! `--log-max-bytes SIZE` and `--log-max-messages N` cap how much of each session is logged in
+//! full, so one misbehaving connection can't fill the disk.  Past either cap, the session's
+//! messages are still logged, but with only their headers (see `tfhlog`).  `SIZE` is in bytes,
+//! or with a suffix of `K`, `M`, or `G` for powers of 1024, and counts the whole records.
+//!
+//! Opcodes are listed as in `opcodes`: `MAJOR:MINOR`, or `MAJOR` for every minor, in hex and
+//! separated by commas.  Skipping is checked first, and then the classes in the order they were
+//! given, so a message goes to the first class that lists its opcode.
+use crate::Error;
+use crate::opcodes;
+use crate::tfh_stream::Message;
+
+
+/// A major opcode, and the minor opcode if only that one is meant.
+pub type OpcodeSpec = (u8, Option<u8>);
+
+/// Parse a comma-separated list of opcodes, like `20:01,20:02,30`.
+pub fn parse_opcode_list(s: &str) -> Result<Vec<OpcodeSpec>, Error> {
+    s.split(',').map(|x| opcodes::parse_opcode(x).map_err(Error)).collect()
+}
+
+fn spec_matches((major, minor): OpcodeSpec, msg: &Message) -> bool {
+    major == msg.header.major && minor.map_or(true, |m| m == msg.header.minor)
+}
+
+pub fn list_matches(list: &[OpcodeSpec], msg: &Message) -> bool {
+    list.iter().any(|&spec| spec_matches(spec, msg))
+}
+
+/// The rate of the first entry in `sampling` that covers `msg`, or 1 if none does.
+pub fn sample_rate(sampling: &[(OpcodeSpec, u32)], msg: &Message) -> u32 {
+    sampling.iter().find(|&&(spec, _)| spec_matches(spec, msg)).map_or(1, |&(_, n)| n)
+}
+
+/// Parse a size in bytes, with an optional suffix of `K`, `M`, or `G`.
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 10),
+        Some((i, 'M')) => (&s[..i], 20),
+        Some((i, 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    let n = num.parse::<u64>().map_err(|e| Error(format!("{:?}: {}", s, e)))?;
+    n.checked_mul(1u64 << shift).ok_or_else(|| Error(format!("{:?}: too large", s)))
+}
+
+/// Which file a message should be logged to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dest {
+    Skip,
+    /// The session's main log.
+    Main,
+    /// The file of the class at this index in `LogLayout::classes`.
+    Class(usize),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LogLayout {
+    pub classes: Vec<(String, Vec<OpcodeSpec>)>,
+    pub skip: Vec<OpcodeSpec>,
+    /// Opcodes to log one in so many of.  The first entry that covers an opcode applies.
+    pub sampling: Vec<(OpcodeSpec, u32)>,
+    /// Bytes of records to log in full for each session before logging only headers.
+    pub max_bytes: Option<u64>,
+    /// Messages to log in full for each session before logging only headers.
+    pub max_messages: Option<u64>,
+    /// Write an index next to each log (see `log_index`).
+    pub index: bool,
+}
+
+impl LogLayout {
+    /// Parse a `--log-class` value, `NAME=OPCODES`, and add the class.  The name becomes part of
+    /// the filename, so it's limited to letters, digits, `_`, and `-`.
+    pub fn add_class(&mut self, s: &str) -> Result<(), Error> {
+        let i = s.find('=').ok_or_else(|| Error(format!("expected NAME=OPCODES, got {:?}", s)))?;
+        let name = &s[..i];
+        let name_ok = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if name.len() == 0 || !name.chars().all(name_ok) {
+            return Err(Error(format!("bad class name {:?}", name)));
+        }
+        if self.classes.iter().any(|(n, _)| n == name) {
+            return Err(Error(format!("class {:?} is given twice", name)));
+        }
+        self.classes.push((name.to_owned(), parse_opcode_list(&s[i + 1 ..])?));
+        Ok(())
+    }
+
+    /// Parse a `--log-sample` value, `OPCODES=N`, and sample those opcodes at one in `N`.
+    pub fn add_sampling(&mut self, s: &str) -> Result<(), Error> {
+        let i = s.find('=').ok_or_else(|| Error(format!("expected OPCODES=N, got {:?}", s)))?;
+        let rate = s[i + 1 ..].parse().map_err(|e| Error(format!("{:?}: {}", &s[i + 1 ..], e)))?;
+        if rate == 0 {
+            return Err("rate must be at least 1".into());
+        }
+        for spec in parse_opcode_list(&s[..i])? {
+            self.sampling.push((spec, rate));
+        }
+        Ok(())
+    }
+
+    /// Whether a session that has logged `messages` messages in full, in `bytes` bytes, has
+    /// reached a cap.
+    pub fn capped(&self, bytes: u64, messages: u64) -> bool {
+        self.max_bytes.map_or(false, |max| bytes >= max) ||
+            self.max_messages.map_or(false, |max| messages >= max)
+    }
+
+    pub fn dest(&self, msg: &Message) -> Dest {
+        if list_matches(&self.skip, msg) {
+            return Dest::Skip;
+        }
+        match self.classes.iter().position(|(_, list)| list_matches(list, msg)) {
+            Some(i) => Dest::Class(i),
+            None => Dest::Main,
+        }
+    }
+}
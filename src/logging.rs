@@ -0,0 +1,141 @@
+//! Console output levels, set separately for each part of the relay.
+//!
+//! Messages are written with the `log!` macro, which names the subsystem and level and takes
+//! `eprintln!`-style arguments.  Everything up to `Info` is shown by default.  The macro starts
+//! errors with `error: ` and warnings with `warning: `, so messages don't repeat their level.
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use crate::Error;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    /// Anomalies found while decoding TFH streams.
+    Stream = 0,
+    /// Reading and writing packets, proxying, queues, and traffic stats.
+    Relay = 1,
+    /// Per-connection events and the outputs fed from decoded messages: logs, status, WebSocket,
+    /// ZeroMQ, and so on.
+    Handler = 2,
+}
+
+pub const SUBSYSTEMS: [Subsystem; 3] = [Subsystem::Stream, Subsystem::Relay, Subsystem::Handler];
+
+pub const DEFAULT_LEVEL: Level = Level::Info;
+
+static LEVELS: [AtomicU8; 3] = [
+    AtomicU8::new(DEFAULT_LEVEL as u8),
+    AtomicU8::new(DEFAULT_LEVEL as u8),
+    AtomicU8::new(DEFAULT_LEVEL as u8),
+];
+
+impl Level {
+    fn from_u8(x: u8) -> Level {
+        match x {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    /// What `log!` puts before messages at this level.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Level::Error => "error: ",
+            Level::Warn => "warning: ",
+            _ => "",
+        }
+    }
+
+    /// `DEFAULT_LEVEL` adjusted by `n` steps, where positive is more verbose.
+    pub fn from_verbosity(n: i32) -> Level {
+        let x = (DEFAULT_LEVEL as i32 + n).max(Level::Off as i32).min(Level::Debug as i32);
+        Level::from_u8(x as u8)
+    }
+}
+
+impl FromStr for Level {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Level, Error> {
+        match s {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(Error(format!("unknown log level {:?}", s))),
+        }
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Subsystem, Error> {
+        match s {
+            "stream" => Ok(Subsystem::Stream),
+            "relay" => Ok(Subsystem::Relay),
+            "handler" => Ok(Subsystem::Handler),
+            _ => Err(Error(format!("unknown log subsystem {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+        fmt.write_str(s)
+    }
+}
+
+/// Parse a list of levels like `stream=error,relay=debug`.
+pub fn parse_levels(s: &str) -> Result<Vec<(Subsystem, Level)>, Error> {
+    s.split(',').filter(|x| x.len() > 0).map(|item| {
+        let i = item.find('=')
+            .ok_or_else(|| Error(format!("expected subsystem=level, but got {:?}", item)))?;
+        Ok((item[..i].parse()?, item[i + 1 ..].parse()?))
+    }).collect()
+}
+
+pub fn set_level(sub: Subsystem, level: Level) {
+    LEVELS[sub as usize].store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level(sub: Subsystem) -> Level {
+    Level::from_u8(LEVELS[sub as usize].load(Ordering::Relaxed))
+}
+
+pub fn enabled(sub: Subsystem, level: Level) -> bool {
+    level as u8 <= LEVELS[sub as usize].load(Ordering::Relaxed)
+}
+
+/// Print a message to stderr if `$level` is enabled for `$sub`, for example
+/// `log!(Relay, Warn, "dropping packet: {}", e)`.
+#[macro_export]
+macro_rules! log {
+    ($sub:ident, $level:ident, $($arg:tt)+) => {
+        if $crate::logging::enabled(
+            $crate::logging::Subsystem::$sub,
+            $crate::logging::Level::$level,
+        ) {
+            eprintln!("{}{}", $crate::logging::Level::$level.prefix(), format_args!($($arg)+));
+        }
+    };
+}
@@ -0,0 +1,103 @@
+//! Match records assembled from the server's match start and end notices, written as JSON lines.
+//!
+//! The server sends each participant its own `MatchStart` and `MatchEnd`, so a match's players
+//! are the connections that got a start notice with its ID, and its winners are those whose end
+//! notice says so.  The record is written once every player has an end notice, or has
+//! disconnected.  A match where nobody got an end notice is written with `"complete": false`.
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use crate::tfh_stream::ConnTuple;
+use crate::util::json;
+
+
+struct Match {
+    start_us: u64,
+    /// Time of the last end notice, or 0 if there hasn't been one.
+    end_us: u64,
+    players: Vec<(ConnTuple, String)>,
+    /// Players still in the match.
+    pending: HashSet<ConnTuple>,
+    winners: Vec<String>,
+}
+
+pub struct MatchTracker {
+    out: File,
+    active: HashMap<u32, Match>,
+}
+
+impl MatchTracker {
+    /// Append records to the file at `path`.
+    pub fn new(path: &str) -> io::Result<MatchTracker> {
+        Ok(MatchTracker {
+            out: OpenOptions::new().create(true).append(true).open(path)?,
+            active: HashMap::new(),
+        })
+    }
+
+    /// Record that the player on `ct` is in match `id`.  `name` is their login name, if known.
+    pub fn start(&mut self, time_us: u64, id: u32, ct: ConnTuple, name: Option<&str>) {
+        let m = self.active.entry(id).or_insert_with(|| Match {
+            start_us: time_us,
+            end_us: 0,
+            players: Vec::new(),
+            pending: HashSet::new(),
+            winners: Vec::new(),
+        });
+        if m.pending.insert(ct) {
+            let name = name.map_or_else(|| ct.to_string(), |s| s.to_owned());
+            m.players.push((ct, name));
+        }
+    }
+
+    /// Record the end of match `id` for the player on `ct`.
+    pub fn end(&mut self, time_us: u64, id: u32, ct: ConnTuple, won: bool) -> io::Result<()> {
+        let m = match self.active.get_mut(&id) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if !m.pending.remove(&ct) {
+            return Ok(());
+        }
+        m.end_us = time_us;
+        if won {
+            let name = m.players.iter().find(|&&(c, _)| c == ct).map(|&(_, ref n)| n.clone());
+            m.winners.extend(name);
+        }
+        if m.pending.is_empty() {
+            let m = self.active.remove(&id).unwrap();
+            self.write(id, &m)?;
+        }
+        Ok(())
+    }
+
+    /// The player on `ct` is gone, so stop waiting for its end notices.
+    pub fn disconnect(&mut self, ct: ConnTuple) -> io::Result<()> {
+        let mut done = Vec::new();
+        for (&id, m) in &mut self.active {
+            if m.pending.remove(&ct) && m.pending.is_empty() {
+                done.push(id);
+            }
+        }
+        for id in done {
+            let m = self.active.remove(&id).unwrap();
+            self.write(id, &m)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, id: u32, m: &Match) -> io::Result<()> {
+        let complete = m.end_us != 0;
+        let mut obj = json::Object::new();
+        obj.num("match_id", id)
+            .num("start_us", m.start_us)
+            .bool("complete", complete);
+        if complete {
+            obj.num("end_us", m.end_us)
+                .num("duration_secs", m.end_us.saturating_sub(m.start_us) / 1_000_000);
+        }
+        obj.raw("players", &json::str_array(m.players.iter().map(|&(_, ref n)| n as &str)))
+            .raw("winners", &json::str_array(m.winners.iter().map(|s| s as &str)));
+        writeln!(self.out, "{}", obj.finish())
+    }
+}
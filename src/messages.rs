@@ -0,0 +1,253 @@
+//! Typed decoding of the message bodies whose layout is (at least partly) understood.
+//!
+//! `decode` picks the right parser from the message's opcodes and direction.  Anything not
+//! covered here is left as raw bytes in `Message::body`.
+use crate::bytes::Bytes;
+use crate::tfh_stream::{Message, MessageHeader};
+
+
+/// Major opcode of the client's login message.
+pub const MAJOR_LOGIN: u8 = 0x0a;
+
+/// Major opcode of lobby chat sent by the client.  This hasn't been confirmed against enough
+/// captures yet, which is why the chat log lets it be overridden with `--chat-major`.
+pub const MAJOR_CHAT: u8 = 0x14;
+
+/// Major opcode of chat and announcements sent by the server, used for injected announcements.
+/// This is a guess, like `MAJOR_CHAT`, so the `say` control command lets it be overridden.
+pub const MAJOR_ANNOUNCE: u8 = 0x15;
+
+/// Major opcode of in-game events, which are told apart by minor opcode.
+pub const MAJOR_GAME: u8 = 0x20;
+
+/// Minor opcodes of the server's match start and end notices.  Like `MAJOR_CHAT`, these are
+/// provisional.
+pub const MINOR_MATCH_START: u8 = 0x05;
+pub const MINOR_MATCH_END: u8 = 0x06;
+/// Minor opcode of the server telling a client its player's current rating.  Also provisional.
+pub const MINOR_RATING: u8 = 0x07;
+
+/// Major opcode of the lobby roster and the presence updates that follow it, sent by the
+/// server.  Provisional, like `MAJOR_CHAT`.
+pub const MAJOR_LOBBY: u8 = 0x16;
+
+/// Minor opcodes of the full roster, and of a player joining or leaving the lobby.  Also
+/// provisional.
+pub const MINOR_ROSTER: u8 = 0x00;
+pub const MINOR_JOINED: u8 = 0x01;
+pub const MINOR_LEFT: u8 = 0x02;
+
+/// A message body decoded into one of the types in this module.
+#[derive(Clone, Debug)]
+pub enum Known {
+    Login(Login),
+    Chat(Chat),
+    MatchStart(MatchStart),
+    MatchEnd(MatchEnd),
+    Rating(Rating),
+    Roster(Roster),
+    Presence(Presence),
+}
+
+/// Decode `msg`, if it's one of the known kinds.  Chat is taken to use `MAJOR_CHAT`.
+pub fn decode(msg: &Message) -> Option<Known> {
+    let body = &msg.body;
+    match (msg.header.dir, msg.header.major, msg.header.minor) {
+        (0, MAJOR_LOGIN, _) => Login::parse(body).map(Known::Login),
+        (0, MAJOR_CHAT, _) => Some(Known::Chat(Chat::parse(body))),
+        (1, MAJOR_GAME, MINOR_MATCH_START) => MatchStart::parse(body).map(Known::MatchStart),
+        (1, MAJOR_GAME, MINOR_MATCH_END) => MatchEnd::parse(body).map(Known::MatchEnd),
+        (1, MAJOR_GAME, MINOR_RATING) => Rating::parse(body).map(Known::Rating),
+        (1, MAJOR_LOBBY, MINOR_ROSTER) => Some(Known::Roster(Roster::parse(body))),
+        (1, MAJOR_LOBBY, MINOR_JOINED) => Presence::parse(body, true).map(Known::Presence),
+        (1, MAJOR_LOBBY, MINOR_LEFT) => Presence::parse(body, false).map(Known::Presence),
+        _ => None,
+    }
+}
+
+/// Decode a NUL-padded string field.  Invalid UTF-8 is replaced rather than rejected, since
+/// player-chosen names sometimes aren't valid.
+pub fn nul_padded_str(b: &[u8]) -> String {
+    let len = b.iter().position(|&x| x == 0).unwrap_or(b.len());
+    String::from_utf8_lossy(&b[..len]).into_owned()
+}
+
+/// The client's login message (major 0x0a, client to server).  Only the name is known for sure;
+/// the meanings of the first 12 bytes are guesses based on their size and how they vary between
+/// accounts and game updates.
+#[derive(Clone, Debug)]
+pub struct Login {
+    /// Bytes 0..8, little-endian.  Constant per account, so probably an account or Steam ID.
+    pub account_id: u64,
+    /// Bytes 8..12, little-endian.  Probably the client's build or protocol version.
+    pub version: u32,
+    /// Bytes 12..76, the player's display name, NUL-padded.
+    pub name: String,
+    /// Everything after the name, undecoded.
+    pub rest: Box<[u8]>,
+}
+
+impl Login {
+    pub const NAME_OFFSET: usize = 12;
+    pub const NAME_LEN: usize = 64;
+
+    pub fn matches(msg: &Message) -> bool {
+        msg.header.dir == 0 && msg.header.major == MAJOR_LOGIN
+    }
+
+    /// Parse a login message body.  Returns `None` if it's too short to hold the name.
+    pub fn parse(body: &[u8]) -> Option<Login> {
+        let name_end = Login::NAME_OFFSET + Login::NAME_LEN;
+        let name = body.get(Login::NAME_OFFSET .. name_end)?;
+        Some(Login {
+            account_id: body.u64_le(0),
+            version: body.u32_le(8),
+            name: nul_padded_str(name),
+            rest: body[name_end..].into(),
+        })
+    }
+}
+
+/// A chat line sent by the client.  The sender isn't included; it's whoever logged in on the
+/// same connection.
+#[derive(Clone, Debug)]
+pub struct Chat {
+    pub text: String,
+}
+
+impl Chat {
+    /// Is `msg` a chat message, if chat uses major opcode `major`?
+    pub fn matches(msg: &Message, major: u8) -> bool {
+        msg.header.dir == 0 && msg.header.major == major
+    }
+
+    /// Parse a chat message body, which is the text, NUL-padded.
+    pub fn parse(body: &[u8]) -> Chat {
+        Chat { text: nul_padded_str(body) }
+    }
+}
+
+/// A line of text from the server, shown to the player like chat.
+#[derive(Clone, Debug)]
+pub struct Announce {
+    pub text: String,
+}
+
+impl Announce {
+    /// Build a server-to-client message with major opcode `major`, whose body is the text,
+    /// NUL-terminated.
+    pub fn to_message(&self, major: u8) -> Message {
+        let mut body = self.text.as_bytes().to_owned();
+        body.push(0);
+        Message {
+            header: MessageHeader {
+                major,
+                minor: 0,
+                dir: 1,
+                ack: 0,
+                len: body.len() as u32,
+            },
+            body: body.into_boxed_slice(),
+            time: 0,
+            index: 0,
+            acks: 0 .. 0,
+        }
+    }
+}
+
+/// The server telling a client that its match has started.  Each participant gets one, with the
+/// same match ID, so the players are found from the connections the notice goes to.
+#[derive(Clone, Debug)]
+pub struct MatchStart {
+    /// Bytes 0..4, little-endian.
+    pub match_id: u32,
+}
+
+impl MatchStart {
+    pub fn parse(body: &[u8]) -> Option<MatchStart> {
+        if body.len() < 4 {
+            return None;
+        }
+        Some(MatchStart { match_id: body.u32_le(0) })
+    }
+}
+
+/// The server telling a client that its match is over, and whether that client's player won.
+#[derive(Clone, Debug)]
+pub struct MatchEnd {
+    /// Bytes 0..4, little-endian.  Matches the `MatchStart`.
+    pub match_id: u32,
+    /// Byte 4, nonzero for the winner.
+    pub won: bool,
+}
+
+impl MatchEnd {
+    pub fn parse(body: &[u8]) -> Option<MatchEnd> {
+        if body.len() < 5 {
+            return None;
+        }
+        Some(MatchEnd { match_id: body.u32_le(0), won: body[4] != 0 })
+    }
+}
+
+/// The server telling a client its player's rating, after login and after each ranked match.
+#[derive(Clone, Debug)]
+pub struct Rating {
+    /// Bytes 0..4, little-endian.
+    pub rating: u32,
+}
+
+impl Rating {
+    pub fn parse(body: &[u8]) -> Option<Rating> {
+        if body.len() < 4 {
+            return None;
+        }
+        Some(Rating { rating: body.u32_le(0) })
+    }
+}
+
+/// The players in the lobby, sent by the server to each client after login.  The layout is a
+/// guess: a run of name fields like the login's, NUL-padded to `Login::NAME_LEN` bytes each.
+#[derive(Clone, Debug)]
+pub struct Roster {
+    pub names: Vec<String>,
+}
+
+impl Roster {
+    pub fn matches(msg: &Message) -> bool {
+        msg.header.dir == 1 && msg.header.major == MAJOR_LOBBY && msg.header.minor == MINOR_ROSTER
+    }
+
+    /// Parse a roster body.  Empty fields are skipped, and so is a partial field at the end.
+    pub fn parse(body: &[u8]) -> Roster {
+        let names = body.chunks_exact(Login::NAME_LEN)
+            .map(nul_padded_str)
+            .filter(|name| !name.is_empty())
+            .collect();
+        Roster { names }
+    }
+}
+
+/// A player joining or leaving the lobby after the roster was sent.  The body is one name field,
+/// laid out as in `Roster`.
+#[derive(Clone, Debug)]
+pub struct Presence {
+    pub name: String,
+    pub joined: bool,
+}
+
+impl Presence {
+    pub fn matches(msg: &Message) -> bool {
+        let minor = msg.header.minor;
+        msg.header.dir == 1 && msg.header.major == MAJOR_LOBBY
+            && (minor == MINOR_JOINED || minor == MINOR_LEFT)
+    }
+
+    pub fn parse(body: &[u8], joined: bool) -> Option<Presence> {
+        let name = nul_padded_str(body.get(.. Login::NAME_LEN)?);
+        if name.is_empty() {
+            return None;
+        }
+        Some(Presence { name, joined })
+    }
+}
@@ -0,0 +1,352 @@
+//! Names and field layouts for opcodes, from a text file kept up to date while reverse
+//! engineering.  With `--opcodes FILE`, the relay adds them to the JSON it sends to WebSocket
+//! clients and control socket subscribers, and reloads the file whenever it changes, so newly
+//! named opcodes and fields show up without restarting the relay in the middle of a capture.
+//!
+//! Each unindented line names an opcode, as `MAJOR:MINOR NAME`, or `MAJOR NAME` to cover every
+//! minor opcode of that major, with opcodes in hex.  The indented lines after it describe fields
+//! of the body, as `OFFSET TYPE NAME`.  `TYPE` is `u8`, `u16`, `u32`, or `u64` (little-endian,
+//! like the fields in `messages`), `str:LEN` for NUL-padded text, or `hex:LEN` for raw bytes.
+//! `#` starts a comment.
+//!
+//! Game updates sometimes move fields around, so layouts can be given for particular versions
+//! of the client, as reported in its login message (see `messages::Login`).  A `version N` or
+//! `version N-M` line starts a section whose opcodes apply only to connections that logged in
+//! with a version in that range; each replaces the entry for the same opcode above the first
+//! section, and opcodes a section doesn't list keep those entries.  Connections whose version
+//! isn't known, or isn't in any section, use the entries above the first section.  For example:
+//!
+//! ```text
+//! 0a login
+//!     0 u64 account_id
+//!     8 u32 version
+//!     12 str:64 name
+//! 20:05 match_start
+//!     0 u32 match_id
+//!
+//! version 1200-1299
+//! 20:05 match_start
+//!     4 u32 match_id
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::{Error, ErrorAt};
+use crate::bytes::Bytes;
+use crate::messages;
+use crate::tfh_stream::Message;
+use crate::util::json;
+
+
+/// How often to check whether the file has changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    U8,
+    U16,
+    U32,
+    U64,
+    Str(usize),
+    Hex(usize),
+}
+
+impl FieldType {
+    /// Number of body bytes the field takes.
+    pub fn size(self) -> usize {
+        match self {
+            FieldType::U8 => 1,
+            FieldType::U16 => 2,
+            FieldType::U32 => 4,
+            FieldType::U64 => 8,
+            FieldType::Str(n) | FieldType::Hex(n) => n,
+        }
+    }
+
+    pub fn is_integer(self) -> bool {
+        match self {
+            FieldType::U8 | FieldType::U16 | FieldType::U32 | FieldType::U64 => true,
+            FieldType::Str(_) | FieldType::Hex(_) => false,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<FieldType, String> {
+        let len = |n: &str| n.parse().map_err(|e| format!("{}: {}", s, e));
+        Ok(match s {
+            "u8" => FieldType::U8,
+            "u16" => FieldType::U16,
+            "u32" => FieldType::U32,
+            "u64" => FieldType::U64,
+            _ if s.starts_with("str:") => FieldType::Str(len(&s[4..])?),
+            _ if s.starts_with("hex:") => FieldType::Hex(len(&s[4..])?),
+            _ => return Err(format!("unknown field type {:?}", s)),
+        })
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FieldType::U8 => write!(fmt, "u8"),
+            FieldType::U16 => write!(fmt, "u16"),
+            FieldType::U32 => write!(fmt, "u32"),
+            FieldType::U64 => write!(fmt, "u64"),
+            FieldType::Str(n) => write!(fmt, "str:{}", n),
+            FieldType::Hex(n) => write!(fmt, "hex:{}", n),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub offset: usize,
+    pub ty: FieldType,
+    pub name: String,
+}
+
+impl Field {
+    /// The value of an integer field in `body`, or `None` if it's text or bytes or doesn't fit.
+    pub fn uint(&self, body: &[u8]) -> Option<u64> {
+        let b = body.get(self.offset .. self.offset + self.ty.size())?;
+        match self.ty {
+            FieldType::U8 => Some(b[0] as u64),
+            FieldType::U16 => Some(b.u16_le(0) as u64),
+            FieldType::U32 => Some(b.u32_le(0) as u64),
+            FieldType::U64 => Some(b.u64_le(0)),
+            FieldType::Str(_) | FieldType::Hex(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Opcode {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    /// Keyed by major and minor opcode.  A minor of `None` covers the whole major.
+    opcodes: HashMap<(u8, Option<u8>), Opcode>,
+    /// For a version section, its first and last version.
+    range: Option<(u32, u32)>,
+    /// The version sections, each complete with the entries it doesn't replace.
+    versions: Vec<Registry>,
+}
+
+impl Registry {
+    pub fn parse(s: &str) -> Result<Registry, Error> {
+        let mut reg = Registry::default();
+        let mut sections = Vec::<Registry>::new();
+        let mut cur = None;
+        for (i, line) in s.lines().enumerate() {
+            let err = |e: String| Error(format!("line {}: {}", i + 1, e));
+            let text = line.find('#').map_or(line, |j| &line[..j]);
+            let words = text.split_whitespace().collect::<Vec<_>>();
+            if words.len() == 0 {
+                continue;
+            }
+
+            if !line.starts_with(char::is_whitespace) && words[0] == "version" {
+                if words.len() != 2 {
+                    return Err(err("expected version N or version N-M".into()));
+                }
+                let (first, last) = parse_version_range(words[1]).map_err(err)?;
+                if let Some(s) = sections.iter().find(|s| {
+                    let (a, b) = s.range.unwrap();
+                    first <= b && a <= last
+                }) {
+                    let (a, b) = s.range.unwrap();
+                    return Err(err(format!("{} overlaps versions {}-{}", words[1], a, b)));
+                }
+                sections.push(Registry { range: Some((first, last)), ..Registry::default() });
+                cur = None;
+                continue;
+            }
+            let target = sections.last_mut().unwrap_or(&mut reg);
+
+            if !line.starts_with(char::is_whitespace) {
+                if words.len() != 2 {
+                    return Err(err("expected MAJOR[:MINOR] NAME".into()));
+                }
+                let key = parse_opcode(words[0]).map_err(err)?;
+                if target.opcodes.contains_key(&key) {
+                    return Err(err(format!("{} is listed twice", words[0])));
+                }
+                let op = Opcode { name: words[1].to_owned(), fields: Vec::new() };
+                target.opcodes.insert(key, op);
+                cur = Some(key);
+                continue;
+            }
+
+            let key = cur.ok_or_else(|| err("field comes before any opcode".into()))?;
+            if words.len() != 3 {
+                return Err(err("expected OFFSET TYPE NAME".into()));
+            }
+            let offset = words[0].parse().map_err(|e| err(format!("{}: {}", words[0], e)))?;
+            let ty = FieldType::parse(words[1]).map_err(err)?;
+            let op = target.opcodes.get_mut(&key).unwrap();
+            op.fields.push(Field { offset, ty, name: words[2].to_owned() });
+        }
+
+        for section in &mut sections {
+            for (key, op) in &reg.opcodes {
+                section.opcodes.entry(*key).or_insert_with(|| op.clone());
+            }
+        }
+        reg.versions = sections;
+        Ok(reg)
+    }
+
+    pub fn load(path: &str) -> Result<Registry, Error> {
+        let s = fs::read_to_string(path).at(path)?;
+        Registry::parse(&s).map_err(|e| Error(format!("{}: {}", path, e)))
+    }
+
+    /// The layouts for connections that logged in with client version `version`.
+    pub fn for_version(&self, version: Option<u32>) -> &Registry {
+        version.and_then(|v| {
+            self.versions.iter().find(|s| s.range.map_or(false, |(a, b)| a <= v && v <= b))
+        }).unwrap_or(self)
+    }
+
+    /// Number of opcodes listed, not counting version sections.
+    pub fn len(&self) -> usize {
+        self.opcodes.len()
+    }
+
+    /// The first and last version of each version section.
+    pub fn version_ranges(&self) -> Vec<(u32, u32)> {
+        self.versions.iter().filter_map(|s| s.range).collect()
+    }
+
+    /// Look up an opcode, preferring an entry for its exact minor opcode over one for the whole
+    /// major.
+    pub fn get(&self, major: u8, minor: u8) -> Option<&Opcode> {
+        self.opcodes.get(&(major, Some(minor))).or_else(|| self.opcodes.get(&(major, None)))
+    }
+
+    /// Add `msg`'s opcode name to `obj`, along with a `fields` object holding the value of each
+    /// field that fits in its body.  Adds nothing for opcodes that aren't listed.  In a version
+    /// section, `layout` gives the section's versions.
+    pub fn write_json(&self, msg: &Message, obj: &mut json::Object) {
+        let op = match self.get(msg.header.major, msg.header.minor) {
+            Some(x) => x,
+            None => return,
+        };
+        obj.str("name", &op.name);
+        if let Some(range) = self.range {
+            obj.str("layout", &format_version_range(range));
+        }
+        if op.fields.len() == 0 {
+            return;
+        }
+
+        let mut fields = json::Object::new();
+        for f in &op.fields {
+            let b = match msg.body.get(f.offset .. f.offset + f.ty.size()) {
+                Some(x) => x,
+                None => continue,
+            };
+            match f.ty {
+                FieldType::U8 => fields.num(&f.name, b[0]),
+                FieldType::U16 => fields.num(&f.name, b.u16_le(0)),
+                FieldType::U32 => fields.num(&f.name, b.u32_le(0)),
+                FieldType::U64 => fields.num(&f.name, b.u64_le(0)),
+                FieldType::Str(_) => fields.str(&f.name, &messages::nul_padded_str(b)),
+                FieldType::Hex(_) => fields.hex(&f.name, b),
+            };
+        }
+        obj.raw("fields", &fields.finish());
+    }
+}
+
+/// Parse `MAJOR:MINOR` or `MAJOR`, in hex.
+pub fn parse_opcode(s: &str) -> Result<(u8, Option<u8>), String> {
+    let hex = |x: &str| u8::from_str_radix(x, 16).map_err(|e| format!("{}: {}", s, e));
+    match s.find(':') {
+        Some(i) => Ok((hex(&s[..i])?, Some(hex(&s[i + 1 ..])?))),
+        None => Ok((hex(s)?, None)),
+    }
+}
+
+/// Parse `N` or `N-M`, in decimal, as the first and last version.
+fn parse_version_range(s: &str) -> Result<(u32, u32), String> {
+    let num = |x: &str| x.parse::<u32>().map_err(|e| format!("{}: {}", s, e));
+    let (first, last) = match s.find('-') {
+        Some(i) => (num(&s[..i])?, num(&s[i + 1 ..])?),
+        None => (num(s)?, num(s)?),
+    };
+    if first > last {
+        return Err(format!("{}: empty version range", s));
+    }
+    Ok((first, last))
+}
+
+fn format_version_range((first, last): (u32, u32)) -> String {
+    if first == last { first.to_string() } else { format!("{}-{}", first, last) }
+}
+
+/// A registry loaded from a file and reloaded in the background when the file changes.  If the
+/// new contents don't parse, the error is logged and the previous registry stays in use, so a
+/// half-finished edit doesn't lose the names.
+#[derive(Clone)]
+pub struct Watched {
+    current: Arc<RwLock<Arc<Registry>>>,
+}
+
+impl Watched {
+    /// Load `path`, which must parse, and start watching it for changes.
+    pub fn start(path: &str) -> Result<Watched, Error> {
+        let mut stamp = file_stamp(path);
+        let reg = Registry::load(path)?;
+        log!(Handler, Info, "loaded {} opcodes from {}{}",
+            reg.len(), path, describe_versions(&reg));
+        let w = Watched { current: Arc::new(RwLock::new(Arc::new(reg))) };
+
+        let w2 = w.clone();
+        let path = path.to_owned();
+        thread::spawn(move || loop {
+            thread::sleep(RELOAD_INTERVAL);
+            let new_stamp = file_stamp(&path);
+            if new_stamp == stamp {
+                continue;
+            }
+            stamp = new_stamp;
+            match Registry::load(&path) {
+                Ok(reg) => {
+                    log!(Handler, Info, "reloaded {} opcodes from {}{}",
+                        reg.len(), path, describe_versions(&reg));
+                    *w2.current.write().unwrap() = Arc::new(reg);
+                },
+                Err(e) => log!(Handler, Warn, "keeping previous opcodes: {}", e),
+            }
+        });
+        Ok(w)
+    }
+
+    /// The registry as of the latest successful load.
+    pub fn get(&self) -> Arc<Registry> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// The version sections, for the log line on loading.
+fn describe_versions(reg: &Registry) -> String {
+    let ranges = reg.version_ranges();
+    if ranges.len() == 0 {
+        return String::new();
+    }
+    let ranges = ranges.into_iter().map(format_version_range).collect::<Vec<_>>();
+    format!(", with layouts for versions {}", ranges.join(", "))
+}
+
+/// Modification time and size, for noticing changes.  Editors that replace the file rather than
+/// rewriting it are caught too, since the new file has a new time.
+fn file_stamp(path: &str) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
@@ -0,0 +1,54 @@
+//! Pacing for packets written toward the lobby server.  Bursts from the relay, such as a
+//! backlog released after a stall, can overflow a constrained uplink and get dropped there.  A
+//! `Pacer` lets at most a fixed number of packets out per tick, and holds the rest for later
+//! ticks, so bursts go out spread over time instead of back to back.
+use std::time::{Duration, Instant};
+
+
+/// Default length of a tick, in microseconds.
+pub const DEFAULT_TICK: u64 = 1000;
+
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    per_tick: u32,
+    tick: Duration,
+    /// End of the current tick, or `None` before the first packet.
+    tick_end: Option<Instant>,
+    /// Packets sent in the current tick.
+    sent: u32,
+}
+
+impl Pacer {
+    /// A pacer letting out `per_tick` packets every `tick` microseconds.
+    pub fn new(per_tick: u32, tick: u64) -> Pacer {
+        Pacer {
+            per_tick,
+            tick: Duration::from_micros(tick),
+            tick_end: None,
+            sent: 0,
+        }
+    }
+
+    /// How long to wait before the next packet can be sent, or `None` if it can go now.  Call
+    /// `sent` once it's gone.
+    pub fn delay(&mut self, now: Instant) -> Option<Duration> {
+        // Ticks start with the first packet after the last one ended, so a quiet period doesn't
+        // build up credit for a burst.
+        let end = match self.tick_end {
+            Some(end) if now < end => end,
+            _ => {
+                self.tick_end = Some(now + self.tick);
+                self.sent = 0;
+                now + self.tick
+            },
+        };
+        if self.sent < self.per_tick {
+            return None;
+        }
+        Some(end - now)
+    }
+
+    pub fn sent(&mut self) {
+        self.sent += 1;
+    }
+}
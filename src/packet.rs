@@ -1,67 +1,59 @@
-use std::convert::TryInto;
-use std::fmt;
-use std::mem::{self, MaybeUninit};
-use std::ops::{Deref, DerefMut};
-use std::slice;
+use alloc::boxed::Box;
+use alloc::format;
+use core::cmp;
+use core::convert::TryInto;
+use core::fmt;
+use core::mem;
+use core::net::SocketAddrV4;
+use core::ops::{Deref, DerefMut, Range};
+use core::ptr;
+use core::slice;
+use crate::Error;
+use crate::arena::FrameRef;
 use crate::bytes::Bytes;
+use crate::sdr;
 
 
 /// Capacity in bytes of a Packet's data buffer.  The MTU of the tun device must not exceed this
 /// value; otherwise, data will be silently dropped.
 pub const PACKET_CAP: usize = 1500;
 
-struct PacketInner {
-    data: MaybeUninit<[u8; PACKET_CAP]>,
+/// An IP packet, in a frame from the `arena`.  Cloning a packet is cheap: the clone shares the
+/// frame, and whichever copy is modified first moves to a frame of its own.  The length and
+/// timestamp belong to each copy, so truncating or timestamping one doesn't touch the others.
+#[derive(Clone)]
+pub struct Packet {
+    frame: FrameRef,
     len: usize,
+    time: Option<u64>,
 }
 
-impl Clone for PacketInner {
-    fn clone(&self) -> PacketInner {
-        unsafe {
-            let mut pi = PacketInner::default();
-            for (i, &b) in self.as_slice().iter().enumerate() {
-                *pi.as_mut_ptr().add(i) = b;
-            }
-            pi.len = self.len;
-            pi
-        }
-    }
-}
-
-impl Default for PacketInner {
-    fn default() -> PacketInner {
-        PacketInner {
-            data: MaybeUninit::uninit(),
+impl Default for Packet {
+    fn default() -> Packet {
+        Packet {
+            frame: FrameRef::alloc(),
             len: 0,
+            time: None,
         }
     }
 }
 
-impl PacketInner {
-    pub fn len(&self) -> usize {
-        self.len
-    }
-
-    pub fn as_slice<'a>(&'a self) -> &'a [u8] {
-        unsafe { slice::from_raw_parts(self.data.as_ptr() as *const u8, self.len) }
-    }
-
-    pub fn as_mut_slice<'a>(&'a mut self) -> &'a mut [u8] {
-        unsafe { slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u8, self.len) }
-    }
-
-    pub fn as_ptr(&self) -> *const u8 {
-        self.data.as_ptr() as *const u8
-    }
+/// A read-only view of part of a packet, such as its UDP payload, that shares the packet's frame
+/// instead of copying the bytes out.
+#[derive(Clone)]
+pub struct PacketSlice {
+    frame: FrameRef,
+    start: usize,
+    end: usize,
+}
 
-    pub fn as_mut_ptr(&mut self) -> *mut u8 {
-        self.data.as_mut_ptr() as *mut u8
+impl Deref for PacketSlice {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.frame.as_ptr().add(self.start), self.end - self.start) }
     }
 }
 
-#[derive(Clone, Default)]
-pub struct Packet(Box<PacketInner>);
-
 macro_rules! define_header_accessors {
     ($Header:ty,
             $header:ident, $header_mut:ident,
@@ -107,12 +99,94 @@ impl Packet {
         p
     }
 
+    /// Build an IPv4 packet holding a UDP datagram from `src` to `dst`, so datagrams received on
+    /// an ordinary socket can be handled like packets read from a tun device.  Returns `None` if
+    /// the payload doesn't fit.
+    pub fn new_udp_ipv4(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Option<Packet> {
+        let mut p = Packet::zeroed(20 + 8);
+        {
+            let ip = Ipv4Header::new_mut(&mut p[.. 20]);
+            ip.set_version(4);
+            ip.set_ihl(5);
+            ip.set_flags(2);            // don't fragment
+            ip.set_ttl(64);
+            ip.set_protocol(17);        // UDP
+            ip.set_source_ip(u32::from(*src.ip()));
+            ip.set_dest_ip(u32::from(*dst.ip()));
+        }
+        {
+            let udp = p.udp_mut();
+            udp.set_source_port(src.port());
+            udp.set_dest_port(dst.port());
+        }
+        if !p.set_udp_payload(payload) {
+            return None;
+        }
+        Some(p)
+    }
+
+    /// Build a UDP packet carrying a TFH stream packet with the given sequence numbers, filling
+    /// in the header's other fields the way the game does for an established connection.
+    /// Returns `None` if `data` doesn't fit.
+    pub fn new_tfh_stream(
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        my_seq: u32,
+        your_seq: u32,
+        data: &[u8],
+    ) -> Option<Packet> {
+        let mut header = [0; TFH_STREAM_HEADER_LEN];
+        header.put_u8_be(0, 1);
+        header.put_u32_be(5, my_seq);
+        header.put_u32_be(9, your_seq);
+        header.put_u16_be(13, if my_seq == 0 { 2 } else { 0 });
+        header.put_u16_be(15, 0xea00);
+        let mut p = Packet::new_udp_ipv4(src, dst, &header)?;
+        if p.try_extend(data) != data.len() {
+            return None;
+        }
+        let (ip_len, udp_len) = (p.len(), p.len() - p.udp_start());
+        p.ipv4_mut().set_total_len(ip_len as u16);
+        p.udp_mut().set_len(udp_len as u16);
+        p.recompute_checksums();
+        Some(p)
+    }
+
+    /// Parse a whole packet written as hex digits, as `util::hex::encode` would print it.
+    /// Whitespace between digits is ignored.  The headers are taken as they are, checksums and
+    /// all.
+    pub fn from_hex(s: &str) -> Result<Packet, Error> {
+        let mut p = Packet::default();
+        let mut digits = s.chars().filter(|c| !c.is_whitespace());
+        while let Some(hi) = digits.next() {
+            let lo = digits.next().ok_or("odd number of hex digits")?;
+            let b = match (hi.to_digit(16), lo.to_digit(16)) {
+                (Some(hi), Some(lo)) => (hi * 16 + lo) as u8,
+                _ => return Err(Error(format!("invalid hex byte {}{}", hi, lo))),
+            };
+            if !p.try_push(b) {
+                return Err(Error(format!("packet is longer than {} bytes", PACKET_CAP)));
+            }
+        }
+        Ok(p)
+    }
+
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.len
     }
 
     pub unsafe fn set_len(&mut self, len: usize) {
-        self.0.len = len;
+        self.len = len;
+    }
+
+    /// Capture time in microseconds since the Unix epoch, for packets read from a capture file.
+    /// Live packets have no timestamp.
+    pub fn time(&self) -> Option<u64> {
+        self.time
+    }
+
+    pub fn set_time(&mut self, time: Option<u64>) {
+        self.time = time;
     }
 
     pub fn truncate(&mut self, len: usize) {
@@ -120,20 +194,49 @@ impl Packet {
         unsafe { self.set_len(len) };
     }
 
+    /// Append `byte`, if there's room for it.  Returns whether it fit.
+    pub fn try_push(&mut self, byte: u8) -> bool {
+        self.try_extend(&[byte]) == 1
+    }
+
+    /// Append as much of `data` as fits within `PACKET_CAP`, and return how many bytes that was.
+    /// Like `truncate`, this doesn't update the length fields or checksums in the headers.
+    pub fn try_extend(&mut self, data: &[u8]) -> usize {
+        let len = self.len();
+        let n = cmp::min(data.len(), PACKET_CAP - len);
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.as_mut_ptr().add(len), n);
+            self.set_len(len + n);
+        }
+        n
+    }
+
     pub fn as_slice(&self) -> &[u8] {
-        self.0.as_slice()
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
     }
 
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        self.0.as_mut_slice()
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
     }
 
     pub fn as_ptr(&self) -> *const u8 {
-        self.0.as_ptr()
+        self.frame.as_ptr()
     }
 
+    /// Pointer to the start of the frame, which has room for `PACKET_CAP` bytes.  If the frame is
+    /// shared with clones of this packet, the packet first moves to a frame of its own.
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
-        self.0.as_mut_ptr()
+        self.frame.make_mut(self.len)
+    }
+
+    /// Share the bytes in `range` without copying them.
+    pub fn slice(&self, range: Range<usize>) -> PacketSlice {
+        assert!(range.start <= range.end && range.end <= self.len);
+        PacketSlice {
+            frame: self.frame.clone(),
+            start: range.start,
+            end: range.end,
+        }
     }
 
 
@@ -172,6 +275,13 @@ impl Packet {
         !acc
     }
 
+    /// Set the IPv4 header checksum to match the rest of the header.  Call this after changing
+    /// any header field, such as an address or the total length.
+    pub fn update_ipv4_checksum(&mut self) {
+        let checksum = self.compute_ipv4_checksum();
+        self.ipv4_mut().set_checksum(checksum);
+    }
+
 
     pub fn is_ipv6(&self) -> bool {
         Ipv4Header::new(&self).version() == 6
@@ -244,11 +354,81 @@ impl Packet {
         }
     }
 
+    /// Set the UDP checksum to match the current pseudo-header, UDP header, and payload.  A zero
+    /// checksum would mean the sender didn't compute one, so a sum that works out to zero is
+    /// written as `0xffff` instead, as RFC 768 says.  The packet must be UDP over IPv4.
     pub fn update_udp_checksum(&mut self) {
         let checksum = self.compute_udp_checksum(self.udp_payload());
         self.udp_mut().set_checksum(checksum);
     }
 
+    /// Replace the UDP payload with `payload`, updating the lengths and checksums in the IPv4
+    /// and UDP headers to match.  Returns `false`, leaving the packet unchanged, if the new
+    /// payload doesn't fit.
+    pub fn set_udp_payload(&mut self, payload: &[u8]) -> bool {
+        let start = self.udp_end();
+        if start + payload.len() > PACKET_CAP {
+            return false;
+        }
+        self.truncate(start);
+        self.try_extend(payload);
+        let (ip_len, udp_len) = (self.len(), self.len() - self.udp_start());
+        self.ipv4_mut().set_total_len(ip_len as u16);
+        self.udp_mut().set_len(udp_len as u16);
+        self.recompute_checksums();
+        true
+    }
+
+    /// Recompute the IPv4 header checksum and, for UDP packets, the UDP checksum, after changing
+    /// the headers or the payload.  Other protocols' checksums are left alone.
+    pub fn recompute_checksums(&mut self) {
+        if !self.is_ipv4() {
+            return;
+        }
+        self.update_ipv4_checksum();
+        if self.is_udp() {
+            self.update_udp_checksum();
+        }
+    }
+
+
+    /// Build the reply to this packet, if it's an ICMP echo request ("ping") addressed to `ip`.
+    /// Fragmented requests aren't answered.
+    pub fn icmp_echo_reply(&self, ip: u32) -> Option<Packet> {
+        if self.len() < 20 || !self.is_ipv4() {
+            return None;
+        }
+        let hdr = self.ipv4();
+        let start = self.ipv4_end();
+        if hdr.protocol() != 1 || hdr.dest_ip() != ip || hdr.flags() & 1 != 0 ||
+                hdr.offset() != 0 || self.len() < start + 8 {
+            return None;
+        }
+        // Type 8, code 0: echo request.
+        if self.u8_be(start) != 8 || self.u8_be(start + 1) != 0 {
+            return None;
+        }
+
+        let mut q = self.clone();
+        q.set_time(None);
+        let src = hdr.source_ip();
+        {
+            let q_hdr = q.ipv4_mut();
+            q_hdr.set_ttl(64);
+            q_hdr.set_source_ip(ip);
+            q_hdr.set_dest_ip(src);
+        }
+        {
+            let b = q.as_mut_slice();
+            b.put_u8_be(start, 0);      // echo reply
+            b.put_u16_be(start + 2, 0);
+            let checksum = !ones_complement_sum(&b[start..]);
+            b.put_u16_be(start + 2, checksum);
+        }
+        q.recompute_checksums();
+        Some(q)
+    }
+
 
     pub fn tfh_stream_start(&self) -> usize {
         if self.is_udp() {
@@ -275,6 +455,11 @@ impl Packet {
         let p = self.udp_payload();
         p.len() >= 25 && p.u8_be(0) == 1 && p.u32_be(1) == 0
     }
+
+    /// Whether this is Steam networking (SDR) traffic.  See `sdr::Envelope`.
+    pub fn is_sdr(&self) -> bool {
+        self.is_udp() && sdr::is_sdr(self.udp_payload())
+    }
 }
 
 impl Deref for Packet {
@@ -291,6 +476,19 @@ fn ones_complement_add(x: u16, y: u16) -> u16 {
     sum + over as u16
 }
 
+/// Sum of the big-endian 16-bit words of `data`, padding an odd-length slice with a zero byte.
+fn ones_complement_sum(data: &[u8]) -> u16 {
+    let mut acc = 0;
+    for i in (0 .. data.len()).step_by(2) {
+        if i + 1 < data.len() {
+            acc = ones_complement_add(acc, data.u16_be(i));
+        } else {
+            acc = ones_complement_add(acc, (data.u8_be(i) as u16) << 8);
+        }
+    }
+    acc
+}
+
 
 macro_rules! define_header {
     ($Header:ident) => {
@@ -336,13 +534,25 @@ impl Ipv4Header {
     pub fn ident(&self) -> u16 { self.0.u16_be(4) }
     pub fn flags(&self) -> u8 { self.0.u8_be(6) >> 5 }
     pub fn offset(&self) -> u16 { self.0.u16_be(6) & 0x1fff }
+    pub fn ttl(&self) -> u8 { self.0.u8_be(8) }
     pub fn protocol(&self) -> u8 { self.0.u8_be(9) }
     pub fn checksum(&self) -> u16 { self.0.u16_be(10) }
     pub fn source_ip(&self) -> u32 { self.0.u32_be(12) }
     pub fn dest_ip(&self) -> u32 { self.0.u32_be(16) }
 
+    pub fn set_version(&mut self, x: u8) { self.0.put_u8_be(0, x << 4 | self.ihl()) }
+    pub fn set_ihl(&mut self, x: u8) { self.0.put_u8_be(0, self.version() << 4 | x & 0x0f) }
     pub fn set_total_len(&mut self, x: u16) { self.0.put_u16_be(2, x) }
+    pub fn set_ident(&mut self, x: u16) { self.0.put_u16_be(4, x) }
+    pub fn set_flags(&mut self, x: u8) { self.0.put_u16_be(6, (x as u16) << 13 | self.offset()) }
+    pub fn set_offset(&mut self, x: u16) {
+        self.0.put_u16_be(6, (self.flags() as u16) << 13 | x & 0x1fff)
+    }
+    pub fn set_ttl(&mut self, x: u8) { self.0.put_u8_be(8, x) }
+    pub fn set_protocol(&mut self, x: u8) { self.0.put_u8_be(9, x) }
     pub fn set_checksum(&mut self, x: u16) { self.0.put_u16_be(10, x) }
+    pub fn set_source_ip(&mut self, x: u32) { self.0.put_u32_be(12, x) }
+    pub fn set_dest_ip(&mut self, x: u32) { self.0.put_u32_be(16, x) }
 
     pub fn is_udp(&self) -> bool {
         self.protocol() == 17
@@ -377,6 +587,8 @@ impl UdpHeader {
     pub fn len(&self) -> u16 { self.0.u16_be(4) }
     pub fn checksum(&self) -> u16 { self.0.u16_be(6) }
 
+    pub fn set_source_port(&mut self, x: u16) { self.0.put_u16_be(0, x) }
+    pub fn set_dest_port(&mut self, x: u16) { self.0.put_u16_be(2, x) }
     pub fn set_len(&mut self, x: u16) { self.0.put_u16_be(4, x) }
     pub fn set_checksum(&mut self, x: u16) { self.0.put_u16_be(6, x) }
 }
@@ -418,6 +630,7 @@ impl TfhStreamHeader {
 
     pub fn set_my_seq(&mut self, x: u32) { self.0.put_u32_be(5, x) }
     pub fn set_your_seq(&mut self, x: u32) { self.0.put_u32_be(9, x) }
+    pub fn set_flags(&mut self, x: u16) { self.0.put_u16_be(13, x) }
 }
 
 pub const TFH_STREAM_HEADER_LEN: usize = 25;
@@ -0,0 +1,114 @@
+//! A text format for writing packets by hand, for tests and for replaying made-up traffic with
+//! `replay-pcap`.  Each line is one packet:
+//!
+//! ```text
+//! # A comment.
+//! ip 4500002b1c4640004011ffcc0a000005c0a85402138969880017e8de010000000068656c6c6f2074666821
+//! udp 10.0.0.5:5001 -> 192.168.84.2:27016 deadbeef
+//! tfh 10.0.0.5:5001 -> 192.168.84.2:27016 seq=0 ack=0 3f
+//! tfh 10.0.0.5:5001 -> 192.168.84.2:27016 seq=1 ack=1 0a:00 0000 0000 20:05
+//! ```
+//!
+//! `ip` gives a whole packet in hex, as `Packet::from_hex` reads it, and is used as it is.  `udp`
+//! gives the addresses and the payload of a UDP datagram, and `tfh` those of a TFH stream packet,
+//! with the stream header's sequence numbers (both default to 0).  In a `tfh` line, a word like
+//! `0a:00` starts a message with those opcodes, and the hex after it, up to the next such word,
+//! is its body, so messages can be written without working out their framing.  Hex before the
+//! first message goes into the stream as it is, as the preamble does.  Lengths and checksums are
+//! filled in for `udp` and `tfh` packets.
+use std::fs;
+use std::net::SocketAddrV4;
+use crate::{Error, ErrorAt};
+use crate::framing;
+use crate::packet::Packet;
+use crate::util::hex;
+
+
+pub fn parse(s: &str) -> Result<Vec<Packet>, Error> {
+    let mut packets = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        if let Some(p) = parse_line(line).map_err(|e| Error(format!("line {}: {}", i + 1, e)))? {
+            packets.push(p);
+        }
+    }
+    Ok(packets)
+}
+
+pub fn load(path: &str) -> Result<Vec<Packet>, Error> {
+    let s = fs::read_to_string(path).at(path)?;
+    parse(&s).at(path)
+}
+
+/// Parse one line.  Returns `None` for blank lines and comments.
+pub fn parse_line(line: &str) -> Result<Option<Packet>, String> {
+    let line = line.find('#').map_or(line, |j| &line[..j]).trim();
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let p = match words.get(0).copied() {
+        None => return Ok(None),
+        Some("ip") => Packet::from_hex(&words[1..].concat()).map_err(|e| e.0)?,
+        Some(kind @ "udp") | Some(kind @ "tfh") => {
+            if words.len() < 4 || words[2] != "->" {
+                return Err(format!("expected `{} SRC:PORT -> DST:PORT ...`", kind));
+            }
+            let addr = |s: &str| s.parse::<SocketAddrV4>().map_err(|e| format!("{}: {}", s, e));
+            let (src, dst) = (addr(words[1])?, addr(words[3])?);
+            let rest = &words[4..];
+            let p = if kind == "udp" {
+                Packet::new_udp_ipv4(src, dst, &hex::parse(&rest.concat())?)
+            } else {
+                let (seq, ack, rest) = seq_ack(rest)?;
+                Packet::new_tfh_stream(src, dst, seq, ack, &stream_data(rest)?)
+            };
+            p.ok_or("payload is too long for a packet")?
+        },
+        Some(x) => return Err(format!("unknown packet kind {:?}", x)),
+    };
+    Ok(Some(p))
+}
+
+/// Take `seq=N` and `ack=N` from the front of `words`.
+fn seq_ack<'a, 'b>(mut words: &'a [&'b str]) -> Result<(u32, u32, &'a [&'b str]), String> {
+    let (mut seq, mut ack) = (0, 0);
+    while let Some(w) = words.get(0) {
+        let field = if w.starts_with("seq=") {
+            &mut seq
+        } else if w.starts_with("ack=") {
+            &mut ack
+        } else {
+            break;
+        };
+        *field = w[4..].parse().map_err(|e| format!("{}: {}", w, e))?;
+        words = &words[1..];
+    }
+    Ok((seq, ack, words))
+}
+
+/// The stream bytes written by `words`: raw hex, then messages, each an opcode pair like `0a:00`
+/// followed by its body.
+fn stream_data(words: &[&str]) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    // The opcodes of the message being read, or `None` for the raw bytes before the first one.
+    let mut opcodes = None;
+    let mut digits = String::new();
+    let flush = |data: &mut Vec<u8>, opcodes: Option<(u8, u8)>, digits: &str| {
+        let bytes = hex::parse(digits)?;
+        match opcodes {
+            Some((major, minor)) => data.extend_from_slice(&framing::encode(major, minor, &bytes)),
+            None => data.extend_from_slice(&bytes),
+        }
+        Ok::<_, String>(())
+    };
+    for w in words {
+        if let Some(i) = w.find(':') {
+            let opcode = |x: &str| u8::from_str_radix(x, 16).map_err(|e| format!("{}: {}", w, e));
+            let next = (opcode(&w[..i])?, opcode(&w[i + 1 ..])?);
+            flush(&mut data, opcodes, &digits)?;
+            opcodes = Some(next);
+            digits.clear();
+        } else {
+            digits.push_str(w);
+        }
+    }
+    flush(&mut data, opcodes, &digits)?;
+    Ok(data)
+}
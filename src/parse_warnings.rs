@@ -0,0 +1,117 @@
+//! The parse-warning log, with `--parse-warnings FILE`: every anomaly found while decoding a
+//! stream (see `StreamWarning`) and every stall, appended to FILE as a line of JSON along with
+//! the connection it was on.  A change to the protocol, as after a game patch, shows up there as
+//! a burst of them.  With the log on, each packet's UDP length and checksum are checked too.
+//!
+//! Each line has `time`, `conn`, `session` and `player` (when known), `dir`, `kind` (from
+//! `StreamWarning::kind`, or `stall`), and `detail`, the text logged for it, followed by the
+//! warning's own fields.  `last_index` and `last_opcodes` give the last message decoded in that
+//! direction before it, the last point the stream was known to be good.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, Message, Stall, StallReason, StreamWarning};
+use crate::util::json;
+
+
+/// The connection a warning was on, as the handler knows it.
+pub struct Context<'a> {
+    pub ct: ConnTuple,
+    pub dir: u8,
+    pub session: Option<SessionId>,
+    pub player: Option<&'a str>,
+}
+
+pub struct ParseWarningLog {
+    out: File,
+    /// Latest time seen on a message or tick, which warnings are stamped with.
+    now: u64,
+    /// Index and opcodes of the last message in each direction of each connection.
+    last: HashMap<(ConnTuple, u8), (u64, u8, u8)>,
+}
+
+impl ParseWarningLog {
+    /// Append records to the file at `path`.
+    pub fn new(path: &str) -> io::Result<ParseWarningLog> {
+        Ok(ParseWarningLog {
+            out: OpenOptions::new().create(true).append(true).open(path)?,
+            now: 0,
+            last: HashMap::new(),
+        })
+    }
+
+    /// Move the clock forward to `now`, in microseconds.
+    pub fn advance(&mut self, now: u64) {
+        self.now = self.now.max(now);
+    }
+
+    pub fn on_message(&mut self, ct: ConnTuple, msg: &Message) {
+        self.advance(msg.time);
+        let h = &msg.header;
+        self.last.insert((ct, h.dir), (msg.index, h.major, h.minor));
+    }
+
+    pub fn close(&mut self, ct: ConnTuple) {
+        self.last.retain(|&(c, _), _| c != ct);
+    }
+
+    pub fn warning(&mut self, cx: &Context, w: &StreamWarning) -> io::Result<()> {
+        let mut obj = self.start(cx, w.kind(), &w.to_string());
+        match *w {
+            StreamWarning::MajorOutOfRange { at, major } => obj.num("at", at).num("major", major),
+            StreamWarning::MinorOutOfRange { at, minor } => obj.num("at", at).num("minor", minor),
+            StreamWarning::ImpossibleLength { at, len } => obj.num("at", at).num("len", len),
+            StreamWarning::RewriteLength { at, from, to } => {
+                obj.num("at", at).num("from", from).num("to", to)
+            },
+            StreamWarning::InjectionTooLong { len } => obj.num("len", len),
+            StreamWarning::Resynced { at, skipped } => obj.num("at", at).num("skipped", skipped),
+            StreamWarning::BadChecksum { seq } => obj.num("seq", seq),
+            StreamWarning::Truncated { seq, len, expected } => {
+                obj.num("seq", seq).num("len", len).num("expected", expected)
+            },
+        };
+        self.write(obj)
+    }
+
+    pub fn stall(&mut self, cx: &Context, stall: &Stall) -> io::Result<()> {
+        let mut obj = self.start(cx, "stall", &stall.to_string());
+        obj.num("at", stall.at).num("packets", stall.packets);
+        match stall.reason {
+            StallReason::Gap(ref r) => {
+                obj.str("reason", "gap").num("gap_start", r.start).num("gap_end", r.end)
+            },
+            StallReason::Incomplete { len, avail } => {
+                obj.str("reason", "incomplete").num("len", len).num("avail", avail)
+            },
+            StallReason::Desync { len, avail } => {
+                obj.str("reason", "desync").num("len", len).num("avail", avail)
+            },
+        };
+        self.write(obj)
+    }
+
+    /// The fields every record has.
+    fn start(&self, cx: &Context, kind: &str, detail: &str) -> json::Object {
+        let mut obj = json::Object::new();
+        obj.num("time", self.now).str("conn", &cx.ct.to_string());
+        if let Some(session) = cx.session {
+            obj.str("session", &session.to_string());
+        }
+        if let Some(player) = cx.player {
+            obj.str("player", player);
+        }
+        obj.num("dir", cx.dir).str("kind", kind).str("detail", detail);
+        if let Some(&(index, major, minor)) = self.last.get(&(cx.ct, cx.dir)) {
+            obj.num("last_index", index)
+                .str("last_opcodes", &format!("{:02x}:{:02x}", major, minor));
+        }
+        obj
+    }
+
+    fn write(&mut self, obj: json::Object) -> io::Result<()> {
+        let line = obj.finish() + "\n";
+        self.out.write_all(line.as_bytes())
+    }
+}
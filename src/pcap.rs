@@ -1,7 +1,29 @@
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::mem;
 use std::slice;
 use crate::packet::{Packet, PACKET_CAP};
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+/// `LINKTYPE_ETHERNET`, the framing `Pcap` reads.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+/// `LINKTYPE_USER0`, used for captures of decoded TFH messages.  Each record holds the 12-byte
+/// connection tuple, the 12-byte message header, and the message body, in the same encodings as
+/// a `.tfhlog` record.  `wireshark/tfh_message.lua` dissects these.
+pub const LINKTYPE_TFH_MESSAGE: u32 = 147;
+
+/// Source MAC address of packets traveling from side A (outside) to side B (inside) in captures
+/// the relay writes, with `MAC_B` as the destination, and the other way round for packets from B.
+/// These are locally administered addresses, so they can't be mistaken for real hardware.  Reading
+/// them back with `try_read_tagged` recovers each packet's direction without knowing the server's
+/// address.
+pub const MAC_A: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0a];
+pub const MAC_B: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0b];
+
+/// Snapshot length recorded in written captures.  This is the largest value Wireshark accepts;
+/// records are never truncated, so it's only advisory.
+const SNAP_LEN: u32 = 262144;
 
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
@@ -41,6 +63,48 @@ struct EthernetHeader {
 }
 
 
+/// Which way a packet in a capture was traveling, as tagged by `Writer::write_tagged_packet`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// From the outside to the inside, toward the server.
+    AToB,
+    /// From the inside to the outside, toward the client.
+    BToA,
+}
+
+impl Direction {
+    /// The direction with the given `flip`, as for `TfhStreamConns::handle`.
+    pub fn from_flip(flip: bool) -> Direction {
+        if flip { Direction::BToA } else { Direction::AToB }
+    }
+
+    pub fn flip(self) -> bool {
+        self == Direction::BToA
+    }
+
+    fn from_macs(src: [u8; 6], dest: [u8; 6]) -> Option<Direction> {
+        match (src, dest) {
+            (MAC_A, MAC_B) => Some(Direction::AToB),
+            (MAC_B, MAC_A) => Some(Direction::BToA),
+            _ => None,
+        }
+    }
+
+    fn macs(self) -> ([u8; 6], [u8; 6]) {
+        match self {
+            Direction::AToB => (MAC_A, MAC_B),
+            Direction::BToA => (MAC_B, MAC_A),
+        }
+    }
+}
+
+
+/// Reads the IP packets in a `LINKTYPE_ETHERNET` capture.  `recv_tagged`, and `recv` as a
+/// `PacketSource`, return them in order, skipping records that don't hold one, and `None` at the
+/// end of the capture.  A capture that ends partway through a record, as when it was copied
+/// while still being written, is an `UnexpectedEof` error rather than a normal end.  The
+/// lower-level `try_read` returns `None` for each skipped record, and an `UnexpectedEof` error
+/// at the end.
 pub struct Pcap<R> {
     r: R,
 }
@@ -58,8 +122,45 @@ impl<R: Read> Pcap<R> {
     }
 
     pub fn try_read(&mut self) -> io::Result<Option<Packet>> {
+        Ok(self.try_read_tagged()?.map(|(p, _)| p))
+    }
+
+    /// Like `try_read`, but also returns the packet's direction, if its MAC addresses are
+    /// `MAC_A` and `MAC_B`.
+    pub fn try_read_tagged(&mut self) -> io::Result<Option<(Packet, Option<Direction>)>> {
+        match self.read_header()? {
+            Some(ph) => self.read_record(ph),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of capture")),
+        }
+    }
+
+    /// The header of the next record, or `None` if the capture ends before it.
+    fn read_header(&mut self) -> io::Result<Option<PacketHeader>> {
+        let mut buf = [0; mem::size_of::<PacketHeader>()];
+        let mut got = 0;
+        while got < buf.len() {
+            match self.r.read(&mut buf[got ..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(truncated()),
+                Ok(n) => got += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
         let mut ph = PacketHeader::default();
-        unsafe { read_into(&mut self.r, &mut ph)? };
+        unsafe { read_into(&mut &buf[..], &mut ph)? };
+        Ok(Some(ph))
+    }
+
+    /// The rest of the record with header `ph`: its packet, or `None` if it doesn't hold one.
+    fn read_record(&mut self, ph: PacketHeader)
+            -> io::Result<Option<(Packet, Option<Direction>)>> {
+        self.read_body(ph).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof { truncated() } else { e }
+        })
+    }
+
+    fn read_body(&mut self, ph: PacketHeader) -> io::Result<Option<(Packet, Option<Direction>)>> {
         let mut len = ph.inc_len as usize;
 
         if len < mem::size_of::<EthernetHeader>() {
@@ -79,7 +180,8 @@ impl<R: Read> Pcap<R> {
 
         let mut p = Packet::zeroed(len);
         self.r.read_exact(&mut p)?;
-        Ok(Some(p))
+        p.set_time(Some(ph.time.sec as u64 * 1_000_000 + ph.time.usec as u64));
+        Ok(Some((p, Direction::from_macs(eh.src_mac, eh.dest_mac))))
     }
 
     pub fn read(&mut self) -> io::Result<Packet> {
@@ -90,4 +192,88 @@ impl<R: Read> Pcap<R> {
             }
         }
     }
+
+    /// The next packet and its direction, or `None` at the end of the capture.
+    pub fn recv_tagged(&mut self) -> io::Result<Option<(Packet, Option<Direction>)>> {
+        loop {
+            let ph = match self.read_header()? {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            if let Some(x) = self.read_record(ph)? {
+                return Ok(Some(x));
+            }
+        }
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "capture ends partway through a record")
+}
+
+
+pub struct Writer<W> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a new capture, writing the global header to `w`.
+    pub fn new(mut w: W, net_type: u32) -> io::Result<Writer<W>> {
+        let mut buf = Vec::with_capacity(24);
+        buf.extend_from_slice(&0xa1b2c3d4_u32.to_ne_bytes());
+        buf.extend_from_slice(&2_u16.to_ne_bytes());
+        buf.extend_from_slice(&4_u16.to_ne_bytes());
+        buf.extend_from_slice(&0_u32.to_ne_bytes());
+        buf.extend_from_slice(&0_u32.to_ne_bytes());
+        buf.extend_from_slice(&SNAP_LEN.to_ne_bytes());
+        buf.extend_from_slice(&net_type.to_ne_bytes());
+        w.write_all(&buf)?;
+        Ok(Writer { w })
+    }
+
+    /// Write one record.  `time` is in microseconds since the Unix epoch.
+    pub fn write(&mut self, time: u64, data: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(16 + data.len());
+        buf.extend_from_slice(&((time / 1_000_000) as u32).to_ne_bytes());
+        buf.extend_from_slice(&((time % 1_000_000) as u32).to_ne_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(data);
+        self.w.write_all(&buf)
+    }
+
+    /// Write a message in the `LINKTYPE_TFH_MESSAGE` format.
+    pub fn write_message(&mut self, time: u64, ct: ConnTuple, msg: &Message) -> io::Result<()> {
+        let mut data = Vec::with_capacity(24 + msg.body.len());
+        data.extend_from_slice(&ct.as_bytes());
+        data.extend_from_slice(&msg.header.as_bytes());
+        data.extend_from_slice(&msg.body);
+        self.write(time, &data)
+    }
+
+    /// Write an IP packet in the `LINKTYPE_ETHERNET` format, with zeroed MAC addresses.  Packets
+    /// without a timestamp are written with time zero.
+    pub fn write_packet(&mut self, p: &Packet) -> io::Result<()> {
+        self.write_ethernet(p, [0; 6], [0; 6])
+    }
+
+    /// Write an IP packet like `write_packet`, with MAC addresses recording its direction.
+    pub fn write_tagged_packet(&mut self, p: &Packet, dir: Direction) -> io::Result<()> {
+        let (src, dest) = dir.macs();
+        self.write_ethernet(p, src, dest)
+    }
+
+    fn write_ethernet(&mut self, p: &Packet, src: [u8; 6], dest: [u8; 6]) -> io::Result<()> {
+        let ethertype: u16 = if p.is_ipv4() { 0x0800 } else { 0x86dd };
+        let mut data = Vec::with_capacity(mem::size_of::<EthernetHeader>() + p.len());
+        data.extend_from_slice(&dest);
+        data.extend_from_slice(&src);
+        data.extend_from_slice(&ethertype.to_be_bytes());
+        data.extend_from_slice(p);
+        self.write(p.time().unwrap_or(0), &data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
 }
@@ -0,0 +1,16 @@
+//! The types needed to embed the stream reassembler in another program, for
+//! `use tfh_mitm::prelude::*`.  Everything here stays under the same name; items may be added,
+//! but are only removed or renamed along with a major version bump.
+//!
+//! Feed `Packet`s, from a `Pcap` or anywhere else, to `TfhStreamConns::handle`, and get the
+//! decoded `Message`s through a `StreamHandler`, by polling an `EventQueue`, or all at once from
+//! a `CollectingHandler`.
+pub use crate::{Error, ErrorAt};
+pub use crate::packet::Packet;
+#[cfg(feature = "pcap")]
+pub use crate::pcap::{Pcap, Writer as PcapWriter};
+#[cfg(feature = "std")]
+pub use crate::tfh_stream::{
+    Collected, CollectingHandler, ConnTuple, EventQueue, Message, MessageHeader, ResumeState,
+    Stall, StallReason, StreamEvent, StreamHandler, StreamWarning, TfhStream, TfhStreamConns,
+};
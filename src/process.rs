@@ -1,22 +1,71 @@
 use std::cmp;
-use std::collections::hash_map::{HashMap, Entry};
+use std::collections::hash_map::{DefaultHasher, HashMap};
+use std::hash::{Hash, Hasher};
 use std::convert::TryInto;
-use std::fmt::Write as _;
 use std::fs::{self, File};
 use std::io::{self, Write as _};
-use std::str;
-use std::sync::mpsc::{self, Sender, Receiver};
+use std::iter;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::panic;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::Ordering;
 use std::thread::{self, JoinHandle};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use rand::{self, Rng};
-use crate::bytes::Bytes;
+use crate::{Error, ErrorAt};
+use crate::affinity::{self, Role};
+use crate::alerts::{self, Alert, RateAlerts};
+use crate::canary::{Canary, CanaryAlert, Profile};
+use crate::anonymize::Anonymizer;
+use crate::capture::Capture;
+use crate::channel::{self, Overflow, Sender, Receiver};
+use crate::chat_log::ChatLog;
+use crate::config::Config;
+use crate::control;
+use crate::disk_watchdog::{Budget, Watchdog};
+use crate::inject::Injector;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::health;
+#[cfg(feature = "kafka")]
+use crate::kafka;
+use crate::keepalive::KeepaliveTracker;
+use crate::logging::{self, Level, Subsystem};
+use crate::matches::MatchTracker;
+use crate::messages::{self, Chat, Known};
+use crate::opcodes;
 use crate::packet::Packet;
-use crate::tfh_stream::{TfhStreamConns, ConnTuple, StreamHandler, Message};
+use crate::parse_warnings::{self, ParseWarningLog};
+use crate::pcap::{Direction, Pcap};
+use crate::ratings::RatingTracker;
+use crate::rewrite::{Mutator, NameRewriter};
+use crate::roster::{Finding, RosterCheck};
+use crate::sdr;
+use crate::session::SessionId;
+use crate::sink::{self, MessageSink};
+use crate::snapshot::{ConnState, SnapshotFile};
+use crate::stats::{Counters, RelayStats};
+use crate::store::{self, MessageStore, Query};
+use crate::stun;
+use crate::supervise::{Restart, Supervisor};
+use crate::tfh_stream::{
+    CollectingHandler, TfhStreamConns, ConnTuple, ResumeState, Stall, StreamHandler,
+    StreamWarning, Message,
+};
+use crate::tfhlog;
+use crate::util::clock::{self, now_us};
+use crate::util::dump::{self, DumpOptions};
+#[cfg(feature = "websocket")]
+use crate::websocket;
+use crate::zmtp;
 
 
 pub enum Input {
     FromA(Packet),
     FromB(Packet),
+    /// Save the state of every connection now, as with `checkpoint`.  Each worker sends on the
+    /// channel once it's done.
+    Checkpoint(mpsc::Sender<()>),
 }
 
 pub enum Output {
@@ -24,134 +73,1211 @@ pub enum Output {
     ToB(Packet),
 }
 
-pub fn start_processing_thread() -> (Sender<Input>, Receiver<Output>, JoinHandle<()>) {
-    let (inp_send, inp_recv) = mpsc::channel();
-    let (out_send, out_recv) = mpsc::channel();
-    let join = thread::spawn(move || process(inp_recv, out_send));
-    (inp_send, out_recv, join)
+fn make_channel<T>(cfg: &Config, default_overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    channel::bounded(
+        cfg.queue_len.unwrap_or(channel::DEFAULT_CAPACITY),
+        cfg.overflow.unwrap_or(default_overflow),
+    )
+}
+
+pub fn start_processing_thread(
+    cfg: &Config,
+    stats: Arc<RelayStats>,
+) -> Result<(Sender<Input>, Receiver<Output>, JoinHandle<()>), Error> {
+    let handler = StreamHandlerImpl::new(cfg)?;
+    let (inp_send, inp_recv) = make_channel(cfg, Overflow::Block);
+    let (out_send, out_recv) = make_channel(cfg, Overflow::Block);
+    let cfg = cfg.clone();
+    let join = thread::Builder::new().name("processing".into()).spawn(move || {
+        affinity::try_pin(Role::Processing);
+        run_workers(&cfg, Overflow::Block, handler, &stats, inp_recv, out_send)
+    })?;
+    Ok((inp_send, out_recv, join))
+}
+
+/// Like `start_processing_thread`, but run the thread under `sup`.  The thread can't be
+/// restarted, since the connection state would be lost along with it, so if it panics the
+/// supervisor shuts down instead.
+pub fn start_supervised_processing_thread(
+    cfg: &Config,
+    sup: &Supervisor,
+    stats: Arc<RelayStats>,
+) -> Result<(Sender<Input>, Receiver<Output>), Error> {
+    let handler = StreamHandlerImpl::new(cfg)?;
+    let (inp_send, inp_recv) = make_channel(cfg, Overflow::DropOldest);
+    let (out_send, out_recv) = make_channel(cfg, Overflow::DropOldest);
+    let mut args = Some((cfg.clone(), handler, inp_recv, out_send));
+    sup.spawn("processing", Restart::Never, move || {
+        let (cfg, handler, inp_recv, out_send) = args.take()
+            .ok_or("processing thread can't be restarted")?;
+        affinity::pin(Role::Processing)?;
+        run_workers(&cfg, Overflow::DropOldest, handler, &stats, inp_recv, out_send);
+        Ok(())
+    });
+    Ok((inp_send, out_recv))
+}
+
+/// Outputs shared by all the processing workers.
+struct Sinks {
+    names: Mutex<HashMap<ConnTuple, String>>,
+    /// Client version of each connection, from its login message.
+    versions: Mutex<HashMap<ConnTuple, u32>>,
+    sessions: Mutex<HashMap<ConnTuple, SessionId>>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<websocket::Feed>,
+    zmq_pub: Option<zmtp::Publisher>,
+    /// Names for the opcodes in the live feeds.
+    opcodes: Option<opcodes::Watched>,
+    store: Option<Arc<Mutex<MessageStore>>>,
+    /// Messages from the control socket, waiting to be sent to clients.
+    injector: Option<Arc<Injector>>,
+    /// Control socket clients following live events.
+    subs: Option<control::Subscribers>,
+    chat: Option<Mutex<ChatLog>>,
+    chat_major: u8,
+    matches: Option<Mutex<MatchTracker>>,
+    ratings: Option<Mutex<RatingTracker>>,
+    roster: Option<Mutex<RosterCheck>>,
+    keepalive: Option<Mutex<KeepaliveTracker>>,
+    keepalive_stats: Option<String>,
+    parse_warnings: Option<Mutex<ParseWarningLog>>,
+    alerts: Option<Mutex<RateAlerts>>,
+    alert_webhook: Option<String>,
+    alert_dump: Option<String>,
+    canary: Option<Mutex<Canary>>,
+    mutators: Vec<Box<dyn Mutator>>,
+    /// How often to rotate the logs, in microseconds.
+    log_rotate: Option<u64>,
+    /// Keeps the logs within `--log-budget`, and asks for them to be rotated when that needs
+    /// files still open.
+    disk_watchdog: Option<Watchdog>,
+    anon: Option<Anonymizer>,
+    capture: Option<Arc<Mutex<Capture>>>,
+    snapshot: Option<SnapshotFile>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<grpc::Service>,
 }
 
-#[derive(Default)]
 struct StreamHandlerImpl {
-    logs: HashMap<ConnTuple, File>,
-    names: HashMap<ConnTuple, String>,
+    /// Each connection is handled by only one worker, so its logs belong to that worker.
+    log: Box<dyn MessageSink>,
+    /// When `log` was last rotated, by the stream clock.
+    last_rotate: Option<u64>,
+    /// Rotations asked for by the disk watchdog so far, as of the last check.
+    disk_rotations: u64,
+    /// This worker's number in the snapshot, once it's first saved to it.
+    snapshot_worker: Option<usize>,
+    /// This worker's number with the disk watchdog, once it's first reported its open logs.
+    watchdog_worker: Option<usize>,
+    sinks: Arc<Sinks>,
 }
 
 impl StreamHandlerImpl {
-    fn try_log_message(&mut self, ct: ConnTuple, msg: Message) -> io::Result<()> {
-        let log = match self.logs.entry(ct) {
-            Entry::Occupied(e) => e.into_mut(),
-            Entry::Vacant(e) => {
-                let (client_ip, client_port, server_port) = match ct {
-                    ConnTuple::Ipv4(ci, cp, si, sp) => (ci, cp, sp),
+    fn new(cfg: &Config) -> Result<StreamHandlerImpl, Error> {
+        let log_dir = cfg.log_dir.as_ref().map_or(sink::LOG_DIR, |s| s);
+        let log = sink::open(cfg.log_sink.as_ref().map_or("tfhlog", |s| s), log_dir,
+            &cfg.log_layout)?;
+        #[cfg(feature = "kafka")]
+        let log = match cfg.kafka {
+            Some(ref hosts) => {
+                let topic = cfg.kafka_topic.as_ref().map_or("tfh", |s| s);
+                let encoding = match cfg.kafka_format {
+                    Some(ref s) => s.parse()?,
+                    None => kafka::Encoding::Json,
                 };
-                let mut client_ip_bytes = [0; 4];
-                client_ip_bytes.put_u32_be(0, client_ip);
-                let [a, b, c, d] = client_ip_bytes;
-                let name = format!("logs/{}-{}.{}.{}.{}-{}-{}.tfhlog",
-                    now(), a, b, c, d, client_port, server_port);
-                e.insert(File::create(name)?)
+                let kafka = kafka::Sink::start(hosts, topic, encoding)?;
+                Box::new(sink::Tee(vec![log, Box::new(kafka)]))
             },
+            None => log,
         };
+        #[cfg(not(feature = "kafka"))]
+        {
+            if cfg.kafka.is_some() {
+                return Err("--kafka requires building with `--features kafka`".into());
+            }
+        }
 
-        log.write_all(&msg.header.as_bytes())?;
-        log.write_all(&msg.body)?;
-        Ok(())
+        #[cfg(feature = "websocket")]
+        let websocket = match cfg.websocket {
+            Some(ref addr) => Some(websocket::Feed::start(addr)?),
+            None => None,
+        };
+        #[cfg(not(feature = "websocket"))]
+        {
+            if cfg.websocket.is_some() {
+                return Err("--websocket requires building with `--features websocket`".into());
+            }
+        }
+        let zmq_pub = match cfg.zmq_pub {
+            Some(ref addr) => Some(zmtp::Publisher::start(addr)?),
+            None => None,
+        };
+        let opcodes = match cfg.opcodes {
+            Some(ref path) => Some(opcodes::Watched::start(path)?),
+            None => None,
+        };
+        let (store, injector, subs) = match cfg.control {
+            Some(ref path) => {
+                let store = Arc::new(Mutex::new(MessageStore::new(
+                    store::DEFAULT_MAX_BYTES,
+                    store::DEFAULT_MAX_PER_CONN,
+                )));
+                let injector = Arc::new(Injector::default());
+                let subs = control::start(path, store.clone(), injector.clone())?;
+                (Some(store), Some(injector), Some(subs))
+            },
+            None => (None, None, None),
+        };
+        let chat = match cfg.chat_log {
+            Some(ref dir) => Some(Mutex::new(ChatLog::new(dir).at(dir)?)),
+            None => None,
+        };
+        let matches = match cfg.match_log {
+            Some(ref path) => Some(Mutex::new(MatchTracker::new(path).at(path)?)),
+            None => None,
+        };
+        let ratings = match cfg.rating_log {
+            Some(ref path) => Some(Mutex::new(RatingTracker::new(path).at(path)?)),
+            None => None,
+        };
+        let keepalive = if cfg.keepalive.len() > 0 {
+            Some(Mutex::new(KeepaliveTracker::new(cfg.keepalive.clone())))
+        } else {
+            if cfg.keepalive_stats.is_some() {
+                return Err("--keepalive-stats requires --keepalive".into());
+            }
+            None
+        };
+        let parse_warnings = match cfg.parse_warnings {
+            Some(ref path) => Some(Mutex::new(ParseWarningLog::new(path).at(path)?)),
+            None => None,
+        };
+        let snapshot = match cfg.snapshot {
+            // Pseudonyms change from run to run, so they can't be carried over.
+            Some(_) if cfg.anonymize.is_some() || cfg.redact_names => {
+                return Err("--snapshot can't be used with --anonymize or --redact-names".into());
+            },
+            Some(ref path) => Some(SnapshotFile::open(path)?),
+            None => None,
+        };
+        let capture = match cfg.capture {
+            // Captures hold whole packets, so they can't honor the privacy options.
+            Some(_) if cfg.anonymize.is_some() || cfg.redact_names => {
+                return Err("--capture can't be used with --anonymize or --redact-names".into());
+            },
+            Some(ref path) => {
+                let capture = Capture::create(path, cfg.capture_filter.clone()).at(path)?;
+                Some(Arc::new(Mutex::new(capture)))
+            },
+            None => {
+                if cfg.capture_filter.is_some() {
+                    return Err("--capture-filter requires --capture".into());
+                }
+                None
+            },
+        };
+        let alerts = if cfg.alert_rates.len() > 0 {
+            Some(Mutex::new(RateAlerts::new(cfg.alert_rates.clone())))
+        } else {
+            if cfg.alert_dump.is_some() {
+                return Err("--alert-dump requires --alert-rate".into());
+            }
+            if cfg.alert_webhook.is_some() && cfg.canary.is_none() {
+                return Err("--alert-webhook requires --alert-rate or --canary".into());
+            }
+            None
+        };
+        let canary = match cfg.canary {
+            Some(ref path) => {
+                let profile = Profile::load(path)?;
+                log!(Handler, Info, "canary: loaded profiles of {} opcodes from {}",
+                    profile.opcodes.len(), path);
+                Some(Mutex::new(Canary::new(profile)))
+            },
+            None => None,
+        };
+        if let Some(ref dir) = cfg.alert_dump {
+            if store.is_none() {
+                return Err("--alert-dump requires --control, which keeps recent messages".into());
+            }
+            fs::create_dir_all(dir).at(dir)?;
+        }
+        let mutators = cfg.rename.iter()
+            .map(|&(ref old, ref new)| {
+                log!(Handler, Info, "rewriting player name {:?} to {:?}", old, new);
+                Box::new(NameRewriter::new(old, new)) as Box<dyn Mutator>
+            })
+            .collect();
+
+        #[cfg(feature = "grpc")]
+        let grpc = match cfg.grpc {
+            Some(ref addr) => Some(grpc::Service::start(addr)?),
+            None => None,
+        };
+        #[cfg(not(feature = "grpc"))]
+        {
+            if cfg.grpc.is_some() {
+                return Err("--grpc requires building with `--features grpc`".into());
+            }
+        }
+
+        let disk_watchdog = if cfg.log_budget.is_some() || cfg.log_min_free.is_some() {
+            let budget = Budget {
+                max_bytes: cfg.log_budget,
+                min_free: cfg.log_min_free,
+                compress: cfg.log_compress,
+            };
+            Some(Watchdog::start(log_dir, budget))
+        } else {
+            if cfg.log_compress {
+                return Err("--log-compress requires --log-budget or --log-min-free".into());
+            }
+            None
+        };
+
+        Ok(StreamHandlerImpl {
+            log,
+            last_rotate: None,
+            disk_rotations: 0,
+            snapshot_worker: None,
+            watchdog_worker: None,
+            sinks: Arc::new(Sinks {
+                names: Mutex::new(HashMap::new()),
+                versions: Mutex::new(HashMap::new()),
+                sessions: Mutex::new(HashMap::new()),
+                #[cfg(feature = "websocket")]
+                websocket,
+                zmq_pub,
+                opcodes,
+                store,
+                injector,
+                subs,
+                chat,
+                chat_major: cfg.chat_major.unwrap_or(messages::MAJOR_CHAT),
+                matches,
+                ratings,
+                roster: if cfg.check_roster { Some(Mutex::new(RosterCheck::new())) } else { None },
+                keepalive,
+                keepalive_stats: cfg.keepalive_stats.clone(),
+                parse_warnings,
+                alerts,
+                alert_webhook: cfg.alert_webhook.clone(),
+                alert_dump: cfg.alert_dump.clone(),
+                canary,
+                mutators,
+                log_rotate: cfg.log_rotate.map(|secs| secs * 1_000_000),
+                disk_watchdog,
+                anon: if cfg.anonymize.is_some() || cfg.redact_names {
+                    Some(Anonymizer::new(cfg.anonymize, cfg.redact_names))
+                } else {
+                    None
+                },
+                capture,
+                snapshot,
+                #[cfg(feature = "grpc")]
+                grpc,
+            }),
+        })
+    }
+
+    /// Create another handler for a new worker, sharing this one's outputs.
+    fn fork(&self) -> StreamHandlerImpl {
+        StreamHandlerImpl {
+            log: self.log.fork(),
+            last_rotate: None,
+            disk_rotations: self.disk_rotations,
+            snapshot_worker: None,
+            watchdog_worker: None,
+            sinks: self.sinks.clone(),
+        }
+    }
+
+    /// The connection as it should appear in outputs.  In privacy mode, the handler works only
+    /// with anonymized connections, so each callback converts `ct` first.
+    fn conn(&self, ct: ConnTuple) -> ConnTuple {
+        self.sinks.anon.as_ref().map_or(ct, |a| a.conn(ct))
     }
 
-    fn try_update_status(&mut self) -> io::Result<()> {
+    /// Write a record to the `--parse-warnings` log, if there is one, with `write`.
+    fn log_parse_warning(
+        &self,
+        ct: ConnTuple,
+        dir: u8,
+        write: impl FnOnce(&mut ParseWarningLog, &parse_warnings::Context) -> io::Result<()>,
+    ) {
+        let log = match self.sinks.parse_warnings {
+            Some(ref x) => x,
+            None => return,
+        };
+        let ct = self.conn(ct);
+        let session = self.sinks.sessions.lock().unwrap().get(&ct).copied();
+        let player = self.sinks.names.lock().unwrap().get(&ct).cloned();
+        let cx = parse_warnings::Context { ct, dir, session, player: player.as_deref() };
+        write(&mut log.lock().unwrap(), &cx)
+            .unwrap_or_else(|e| log!(Handler, Error, "failed to log parse warning: {}", e));
+    }
+
+    /// The session of `ct`, starting a new one if `msg` is its first message.
+    fn session(&self, ct: ConnTuple, msg: &Message) -> SessionId {
+        let mut sessions = self.sinks.sessions.lock().unwrap();
+        if let Some(&session) = sessions.get(&ct) {
+            return session;
+        }
+        let session = SessionId::new(ct, msg.time);
+        log!(Handler, Info, "{:?}: session {}", ct, session);
+        sessions.insert(ct, session);
+        drop(sessions);
+        if let Some(ref store) = self.sinks.store {
+            store.lock().unwrap().open(ct, session);
+        }
+        self.publish(ct, |subs, session, player| subs.conn_event("session", ct, session, player));
+        session
+    }
+
+    /// Record that the player on `ct` is `name`, and publish `event` about it.
+    fn set_name(&self, ct: ConnTuple, name: String, event: &str) {
+        #[cfg(feature = "grpc")]
+        {
+            if let Some(ref grpc) = self.sinks.grpc {
+                grpc.set_name(ct, &name);
+            }
+        }
+        if let Some(ref store) = self.sinks.store {
+            store.lock().unwrap().set_name(ct, &name);
+        }
+        let mut names = self.sinks.names.lock().unwrap();
+        names.insert(ct, name);
+        self.update_status(&names);
+        drop(names);
+        self.publish(ct, |subs, session, player| subs.conn_event(event, ct, session, player));
+    }
+
+    /// Pass a message on `ct` to the roster check, if it's on, and act on what it finds.
+    fn check_roster(&self, ct: ConnTuple, time: u64, known: &Option<Known>) {
+        let mut roster = match self.sinks.roster {
+            Some(ref x) => x.lock().unwrap(),
+            None => return,
+        };
+        let findings = match *known {
+            Some(Known::Login(ref login)) => {
+                roster.login(time, ct, &login.name);
+                return;
+            },
+            Some(Known::Roster(ref r)) => roster.roster(time, &r.names),
+            Some(Known::Presence(ref p)) => roster.presence(time, &p.name, p.joined),
+            _ => {
+                roster.seen(ct);
+                return;
+            },
+        };
+        drop(roster);
+        for f in findings {
+            if let Finding::Seeded { ct, ref name } = f {
+                log!(Handler, Info, "roster: {}", f);
+                self.set_name(ct, name.clone(), "seeded");
+            } else {
+                log!(Handler, Warn, "roster: {}", f);
+            }
+        }
+    }
+
+    /// Publish an event about `ct` to control socket subscribers, if there are any.  `f` gets the
+    /// connection's session and player name, when they're known.  The caller must not hold the
+    /// lock on `names` or `sessions`.
+    fn publish(
+        &self,
+        ct: ConnTuple,
+        f: impl FnOnce(&control::Subscribers, Option<SessionId>, Option<&str>),
+    ) {
+        let subs = match self.sinks.subs {
+            Some(ref subs) if !subs.is_empty() => subs,
+            _ => return,
+        };
+        let names = self.sinks.names.lock().unwrap();
+        let session = self.sinks.sessions.lock().unwrap().get(&ct).cloned();
+        f(subs, session, names.get(&ct).map(|s| s as &str));
+    }
+
+    fn try_update_status(
+        names: &HashMap<ConnTuple, String>,
+        sessions: &HashMap<ConnTuple, SessionId>,
+    ) -> io::Result<()> {
         let mut f = File::create("status.txt")?;
-        if self.names.len() == 0 {
+        if names.len() == 0 {
             writeln!(f, "0 players connected")?;
             return Ok(());
         }
         writeln!(f, "{} player{} connected:",
-            self.names.len(),
-            if self.names.len() != 1 { "s" } else { "" },
+            names.len(),
+            if names.len() != 1 { "s" } else { "" },
         )?;
-        let mut names = self.names.values().collect::<Vec<_>>();
-        names.sort();
-        for name in names {
-            writeln!(f, "- {}", name)?;
+        let mut names = names.iter().collect::<Vec<_>>();
+        names.sort_by_key(|&(ct, name)| (name, sessions.get(ct)));
+        for (ct, name) in names {
+            match sessions.get(ct) {
+                Some(session) => writeln!(f, "- {} (session {})", name, session)?,
+                None => writeln!(f, "- {}", name)?,
+            }
         }
         Ok(())
     }
 
-    fn update_status(&mut self) {
-        match self.try_update_status() {
+    fn raise_alert(&self, alert: &Alert) {
+        log!(Handler, Warn, "alert: {}", alert);
+        self.publish(alert.ct, |subs, session, player| subs.alert(alert, session, player));
+        if let Some(ref url) = self.sinks.alert_webhook {
+            alerts::post_webhook(url, alert.to_json());
+        }
+        if let (Some(dir), Some(store)) = (&self.sinks.alert_dump, &self.sinks.store) {
+            match StreamHandlerImpl::try_dump(dir, alert, &store.lock().unwrap()) {
+                Ok(path) => log!(Handler, Info, "alert: wrote recent messages to {}", path),
+                Err(e) => log!(Handler, Error, "failed to write alert dump: {}", e),
+            }
+        }
+    }
+
+    /// Forget connection `ct`, which timed out or was closed, as `event` says.
+    fn end_conn(&mut self, ct: ConnTuple, event: &str) {
+        // `--capture` rules out anonymizing, so `ct` needs no converting.
+        if let Some(ref capture) = self.sinks.capture {
+            capture.lock().unwrap().close(ct);
+        }
+        let ct = self.conn(ct);
+        let how = if event == "timeout" { "timed out" } else { "closed" };
+        log!(Handler, Info, "{:?}: {}", ct, how);
+        self.publish(ct, |subs, session, player| subs.conn_event(event, ct, session, player));
+        let session = self.sinks.sessions.lock().unwrap().get(&ct).cloned();
+        self.log.event(ct, session, event);
+        self.log.close(ct)
+            .unwrap_or_else(|e| log!(Handler, Error, "failed to close log: {}", e));
+        if let Some(ref alerts) = self.sinks.alerts {
+            alerts.lock().unwrap().close(ct);
+        }
+        if let Some(ref injector) = self.sinks.injector {
+            injector.clear(ct);
+        }
+        if let Some(ref roster) = self.sinks.roster {
+            roster.lock().unwrap().close(ct);
+        }
+        if let Some(ref keepalive) = self.sinks.keepalive {
+            keepalive.lock().unwrap().close(ct);
+        }
+        if let Some(ref log) = self.sinks.parse_warnings {
+            log.lock().unwrap().close(ct);
+        }
+        if let Some(ref store) = self.sinks.store {
+            store.lock().unwrap().close(ct);
+        }
+        #[cfg(feature = "grpc")]
+        {
+            if let Some(ref grpc) = self.sinks.grpc {
+                grpc.remove_conn(ct);
+            }
+        }
+        if let Some(ref matches) = self.sinks.matches {
+            matches.lock().unwrap().disconnect(ct).unwrap_or_else(|e| {
+                log!(Handler, Error, "failed to write match record: {}", e)
+            });
+        }
+        let mut names = self.sinks.names.lock().unwrap();
+        if names.remove(&ct).is_some() {
+            self.update_status(&names);
+        }
+        drop(names);
+        self.sinks.versions.lock().unwrap().remove(&ct);
+        self.sinks.sessions.lock().unwrap().remove(&ct);
+    }
+
+
+    fn raise_canary_alert(&self, alert: &CanaryAlert) {
+        log!(Handler, Warn, "canary: {}", alert);
+        self.publish(alert.ct, |subs, session, player| subs.canary(alert, session, player));
+        if let Some(ref url) = self.sinks.alert_webhook {
+            alerts::post_webhook(url, alert.to_json());
+        }
+    }
+
+    /// Write the recent messages of the alerting connection from `store` to a new tfhlog in
+    /// `dir`, returning its path.
+    fn try_dump(dir: &str, alert: &Alert, store: &MessageStore) -> io::Result<String> {
+        let (client, server) = (alert.ct.client(), alert.ct.server());
+        // The cooldown keeps the same connection and opcode from alerting twice in one second.
+        let path = format!("{}/{}-{}-{}-{}-alert-{:02x}.tfhlog",
+            dir, alert.time / 1_000_000, client.ip(), client.port(), server.port(), alert.major);
+        let mut w = tfhlog::Writer::new(File::create(&path)?)?;
+        let q = Query { conns: Some(vec![alert.ct]), limit: usize::MAX, .. Query::default() };
+        for (ct, e) in store.query(&q) {
+            w.write(e.time, ct, store.session(ct), &e.msg)?;
+        }
+        w.flush()?;
+        Ok(path)
+    }
+
+    /// Rewrite `status.txt` from `names`.  The caller holds the lock on `names`, which keeps
+    /// workers from writing the file at the same time.
+    fn update_status(&self, names: &HashMap<ConnTuple, String>) {
+        let sessions = self.sinks.sessions.lock().unwrap();
+        match StreamHandlerImpl::try_update_status(names, &sessions) {
             Ok(()) => {},
             Err(e) => {
-                eprintln!("error: failed to update status.txt: {}", e);
+                log!(Handler, Error, "failed to update status.txt: {}", e);
             },
         }
     }
 }
 
 impl StreamHandler for StreamHandlerImpl {
-    fn on_message(&mut self, ct: ConnTuple, msg: Message) {
-        if msg.header.dir == 0 && msg.header.major == 0x0a {
-            if let Some(name_bytes) = msg.body.get(12 .. 12 + 64) {
-                let len = name_bytes.iter().position(|&b| b == 0).unwrap_or(name_bytes.len());
-                let name = String::from_utf8_lossy(&name_bytes[..len]).into_owned();
-                eprintln!("{:?}: logged in as {}", ct, name);
-                self.names.insert(ct, name);
-                self.update_status();
+    fn log_conn(&self, ct: ConnTuple) -> ConnTuple {
+        self.conn(ct)
+    }
+
+    fn on_connect(&mut self, ct: ConnTuple) {
+        let ct = self.conn(ct);
+        self.publish(ct, |subs, session, player| subs.conn_event("connect", ct, session, player));
+        self.log.event(ct, None, "connect");
+    }
+
+    fn on_message(&mut self, ct: ConnTuple, mut msg: Message) {
+        if let Some(ref capture) = self.sinks.capture {
+            capture.lock().unwrap().on_message(ct, &msg);
+        }
+        let ct = self.conn(ct);
+        let session = self.session(ct, &msg);
+        if let Some(ref anon) = self.sinks.anon {
+            anon.redact(&mut msg);
+        }
+        log!(Handler, Debug, "{:?}: dir {} message {:02x}:{:02x}, {} bytes",
+            ct, msg.header.dir, msg.header.major, msg.header.minor, msg.header.len);
+        let time = msg.time;
+        let known = messages::decode(&msg);
+        if let Some(Known::Login(ref login)) = known {
+            log!(Handler, Info, "{:?}: logged in as {}, client version {}",
+                ct, login.name, login.version);
+            self.sinks.versions.lock().unwrap().insert(ct, login.version);
+            self.set_name(ct, login.name.clone(), "login");
+        }
+        self.check_roster(ct, time, &known);
+        if let Some(ref log) = self.sinks.parse_warnings {
+            log.lock().unwrap().on_message(ct, &msg);
+        }
+        if let Some(ref keepalive) = self.sinks.keepalive {
+            let mut keepalive = keepalive.lock().unwrap();
+            if keepalive.matches(&msg) {
+                if let Some(e) = keepalive.record(ct, msg.header.dir, time) {
+                    log!(Handler, Info, "keepalive: {}", e);
+                }
+            }
+        }
+
+        if let Some(ref matches) = self.sinks.matches {
+            let mut matches = matches.lock().unwrap();
+            match known {
+                Some(Known::MatchStart(ref m)) => {
+                    let name = self.sinks.names.lock().unwrap().get(&ct).cloned();
+                    matches.start(time, m.match_id, ct, name.as_ref().map(|s| s as &str));
+                },
+                Some(Known::MatchEnd(ref m)) => {
+                    matches.end(time, m.match_id, ct, m.won).unwrap_or_else(|e| {
+                        log!(Handler, Error, "failed to write match record: {}", e)
+                    });
+                },
+                _ => {},
+            }
+        }
+
+        if let (Some(ref ratings), Some(Known::Rating(ref r))) = (&self.sinks.ratings, &known) {
+            let name = self.sinks.names.lock().unwrap().get(&ct).cloned()
+                .unwrap_or_else(|| ct.to_string());
+            ratings.lock().unwrap().update(time, &name, r.rating)
+                .unwrap_or_else(|e| log!(Handler, Error, "failed to log rating: {}", e));
+        }
+
+        if let Some(ref chat) = self.sinks.chat {
+            if Chat::matches(&msg, self.sinks.chat_major) {
+                let text = Chat::parse(&msg.body).text;
+                let name = self.sinks.names.lock().unwrap().get(&ct).cloned();
+                chat.lock().unwrap().write(time, ct, name.as_ref().map(|s| s as &str), &text)
+                    .unwrap_or_else(|e| log!(Handler, Error, "failed to log chat: {}", e));
             }
         }
 
-        match self.try_log_message(ct, msg) {
+        let registry = self.sinks.opcodes.as_ref().map(|o| o.get());
+        let opcodes = registry.as_ref().map(|r| {
+            r.for_version(self.sinks.versions.lock().unwrap().get(&ct).copied())
+        });
+        #[cfg(feature = "websocket")]
+        {
+            if let Some(ref ws) = self.sinks.websocket {
+                ws.publish(ct, session, &msg, opcodes);
+            }
+        }
+        if let Some(ref zmq_pub) = self.sinks.zmq_pub {
+            zmq_pub.publish(ct, session, &msg);
+        }
+        self.publish(ct, |subs, session, player| {
+            subs.message(ct, session, player, &msg, opcodes)
+        });
+        #[cfg(feature = "grpc")]
+        {
+            if let Some(ref grpc) = self.sinks.grpc {
+                grpc.publish(ct, session, &msg);
+            }
+        }
+        match self.log.append(ct, session, &msg) {
             Ok(()) => {},
             Err(e) => {
-                eprintln!("error: failed to log message for {:?}: {}", ct, e);
+                log!(Handler, Error, "failed to log message for {:?}: {}", ct, e);
             },
         }
+
+        let alert = self.sinks.alerts.as_ref().and_then(|a| a.lock().unwrap().check(ct, &msg));
+        let canary = self.sinks.canary.as_ref().and_then(|c| c.lock().unwrap().check(ct, &msg));
+
+        if let Some(ref store) = self.sinks.store {
+            store.lock().unwrap().push(time, ct, msg);
+        }
+
+        if let Some(alert) = alert {
+            self.raise_alert(&alert);
+        }
+        if let Some(alert) = canary {
+            self.raise_canary_alert(&alert);
+        }
+    }
+
+    fn rewrite(&mut self, ct: ConnTuple, msg: &mut Message) -> bool {
+        let mut changed = false;
+        for m in &self.sinks.mutators {
+            changed |= m.rewrite(ct, msg);
+        }
+        changed
+    }
+
+    fn next_injection(&mut self, ct: ConnTuple) -> Option<Message> {
+        let ct = self.conn(ct);
+        self.sinks.injector.as_ref()?.take(ct)
     }
 
     fn on_timeout(&mut self, ct: ConnTuple) {
-        eprintln!("{:?}: timed out", ct);
-        self.logs.remove(&ct);
-        if self.names.remove(&ct).is_some() {
-            self.update_status();
+        self.end_conn(ct, "timeout");
+    }
+
+    fn on_close(&mut self, ct: ConnTuple) {
+        self.end_conn(ct, "close");
+    }
+
+    fn resume(&mut self, ct: ConnTuple) -> Option<ResumeState> {
+        // `--snapshot` rules out anonymizing, so `ct` needs no converting.
+        let conn = self.sinks.snapshot.as_ref()?.take(ct, clock::monotonic_us())?;
+        log!(Handler, Info, "{:?}: resumed from snapshot", ct);
+        if let Some(session) = conn.session {
+            self.sinks.sessions.lock().unwrap().insert(ct, session);
+            if let Some(ref store) = self.sinks.store {
+                store.lock().unwrap().open(ct, session);
+            }
+        }
+        self.log.reopen(ct, &conn.logs);
+        if let Some(version) = conn.version {
+            self.sinks.versions.lock().unwrap().insert(ct, version);
+        }
+        match conn.name {
+            Some(ref name) => {
+                if let Some(ref roster) = self.sinks.roster {
+                    roster.lock().unwrap().login(now_us(), ct, name);
+                }
+                self.set_name(ct, name.clone(), "resumed");
+            },
+            None => {
+                self.publish(ct, |subs, session, player| {
+                    subs.conn_event("resumed", ct, session, player)
+                });
+            },
+        }
+        Some(conn.stream)
+    }
+
+    fn on_checkpoint(&mut self, conns: &[(ConnTuple, ResumeState)]) {
+        if let Some(ref watchdog) = self.sinks.disk_watchdog {
+            let worker = *self.watchdog_worker.get_or_insert_with(|| watchdog.register());
+            let open = conns.iter().flat_map(|&(ct, _)| self.log.locations(self.conn(ct)));
+            watchdog.set_open(worker, open.map(|(_, path)| path));
+        }
+
+        let snapshot = match self.sinks.snapshot {
+            Some(ref x) => x,
+            None => return,
+        };
+        let names = self.sinks.names.lock().unwrap();
+        let versions = self.sinks.versions.lock().unwrap();
+        let sessions = self.sinks.sessions.lock().unwrap();
+        let states = conns.iter().map(|&(ct, ref stream)| ConnState {
+            ct,
+            stream: stream.clone(),
+            name: names.get(&ct).cloned(),
+            version: versions.get(&ct).copied(),
+            session: sessions.get(&ct).copied(),
+            logs: self.log.locations(ct),
+        }).collect();
+        drop(names);
+        drop(versions);
+        drop(sessions);
+        let worker = *self.snapshot_worker.get_or_insert_with(|| snapshot.register());
+        snapshot.update(worker, clock::monotonic_us(), states)
+            .unwrap_or_else(|e| log!(Handler, Error, "failed to write snapshot: {}", e));
+    }
+
+    fn on_warning(&mut self, ct: ConnTuple, dir: u8, warning: &StreamWarning) {
+        self.log_parse_warning(ct, dir, |log, cx| log.warning(cx, warning));
+    }
+
+    fn on_stall(&mut self, ct: ConnTuple, dir: u8, stall: &Stall) {
+        self.log_parse_warning(ct, dir, |log, cx| log.stall(cx, stall));
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        if let Some(ref log) = self.sinks.parse_warnings {
+            log.lock().unwrap().advance(now);
+        }
+        self.log.flush()
+            .unwrap_or_else(|e| log!(Handler, Error, "failed to flush logs: {}", e));
+        let mut rotate = false;
+        if let Some(interval) = self.sinks.log_rotate {
+            let last = *self.last_rotate.get_or_insert(now);
+            rotate = now.saturating_sub(last) >= interval;
+        }
+        if let Some(ref watchdog) = self.sinks.disk_watchdog {
+            let n = watchdog.rotations();
+            rotate |= n != self.disk_rotations;
+            self.disk_rotations = n;
+        }
+        if rotate {
+            self.log.rotate().unwrap_or_else(|e| {
+                log!(Handler, Error, "failed to rotate logs: {}", e)
+            });
+            self.last_rotate = Some(now);
+        }
+
+        let keepalive = match self.sinks.keepalive {
+            Some(ref x) => x,
+            None => return,
+        };
+        let mut keepalive = keepalive.lock().unwrap();
+        for e in keepalive.check(now) {
+            log!(Handler, Warn, "keepalive: {}", e);
+        }
+        if let Some(ref path) = self.sinks.keepalive_stats {
+            keepalive.write_json(path, now).unwrap_or_else(|e| {
+                log!(Handler, Error, "failed to write {}: {}", path, e)
+            });
+        }
+    }
+}
+
+/// Whether `process` does anything with `p` besides forwarding it.  The relay's readers write
+/// other packets straight to the opposite side instead of sending them through the processing
+/// thread.  `from_b` is true for packets coming from the server side.
+pub fn should_process(p: &Packet, from_b: bool) -> bool {
+    p.is_tfh_stream() || (from_b && is_server_status(p))
+}
+
+/// Count and log the kinds of packets that `should_process` passes over.
+pub fn note_passthrough(p: &Packet, counters: &Counters) {
+    if !p.is_udp() {
+        return;
+    }
+    let src = SocketAddrV4::new(Ipv4Addr::from(p.ipv4().source_ip()), p.udp().source_port());
+    let dst = SocketAddrV4::new(Ipv4Addr::from(p.ipv4().dest_ip()), p.udp().dest_port());
+    if let Some(msg) = stun::Message::parse(p.udp_payload()) {
+        counters.stun.fetch_add(1, Ordering::Relaxed);
+        // Responses say which public address a peer will use for the match.
+        if msg.mapped.is_some() {
+            log!(Relay, Info, "stun: {} -> {}: {}", src, dst, msg);
+        } else {
+            log!(Relay, Debug, "stun: {} -> {}: {}", src, dst, msg);
         }
+    } else if let Some(env) = sdr::Envelope::parse(p.udp_payload()) {
+        counters.sdr.fetch_add(1, Ordering::Relaxed);
+        log!(Relay, Debug, "sdr: {} -> {}: {}", src, dst, env);
     }
 }
 
-pub fn process(input: Receiver<Input>, output: Sender<Output>) {
-    fs::create_dir_all("logs").unwrap();
+/// Whether `p` might be a server query response (A2S), which `process` edits.
+fn is_server_status(p: &Packet) -> bool {
+    if !p.is_udp() {
+        return false;
+    }
+    let port = p.udp().source_port();
+    port >= 27010 && port <= 27030 && !p.is_sdr()
+}
+
+/// How often to check for timed-out connections, in microseconds.
+pub const TIMEOUT_CHECK_INTERVAL: u64 = 5_000_000;
+
+/// Settings for each worker's `TfhStreamConns`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamOptions {
+    /// See `TfhStreamConns::set_handshake_timeout`.  In microseconds.
+    pub handshake_timeout: Option<u64>,
+    /// See `TfhStreamConns::set_trace`.
+    pub trace: Option<(u32, Option<u16>)>,
+    /// See `TfhStreamConns::set_max_message_len`.  `None` keeps the default.
+    pub max_message_len: Option<usize>,
+    /// See `TfhStreamConns::set_check_packets`.
+    pub check_packets: bool,
+}
+
+impl StreamOptions {
+    pub fn from_config(cfg: &Config) -> StreamOptions {
+        StreamOptions {
+            handshake_timeout: cfg.handshake_timeout.map(|secs| secs * 1_000_000),
+            trace: cfg.trace,
+            max_message_len: cfg.max_message_len,
+            check_packets: cfg.parse_warnings.is_some(),
+        }
+    }
+
+    fn apply<H: StreamHandler>(&self, stream_conns: &mut TfhStreamConns<H>) {
+        stream_conns.set_handshake_timeout(self.handshake_timeout);
+        stream_conns.set_trace(self.trace);
+        if let Some(len) = self.max_message_len {
+            stream_conns.set_max_message_len(len);
+        }
+        stream_conns.set_check_packets(self.check_packets);
+    }
+}
+
+/// Input to a processing worker.
+enum Work {
+    Packet(Input),
+    /// Check for timed-out connections, after advancing the clock to this capture time if
+    /// there is one.  Sent to every worker periodically, since a worker whose connections have
+    /// all gone quiet gets no packets to trigger the check.
+    Tick(Option<u64>),
+}
+
+/// Process `input` on `cfg.workers` threads, or on the current thread if there's just one.
+/// TFH packets are assigned to workers by connection, so each connection's packets are still
+/// handled in order.  Other packets all go to the first worker.
+fn run_workers(
+    cfg: &Config,
+    default_overflow: Overflow,
+    handler: StreamHandlerImpl,
+    stats: &Arc<RelayStats>,
+    input: Receiver<Input>,
+    output: Sender<Output>,
+) {
+    let opts = StreamOptions::from_config(cfg);
+    let n = cfg.workers.unwrap_or(1);
+    if n == 1 {
+        let capture = handler.sinks.capture.clone();
+        return process(handler, opts, capture.as_deref(), stats, input, output);
+    }
 
-    let mut stream_conns = TfhStreamConns::new(StreamHandlerImpl::default());
+    let mut senders = Vec::with_capacity(n);
+    let mut joins = Vec::with_capacity(n);
+    for i in 0 .. n {
+        let (send, recv) = make_channel(cfg, default_overflow);
+        let handler = handler.fork();
+        let output = output.clone();
+        let stats = stats.clone();
+        let capture = handler.sinks.capture.clone();
+        let join = thread::Builder::new().name(format!("worker {}", i))
+            .spawn(move || {
+                health::register(&format!("worker {}", i));
+                affinity::try_pin(Role::Processing);
+                let capture = capture.as_deref();
+                run(handler, opts, capture, &stats, recv.iter(), &output);
+            })
+            .unwrap();
+        senders.push(send);
+        joins.push(join);
+    }
+    drop(output);
 
+    dispatch(input, &senders);
 
-    let mut last_timeout_check = Instant::now();
+    drop(senders);
+    for join in joins {
+        if let Err(e) = join.join() {
+            panic::resume_unwind(e);
+        }
+    }
+}
+
+/// Send each packet from `input` to its worker.  Returns when `input` closes or a worker has
+/// stopped.
+fn dispatch(input: Receiver<Input>, workers: &[Sender<Work>]) {
+    let mut capture_time = None;
+    let mut last_tick = None;
     for inp in input.iter() {
+        health::beat();
+        let (i, time) = {
+            let (p, flip) = match inp {
+                Input::FromA(ref p) => (p, false),
+                Input::FromB(ref p) => (p, true),
+                Input::Checkpoint(ref done) => {
+                    for w in workers {
+                        if w.send(Work::Packet(Input::Checkpoint(done.clone()))).is_err() {
+                            return;
+                        }
+                    }
+                    continue;
+                },
+            };
+            let i = if p.is_tfh_stream() {
+                let mut h = DefaultHasher::new();
+                ConnTuple::from_udp_packet(p, flip).hash(&mut h);
+                (h.finish() % workers.len() as u64) as usize
+            } else {
+                0
+            };
+            (i, p.time())
+        };
+        if let Some(t) = time {
+            capture_time = Some(cmp::max(capture_time.unwrap_or(t), t));
+        }
+
+        if workers[i].send(Work::Packet(inp)).is_err() {
+            return;
+        }
+
+        let now = capture_time.unwrap_or_else(clock::monotonic_us);
+        let last = *last_tick.get_or_insert(now);
+        if now.saturating_sub(last) >= TIMEOUT_CHECK_INTERVAL {
+            for w in workers {
+                if w.send(Work::Tick(capture_time)).is_err() {
+                    return;
+                }
+            }
+            last_tick = Some(now);
+        }
+    }
+}
+
+/// Have every processing worker fed by `input` save its connections' state for `--snapshot`
+/// (see `TfhStreamConns::checkpoint`), as before shutting down, and wait up to `timeout` for them
+/// to finish.  Returns whether they all did.
+pub fn checkpoint(cfg: &Config, input: &Sender<Input>, timeout: Duration) -> bool {
+    let (done, finished) = mpsc::channel();
+    if input.send(Input::Checkpoint(done)).is_err() {
+        return false;
+    }
+    let deadline = Instant::now() + timeout;
+    for _ in 0 .. cfg.workers.unwrap_or(1) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if finished.recv_timeout(left).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+pub fn process(
+    handler: impl StreamHandler,
+    opts: StreamOptions,
+    capture: Option<&Mutex<Capture>>,
+    stats: &RelayStats,
+    input: Receiver<Input>,
+    output: Sender<Output>,
+) {
+    let work = input.iter().map(Work::Packet);
+    run(handler, opts, capture, stats, work, &output);
+}
+
+/// Run the capture at `path` through the processing pipeline, as `replay-pcap` does, and return
+/// each connection's messages, both directions together, in the order they were decoded.
+/// Packets to or from `server` are taken to come from the outside and the inside respectively,
+/// with the pipeline's clock following their timestamps.  Packets tagged with their direction
+/// (see `pcap::MAC_A`) go the way the tag says instead.  Other packets are ignored.
+pub fn decode_capture(
+    path: &str,
+    server: Ipv4Addr,
+) -> Result<Vec<(ConnTuple, Vec<Message>)>, Error> {
+    let mut pcap = Pcap::new(File::open(path).at(path)?).at(path)?;
+    let mut err = None;
+    let work = iter::from_fn(|| loop {
+        let (p, tag) = match pcap.recv_tagged() {
+            Ok(Some(x)) => x,
+            Ok(None) => return None,
+            Err(e) => {
+                err = Some(e);
+                return None;
+            },
+        };
+        if !p.is_ipv4() {
+            continue;
+        }
+        let inp = match tag {
+            Some(Direction::AToB) => Input::FromA(p),
+            Some(Direction::BToA) => Input::FromB(p),
+            None if Ipv4Addr::from(p.ipv4().dest_ip()) == server => Input::FromA(p),
+            None if Ipv4Addr::from(p.ipv4().source_ip()) == server => Input::FromB(p),
+            None => continue,
+        };
+        return Some(Work::Packet(inp));
+    });
+    // Nothing reads the forwarded packets, so they're dropped as the queue fills.
+    let (out_send, _out_recv) = channel::bounded(1, Overflow::DropOldest);
+    let stats = RelayStats::default();
+    let opts = StreamOptions::default();
+    let mut handler = run(CollectingHandler::new(), opts, None, &stats, work, &out_send);
+    if let Some(e) = err {
+        return Err(e).at(path);
+    }
+    let mut conns = Vec::new();
+    for ct in handler.conns().to_owned() {
+        let collected = handler.take(ct).unwrap();
+        conns.push((ct, collected.messages.into_iter().collect()));
+    }
+    Ok(conns)
+}
+
+/// Update the connection counts and round-trip times in `stats` after a timeout check.
+/// `reported` is this worker's share of `stats.handshaking`.  Connections that timed out or
+/// were closed are dropped from `stats.latency.conns`.
+fn report_conns<H: StreamHandler>(
+    stream_conns: &mut TfhStreamConns<H>,
+    stats: &RelayStats,
+    reported: &mut u64,
+) {
+    let n = stream_conns.handshaking() as u64;
+    if n >= *reported {
+        stats.handshaking.fetch_add(n - *reported, Ordering::Relaxed);
+    } else {
+        stats.handshaking.fetch_sub(*reported - n, Ordering::Relaxed);
+    }
+    *reported = n;
+    stats.handshake_timeouts.fetch_add(stream_conns.take_handshake_timeouts(), Ordering::Relaxed);
+    stats.record_marks(stream_conns.peak_buffer_marks());
+
+    let mut latency = stats.latency.lock().unwrap();
+    latency.total.merge(&stream_conns.take_rtt());
+    for ct in stream_conns.take_ended() {
+        latency.conns.remove(&ct);
+    }
+    for (ct, rtt) in stream_conns.rtts() {
+        latency.conns.insert(ct, rtt.clone());
+    }
+}
+
+/// Handle `work` until it runs out, then return the handler.
+fn run<H: StreamHandler>(
+    handler: H,
+    opts: StreamOptions,
+    capture: Option<&Mutex<Capture>>,
+    stats: &RelayStats,
+    work: impl Iterator<Item = Work>,
+    output: &Sender<Output>,
+) -> H {
+    let mut stream_conns = TfhStreamConns::new(handler);
+    opts.apply(&mut stream_conns);
+    let mut reported = 0;
+    let dump_opts = DumpOptions {
+        color: nix::unistd::isatty(1).unwrap_or(false),
+        .. DumpOptions::default()
+    };
+    let record = |p: &Packet, flip: bool, now: u64| {
+        if let Some(capture) = capture {
+            capture.lock().unwrap().record(p, flip, now)
+                .unwrap_or_else(|e| log!(Handler, Error, "failed to write capture: {}", e));
+        }
+    };
+    let flush = || {
+        if let Some(capture) = capture {
+            capture.lock().unwrap().flush()
+                .unwrap_or_else(|e| log!(Handler, Error, "failed to write capture: {}", e));
+        }
+    };
+
+
+    // Timeouts follow the stream clock, which is the capture time when replaying a pcap.
+    let mut last_timeout_check = None;
+    for w in work {
+        health::beat();
+        let inp = match w {
+            Work::Packet(Input::Checkpoint(done)) => {
+                stream_conns.checkpoint();
+                let _ = done.send(());
+                continue;
+            },
+            Work::Packet(inp) => inp,
+            Work::Tick(time) => {
+                if let Some(t) = time {
+                    stream_conns.advance(t);
+                }
+                stream_conns.check_timeout();
+                stream_conns.checkpoint();
+                report_conns(&mut stream_conns, stats, &mut reported);
+                flush();
+                last_timeout_check = Some(stream_conns.now());
+                continue;
+            },
+        };
+
+        // Expire connections before handling the packet, so a capture with a long quiet gap
+        // times out the old connections first, as happened live.
+        let time = match inp {
+            Input::FromA(ref p) | Input::FromB(ref p) => p.time(),
+            Input::Checkpoint(_) => None,
+        };
+        if let Some(t) = time {
+            stream_conns.advance(t);
+        }
+        let now = stream_conns.now();
+        let last = *last_timeout_check.get_or_insert(now);
+        if now.saturating_sub(last) >= TIMEOUT_CHECK_INTERVAL {
+            stream_conns.check_timeout();
+            stream_conns.checkpoint();
+            report_conns(&mut stream_conns, stats, &mut reported);
+            flush();
+            last_timeout_check = Some(now);
+        }
+
         match inp {
-            Input::FromA(p) => {
-                stream_conns.handle(&p, false);
+            Input::FromA(mut p) => {
+                let extra = stream_conns.handle_mut(&mut p, false);
+                record(&p, false, now);
                 output.send(Output::ToB(p)).unwrap();
+                for q in extra {
+                    record(&q, false, now);
+                    output.send(Output::ToB(q)).unwrap();
+                }
             },
 
             Input::FromB(mut p) => {
-                stream_conns.handle(&p, true);
-
-                if p.is_udp() {
-                    let port = p.udp().source_port();
-                    if port >= 27010 && port <= 27030 {
-                        edit_server_status(&mut p)
-                            .unwrap_or_else(|e| eprintln!("status: {}", e));
-                        println!("status: {}", dump_mixed(p.udp_payload()));
+                let extra = stream_conns.handle_mut(&mut p, true);
+
+                if is_server_status(&p) {
+                    edit_server_status(&mut p)
+                        .unwrap_or_else(|e| log!(Handler, Warn, "status: {}", e));
+                    if logging::enabled(Subsystem::Handler, Level::Info) {
+                        println!("status: {}", dump::mixed_with(p.udp_payload(), &dump_opts));
                     }
                 }
 
+                record(&p, true, now);
                 output.send(Output::ToA(p)).unwrap();
+                for q in extra {
+                    record(&q, true, now);
+                    output.send(Output::ToA(q)).unwrap();
+                }
             },
-        }
 
-        let now = Instant::now();
-        if now.duration_since(last_timeout_check).as_secs() >= 5 {
-            stream_conns.check_timeout();
-            last_timeout_check = now;
+            // Handled above.
+            Input::Checkpoint(_) => {},
         }
+
+        let [ab, ba] = stream_conns.take_warnings();
+        stats.a_to_b.parse_warnings.fetch_add(ab, Ordering::Relaxed);
+        stats.b_to_a.parse_warnings.fetch_add(ba, Ordering::Relaxed);
+        let [ab, ba] = stream_conns.take_stalls();
+        stats.a_to_b.stalls.fetch_add(ab, Ordering::Relaxed);
+        stats.b_to_a.stalls.fetch_add(ba, Ordering::Relaxed);
     }
+    // Pick up whatever was measured since the last timeout check.
+    report_conns(&mut stream_conns, stats, &mut reported);
+    stream_conns.checkpoint();
+    stream_conns.into_handler()
 }
 
 macro_rules! require {
@@ -202,60 +1328,3 @@ fn contains_drop_command(p: &Packet) -> bool {
     false
 }
 
-fn dump_hex(b: &[u8]) -> String {
-    if b.len() == 0 {
-        return String::new();
-    }
-
-    let mut s = String::with_capacity(b.len() * 3 - 1);
-    for (i, &x) in b.iter().enumerate() {
-        if i > 0 {
-            s.push(' ');
-        }
-        write!(s, "{:02x}", x).unwrap();
-    }
-    s
-}
-
-fn dump_mixed(b: &[u8]) -> String {
-    if b.len() == 0 {
-        return String::new();
-    }
-
-    let mut out = String::with_capacity(b.len() * 3 - 1);
-    let mut i = 0;
-    while i < b.len() {
-        if i > 0 {
-            out.push(' ');
-        }
-        let x = b[i];
-        if is_printable_ascii(x) {
-            let j = b[i..].iter().position(|&x| !is_printable_ascii(x))
-                .map_or(b.len(), |off| i + off);
-            assert!(j > i);
-            if j >= i + 2 {
-                let s = str::from_utf8(&b[i..j]).unwrap();
-                write!(out, "{:?}", s).unwrap();
-            } else {
-                write!(out, "{:?}", x as char).unwrap();
-            }
-            i = j;
-        } else {
-            write!(out, "{:02x}", x).unwrap();
-            i += 1;
-        }
-    }
-    out
-}
-
-fn is_printable_ascii(x: u8) -> bool {
-    x >= 0x20 && x < 0x7f
-}
-
-
-fn now() -> i64 {
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(dur) => dur.as_secs() as i64,
-        Err(e) => -(e.duration().as_secs() as i64),
-    }
-}
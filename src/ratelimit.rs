@@ -0,0 +1,168 @@
+//! Per-source rate limits for traffic arriving from outside the sandbox.
+//!
+//! Each source IP gets two token buckets, one for packets and one for new connections (UDP flows
+//! whose client port and server address haven't been seen recently).  Packets that find a bucket
+//! empty are dropped before they reach stream processing or the lobby server.  Optionally, a
+//! source that goes over a limit is banned for a while, and everything it sends is dropped.
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use crate::packet::Packet;
+
+
+/// A flow is forgotten, and counts as new again, after this many microseconds without packets.
+const FLOW_TIMEOUT: u64 = 60_000_000;
+
+/// How often to forget idle flows and sources.
+const PRUNE_INTERVAL: u64 = 10_000_000;
+
+/// Buckets hold this many seconds' worth of tokens, which is the largest burst allowed.
+const BURST_SECS: f64 = 2.;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Packets per second from each source IP.
+    pub packets: Option<f64>,
+    /// New connections per second from each source IP.
+    pub conns: Option<f64>,
+    /// Ban sources that go over a limit for this many seconds.
+    pub ban_secs: Option<u64>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: u64,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: u64) -> Bucket {
+        Bucket { tokens: rate * BURST_SECS, last: now }
+    }
+
+    /// Refill at `rate` tokens per second, then take one if there is one.
+    fn take(&mut self, rate: f64, now: u64) -> bool {
+        let secs = now.saturating_sub(self.last) as f64 / 1_000_000.;
+        self.tokens = (self.tokens + rate * secs).min(rate * BURST_SECS);
+        self.last = now;
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+}
+
+struct Source {
+    packets: Bucket,
+    conns: Bucket,
+    banned_until: u64,
+    /// Packets dropped since the source last had one let through.
+    dropped: u64,
+    last_seen: u64,
+}
+
+/// Source IP, source port, destination IP, and destination port.
+type Flow = (u32, u16, u32, u16);
+
+pub struct RateLimiter {
+    limits: Limits,
+    sources: HashMap<u32, Source>,
+    /// Time each known flow last had a packet let through.
+    flows: HashMap<Flow, u64>,
+    last_prune: u64,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            sources: HashMap::new(),
+            flows: HashMap::new(),
+            last_prune: 0,
+        }
+    }
+
+    /// Decide whether to let `p` through.  Packets other than UDP only count against the packet
+    /// limit.
+    pub fn check_packet(&mut self, p: &Packet, now: u64) -> bool {
+        if p.len() < 20 || !p.is_ipv4() {
+            return true;
+        }
+        let src = p.ipv4().source_ip();
+        let flow = if p.is_udp() && p.len() >= p.udp_end() {
+            Some((src, p.udp().source_port(), p.ipv4().dest_ip(), p.udp().dest_port()))
+        } else {
+            None
+        };
+        self.check(src, flow, now)
+    }
+
+    /// Decide whether to let through a datagram from `src` to `dst`.
+    pub fn check_datagram(&mut self, src: SocketAddrV4, dst: SocketAddrV4, now: u64) -> bool {
+        let src_ip = u32::from(*src.ip());
+        self.check(src_ip, Some((src_ip, src.port(), u32::from(*dst.ip()), dst.port())), now)
+    }
+
+    fn check(&mut self, src: u32, flow: Option<Flow>, now: u64) -> bool {
+        if now.saturating_sub(self.last_prune) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+
+        let limits = self.limits;
+        let flows = &mut self.flows;
+        let source = self.sources.entry(src).or_insert_with(|| Source {
+            packets: Bucket::new(limits.packets.unwrap_or(0.), now),
+            conns: Bucket::new(limits.conns.unwrap_or(0.), now),
+            banned_until: 0,
+            dropped: 0,
+            last_seen: now,
+        });
+        source.last_seen = now;
+
+        if source.banned_until > now {
+            source.dropped += 1;
+            return false;
+        }
+
+        let new_flow = flow.map_or(false, |f| !flows.contains_key(&f));
+        let over = if new_flow && limits.conns.map_or(false, |r| !source.conns.take(r, now)) {
+            Some("connection")
+        } else if limits.packets.map_or(false, |r| !source.packets.take(r, now)) {
+            Some("packet")
+        } else {
+            None
+        };
+
+        if let Some(what) = over {
+            if source.dropped == 0 {
+                log!(Relay, Warn, "rate limit: {} is over the {} limit, dropping its packets",
+                    Ipv4Addr::from(src), what);
+            }
+            source.dropped += 1;
+            if let Some(secs) = limits.ban_secs {
+                source.banned_until = now.saturating_add(secs.saturating_mul(1_000_000));
+                log!(Relay, Warn, "rate limit: banning {} for {} seconds",
+                    Ipv4Addr::from(src), secs);
+            }
+            return false;
+        }
+
+        if source.dropped > 0 {
+            log!(Relay, Info, "rate limit: letting {} through again, after dropping {} packets",
+                Ipv4Addr::from(src), source.dropped);
+            source.dropped = 0;
+        }
+        if let Some(f) = flow {
+            flows.insert(f, now);
+        }
+        true
+    }
+
+    fn prune(&mut self, now: u64) {
+        self.last_prune = now;
+        self.flows.retain(|_, &mut last| now.saturating_sub(last) < FLOW_TIMEOUT);
+        // A source idle this long has full buckets again, so it can be recreated from scratch.
+        self.sources.retain(|_, s| {
+            s.banned_until > now || now.saturating_sub(s.last_seen) < FLOW_TIMEOUT
+        });
+    }
+}
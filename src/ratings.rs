@@ -0,0 +1,36 @@
+//! Per-player rating history, written as a CSV time series.
+//!
+//! Each row is `time_us,player,rating`.  A row is written only when a player's rating differs
+//! from the last one seen for them, since the server repeats it on every login.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use crate::export::csv::quote;
+
+
+pub struct RatingTracker {
+    out: File,
+    last: HashMap<String, u32>,
+}
+
+impl RatingTracker {
+    /// Append rows to the file at `path`, writing a header first if it's empty.
+    pub fn new(path: &str) -> io::Result<RatingTracker> {
+        let mut out = OpenOptions::new().create(true).append(true).open(path)?;
+        if out.metadata()?.len() == 0 {
+            writeln!(out, "time_us,player,rating")?;
+        }
+        Ok(RatingTracker {
+            out,
+            last: HashMap::new(),
+        })
+    }
+
+    pub fn update(&mut self, time_us: u64, player: &str, rating: u32) -> io::Result<()> {
+        if self.last.get(player) == Some(&rating) {
+            return Ok(());
+        }
+        self.last.insert(player.to_owned(), rating);
+        writeln!(self.out, "{},{},{}", time_us, quote(player), rating)
+    }
+}
@@ -0,0 +1,276 @@
+//! The relay between the two sides of the sandbox: a reader thread for each side, which forwards
+//! packets that don't need processing straight to the other side and queues the rest for the
+//! processing thread, and a writer thread that sends the processed packets on.  Each side is a
+//! `PacketSource` and `PacketSink` from `transport`, normally a tun device.
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use crate::Error;
+use crate::affinity::{self, Role};
+use crate::channel::{Receiver, RecvTimeoutError, Sender};
+use crate::config::Config;
+use crate::health;
+use crate::pacing::Pacer;
+use crate::packet::Packet;
+use crate::process::{self, Input, Output};
+use crate::stats::{Counters, RelayStats};
+use crate::supervise::{Restart, Supervisor};
+use crate::transport::{PacketSink, PacketSource};
+use crate::util::clock;
+
+
+/// Most packets to hold while a sink isn't accepting writes.
+const MAX_PENDING: usize = 256;
+
+/// How long to wait for a full sink to become writable.
+const WRITABLE_WAIT: Duration = Duration::from_millis(10);
+
+/// Number of failed writes in a row after which the sink is assumed to be broken.
+const MAX_CONSECUTIVE_FAILURES: usize = 100;
+
+/// Number of times per minute a reader or writer thread may fail before the relay gives up.
+const MAX_RESTARTS: usize = 5;
+
+/// Writes packets to a sink, riding out transient errors.  Interrupted writes are retried.  If
+/// the sink is full (`EAGAIN`), packets wait in a small queue until it's writable, and the
+/// oldest are dropped if the queue overflows.  Any other error drops just the failed packet,
+/// unless it keeps happening.
+///
+/// With a `Pacer`, packets beyond its limit wait in the same queue for a later tick.
+struct Writer<K> {
+    sink: K,
+    pending: VecDeque<Packet>,
+    failures: usize,
+    pacer: Option<Pacer>,
+    /// Number of packets at the front of `pending` already counted as paced.
+    paced: usize,
+}
+
+impl<K: PacketSink> Writer<K> {
+    fn new(sink: K) -> Writer<K> {
+        Writer {
+            sink,
+            pending: VecDeque::new(),
+            failures: 0,
+            pacer: None,
+            paced: 0,
+        }
+    }
+
+    fn with_pacer(sink: K, pacer: Option<Pacer>) -> Writer<K> {
+        Writer { pacer, .. Writer::new(sink) }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pending.len() == 0
+    }
+
+    /// How long to wait before calling `flush` again while packets are pending.
+    fn retry_delay(&mut self) -> Duration {
+        let pace = self.pacer.as_mut().and_then(|p| p.delay(Instant::now()));
+        pace.unwrap_or(WRITABLE_WAIT)
+    }
+
+    fn write(&mut self, p: Packet, counters: &Counters) -> Result<(), Error> {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+            self.paced = self.paced.saturating_sub(1);
+            counters.write_failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.pending.push_back(p);
+        self.flush(counters)
+    }
+
+    /// Write pending packets until there are none left, the sink stays full, or the pacer
+    /// holds the rest for a later tick.
+    fn flush(&mut self, counters: &Counters) -> Result<(), Error> {
+        while let Some(p) = self.pending.front() {
+            if let Some(ref mut pacer) = self.pacer {
+                if pacer.delay(Instant::now()).is_some() {
+                    let n = self.pending.len() - self.paced;
+                    counters.paced.fetch_add(n as u64, Ordering::Relaxed);
+                    self.paced = self.pending.len();
+                    return Ok(());
+                }
+            }
+            match self.sink.send(p) {
+                Ok(len) => {
+                    // A tun device takes whole packets, so the rest of a short write can't be
+                    // sent separately.
+                    if len != p.len() {
+                        counters.write_partial.fetch_add(1, Ordering::Relaxed);
+                    }
+                    self.failures = 0;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if self.sink.wait_writable(WRITABLE_WAIT)? {
+                        continue;
+                    }
+                    return Ok(());
+                },
+                Err(e) => {
+                    counters.write_failed.fetch_add(1, Ordering::Relaxed);
+                    self.failures += 1;
+                    if self.failures >= MAX_CONSECUTIVE_FAILURES {
+                        let msg = format!("{} writes failed in a row: {}", self.failures, e);
+                        return Err(Error(msg));
+                    }
+                    if self.failures == 1 {
+                        log!(Relay, Warn, "dropping packet: write failed: {}", e);
+                    }
+                },
+            }
+            self.pending.pop_front();
+            self.paced = self.paced.saturating_sub(1);
+            if let Some(ref mut pacer) = self.pacer {
+                pacer.sent();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read(src: &mut impl PacketSource) -> Result<Option<Packet>, Error> {
+    loop {
+        match src.recv() {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            res => return Ok(res?),
+        }
+    }
+}
+
+/// Relay packets between side A (`a_src` and `a_sink`, outside the sandbox) and side B (inside),
+/// running the reader and writer threads under `sup`.  `inp_send` and `out_recv` connect to the
+/// processing thread.  A reader that reaches the end of its input stops the relay.
+pub fn start<AS, AK, BS, BK>(
+    cfg: &Config,
+    sup: &Supervisor,
+    stats: Arc<RelayStats>,
+    (mut a_src, a_sink): (AS, AK),
+    (mut b_src, b_sink): (BS, BK),
+    inp_send: Sender<Input>,
+    out_recv: Receiver<Output>,
+) -> Result<(), Error>
+where
+    AS: PacketSource + 'static,
+    AK: PacketSink + 'static,
+    BS: PacketSource + 'static,
+    BK: PacketSink + 'static,
+{
+    let inp_send_a = inp_send.clone();
+    let inp_send_b = inp_send;
+
+    let stats_a = stats.clone();
+    let mut dedup_a = cfg.dedup();
+    let mut limiter = cfg.rate_limiter();
+    let b_sink2 = b_sink.try_clone()?;
+    sup.spawn("side A reader", Restart::Limit(MAX_RESTARTS), move || -> Result<(), Error> {
+        affinity::pin(Role::Reader)?;
+        let mut to_b = Writer::new(b_sink2.try_clone()?);
+        while let Some(p) = read(&mut a_src)? {
+            health::beat();
+            if let Some(ref mut d) = dedup_a {
+                if !d.check(&p, clock::monotonic_us()) {
+                    stats_a.a_to_b.duplicates.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+            stats_a.a_to_b.count_packet(p.len());
+            if let Some(ref mut l) = limiter {
+                if !l.check_packet(&p, clock::monotonic_us()) {
+                    stats_a.a_to_b.rate_limited.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+            if !process::should_process(&p, false) {
+                process::note_passthrough(&p, &stats_a.a_to_b);
+                to_b.write(p, &stats_a.a_to_b)?;
+                continue;
+            }
+            inp_send_a.send(Input::FromA(p)).map_err(|_| "processing thread is gone")?;
+        }
+        Ok(())
+    });
+
+    let stats_b = stats.clone();
+    let mut dedup_b = cfg.dedup();
+    let gateway = cfg.gateway.map(u32::from);
+    let (a_sink2, b_sink2) = (a_sink.try_clone()?, b_sink.try_clone()?);
+    sup.spawn("side B reader", Restart::Limit(MAX_RESTARTS), move || -> Result<(), Error> {
+        affinity::pin(Role::Reader)?;
+        let mut to_a = Writer::new(a_sink2.try_clone()?);
+        let mut to_b = Writer::new(b_sink2.try_clone()?);
+        while let Some(p) = read(&mut b_src)? {
+            health::beat();
+            if let Some(ref mut d) = dedup_b {
+                if !d.check(&p, clock::monotonic_us()) {
+                    stats_b.b_to_a.duplicates.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            }
+            stats_b.b_to_a.count_packet(p.len());
+            // Answer pings to the gateway here, so they work before the outside device is
+            // configured, or when the host's firewall drops them.
+            if let Some(reply) = gateway.and_then(|ip| p.icmp_echo_reply(ip)) {
+                to_b.write(reply, &stats_b.a_to_b)?;
+                continue;
+            }
+            if !process::should_process(&p, true) {
+                process::note_passthrough(&p, &stats_b.b_to_a);
+                to_a.write(p, &stats_b.b_to_a)?;
+                continue;
+            }
+            inp_send_b.send(Input::FromB(p)).map_err(|_| "processing thread is gone")?;
+        }
+        Ok(())
+    });
+
+    let pacer = cfg.pacer();
+    sup.spawn("writer", Restart::Limit(MAX_RESTARTS), move || {
+        affinity::pin(Role::Writer)?;
+        let mut to_a = Writer::new(a_sink.try_clone()?);
+        // Only packets toward the server are paced.
+        let mut to_b = Writer::with_pacer(b_sink.try_clone()?, pacer.clone());
+        loop {
+            // While packets are held back, keep retrying them even if nothing new arrives.
+            let out = if to_a.is_idle() && to_b.is_idle() {
+                match out_recv.recv() {
+                    Some(x) => x,
+                    None => return Ok(()),
+                }
+            } else {
+                let delay = match (to_a.is_idle(), to_b.is_idle()) {
+                    (false, false) => cmp::min(to_a.retry_delay(), to_b.retry_delay()),
+                    (false, true) => to_a.retry_delay(),
+                    _ => to_b.retry_delay(),
+                };
+                match out_recv.recv_timeout(delay) {
+                    Ok(x) => x,
+                    Err(RecvTimeoutError::Timeout) => {
+                        to_a.flush(&stats.b_to_a)?;
+                        to_b.flush(&stats.a_to_b)?;
+                        continue;
+                    },
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            };
+            health::beat();
+            // A steady stream one way mustn't hold up packets waiting to go the other way.
+            match out {
+                Output::ToA(p) => {
+                    to_a.write(p, &stats.b_to_a)?;
+                    to_b.flush(&stats.a_to_b)?;
+                },
+                Output::ToB(p) => {
+                    to_b.write(p, &stats.a_to_b)?;
+                    to_a.flush(&stats.b_to_a)?;
+                },
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,90 @@
+//! Active MITM: mutators that edit messages on their way through the relay.
+//!
+//! A `Mutator` is given each decoded message (in both directions) before it's logged or
+//! forwarded, and may change its body in place.  `TfhStreamConns::handle_mut` writes the new
+//! body back into the packets, so the edit has to keep the body the same length.  `NameRewriter`
+//! is the one built in, and is the model for writing others.
+use crate::messages::Login;
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+pub trait Mutator: Send + Sync {
+    /// Edit `msg` in place, returning whether anything changed.
+    fn rewrite(&self, ct: ConnTuple, msg: &mut Message) -> bool;
+}
+
+/// Replaces a player's display name with another.
+///
+/// Names appear in the login message and in the lobby roster the server sends out, both as
+/// NUL-padded fixed-size fields.  The login's name field is at a known offset.  The roster's
+/// layout isn't known, so in other messages this looks for the old name followed by enough NULs
+/// to hold the new one.  Requiring the padding keeps it from touching the name where it appears
+/// inside other text, such as chat, and means the new name always fits the field.
+pub struct NameRewriter {
+    old: Vec<u8>,
+    new: Vec<u8>,
+}
+
+impl NameRewriter {
+    pub fn new(old: &str, new: &str) -> NameRewriter {
+        NameRewriter {
+            old: old.as_bytes().to_owned(),
+            new: new.as_bytes().to_owned(),
+        }
+    }
+
+    /// Does the old name appear at `body[i..]`, with room after it for the new name and a NUL?
+    fn matches_at(&self, body: &[u8], i: usize) -> bool {
+        let span = self.span();
+        if i + span > body.len() || !body[i..].starts_with(&self.old) {
+            return false;
+        }
+        body[i + self.old.len() .. i + span].iter().all(|&b| b == 0)
+    }
+
+    /// Number of bytes the old name and its padding must occupy.
+    fn span(&self) -> usize {
+        self.old.len().max(self.new.len()) + 1
+    }
+
+    /// Overwrite the name at `body[i..]`.
+    fn replace_at(&self, body: &mut [u8], i: usize) {
+        let field = &mut body[i .. i + self.span()];
+        for b in field.iter_mut() {
+            *b = 0;
+        }
+        field[..self.new.len()].copy_from_slice(&self.new);
+    }
+}
+
+impl Mutator for NameRewriter {
+    fn rewrite(&self, _ct: ConnTuple, msg: &mut Message) -> bool {
+        if self.old.is_empty() || msg.body.len() < self.span() {
+            return false;
+        }
+        if Login::matches(msg) {
+            let i = Login::NAME_OFFSET;
+            if self.span() > Login::NAME_LEN || !self.matches_at(&msg.body, i) {
+                return false;
+            }
+            self.replace_at(&mut msg.body, i);
+            return true;
+        }
+
+        let span = self.span();
+        let mut changed = false;
+        let mut i = 0;
+        while i + span <= msg.body.len() {
+            // Skip matches that are the end of a longer name.
+            let after_name = i > 0 && msg.body[i - 1].is_ascii_alphanumeric();
+            if !after_name && self.matches_at(&msg.body, i) {
+                self.replace_at(&mut msg.body, i);
+                changed = true;
+                i += span;
+            } else {
+                i += 1;
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,130 @@
+//! Cross-checks the player names learned from login messages against the lobby roster the server
+//! sends out, with `--check-roster`.
+//!
+//! The two should agree: every connection that logged in should be listed, and every listed
+//! player should have logged in on some connection.  A login that stays unlisted is a ghost, such
+//! as a connection the server has dropped without the relay noticing.  A listed player with no
+//! login usually means the login went by before the relay started, as after a restart in the
+//! middle of a session.  When exactly one connection has no name and exactly one listed player is
+//! unclaimed, that player is taken to be on that connection, so a restarted relay gets its names
+//! back as the rosters come in.
+//!
+//! The roster's opcodes and layout are guesses (see `messages::Roster`), so this is off by
+//! default.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::tfh_stream::ConnTuple;
+
+
+/// How long after a login the roster may still leave the player out, in microseconds, before
+/// the login counts as a ghost.  The server may send the roster before it has processed the
+/// login.
+pub const GRACE_US: u64 = 10_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Finding {
+    /// `ct` logged in as `name`, but the roster doesn't list `name`.
+    Ghost { ct: ConnTuple, name: String },
+    /// The roster lists `name`, but no connection has logged in with that name.
+    Unclaimed { name: String },
+    /// `ct` hasn't logged in, as far as the relay knows, and `name` was the only unclaimed name
+    /// in the roster, so `ct` is now taken to be `name`.
+    Seeded { ct: ConnTuple, name: String },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Finding::Ghost { ct, ref name } =>
+                write!(fmt, "{}: logged in as {}, who isn't in the lobby roster", ct, name),
+            Finding::Unclaimed { ref name } =>
+                write!(fmt, "{} is in the lobby roster, but no connection logged in as them", name),
+            Finding::Seeded { ct, ref name } =>
+                write!(fmt, "{}: named {} from the lobby roster", ct, name),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RosterCheck {
+    /// Names in the lobby, from the last roster and the presence updates since.  `None` until the
+    /// first roster arrives.
+    listed: Option<HashSet<String>>,
+    /// Login name and time of each connection that has one.
+    names: HashMap<ConnTuple, (String, u64)>,
+    /// Connections seen without a login.
+    unnamed: HashSet<ConnTuple>,
+    /// Findings from the last check, so they aren't reported again each time the roster is
+    /// repeated.
+    reported: HashSet<Finding>,
+}
+
+impl RosterCheck {
+    pub fn new() -> RosterCheck {
+        RosterCheck::default()
+    }
+
+    /// Note a message on `ct`, which may not have logged in.
+    pub fn seen(&mut self, ct: ConnTuple) {
+        if !self.names.contains_key(&ct) {
+            self.unnamed.insert(ct);
+        }
+    }
+
+    pub fn login(&mut self, time: u64, ct: ConnTuple, name: &str) {
+        self.unnamed.remove(&ct);
+        self.names.insert(ct, (name.to_owned(), time));
+    }
+
+    pub fn close(&mut self, ct: ConnTuple) {
+        self.unnamed.remove(&ct);
+        self.names.remove(&ct);
+    }
+
+    /// Replace the list of players with `names`, and return anything new that doesn't add up.
+    pub fn roster(&mut self, time: u64, names: &[String]) -> Vec<Finding> {
+        self.listed = Some(names.iter().cloned().collect());
+        self.check(time)
+    }
+
+    /// Add or remove `name` from the list of players, if there's been a roster to add it to.
+    pub fn presence(&mut self, time: u64, name: &str, joined: bool) -> Vec<Finding> {
+        match self.listed {
+            Some(ref mut listed) if joined => { listed.insert(name.to_owned()); },
+            Some(ref mut listed) => { listed.remove(name); },
+            None => return Vec::new(),
+        }
+        self.check(time)
+    }
+
+    fn check(&mut self, time: u64) -> Vec<Finding> {
+        let listed = match self.listed {
+            Some(ref x) => x,
+            None => return Vec::new(),
+        };
+        let mut found = Vec::new();
+        for (&ct, &(ref name, login_time)) in &self.names {
+            if time >= login_time + GRACE_US && !listed.contains(name) {
+                found.push(Finding::Ghost { ct, name: name.clone() });
+            }
+        }
+        let claimed = self.names.values().map(|x| &x.0).collect::<HashSet<_>>();
+        let mut unclaimed = listed.iter().filter(|&name| !claimed.contains(name))
+            .collect::<Vec<_>>();
+        unclaimed.sort();
+        if let ([name], Some(&ct)) = (&unclaimed[..], self.unnamed.iter().next()) {
+            if self.unnamed.len() == 1 {
+                let name = (*name).clone();
+                self.unnamed.clear();
+                self.names.insert(ct, (name.clone(), time));
+                found.push(Finding::Seeded { ct, name });
+                unclaimed.clear();
+            }
+        }
+        found.extend(unclaimed.into_iter().map(|name| Finding::Unclaimed { name: name.clone() }));
+
+        let new = found.iter().filter(|f| !self.reported.contains(f)).cloned().collect();
+        self.reported = found.into_iter().collect();
+        new
+    }
+}
@@ -0,0 +1,101 @@
+//! Setting up the network namespace that the lobby server runs in, by driving the `ip` command.
+//!
+//! The sandbox is a named network namespace containing the inside tun device, with the outside
+//! device left in the current namespace.  The relay connects the two, and traffic from the
+//! sandbox reaches the rest of the world through the outside device's address.
+use std::process::Command;
+use crate::{Error, ErrorAt};
+
+
+pub struct Sandbox {
+    /// Name of the network namespace, as used by `ip netns`.
+    pub netns: String,
+    /// User that will own the tun devices, so the relay and `tun-server` needn't run as root.
+    pub user: Option<String>,
+    pub inside: String,
+    pub outside: String,
+    /// Address of the inside device, with prefix length, such as `192.168.84.2/24`.
+    pub inside_addr: String,
+    /// Address of the outside device.  This is also the sandbox's default gateway.
+    pub outside_addr: String,
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox {
+            netns: "tfh".into(),
+            user: None,
+            inside: "tun-tfh-inside".into(),
+            outside: "tun-tfh-outside".into(),
+            inside_addr: "192.168.84.2/24".into(),
+            outside_addr: "192.168.84.1/24".into(),
+        }
+    }
+}
+
+/// Run `ip` with `args`, returning its stderr as the error if it fails.
+fn ip(args: &[&str]) -> Result<(), Error> {
+    let cmd = format!("ip {}", args.join(" "));
+    eprintln!("+ {}", cmd);
+    let out = Command::new("ip").args(args).output().at("running ip")?;
+    if !out.status.success() {
+        let msg = String::from_utf8_lossy(&out.stderr);
+        return Err(Error(format!("{}: {}", cmd, msg.trim())));
+    }
+    Ok(())
+}
+
+impl Sandbox {
+    fn gateway(&self) -> &str {
+        self.outside_addr.split('/').next().unwrap()
+    }
+
+    fn tuntap_add<'a>(&'a self, name: &'a str) -> Vec<&'a str> {
+        let mut v = vec!["tuntap", "add", "dev", name, "mode", "tun"];
+        if let Some(ref user) = self.user {
+            v.extend_from_slice(&["user", user]);
+        }
+        v
+    }
+
+    /// Run `ip` inside the namespace.
+    fn ip_in_ns(&self, args: &[&str]) -> Result<(), Error> {
+        let mut v = vec!["netns", "exec", &self.netns, "ip"];
+        v.extend_from_slice(args);
+        ip(&v)
+    }
+
+    /// Create the namespace and both tun devices, and configure their addresses and routes.  On
+    /// failure, whatever was already created is removed again.
+    pub fn setup(&self) -> Result<(), Error> {
+        ip(&["netns", "add", &self.netns])?;
+        if let Err(e) = ip(&self.tuntap_add(&self.outside)) {
+            let _ = ip(&["netns", "del", &self.netns]);
+            return Err(e);
+        }
+        let res = self.setup_devices();
+        if res.is_err() {
+            let _ = self.teardown();
+        }
+        res
+    }
+
+    fn setup_devices(&self) -> Result<(), Error> {
+        ip(&["link", "set", "dev", &self.outside, "up"])?;
+        ip(&["addr", "add", "dev", &self.outside, &self.outside_addr])?;
+
+        self.ip_in_ns(&["link", "set", "dev", "lo", "up"])?;
+        self.ip_in_ns(&self.tuntap_add(&self.inside))?;
+        self.ip_in_ns(&["link", "set", "dev", &self.inside, "up"])?;
+        self.ip_in_ns(&["addr", "add", "dev", &self.inside, &self.inside_addr])?;
+        self.ip_in_ns(&["route", "add", "default", "via", self.gateway()])?;
+        Ok(())
+    }
+
+    /// Remove the outside device and the namespace, which takes the inside device with it.
+    pub fn teardown(&self) -> Result<(), Error> {
+        let res = ip(&["link", "del", "dev", &self.outside]);
+        ip(&["netns", "del", &self.netns])?;
+        res
+    }
+}
@@ -0,0 +1,99 @@
+//! Recognizing Valve's Steam networking (SteamNetworkingSockets) UDP traffic, which includes
+//! sessions relayed through the Steam Datagram Relay (SDR).  This traffic shares ports with the
+//! game's own, but isn't a TFH stream, so it's classified and counted separately rather than fed
+//! to the stream parser or the server status editor.
+//!
+//! Only the envelope is parsed.  The layout follows the open-source GameNetworkingSockets
+//! library: a data packet starts with a flags byte with the top bit set, then the recipient's
+//! connection ID and a sequence number, both little-endian; anything else starts with a message
+//! type byte.  Payloads are encrypted, so nothing past the envelope is decoded.
+//!
+//! The message type numbers are provisional.  They come from that library's direct UDP
+//! transport, and haven't been checked against SDR traffic from the game.
+use core::fmt;
+use crate::bytes::Bytes;
+
+
+/// Set in the first byte of every data packet.
+const DATA_FLAG: u8 = 0x80;
+/// Flag bits in a data packet's first byte other than `DATA_FLAG`.  Only "protobuf blob follows"
+/// is defined.
+const DATA_FLAGS_KNOWN: u8 = 0x01;
+/// Flags byte, connection ID, and sequence number.
+const DATA_HEADER_LEN: usize = 7;
+
+/// Connection setup messages are padded to at least this size, so they can't be used to amplify
+/// traffic from a spoofed address.
+const MIN_PADDED_LEN: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MsgType {
+    ChallengeRequest,
+    ChallengeReply,
+    ConnectRequest,
+    ConnectOk,
+    ConnectionClosed,
+    NoConnection,
+}
+
+impl MsgType {
+    fn from_u8(x: u8) -> Option<MsgType> {
+        Some(match x {
+            32 => MsgType::ChallengeRequest,
+            33 => MsgType::ChallengeReply,
+            34 => MsgType::ConnectRequest,
+            35 => MsgType::ConnectOk,
+            36 => MsgType::ConnectionClosed,
+            37 => MsgType::NoConnection,
+            _ => return None,
+        })
+    }
+
+    /// Whether the sender pads this message to `MIN_PADDED_LEN`.
+    fn padded(self) -> bool {
+        self == MsgType::ChallengeRequest || self == MsgType::ConnectRequest
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Envelope {
+    Data {
+        /// The recipient's ID for the connection.
+        to_conn: u32,
+        seq: u16,
+    },
+    Control(MsgType),
+}
+
+impl Envelope {
+    /// Parse the envelope of a UDP payload, or return `None` if it doesn't look like Steam
+    /// networking traffic.
+    pub fn parse(p: &[u8]) -> Option<Envelope> {
+        let first = *p.first()?;
+        if first & DATA_FLAG != 0 {
+            if first & !(DATA_FLAG | DATA_FLAGS_KNOWN) != 0 || p.len() < DATA_HEADER_LEN {
+                return None;
+            }
+            return Some(Envelope::Data { to_conn: p.u32_le(1), seq: p.u16_le(5) });
+        }
+        let ty = MsgType::from_u8(first)?;
+        if ty.padded() && p.len() < MIN_PADDED_LEN {
+            return None;
+        }
+        Some(Envelope::Control(ty))
+    }
+}
+
+impl fmt::Display for Envelope {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Envelope::Data { to_conn, seq } => write!(fmt, "data conn={:08x} seq={}", to_conn, seq),
+            Envelope::Control(ty) => write!(fmt, "{:?}", ty),
+        }
+    }
+}
+
+/// Whether a UDP payload looks like Steam networking traffic.
+pub fn is_sdr(p: &[u8]) -> bool {
+    Envelope::parse(p).is_some()
+}
@@ -0,0 +1,54 @@
+//! Sending several UDP datagrams with one system call.  When processing hands the UDP proxy a run
+//! of packets for the same flow, such as a backlog released after a stall, `sendmmsg` sends them
+//! all at once instead of making a system call for each.
+use std::io;
+use std::mem;
+use std::net::{SocketAddrV4, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use libc::{c_uint, c_void, iovec, mmsghdr, sockaddr_in, socklen_t, AF_INET};
+
+
+/// Most datagrams to pass to one `sendmmsg` call.  This is the kernel's `UIO_MAXIOV`.
+pub const MAX_BATCH: usize = 1024;
+
+fn to_sockaddr_in(addr: SocketAddrV4) -> sockaddr_in {
+    let mut sin: sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = AF_INET as _;
+    sin.sin_port = addr.port().to_be();
+    sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+    sin
+}
+
+/// Send `datagrams` on `sock`, to `dst`, or to the address the socket is connected to if `dst`
+/// is `None`.  Returns how many were sent, which can be fewer than all of them if the socket's
+/// buffer fills up.  An error means the first datagram couldn't be sent.
+pub fn send(
+    sock: &UdpSocket,
+    dst: Option<SocketAddrV4>,
+    datagrams: &[&[u8]],
+) -> io::Result<usize> {
+    let datagrams = &datagrams[.. datagrams.len().min(MAX_BATCH)];
+    let mut sin = dst.map(to_sockaddr_in);
+    let mut iovs = datagrams.iter().map(|d| iovec {
+        iov_base: d.as_ptr() as *mut c_void,
+        iov_len: d.len(),
+    }).collect::<Vec<_>>();
+    let mut msgs = iovs.iter_mut().map(|iov| {
+        let mut m: mmsghdr = unsafe { mem::zeroed() };
+        if let Some(ref mut sin) = sin {
+            m.msg_hdr.msg_name = sin as *mut sockaddr_in as *mut c_void;
+            m.msg_hdr.msg_namelen = mem::size_of::<sockaddr_in>() as socklen_t;
+        }
+        m.msg_hdr.msg_iov = iov;
+        m.msg_hdr.msg_iovlen = 1;
+        m
+    }).collect::<Vec<_>>();
+
+    let n = unsafe {
+        libc::sendmmsg(sock.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as c_uint, 0)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
@@ -0,0 +1,84 @@
+//! Session IDs, which tell apart successive connections from the same address and port.  The
+//! relay gives each connection an ID when its first message arrives, and uses it in log
+//! filenames, tfhlog records, `status.txt`, and the control socket, so a client that reconnects
+//! from the same port after a timeout doesn't get mixed up with its earlier session.
+//!
+//! The ID is the CRC-32 of the connection tuple and the time of that first message, which
+//! doesn't change between builds or Rust versions.  Replaying a capture gives each session the
+//! same ID it had live, so the logs come out the same.
+use std::collections::HashMap;
+use std::fmt;
+use crate::messages::Login;
+use crate::tfh_stream::ConnTuple;
+use crate::tfhlog::Record;
+use crate::util::crc32;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub struct SessionId(pub u32);
+
+impl SessionId {
+    /// The ID of the session of `ct` whose first message arrived at `time`, in microseconds
+    /// since the Unix epoch.  Never zero, which tfhlog records use for "no session".
+    pub fn new(ct: ConnTuple, time: u64) -> SessionId {
+        let crc = crc32::update(crc32::update(0, &ct.as_bytes()), &time.to_be_bytes());
+        SessionId(crc.max(1))
+    }
+
+    /// Parse the 8-digit hex form produced by `Display`.
+    pub fn parse(s: &str) -> Result<SessionId, String> {
+        match u32::from_str_radix(s, 16) {
+            Ok(x) if x != 0 && s.len() == 8 => Ok(SessionId(x)),
+            _ => Err(format!("bad session ID {:?}: expected 8 hex digits", s)),
+        }
+    }
+
+    /// Convert from the value stored in tfhlog records, where zero means none.
+    pub fn from_u32(x: u32) -> Option<SessionId> {
+        if x == 0 { None } else { Some(SessionId(x)) }
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:08x}", self.0)
+    }
+}
+
+
+/// Who played each session in saved logs, learned from the sessions' login messages, so tools
+/// can select a player's traffic by name instead of by connection.  Records from logs too old to
+/// name their connection never belong to a known player.
+#[derive(Clone, Debug, Default)]
+pub struct Players {
+    names: HashMap<(ConnTuple, Option<SessionId>), String>,
+}
+
+impl Players {
+    /// Note the player's name if `r` is a login message.
+    pub fn add(&mut self, r: &Record) {
+        let ct = match r.conn {
+            Some(x) if Login::matches(&r.msg) => x,
+            _ => return,
+        };
+        if let Some(login) = Login::parse(&r.msg.body) {
+            self.names.insert((ct, r.session), login.name);
+        }
+    }
+
+    /// The name the player of `r`'s session logged in as.
+    pub fn name(&self, r: &Record) -> Option<&str> {
+        let ct = r.conn?;
+        self.names.get(&(ct, r.session)).map(|s| s as &str)
+    }
+
+    /// The sessions whose player logged in as `name`.
+    pub fn sessions(&self, name: &str) -> Vec<(ConnTuple, Option<SessionId>)> {
+        let mut v = self.names.iter()
+            .filter(|&(_, n)| n == name)
+            .map(|(&k, _)| k)
+            .collect::<Vec<_>>();
+        v.sort_by_key(|&(ct, session)| (ct.as_bytes(), session));
+        v
+    }
+}
@@ -0,0 +1,181 @@
+//! Deterministic runs of the whole processing pipeline, for testing.  A `Sim` collects a script
+//! of packets from either side, each stamped with the time on a virtual clock, then feeds them to
+//! `process::process` and collects everything it produces in memory: the forwarded packets, the
+//! handler, and the stats.
+//!
+//! Packets carry their virtual time as a capture timestamp, so the pipeline's clock follows the
+//! script exactly as it follows a pcap being replayed.  In particular, connections time out only
+//! when a later packet arrives, at least `process::TIMEOUT_CHECK_INTERVAL` after the last check,
+//! just as they do live.
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::channel::{self, Overflow};
+use crate::latency::Rtt;
+use crate::packet::Packet;
+use crate::process::{self, Input, Output, StreamOptions};
+use crate::stats::{RelaySnapshot, RelayStats};
+use crate::tfh_stream::{
+    ConnTuple, Message, ResumeState, Stall, StreamHandler, StreamWarning,
+};
+
+
+pub struct Sim {
+    now: u64,
+    script: Vec<Input>,
+    handshake_timeout: Option<u64>,
+}
+
+/// Everything a `Sim` run produced.
+pub struct Outcome<H> {
+    /// The handler, with whatever it recorded.
+    pub handler: H,
+    /// Packets in the order the pipeline sent them.
+    pub outputs: Vec<Output>,
+    pub stats: RelaySnapshot,
+    /// Round-trip times measured over the whole run.
+    pub rtt: Rtt,
+}
+
+impl Sim {
+    /// Start a script with the virtual clock at `start`, in microseconds since the Unix epoch.
+    pub fn new(start: u64) -> Sim {
+        Sim {
+            now: start,
+            script: Vec::new(),
+            handshake_timeout: None,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Move the virtual clock forward by `us` microseconds.
+    pub fn advance(&mut self, us: u64) {
+        self.now += us;
+    }
+
+    /// As for `TfhStreamConns::set_handshake_timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<u64>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Add a packet arriving from the outside at the current virtual time.
+    pub fn from_a(&mut self, mut p: Packet) {
+        p.set_time(Some(self.now));
+        self.script.push(Input::FromA(p));
+    }
+
+    /// Add a packet arriving from the inside at the current virtual time.
+    pub fn from_b(&mut self, mut p: Packet) {
+        p.set_time(Some(self.now));
+        self.script.push(Input::FromB(p));
+    }
+
+    /// Run the script through `process::process` with `handler`.
+    pub fn run<H: StreamHandler + Send>(self, handler: H) -> Outcome<H> {
+        // Queue the whole script up front, then collect the output while the pipeline runs.
+        let (inp_send, inp_recv) = channel::bounded(self.script.len().max(1), Overflow::Block);
+        let (out_send, out_recv) = channel::bounded(channel::DEFAULT_CAPACITY, Overflow::Block);
+        for inp in self.script {
+            inp_send.send(inp).unwrap();
+        }
+        drop(inp_send);
+
+        let shared = Shared(Arc::new(Mutex::new(handler)));
+        let stats = RelayStats::default();
+        let opts = StreamOptions {
+            handshake_timeout: self.handshake_timeout,
+            .. StreamOptions::default()
+        };
+        let outputs = thread::scope(|s| {
+            let handler = shared.clone();
+            let stats = &stats;
+            s.spawn(move || {
+                process::process(handler, opts, None, stats, inp_recv, out_send)
+            });
+            out_recv.iter().collect()
+        });
+
+        let handler = match Arc::try_unwrap(shared.0) {
+            Ok(m) => m.into_inner().unwrap(),
+            Err(_) => unreachable!("handler still in use after the pipeline stopped"),
+        };
+        let snap = stats.snapshot();
+        let rtt = stats.latency.into_inner().unwrap().total;
+        Outcome { handler, outputs, stats: snap, rtt }
+    }
+}
+
+impl<H> Outcome<H> {
+    /// Packets sent to the outside.
+    pub fn to_a(&self) -> impl Iterator<Item = &Packet> {
+        self.outputs.iter().filter_map(|o| match *o {
+            Output::ToA(ref p) => Some(p),
+            Output::ToB(_) => None,
+        })
+    }
+
+    /// Packets sent to the inside.
+    pub fn to_b(&self) -> impl Iterator<Item = &Packet> {
+        self.outputs.iter().filter_map(|o| match *o {
+            Output::ToB(ref p) => Some(p),
+            Output::ToA(_) => None,
+        })
+    }
+}
+
+/// Lets the caller get the handler back from `process::process`, which consumes it.
+struct Shared<H>(Arc<Mutex<H>>);
+
+impl<H> Clone for Shared<H> {
+    fn clone(&self) -> Shared<H> {
+        Shared(self.0.clone())
+    }
+}
+
+impl<H: StreamHandler> StreamHandler for Shared<H> {
+    fn on_connect(&mut self, ct: ConnTuple) {
+        self.0.lock().unwrap().on_connect(ct)
+    }
+
+    fn on_message(&mut self, ct: ConnTuple, msg: Message) {
+        self.0.lock().unwrap().on_message(ct, msg)
+    }
+
+    fn rewrite(&mut self, ct: ConnTuple, msg: &mut Message) -> bool {
+        self.0.lock().unwrap().rewrite(ct, msg)
+    }
+
+    fn next_injection(&mut self, ct: ConnTuple) -> Option<Message> {
+        self.0.lock().unwrap().next_injection(ct)
+    }
+
+    fn on_timeout(&mut self, ct: ConnTuple) {
+        self.0.lock().unwrap().on_timeout(ct)
+    }
+
+    fn on_stall(&mut self, ct: ConnTuple, dir: u8, stall: &Stall) {
+        self.0.lock().unwrap().on_stall(ct, dir, stall)
+    }
+
+    fn on_warning(&mut self, ct: ConnTuple, dir: u8, warning: &StreamWarning) {
+        self.0.lock().unwrap().on_warning(ct, dir, warning)
+    }
+
+    fn on_close(&mut self, ct: ConnTuple) {
+        self.0.lock().unwrap().on_close(ct)
+    }
+
+    fn resume(&mut self, ct: ConnTuple) -> Option<ResumeState> {
+        self.0.lock().unwrap().resume(ct)
+    }
+
+    fn on_checkpoint(&mut self, conns: &[(ConnTuple, ResumeState)]) {
+        self.0.lock().unwrap().on_checkpoint(conns)
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        self.0.lock().unwrap().on_tick(now)
+    }
+}
@@ -0,0 +1,461 @@
+//! Where the processing handler stores each session's messages.  `MessageSink` is the interface,
+//! and `--log-sink NAME` picks an implementation by name (see `open`):
+//!
+//!  - `tfhlog`, the default: one tfhlog per session under `logs/`, divided up as `LogLayout` says
+//!  - `none`: don't store messages at all, for when the other outputs are enough
+//!  - `memory`: keep every message in a `Vec` (`MemorySink`), for tests and programs embedding
+//!    the handler
+//!
+//! With `--features kafka`, `--kafka` adds the Kafka producer (`kafka::Sink`) alongside the sink
+//! named here, through `Tee`.  Other storage goes in as another implementation and another name
+//! in `open`.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter};
+use crate::{Error, ErrorAt};
+use crate::log_index;
+use crate::log_layout::{self, Dest, LogLayout};
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::tfhlog::{self, Record};
+
+
+/// Storage for the messages of every session handled by one processing worker.  Each worker has
+/// its own, made with `fork`, and each connection is only ever given to one of them.
+pub trait MessageSink: Send {
+    /// Store `msg`, which arrived on `ct` in `session`.
+    fn append(&mut self, ct: ConnTuple, session: SessionId, msg: &Message) -> io::Result<()>;
+    /// Make sure everything appended so far is stored.  Called every few seconds.
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    /// Start storing messages afresh, such as in new files, leaving what's stored so far as it
+    /// is.  Called every `--log-rotate` seconds.
+    fn rotate(&mut self) -> io::Result<()> { Ok(()) }
+    /// `ct` is finished with, so anything kept for it can be released.
+    fn close(&mut self, _ct: ConnTuple) -> io::Result<()> { Ok(()) }
+    /// `ct` connected (`"connect"`) or ended, as `event` says, such as `"timeout"`.  `session` is
+    /// `None` until the connection's first message.
+    fn event(&mut self, _ct: ConnTuple, _session: Option<SessionId>, _event: &str) {}
+    /// Another sink storing to the same place, for another worker.
+    fn fork(&self) -> Box<dyn MessageSink>;
+
+    /// Where the messages of `ct` are stored, as a path for each log class, with `None` for the
+    /// main log.  Saved in the `--snapshot` file, so the next run can carry on with them.
+    fn locations(&self, _ct: ConnTuple) -> Vec<(Option<String>, String)> { Vec::new() }
+    /// Carry on storing the messages of `ct` in `locations`, as returned by `locations` in the
+    /// previous run.
+    fn reopen(&mut self, _ct: ConnTuple, _locations: &[(Option<String>, String)]) {}
+}
+
+/// Where the `tfhlog` sink writes its logs, unless `--log-dir` says otherwise.
+pub const LOG_DIR: &str = "logs";
+
+/// The sink called `name`, storing under `dir` and dividing messages as `layout` says if it has
+/// files of its own.
+pub fn open(name: &str, dir: &str, layout: &LogLayout) -> Result<Box<dyn MessageSink>, Error> {
+    match name {
+        "tfhlog" => Ok(Box::new(TfhlogSink::new(dir, layout)?)),
+        "none" => Ok(Box::new(NullSink)),
+        "memory" => Ok(Box::new(MemorySink::default())),
+        _ => Err(Error(format!("unknown log sink {:?}", name))),
+    }
+}
+
+/// One log file, open for appending.
+struct OpenLog {
+    path: String,
+    w: tfhlog::Writer<File>,
+    /// The log's index, with `--log-index`.
+    index: Option<log_index::Writer<BufWriter<File>>>,
+}
+
+impl OpenLog {
+    fn create(base: &str, layout: &LogLayout) -> io::Result<OpenLog> {
+        let (file, path) = create_log_file(base)?;
+        let w = tfhlog::Writer::with_sampling(file, &layout.sampling)?;
+        let index = if layout.index { Some(create_index(&path)?) } else { None };
+        Ok(OpenLog { path, w, index })
+    }
+
+    fn write(
+        &mut self,
+        ct: ConnTuple,
+        session: SessionId,
+        msg: &Message,
+        header_only: bool,
+    ) -> io::Result<()> {
+        let offset = self.w.position();
+        if header_only {
+            self.w.write_header_only(msg.time, ct, Some(session), msg)?;
+        } else {
+            self.w.write(msg.time, ct, Some(session), msg)?;
+        }
+        if let Some(ref mut index) = self.index {
+            let end = self.w.position();
+            index.write(&log_index::Entry::new(offset, end, msg.time, &msg.header))?;
+        }
+        Ok(())
+    }
+}
+
+/// The log files of one session: the main log, and one for each log class.  Each is created when
+/// its first message arrives.
+struct SessionLogs {
+    /// Path of the main log, without the extension.  The class logs add `-CLASS` to it.
+    base: String,
+    main: Option<OpenLog>,
+    classes: Vec<Option<OpenLog>>,
+    /// Messages seen of each sampled direction and opcode, logged or not.
+    sampled: HashMap<(u8, u8, u8), u64>,
+    /// Bytes and messages logged in full, counted against the caps in `LogLayout`.
+    bytes: u64,
+    messages: u64,
+    /// Whether a cap has been reached, so only headers are logged.
+    capped: bool,
+}
+
+impl SessionLogs {
+    fn new(base: String, layout: &LogLayout) -> SessionLogs {
+        SessionLogs {
+            base,
+            main: None,
+            classes: layout.classes.iter().map(|_| None).collect(),
+            sampled: HashMap::new(),
+            bytes: 0,
+            messages: 0,
+            capped: false,
+        }
+    }
+}
+
+/// Writes a tfhlog for each session in a directory, with its messages divided between the main
+/// log and the class logs, and sampled, as `LogLayout` says.
+pub struct TfhlogSink {
+    dir: String,
+    layout: LogLayout,
+    logs: HashMap<ConnTuple, SessionLogs>,
+}
+
+impl TfhlogSink {
+    pub fn new(dir: &str, layout: &LogLayout) -> Result<TfhlogSink, Error> {
+        fs::create_dir_all(dir).at(&format!("creating {} directory", dir))?;
+        Ok(TfhlogSink { dir: dir.to_owned(), layout: layout.clone(), logs: HashMap::new() })
+    }
+}
+
+impl MessageSink for TfhlogSink {
+    fn append(&mut self, ct: ConnTuple, session: SessionId, msg: &Message) -> io::Result<()> {
+        let layout = &self.layout;
+        let dest = layout.dest(msg);
+        if dest == Dest::Skip {
+            return Ok(());
+        }
+        let dir = &self.dir;
+        let logs = self.logs.entry(ct).or_insert_with(|| {
+            let (client, server) = (ct.client(), ct.server());
+            let base = format!("{}/{}-{}-{}-{}-{}",
+                dir, msg.time / 1_000_000, client.ip(), client.port(), server.port(), session);
+            SessionLogs::new(base, layout)
+        });
+        let rate = log_layout::sample_rate(&layout.sampling, msg);
+        if rate > 1 {
+            let h = &msg.header;
+            let seen = logs.sampled.entry((h.dir, h.major, h.minor)).or_insert(0);
+            *seen += 1;
+            // Keep the first of each `rate` messages, so a replay samples the same ones.
+            if (*seen - 1) % rate as u64 != 0 {
+                return Ok(());
+            }
+        }
+        let (log, base) = match dest {
+            Dest::Class(i) => {
+                (&mut logs.classes[i], format!("{}-{}", logs.base, layout.classes[i].0))
+            },
+            _ => (&mut logs.main, logs.base.clone()),
+        };
+        if log.is_none() {
+            *log = Some(OpenLog::create(&base, layout)?);
+        }
+
+        if !logs.capped && layout.capped(logs.bytes, logs.messages) {
+            logs.capped = true;
+            log!(Handler, Warn, "{:?}: logged {} messages in {} bytes, logging only headers now",
+                ct, logs.messages, logs.bytes);
+        }
+        let log = log.as_mut().unwrap();
+        if logs.capped {
+            return log.write(ct, session, msg, true);
+        }
+        let len = tfhlog::RECORD_HEADER_LEN + msg.body.len() + tfhlog::RECORD_TRAILER_LEN;
+        logs.bytes += len as u64;
+        logs.messages += 1;
+        log.write(ct, session, msg, false)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Only the indexes are buffered.
+        for logs in self.logs.values_mut() {
+            for log in logs.main.iter_mut().chain(logs.classes.iter_mut().flatten()) {
+                if let Some(ref mut index) = log.index {
+                    index.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // The next message of each session opens a new file, which `create_log_file` gives a
+        // `-N` suffix.  The sampling counts carry on, so sampling doesn't restart.
+        for logs in self.logs.values_mut() {
+            logs.main = None;
+            for log in &mut logs.classes {
+                *log = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, ct: ConnTuple) -> io::Result<()> {
+        self.logs.remove(&ct);
+        Ok(())
+    }
+
+    fn fork(&self) -> Box<dyn MessageSink> {
+        Box::new(TfhlogSink {
+            dir: self.dir.clone(),
+            layout: self.layout.clone(),
+            logs: HashMap::new(),
+        })
+    }
+
+    fn locations(&self, ct: ConnTuple) -> Vec<(Option<String>, String)> {
+        let mut locations = Vec::new();
+        if let Some(l) = self.logs.get(&ct) {
+            if let Some(ref log) = l.main {
+                locations.push((None, log.path.clone()));
+            }
+            for (class, log) in self.layout.classes.iter().zip(&l.classes) {
+                if let Some(ref log) = *log {
+                    locations.push((Some(class.0.clone()), log.path.clone()));
+                }
+            }
+        }
+        locations
+    }
+
+    /// Reopen the logs to append to them.  Logs that can't be opened, or were written in an
+    /// older format, are started afresh when they're next needed.  The sizes of the reopened logs
+    /// count against `--log-max-bytes`, but the message count starts over.
+    fn reopen(&mut self, ct: ConnTuple, locations: &[(Option<String>, String)]) {
+        let classes = &self.layout.classes;
+        let mut logs = SessionLogs::new(String::new(), &self.layout);
+        for &(ref class, ref path) in locations {
+            let base = path.trim_end_matches(".tfhlog");
+            let slot = match *class {
+                None => {
+                    logs.base = base.to_owned();
+                    &mut logs.main
+                },
+                Some(ref class) => {
+                    let i = match classes.iter().position(|c| &c.0 == class) {
+                        Some(i) => i,
+                        // Not a class any more.
+                        None => continue,
+                    };
+                    if logs.base.is_empty() {
+                        logs.base = base.trim_end_matches(&format!("-{}", class) as &str)
+                            .to_owned();
+                    }
+                    &mut logs.classes[i]
+                },
+            };
+            match reopen_log(path, &self.layout) {
+                Ok(log) => {
+                    logs.bytes += log.w.position();
+                    *slot = Some(log);
+                },
+                Err(e) => log!(Handler, Warn, "{:?}: can't reopen log {}: {}", ct, path, e),
+            }
+        }
+        if !logs.base.is_empty() {
+            self.logs.insert(ct, logs);
+        }
+    }
+}
+
+/// Open the log at `path` for appending, if it's in the current format, sampled as `layout` says,
+/// and its last records are undamaged.  A record cut short at the end, as a crash leaves, is cut
+/// off, since nothing appended after it could be read.  Only the records past the end of the
+/// log's index, or with no index, those near the end of the log (see `Reader::seek_tail`), are
+/// read, so a long log doesn't hold up the worker.  With `layout.index`, the records the index
+/// has fallen behind on are added to it, and a missing or damaged index is written afresh.
+fn reopen_log(path: &str, layout: &LogLayout) -> io::Result<OpenLog> {
+    let f = OpenOptions::new().read(true).append(true).open(path)?;
+    let len = f.metadata()?.len();
+    let mut r = tfhlog::Reader::new(BufReader::new(&f))?;
+    if r.version() != tfhlog::VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("written in tfhlog version {}", r.version())));
+    }
+    // The header's sampling table has to describe every record, so a log sampled differently
+    // can't be added to.
+    if r.sampling() != &layout.sampling[..] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "sampled differently"));
+    }
+    let mut index = None;
+    if layout.index {
+        match log_index::reopen(&log_index::path(path), len, r.position()) {
+            Ok((w, end)) => {
+                r.seek(end)?;
+                index = Some(w);
+            },
+            // Index the whole log.
+            Err(_) => index = Some(create_index(path)?),
+        }
+    } else {
+        r.seek_tail(len)?;
+    }
+    loop {
+        let offset = r.position();
+        match r.read() {
+            Ok(Some(rec)) => {
+                if let Some(ref mut w) = index {
+                    let end = r.position();
+                    w.write(&log_index::Entry::new(offset, end, rec.time, &rec.msg.header))?;
+                }
+            },
+            Ok(None) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                log!(Handler, Warn, "{}: cutting off a partial record at byte {}",
+                    path, r.position());
+                f.set_len(r.position())?;
+                break;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    let len = f.metadata()?.len();
+    Ok(OpenLog { path: path.to_owned(), w: tfhlog::Writer::append(f, len), index })
+}
+
+/// Start the index of the log at `path`, replacing any there was.
+fn create_index(path: &str) -> io::Result<log_index::Writer<BufWriter<File>>> {
+    let f = File::create(log_index::path(path))?;
+    log_index::Writer::new(BufWriter::new(f))
+}
+
+/// Create `base.tfhlog`, or `base-N.tfhlog` if that's taken, so a connection that reconnects
+/// within the same second doesn't overwrite its earlier log.  Returns the file and its path.
+fn create_log_file(base: &str) -> io::Result<(File, String)> {
+    let mut name = format!("{}.tfhlog", base);
+    for i in 1 .. {
+        match OpenOptions::new().write(true).create_new(true).open(&name) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                name = format!("{}-{}.tfhlog", base, i);
+            },
+            res => return res.map(|f| (f, name)),
+        }
+    }
+    unreachable!()
+}
+
+/// Stores nothing.
+pub struct NullSink;
+
+impl MessageSink for NullSink {
+    fn append(&mut self, _ct: ConnTuple, _session: SessionId, _msg: &Message) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn fork(&self) -> Box<dyn MessageSink> {
+        Box::new(NullSink)
+    }
+}
+
+/// Gives everything to each of several sinks, stopping at none of their errors.  The first error
+/// is returned.
+pub struct Tee(pub Vec<Box<dyn MessageSink>>);
+
+impl Tee {
+    fn each(
+        &mut self,
+        mut f: impl FnMut(&mut dyn MessageSink) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut res = Ok(());
+        for sink in &mut self.0 {
+            let r = f(&mut **sink);
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+}
+
+impl MessageSink for Tee {
+    fn append(&mut self, ct: ConnTuple, session: SessionId, msg: &Message) -> io::Result<()> {
+        self.each(|s| s.append(ct, session, msg))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.each(|s| s.flush())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.each(|s| s.rotate())
+    }
+
+    fn close(&mut self, ct: ConnTuple) -> io::Result<()> {
+        self.each(|s| s.close(ct))
+    }
+
+    fn event(&mut self, ct: ConnTuple, session: Option<SessionId>, event: &str) {
+        for sink in &mut self.0 {
+            sink.event(ct, session, event);
+        }
+    }
+
+    fn fork(&self) -> Box<dyn MessageSink> {
+        Box::new(Tee(self.0.iter().map(|s| s.fork()).collect()))
+    }
+
+    fn locations(&self, ct: ConnTuple) -> Vec<(Option<String>, String)> {
+        self.0.iter().flat_map(|s| s.locations(ct)).collect()
+    }
+
+    fn reopen(&mut self, ct: ConnTuple, locations: &[(Option<String>, String)]) {
+        for sink in &mut self.0 {
+            sink.reopen(ct, locations);
+        }
+    }
+}
+
+/// Keeps every message in memory, as tfhlog records.  Forks start out empty.
+#[derive(Default)]
+pub struct MemorySink {
+    pub records: Vec<Record>,
+    /// Times `rotate` has been called.
+    pub rotations: usize,
+}
+
+impl MessageSink for MemorySink {
+    fn append(&mut self, ct: ConnTuple, session: SessionId, msg: &Message) -> io::Result<()> {
+        self.records.push(Record {
+            time: msg.time,
+            conn: Some(ct),
+            session: Some(session),
+            msg: msg.clone(),
+            body_omitted: false,
+        });
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.rotations += 1;
+        Ok(())
+    }
+
+    fn fork(&self) -> Box<dyn MessageSink> {
+        Box::new(MemorySink::default())
+    }
+}
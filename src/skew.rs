@@ -0,0 +1,87 @@
+//! Lining up the clocks of different sources of messages, such as a relay's logs and a capture
+//! taken on another host, so their timelines can be merged.  Each source gets an offset added to
+//! its times, either given by hand or estimated from the messages both sources saw.
+//!
+//! A message seen by two sources is recognized by its connection, direction, and index, plus its
+//! opcodes and body to be safe.  Indices only agree when both sources saw each connection from
+//! its start, as the relay's own logs and a capture of the whole session do.
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+/// Identifies one message across sources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EventKey {
+    pub ct: ConnTuple,
+    pub dir: u8,
+    pub index: u64,
+    pub opcodes: (u8, u8),
+    pub body_hash: u64,
+}
+
+impl EventKey {
+    pub fn new(ct: ConnTuple, msg: &Message) -> EventKey {
+        let mut h = DefaultHasher::new();
+        msg.body.hash(&mut h);
+        EventKey {
+            ct,
+            dir: msg.header.dir,
+            index: msg.index,
+            opcodes: (msg.header.major, msg.header.minor),
+            body_hash: h.finish(),
+        }
+    }
+}
+
+/// Parse an offset: a number of seconds, like `1.5` or `-2`, or a number with a unit of `s`,
+/// `ms`, or `us`.  Returns microseconds.
+pub fn parse_offset(s: &str) -> Result<i64, String> {
+    let (num, scale) = if let Some(x) = s.strip_suffix("us") {
+        (x, 1.)
+    } else if let Some(x) = s.strip_suffix("ms") {
+        (x, 1e3)
+    } else {
+        (s.strip_suffix('s').unwrap_or(s), 1e6)
+    };
+    let n = num.trim_start_matches('+').parse::<f64>()
+        .map_err(|e| format!("bad offset {:?}: {}", s, e))?;
+    if !n.is_finite() {
+        return Err(format!("bad offset {:?}", s));
+    }
+    Ok((n * scale).round() as i64)
+}
+
+/// `offset` microseconds as text that `parse_offset` reads back.
+pub fn format_offset(offset: i64) -> String {
+    format!("{:+}ms", offset as f64 / 1e3)
+}
+
+/// `time` moved by `offset`, clamped to the range of `u64`.
+pub fn apply(time: u64, offset: i64) -> u64 {
+    if offset >= 0 {
+        time.saturating_add(offset as u64)
+    } else {
+        time.saturating_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+/// Estimate the offset to add to the times in `other` to line them up with those in
+/// `reference`, as the median difference over the messages both contain.  Returns the offset
+/// and the number of messages it's based on, or `None` if there are none in common.
+pub fn estimate(
+    reference: &HashMap<EventKey, u64>,
+    other: &[(EventKey, u64)],
+) -> Option<(i64, usize)> {
+    let mut diffs = other.iter()
+        .filter_map(|&(k, t)| reference.get(&k).map(|&r| r as i64 - t as i64))
+        .collect::<Vec<_>>();
+    if diffs.is_empty() {
+        return None;
+    }
+    diffs.sort();
+    let n = diffs.len();
+    let median = if n % 2 == 1 { diffs[n / 2] } else { (diffs[n / 2 - 1] + diffs[n / 2]) / 2 };
+    Some((median, n))
+}
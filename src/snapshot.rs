@@ -0,0 +1,243 @@
+//! Per-connection state saved with `--snapshot FILE`, so restarting the relay in the middle of a
+//! session doesn't lose track of its connections.  The processing workers hand their connections
+//! to `SnapshotFile` every few seconds, and the last of them to do so rewrites the file, if
+//! anything changed.  The relay has them all hand them over once more on SIGINT or SIGTERM (see
+//! `process::checkpoint`).  At startup, each connection in it is picked up where it left off
+//! when its next packet arrives: its player name, client version and session are restored, its
+//! messages go on being appended to the same logs, and its streams resume decoding at the saved
+//! message boundaries instead of having to find their place again.
+//!
+//! If the file is a few seconds old, as after a crash, a stream whose first packet after the
+//! restart starts past the saved position falls back to finding its place as usual (see
+//! `TfhStream::resume_at`).  Connections that have been quiet for longer than `MAX_AGE` aren't
+//! resumed.
+//!
+//! The file is text, one connection after another:
+//!
+//! ```text
+//! conn 10.0.0.5:5001 -> 192.168.84.2:27016
+//! last 1700000000000000
+//! next 0 1234 17
+//! next 1 56789 42
+//! injected 5000 40
+//! name Velvet
+//! version 1234
+//! session 1a2b3c4d
+//! log - logs/1700000000-10.0.0.5-5001-27016-1a2b3c4d.tfhlog
+//! log chat logs/1700000000-10.0.0.5-5001-27016-1a2b3c4d-chat.tfhlog
+//! ```
+//!
+//! `last` is the time of the connection's last packet.  Each `next` line gives a direction (0 for
+//! client to server), the sequence number where its next message starts, and that message's
+//! index; a direction that wasn't in sync is left out.  `injected` lines record messages
+//! injected into the server-to-client stream, as the server's sequence number where each went
+//! in and its length.  The `log` lines give the main log (`-`) and each class log by class name.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::Mutex;
+use crate::{Error, ErrorAt};
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, ResumeState};
+
+
+/// Connections whose last packet is older than this, in microseconds, aren't resumed.  This
+/// matches how long a quiet connection lasts before timing out.
+pub const MAX_AGE: u64 = 60_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnState {
+    pub ct: ConnTuple,
+    pub stream: ResumeState,
+    pub name: Option<String>,
+    /// The client version from its login message, which picks the opcode layouts it uses.
+    pub version: Option<u32>,
+    pub session: Option<SessionId>,
+    /// Paths of the connection's logs: the main log, with no class, and the class logs.
+    pub logs: Vec<(Option<String>, String)>,
+}
+
+pub fn parse(s: &str) -> Result<Vec<ConnState>, Error> {
+    let mut conns: Vec<ConnState> = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let err = |e: String| Error(format!("line {}: {}", i + 1, e));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, rest) = match line.find(' ') {
+            Some(j) => (&line[..j], line[j + 1 ..].trim_start()),
+            None => (line, ""),
+        };
+        if key == "conn" {
+            conns.push(ConnState {
+                ct: parse_conn(rest).map_err(err)?,
+                stream: ResumeState::default(),
+                name: None,
+                version: None,
+                session: None,
+                logs: Vec::new(),
+            });
+            continue;
+        }
+        let conn = conns.last_mut().ok_or_else(|| err(format!("{} comes before any conn", key)))?;
+        let words = rest.split_whitespace().collect::<Vec<_>>();
+        let num = |w: &str| w.parse::<u64>().map_err(|e| err(format!("{}: {}", w, e)));
+        match (key, words.len()) {
+            ("last", 1) => conn.stream.last_packet = num(words[0])?,
+            ("next", 3) => {
+                let dir = match words[0] {
+                    "0" => 0,
+                    "1" => 1,
+                    x => return Err(err(format!("bad direction {:?}", x))),
+                };
+                conn.stream.next[dir] = Some((num(words[1])? as u32, num(words[2])?));
+            },
+            ("injected", 2) => {
+                conn.stream.injected.push((num(words[0])? as u32, num(words[1])? as usize));
+            },
+            ("name", _) if !rest.is_empty() => conn.name = Some(rest.to_owned()),
+            ("version", 1) => conn.version = Some(num(words[0])? as u32),
+            ("session", 1) => conn.session = Some(SessionId::parse(words[0]).map_err(err)?),
+            ("log", n) if n >= 2 => {
+                let class = if words[0] == "-" { None } else { Some(words[0].to_owned()) };
+                let path = rest[words[0].len() ..].trim_start();
+                conn.logs.push((class, path.to_owned()));
+            },
+            _ => return Err(err(format!("can't parse {:?}", line))),
+        }
+    }
+    Ok(conns)
+}
+
+/// `conns` in the format `parse` reads.
+pub fn to_text(conns: &[ConnState]) -> String {
+    let mut s = String::new();
+    for conn in conns {
+        writeln!(s, "conn {}", conn.ct).unwrap();
+        writeln!(s, "last {}", conn.stream.last_packet).unwrap();
+        for (dir, next) in conn.stream.next.iter().enumerate() {
+            if let Some((seq, index)) = *next {
+                writeln!(s, "next {} {} {}", dir, seq, index).unwrap();
+            }
+        }
+        for &(at, len) in &conn.stream.injected {
+            writeln!(s, "injected {} {}", at, len).unwrap();
+        }
+        if let Some(ref name) = conn.name {
+            writeln!(s, "name {}", name).unwrap();
+        }
+        if let Some(version) = conn.version {
+            writeln!(s, "version {}", version).unwrap();
+        }
+        if let Some(session) = conn.session {
+            writeln!(s, "session {}", session).unwrap();
+        }
+        for &(ref class, ref path) in &conn.logs {
+            writeln!(s, "log {} {}", class.as_ref().map_or("-", |c| c), path).unwrap();
+        }
+    }
+    s
+}
+
+fn parse_conn(s: &str) -> Result<ConnTuple, String> {
+    let words = s.split_whitespace().collect::<Vec<_>>();
+    if words.len() != 3 || words[1] != "->" {
+        return Err(format!("expected CLIENT:PORT -> SERVER:PORT, not {:?}", s));
+    }
+    let addr = |s: &str| s.parse::<SocketAddrV4>().map_err(|e| format!("{}: {}", s, e));
+    let (client, server) = (addr(words[0])?, addr(words[2])?);
+    Ok(ConnTuple::Ipv4(
+        (*client.ip()).into(), client.port(), (*server.ip()).into(), server.port(),
+    ))
+}
+
+/// The snapshot file, shared by the processing workers.  Each worker saves its own connections,
+/// and they're all written out together, once every worker has saved since the last write.
+pub struct SnapshotFile {
+    path: String,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Connections saved by each worker this run, by the number `register` gave it.
+    saved: Vec<Vec<ConnState>>,
+    /// Workers that have saved since the file was last written.
+    fresh: HashSet<usize>,
+    /// Connections from the previous run that haven't been resumed yet.  They're written back
+    /// out until they're too old to resume, in case the relay restarts again first.
+    pending: HashMap<ConnTuple, ConnState>,
+    /// What the file holds now, so it isn't rewritten unchanged.
+    written: String,
+}
+
+impl SnapshotFile {
+    /// Save to the file at `path`, first loading the connections a previous run saved there, if
+    /// it exists.
+    pub fn open(path: &str) -> Result<SnapshotFile, Error> {
+        let written = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).at(path),
+        };
+        let pending = parse(&written).at(path)?;
+        if pending.len() > 0 {
+            log!(Handler, Info, "snapshot: {} connections to resume from {}", pending.len(), path);
+        }
+        Ok(SnapshotFile {
+            path: path.to_owned(),
+            inner: Mutex::new(Inner {
+                saved: Vec::new(),
+                fresh: HashSet::new(),
+                pending: pending.into_iter().map(|c| (c.ct, c)).collect(),
+                written,
+            }),
+        })
+    }
+
+    /// Take the state a previous run saved for `ct`, if it isn't too old to resume at `now`.
+    pub fn take(&self, ct: ConnTuple, now: u64) -> Option<ConnState> {
+        let conn = self.inner.lock().unwrap().pending.remove(&ct)?;
+        if now.saturating_sub(conn.stream.last_packet) >= MAX_AGE {
+            return None;
+        }
+        Some(conn)
+    }
+
+    /// Add a worker, returning the number it passes to `update`.
+    pub fn register(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.saved.push(Vec::new());
+        inner.saved.len() - 1
+    }
+
+    /// Replace the connections `worker` saved last time with `conns`.  Once every worker has
+    /// saved since the last write, the file is rewritten, if that changes it.
+    pub fn update(&self, worker: usize, now: u64, conns: Vec<ConnState>) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.saved[worker] = conns;
+        inner.fresh.insert(worker);
+        if inner.fresh.len() < inner.saved.len() {
+            return Ok(());
+        }
+        inner.fresh.clear();
+        inner.pending.retain(|_, c| now.saturating_sub(c.stream.last_packet) < MAX_AGE);
+
+        let mut all = inner.saved.iter().flatten().chain(inner.pending.values()).cloned()
+            .collect::<Vec<_>>();
+        all.sort_by_key(|c| c.ct.to_string());
+        let text = to_text(&all);
+        if text == inner.written {
+            return Ok(());
+        }
+        // Write a new file and rename it over the old one, so a crash partway through never
+        // leaves a truncated snapshot.
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, &text)?;
+        fs::rename(&tmp, &self.path)?;
+        inner.written = text;
+        Ok(())
+    }
+}
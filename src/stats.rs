@@ -0,0 +1,260 @@
+//! Traffic counters for the relay, shared between its threads.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::latency::Rtt;
+use crate::tfh_stream::{BufferMarks, ConnTuple};
+use crate::util::clock;
+use crate::util::json;
+
+
+#[derive(Default)]
+pub struct Counters {
+    /// Packets read from this direction's source interface.
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    /// Anomalies found while decoding TFH streams, such as out-of-range opcodes.
+    pub parse_warnings: AtomicU64,
+    /// Times a TFH stream stopped decoding messages while packets kept arriving.
+    pub stalls: AtomicU64,
+    /// Packets that couldn't be written to the destination interface.
+    pub write_failed: AtomicU64,
+    /// Packets that were only partly written.
+    pub write_partial: AtomicU64,
+    /// Packets dropped by the rate limiter.
+    pub rate_limited: AtomicU64,
+    /// Packets dropped as duplicates by `--dedup`.  These aren't included in `packets`.
+    pub duplicates: AtomicU64,
+    /// Packets `--pace` held back to a later tick.
+    pub paced: AtomicU64,
+    /// System calls that wrote more than one packet, with `--write-batch`.
+    pub batches: AtomicU64,
+    /// Packets written by those system calls.
+    pub batched: AtomicU64,
+    /// Steam networking (SDR) packets, which are passed through without processing.
+    pub sdr: AtomicU64,
+    /// STUN packets, which are passed through without processing.
+    pub stun: AtomicU64,
+    /// Time of the latest packet, in microseconds since the Unix epoch, or zero if none.
+    pub last_packet: AtomicU64,
+}
+
+/// A point-in-time copy of `Counters`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Snapshot {
+    pub packets: u64,
+    pub bytes: u64,
+    pub parse_warnings: u64,
+    pub stalls: u64,
+    pub write_failed: u64,
+    pub write_partial: u64,
+    pub rate_limited: u64,
+    pub duplicates: u64,
+    pub paced: u64,
+    pub batches: u64,
+    pub batched: u64,
+    pub sdr: u64,
+    pub stun: u64,
+    pub last_packet: u64,
+}
+
+impl Counters {
+    pub fn count_packet(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.last_packet.store(clock::now_us(), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            parse_warnings: self.parse_warnings.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+            write_failed: self.write_failed.load(Ordering::Relaxed),
+            write_partial: self.write_partial.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            paced: self.paced.load(Ordering::Relaxed),
+            batches: self.batches.load(Ordering::Relaxed),
+            batched: self.batched.load(Ordering::Relaxed),
+            sdr: self.sdr.load(Ordering::Relaxed),
+            stun: self.stun.load(Ordering::Relaxed),
+            last_packet: self.last_packet.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Snapshot {
+    fn to_json(&self) -> String {
+        json::Object::new()
+            .num("packets", self.packets)
+            .num("bytes", self.bytes)
+            .num("parse_warnings", self.parse_warnings)
+            .num("stalls", self.stalls)
+            .num("write_failed", self.write_failed)
+            .num("write_partial", self.write_partial)
+            .num("rate_limited", self.rate_limited)
+            .num("duplicates", self.duplicates)
+            .num("paced", self.paced)
+            .num("batches", self.batches)
+            .num("batched", self.batched)
+            .num("sdr", self.sdr)
+            .num("stun", self.stun)
+            .finish()
+    }
+
+    /// One-line summary of the traffic since `prev`, which was taken `secs` seconds earlier.
+    pub fn describe_since(&self, prev: &Snapshot, secs: f64) -> String {
+        let packets = self.packets - prev.packets;
+        let bytes = self.bytes - prev.bytes;
+        format!(
+            "{} packets ({:.1}/s), {} bytes ({:.1} KiB/s), {} parse warnings, {} stalls, \
+                {} writes failed, {} partial, {} rate-limited, {} duplicates, {} paced, \
+                {} batched in {} writes, {} SDR, {} STUN",
+            packets, packets as f64 / secs, bytes, bytes as f64 / secs / 1024.,
+            self.parse_warnings - prev.parse_warnings,
+            self.stalls - prev.stalls,
+            self.write_failed - prev.write_failed,
+            self.write_partial - prev.write_partial,
+            self.rate_limited - prev.rate_limited,
+            self.duplicates - prev.duplicates,
+            self.paced - prev.paced,
+            self.batched - prev.batched,
+            self.batches - prev.batches,
+            self.sdr - prev.sdr,
+            self.stun - prev.stun,
+        )
+    }
+}
+
+/// Counters for both directions through the relay.  `A` is the outside of the sandbox and `B` is
+/// the inside, so `a_to_b` is traffic toward the lobby server.
+#[derive(Default)]
+pub struct RelayStats {
+    pub a_to_b: Counters,
+    pub b_to_a: Counters,
+    /// Connections that haven't finished the TFH handshake.  This is a current count, not a
+    /// running total.
+    pub handshaking: AtomicU64,
+    /// Connections dropped for not finishing the handshake within `--handshake-timeout`.
+    pub handshake_timeouts: AtomicU64,
+    /// Most bytes and packets any one stream has had buffered awaiting reassembly.
+    pub buf_high_water: AtomicU64,
+    pub chunks_high_water: AtomicU64,
+    pub latency: Mutex<Latency>,
+}
+
+/// Round-trip times over the whole run, and for each open connection.  Each processing worker
+/// updates its own connections whenever it checks for timeouts.
+#[derive(Default)]
+pub struct Latency {
+    pub total: Rtt,
+    pub conns: HashMap<ConnTuple, Rtt>,
+}
+
+impl Latency {
+    /// The totals with their buckets, and percentiles for each open connection.
+    fn to_json(&self) -> String {
+        let mut conns = self.conns.iter().collect::<Vec<_>>();
+        conns.sort_by_key(|&(ct, _)| ct.as_bytes());
+        let mut s = String::from("[");
+        for (i, (ct, rtt)) in conns.into_iter().enumerate() {
+            if i > 0 {
+                s.push(',');
+            }
+            s.push_str(&json::Object::new()
+                .str("conn", &ct.to_string())
+                .raw("client", &rtt.client.to_json(false))
+                .raw("server", &rtt.server.to_json(false))
+                .finish());
+        }
+        s.push(']');
+        json::Object::new()
+            .raw("client", &self.total.client.to_json(true))
+            .raw("server", &self.total.server.to_json(true))
+            .raw("conns", &s)
+            .finish()
+    }
+}
+
+/// A point-in-time copy of `RelayStats`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RelaySnapshot {
+    pub a_to_b: Snapshot,
+    pub b_to_a: Snapshot,
+    pub handshaking: u64,
+    pub handshake_timeouts: u64,
+    pub buf_high_water: u64,
+    pub chunks_high_water: u64,
+}
+
+impl RelayStats {
+    pub fn record_marks(&self, marks: BufferMarks) {
+        self.buf_high_water.fetch_max(marks.buf as u64, Ordering::Relaxed);
+        self.chunks_high_water.fetch_max(marks.chunks as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> RelaySnapshot {
+        RelaySnapshot {
+            a_to_b: self.a_to_b.snapshot(),
+            b_to_a: self.b_to_a.snapshot(),
+            handshaking: self.handshaking.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            buf_high_water: self.buf_high_water.load(Ordering::Relaxed),
+            chunks_high_water: self.chunks_high_water.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl RelaySnapshot {
+    /// One-line summary of the connection counts, with the change in timeouts since `prev`.
+    pub fn describe_conns_since(&self, prev: &RelaySnapshot) -> String {
+        format!(
+            "{} handshaking, {} dropped during handshake",
+            self.handshaking, self.handshake_timeouts - prev.handshake_timeouts,
+        )
+    }
+
+    /// One-line summary of the high-water marks, including the depth of each of `queues`.
+    pub fn describe_high_water(&self, queues: &[(&str, u64)]) -> String {
+        let mut s = format!(
+            "stream buffer {} bytes, {} packets", self.buf_high_water, self.chunks_high_water,
+        );
+        for &(name, depth) in queues {
+            s.push_str(&format!(", {} queue {}", name, depth));
+        }
+        s
+    }
+}
+
+/// Write the totals as JSON to `path`, along with the high-water depth of each of `queues` and the
+/// round-trip times.  The file is replaced atomically, so a monitoring script never sees a partial
+/// write.
+pub fn write_json(
+    path: &str,
+    time: i64,
+    snap: &RelaySnapshot,
+    queues: &[(&str, u64)],
+    latency: &Latency,
+) -> io::Result<()> {
+    let mut high_water = json::Object::new();
+    high_water.num("buf", snap.buf_high_water).num("chunks", snap.chunks_high_water);
+    for &(name, depth) in queues {
+        high_water.num(&format!("{}_queue", name), depth);
+    }
+    let s = json::Object::new()
+        .num("time", time)
+        .raw("a_to_b", &snap.a_to_b.to_json())
+        .raw("b_to_a", &snap.b_to_a.to_json())
+        .num("handshaking", snap.handshaking)
+        .num("handshake_timeouts", snap.handshake_timeouts)
+        .raw("high_water", &high_water.finish())
+        .raw("latency", &latency.to_json())
+        .finish();
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, s + "\n")?;
+    fs::rename(&tmp, path)
+}
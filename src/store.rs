@@ -0,0 +1,207 @@
+//! Recent message history kept in memory, so the control socket can answer questions like "what
+//! were the last 50 messages from this player" without digging through log files.
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use crate::filter::MessageFilter;
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+/// Default limit on the total size of stored messages.
+pub const DEFAULT_MAX_BYTES: usize = 64 << 20;
+/// Default limit on the number of stored messages per connection.
+pub const DEFAULT_MAX_PER_CONN: usize = 10000;
+
+pub struct Entry {
+    /// Microseconds since the Unix epoch.
+    pub time: u64,
+    pub msg: Message,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        mem::size_of::<Entry>() + self.msg.body.len()
+    }
+}
+
+#[derive(Default)]
+struct History {
+    session: Option<SessionId>,
+    name: Option<String>,
+    entries: VecDeque<Entry>,
+    /// Set once the connection times out.  Closed connections are forgotten entirely once all
+    /// their messages have been evicted.
+    closed: bool,
+}
+
+/// Selects messages from the store.  Only the most recent `limit` matches are returned.
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub filter: MessageFilter,
+    /// Only these connections.  `None` means all connections.
+    pub conns: Option<Vec<ConnTuple>>,
+    /// Time range in microseconds since the Unix epoch.  `since` is inclusive, `until` exclusive.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: usize,
+}
+
+impl Default for Query {
+    fn default() -> Query {
+        Query {
+            filter: MessageFilter::default(),
+            conns: None,
+            since: None,
+            until: None,
+            limit: 50,
+        }
+    }
+}
+
+pub struct MessageStore {
+    conns: HashMap<ConnTuple, History>,
+    bytes: usize,
+    max_bytes: usize,
+    max_per_conn: usize,
+}
+
+impl MessageStore {
+    pub fn new(max_bytes: usize, max_per_conn: usize) -> MessageStore {
+        MessageStore {
+            conns: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            max_per_conn,
+        }
+    }
+
+    pub fn push(&mut self, time: u64, ct: ConnTuple, msg: Message) {
+        let entry = Entry { time, msg };
+        self.bytes += entry.size();
+
+        let h = self.conns.entry(ct).or_default();
+        h.entries.push_back(entry);
+        if h.entries.len() > self.max_per_conn {
+            let old = h.entries.pop_front().unwrap();
+            self.bytes -= old.size();
+        }
+
+        while self.bytes > self.max_bytes {
+            if !self.evict_oldest() {
+                break;
+            }
+        }
+    }
+
+    /// Drop the oldest message across all connections.  Returns `false` if the store is empty.
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.conns.iter()
+            .filter_map(|(&ct, h)| h.entries.front().map(|e| (e.time, ct)))
+            .min_by_key(|&(time, _)| time);
+        let ct = match oldest {
+            Some((_, ct)) => ct,
+            None => return false,
+        };
+
+        let h = self.conns.get_mut(&ct).unwrap();
+        let old = h.entries.pop_front().unwrap();
+        self.bytes -= old.size();
+        if h.closed && h.entries.len() == 0 {
+            self.conns.remove(&ct);
+        }
+        true
+    }
+
+    /// Note that `ct` has started session `session`.  The history of an earlier session of the
+    /// same connection tuple is dropped, so the two don't run together.
+    pub fn open(&mut self, ct: ConnTuple, session: SessionId) {
+        let stale = self.conns.get(&ct).map_or(false, |h| h.session != Some(session));
+        if stale {
+            let old = self.conns.remove(&ct).unwrap();
+            self.bytes -= old.entries.iter().map(Entry::size).sum::<usize>();
+        }
+        self.conns.entry(ct).or_default().session = Some(session);
+    }
+
+    pub fn set_name(&mut self, ct: ConnTuple, name: &str) {
+        self.conns.entry(ct).or_default().name = Some(name.to_owned());
+    }
+
+    /// Note that the connection has ended.  Its messages are kept until they're evicted.
+    pub fn close(&mut self, ct: ConnTuple) {
+        if let Some(h) = self.conns.get_mut(&ct) {
+            h.closed = true;
+            if h.entries.len() == 0 {
+                self.conns.remove(&ct);
+            }
+        }
+    }
+
+    pub fn session(&self, ct: ConnTuple) -> Option<SessionId> {
+        self.conns.get(&ct).and_then(|h| h.session)
+    }
+
+    /// Find the connection with session ID `session`.
+    pub fn find_session(&self, session: SessionId) -> Option<ConnTuple> {
+        self.conns.iter().find(|(_, h)| h.session == Some(session)).map(|(&ct, _)| ct)
+    }
+
+    /// Find connections whose player logged in as `name`.
+    pub fn find_name(&self, name: &str) -> Vec<ConnTuple> {
+        self.conns.iter()
+            .filter(|(_, h)| h.name.as_ref().map_or(false, |n| n == name))
+            .map(|(&ct, _)| ct)
+            .collect()
+    }
+
+    /// List the known connections, with their session, player name, message count, and whether
+    /// they're still open.
+    pub fn conns(&self) -> Vec<(ConnTuple, Option<SessionId>, Option<&str>, usize, bool)> {
+        let mut v = self.conns.iter()
+            .map(|(&ct, h)| {
+                let name = h.name.as_ref().map(|s| s as &str);
+                (ct, h.session, name, h.entries.len(), !h.closed)
+            })
+            .collect::<Vec<_>>();
+        v.sort_by_key(|&(ct, ..)| ct.as_bytes());
+        v
+    }
+
+    /// Run `q`, returning matching messages oldest first.
+    pub fn query(&self, q: &Query) -> Vec<(ConnTuple, &Entry)> {
+        let mut found = Vec::new();
+        for (&ct, h) in &self.conns {
+            if let Some(ref conns) = q.conns {
+                if !conns.contains(&ct) {
+                    continue;
+                }
+            }
+            if !q.filter.matches_conn(ct) {
+                continue;
+            }
+
+            // Walk backward from the newest message.  Once this connection has supplied `limit`
+            // matches, its older messages can't make the cut.
+            let mut n = 0;
+            for e in h.entries.iter().rev() {
+                if n >= q.limit || q.since.map_or(false, |t| e.time < t) {
+                    break;
+                }
+                if q.until.map_or(false, |t| e.time >= t) || !q.filter.matches_msg(&e.msg) {
+                    continue;
+                }
+                found.push((ct, e));
+                n += 1;
+            }
+        }
+
+        found.sort_by_key(|&(_, e)| e.time);
+        let skip = found.len().saturating_sub(q.limit);
+        found.drain(..skip);
+        found
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
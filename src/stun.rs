@@ -0,0 +1,125 @@
+//! Recognizing STUN (RFC 5389) messages, which peers use to find their public addresses when
+//! setting up peer-to-peer matches.  These are passed through untouched, but classified and
+//! logged, along with the reflexive (public) address a binding response reports.
+use core::fmt;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use crate::bytes::Bytes;
+
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+
+const METHOD_BINDING: u16 = 0x001;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// Used for XOR-MAPPED-ADDRESS by servers following drafts of RFC 5389.
+const ATTR_XOR_MAPPED_ADDRESS_OLD: u16 = 0x8020;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub class: Class,
+    pub method: u16,
+    pub transaction: [u8; 12],
+    /// The sender's address as the server saw it, from a binding response.
+    pub mapped: Option<SocketAddr>,
+}
+
+impl Message {
+    /// Parse `p`, a UDP payload, or return `None` if it isn't a STUN message.
+    pub fn parse(p: &[u8]) -> Option<Message> {
+        if p.len() < HEADER_LEN || p.u32_be(4) != MAGIC_COOKIE {
+            return None;
+        }
+        let ty = p.u16_be(0);
+        let len = p.u16_be(2) as usize;
+        if ty & 0xc000 != 0 || len % 4 != 0 || HEADER_LEN + len != p.len() {
+            return None;
+        }
+        // The class bits are interleaved with the method bits.
+        let class = match (ty >> 7 & 2) | (ty >> 4 & 1) {
+            0 => Class::Request,
+            1 => Class::Indication,
+            2 => Class::Success,
+            _ => Class::Error,
+        };
+        let method = (ty & 0x000f) | (ty >> 1 & 0x0070) | (ty >> 2 & 0x0f80);
+        let mut transaction = [0; 12];
+        transaction.copy_from_slice(&p[8..20]);
+
+        let mut mapped = None;
+        let mut i = HEADER_LEN;
+        while i + 4 <= p.len() {
+            let attr = p.u16_be(i);
+            let attr_len = p.u16_be(i + 2) as usize;
+            let value = p.get(i + 4 .. i + 4 + attr_len)?;
+            match attr {
+                ATTR_XOR_MAPPED_ADDRESS | ATTR_XOR_MAPPED_ADDRESS_OLD => {
+                    mapped = parse_address(value, Some(&p[4..20])).or(mapped);
+                },
+                ATTR_MAPPED_ADDRESS if mapped.is_none() => {
+                    mapped = parse_address(value, None);
+                },
+                _ => {},
+            }
+            // Attributes are padded to a multiple of 4 bytes.
+            i += 4 + (attr_len + 3) / 4 * 4;
+        }
+
+        Some(Message { class, method, transaction, mapped })
+    }
+
+    pub fn is_binding(&self) -> bool {
+        self.method == METHOD_BINDING
+    }
+}
+
+/// Parse a MAPPED-ADDRESS value, or an XOR-MAPPED-ADDRESS one if `xor` gives the cookie and
+/// transaction ID to undo the XOR with.
+fn parse_address(v: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    if v.len() < 4 {
+        return None;
+    }
+    let key = xor.unwrap_or(&[0; 16]);
+    let port = v.u16_be(2) ^ key.u16_be(0);
+    let ip = match v[1] {
+        1 if v.len() >= 8 => IpAddr::V4(Ipv4Addr::from(v.u32_be(4) ^ key.u32_be(0))),
+        2 if v.len() >= 20 => {
+            let mut b = [0; 16];
+            for (j, x) in b.iter_mut().enumerate() {
+                *x = v[4 + j] ^ key[j];
+            }
+            IpAddr::V6(Ipv6Addr::from(b))
+        },
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let class = match self.class {
+            Class::Request => "request",
+            Class::Indication => "indication",
+            Class::Success => "response",
+            Class::Error => "error response",
+        };
+        if self.is_binding() {
+            write!(fmt, "binding {}", class)?;
+        } else {
+            write!(fmt, "method {:#x} {}", self.method, class)?;
+        }
+        if let Some(addr) = self.mapped {
+            write!(fmt, ", reflexive address {}", addr)?;
+        }
+        Ok(())
+    }
+}
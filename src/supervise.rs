@@ -0,0 +1,104 @@
+//! Keeping worker threads alive.  A supervised thread that panics or fails is logged and
+//! restarted, and if it keeps failing (or can't be restarted at all), the supervisor asks the
+//! program to shut down, instead of leaving the relay running with one side dead.
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::Error;
+use crate::health::{self, State};
+
+
+/// Delay before restarting a failed thread.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Restart limits apply to failures within this window.
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Restart {
+    /// Any exit shuts down the program.
+    Never,
+    /// Restart after a panic or error, unless there have been this many restarts within the last
+    /// minute.  Returning `Ok` still shuts down, since that means the thread's work is done.
+    Limit(usize),
+}
+
+pub struct Supervisor {
+    send: Sender<String>,
+    recv: Receiver<String>,
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        let (send, recv) = mpsc::channel();
+        Supervisor { send, recv }
+    }
+
+    /// Run `body` on a new thread, restarting it according to `restart`.
+    pub fn spawn(
+        &self,
+        name: &str,
+        restart: Restart,
+        mut body: impl FnMut() -> Result<(), Error> + Send + 'static,
+    ) {
+        let name = name.to_owned();
+        let shutdown = self.send.clone();
+        thread::Builder::new().name(name.clone()).spawn(move || {
+            health::register(&name);
+            let mut failures: Vec<Instant> = Vec::new();
+            loop {
+                let reason = match panic::catch_unwind(AssertUnwindSafe(&mut body)) {
+                    Ok(Ok(())) => {
+                        health::set_state(State::Stopped);
+                        let _ = shutdown.send(format!("{} exited", name));
+                        return;
+                    },
+                    Ok(Err(e)) => format!("{} failed: {}", name, e),
+                    Err(p) => format!("{} panicked: {}", name, panic_message(&*p)),
+                };
+                log!(Relay, Error, "{}", reason);
+
+                let limit = match restart {
+                    Restart::Never => 0,
+                    Restart::Limit(n) => n,
+                };
+                let now = Instant::now();
+                failures.retain(|&t| now.duration_since(t) < RESTART_WINDOW);
+                if failures.len() >= limit {
+                    health::set_state(State::Stopped);
+                    let _ = shutdown.send(reason);
+                    return;
+                }
+                failures.push(now);
+
+                health::set_state(State::Restarting);
+                thread::sleep(RESTART_DELAY);
+                log!(Relay, Warn, "restarting {}", name);
+                health::set_state(State::Running);
+            }
+        }).expect("failed to spawn thread");
+    }
+
+    /// Get a handle for requesting shutdown from outside a supervised thread.
+    pub fn shutdown_handle(&self) -> Sender<String> {
+        self.send.clone()
+    }
+
+    /// Block until some thread gives up or shutdown is requested, and return the reason.
+    pub fn wait(&self) -> String {
+        // `self.send` keeps the channel open, so this never fails.
+        self.recv.recv().unwrap()
+    }
+}
+
+/// Extract the message from a panic payload, which is usually a `&str` or `String`.
+pub fn panic_message(p: &(dyn Any + Send)) -> String {
+    if let Some(s) = p.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = p.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "(non-string panic payload)".to_owned()
+    }
+}
@@ -0,0 +1,207 @@
+//! Synthetic TFH streams for testing the reassembler.  A known sequence of messages is encoded
+//! as a stream, then cut into packets the way a lossy network would deliver them: in chunks of
+//! random size, some retransmitted with extra data, some duplicated, and reordered within a
+//! window.  Feeding the packets to a `TfhStream` should give back the original messages.
+//!
+//! Everything is driven by a seeded `Rng`, so a failing case can be reproduced from its seed.
+use std::env;
+use std::fs;
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::process;
+use crate::framing;
+use crate::packet::{Packet, PACKET_CAP, TFH_STREAM_HEADER_LEN};
+use crate::tfh_stream::{ConnTuple, Message, MessageHeader};
+
+
+/// Small xorshift generator.  Not for anything but tests.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Zero is a fixed point of xorshift, and nearby seeds give similar first outputs, so
+        // scramble the seed first.
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0 .. n`.  `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// True with probability `percent` / 100.
+    pub fn chance(&mut self, percent: u32) -> bool {
+        self.below(100) < percent as usize
+    }
+}
+
+/// A client talking to the lobby server, for tests that need a connection but don't care which.
+pub fn conn() -> ConnTuple {
+    ConnTuple::Ipv4(0x0a00_0005, 5001, 0xc0a8_5402, 27016)
+}
+
+/// An empty directory for a test to write into, named after `name` and this process so tests
+/// running at the same time don't share one.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("tfh-mitm-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The preamble as `TfhStream` reports it: the single byte each side sends before any messages.
+pub fn preamble(byte: u8) -> Message {
+    message(0, 0, vec![byte])
+}
+
+pub fn message(major: u8, minor: u8, body: Vec<u8>) -> Message {
+    Message {
+        header: MessageHeader {
+            major,
+            minor,
+            dir: 0xff,
+            ack: 0,
+            len: body.len() as u32,
+        },
+        body: body.into_boxed_slice(),
+        time: 0,
+        index: 0,
+        acks: 0 .. 0,
+    }
+}
+
+/// A preamble followed by `count` messages with random opcodes and bodies of up to `max_body`
+/// bytes.  About one in four messages uses major 0x20, which also carries a minor opcode.
+pub fn random_messages(rng: &mut Rng, count: usize, max_body: usize) -> Vec<Message> {
+    let mut msgs = vec![preamble(rng.next_u64() as u8)];
+    for _ in 0 .. count {
+        let major = if rng.chance(25) { 0x20 } else { rng.next_u64() as u8 };
+        let minor = if major == 0x20 { rng.next_u64() as u8 } else { 0 };
+        let body = (0 .. rng.below(max_body + 1)).map(|_| rng.next_u64() as u8).collect();
+        msgs.push(message(major, minor, body));
+    }
+    msgs
+}
+
+/// The bytes of a stream carrying `msgs`.  The first message must be the preamble, which is
+/// sent as is; the rest are framed.
+pub fn encode_stream(msgs: &[Message]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (i, msg) in msgs.iter().enumerate() {
+        if i == 0 {
+            assert_eq!(msg.body.len(), 1, "first message must be the preamble");
+            buf.extend_from_slice(&msg.body);
+        } else {
+            buf.extend_from_slice(&framing::encode(msg.header.major, msg.header.minor, &msg.body));
+        }
+    }
+    buf
+}
+
+/// How `packetize` mistreats a stream.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    /// Largest payload in one packet.
+    pub max_chunk: usize,
+    /// Chance, in percent, that a packet is a retransmission that also repeats some of the data
+    /// before it.
+    pub overlap: u32,
+    /// Chance, in percent, that a packet is delivered twice.
+    pub duplicate: u32,
+    /// How far a packet can move from its place in the stream, in packets.  Zero keeps them in
+    /// order.
+    pub reorder: usize,
+}
+
+impl Default for Delivery {
+    fn default() -> Delivery {
+        Delivery {
+            max_chunk: 1200,
+            overlap: 10,
+            duplicate: 10,
+            reorder: 4,
+        }
+    }
+}
+
+impl Delivery {
+    /// Every packet in order, exactly once.
+    pub fn in_order(max_chunk: usize) -> Delivery {
+        Delivery { max_chunk, overlap: 0, duplicate: 0, reorder: 0 }
+    }
+}
+
+/// Cut `data` into chunks and reorder them as `delivery` says, returning the byte range of each
+/// packet in the order they arrive.  The first packet is always the one starting at 0, since
+/// `TfhStream` takes its position in the stream from the first packet it sees.
+pub fn chunks(rng: &mut Rng, len: usize, delivery: &Delivery) -> Vec<(usize, usize)> {
+    let max_chunk = delivery.max_chunk.clamp(1, PACKET_CAP - 20 - 8 - TFH_STREAM_HEADER_LEN);
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < len {
+        let end = pos + 1 + rng.below(max_chunk);
+        let end = end.min(len);
+        let start = if pos > 0 && rng.chance(delivery.overlap) {
+            let back = rng.below(pos.min(max_chunk - (end - pos)) + 1);
+            pos - back
+        } else {
+            pos
+        };
+        out.push((start, end));
+        pos = end;
+    }
+
+    let mut i = 0;
+    while i < out.len() {
+        if rng.chance(delivery.duplicate) {
+            let copy = out[i];
+            let at = i + 1 + rng.below(delivery.reorder + 1);
+            out.insert(at.min(out.len()), copy);
+            i += 1;
+        }
+        i += 1;
+    }
+
+    if delivery.reorder > 0 {
+        for i in 1 .. out.len() {
+            let j = i + rng.below((delivery.reorder + 1).min(out.len() - i));
+            out.swap(i, j);
+        }
+    }
+    out
+}
+
+/// A TFH stream packet from `src` to `dst` carrying `data` at sequence number `seq`, and
+/// acknowledging `ack` bytes of the other direction.
+pub fn packet(src: SocketAddrV4, dst: SocketAddrV4, seq: u32, ack: u32, data: &[u8]) -> Packet {
+    Packet::new_tfh_stream(src, dst, seq, ack, data).expect("chunk too large for a packet")
+}
+
+/// Encode `msgs` with `encode_stream` and deliver them from `src` to `dst` as `chunks` says.
+/// Each packet acknowledges `ack` bytes of the other direction.
+pub fn packetize(
+    rng: &mut Rng,
+    msgs: &[Message],
+    delivery: &Delivery,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    ack: u32,
+) -> Vec<Packet> {
+    let data = encode_stream(msgs);
+    chunks(rng, data.len(), delivery).into_iter()
+        .map(|(start, end)| packet(src, dst, start as u32, ack, &data[start .. end]))
+        .collect()
+}
+
+/// Whether `a` and `b` have the same opcodes and body, ignoring where and when they were seen.
+pub fn same_message(a: &Message, b: &Message) -> bool {
+    a.header.major == b.header.major && a.header.minor == b.header.minor && a.body == b.body
+}
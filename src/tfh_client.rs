@@ -0,0 +1,115 @@
+//! Minimal client side of a TFH lobby stream, for sending recorded messages to a live server.
+//!
+//! Outgoing data gets fresh sequence numbers and acknowledges whatever the server has sent so
+//! far.  Nothing is ever retransmitted, so this is only suitable for test servers on a reliable
+//! network.
+use std::io;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use crate::{Error, ErrorAt};
+use crate::bytes::Bytes;
+use crate::packet::{TfhStreamHeader, TFH_STREAM_HEADER_LEN};
+use crate::tfh_stream::{Message, TfhStream};
+use crate::util::clock;
+
+
+/// Largest payload to put in one datagram.  Longer data is split across several.
+pub const MAX_PAYLOAD: usize = 1200;
+
+#[derive(Default)]
+struct State {
+    /// Bytes sent so far.
+    sent: u32,
+    /// Bytes received from the server, counting only data with no gaps before it.
+    received: u32,
+    /// `my_time` from the server's latest packet.
+    peer_time: u32,
+}
+
+pub struct Client {
+    socket: UdpSocket,
+    state: Arc<Mutex<State>>,
+    start: Instant,
+}
+
+impl Client {
+    /// Start a session with the server at `addr`.  Messages from the server are decoded in a
+    /// background thread and passed to `on_message`.
+    pub fn connect(
+        addr: &str,
+        mut on_message: impl FnMut(Message) + Send + 'static,
+    ) -> Result<Client, Error> {
+        let socket = UdpSocket::bind("0.0.0.0:0").at("tfh client: bind")?;
+        socket.connect(addr).at("tfh client: connect")?;
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let recv_socket = socket.try_clone()?;
+        let recv_state = state.clone();
+        thread::spawn(move || {
+            let mut stream = TfhStream::new();
+            let mut buf = vec![0; 65536];
+            loop {
+                let n = match recv_socket.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        eprintln!("tfh client: recv failed: {}", e);
+                        break;
+                    },
+                };
+                let p = &buf[..n];
+                if n < TFH_STREAM_HEADER_LEN || p.u8_be(0) != 1 || p.u32_be(1) != 0 {
+                    continue;
+                }
+                let hdr = TfhStreamHeader::new(&p[..TFH_STREAM_HEADER_LEN]);
+                let data = &p[TFH_STREAM_HEADER_LEN..];
+
+                {
+                    let mut state = recv_state.lock().unwrap();
+                    if hdr.my_seq() <= state.received {
+                        state.received = state.received.max(hdr.my_seq() + data.len() as u32);
+                    }
+                    state.peer_time = hdr.my_time();
+                }
+
+                stream.handle_data(hdr, data);
+                while let Some(mut msg) = stream.next_message() {
+                    msg.header.dir = 1;
+                    msg.time = clock::now_us();
+                    on_message(msg);
+                }
+            }
+        });
+
+        Ok(Client { socket, state, start: Instant::now() })
+    }
+
+    /// Send raw stream data, splitting it across datagrams as needed.
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(MAX_PAYLOAD) {
+            let mut state = self.state.lock().unwrap();
+            let mut dgram = vec![0; TFH_STREAM_HEADER_LEN];
+            dgram.put_u8_be(0, 1);
+            dgram.put_u32_be(5, state.sent);
+            dgram.put_u32_be(9, state.received);
+            dgram.put_u16_be(13, if state.sent == 0 { 2 } else { 0 });
+            dgram.put_u16_be(15, 0xea00);
+            dgram.put_u32_be(17, self.start.elapsed().as_millis() as u32);
+            dgram.put_u32_be(21, state.peer_time);
+            dgram.extend_from_slice(chunk);
+            self.socket.send(&dgram)?;
+            state.sent += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    pub fn send_message(&self, msg: &Message) -> io::Result<()> {
+        self.send(&msg.encode())
+    }
+
+    /// Number of contiguous bytes received from the server so far.
+    pub fn received(&self) -> u32 {
+        self.state.lock().unwrap().received
+    }
+}
@@ -2,10 +2,15 @@ use std::cmp;
 use std::collections::{HashMap, VecDeque};
 use std::collections::btree_map::{BTreeMap, Entry};
 use std::fmt;
-use std::ops::{Add, AddAssign, Sub, RangeBounds, Bound};
-use std::time::Instant;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::{Add, AddAssign, Sub, Range, RangeBounds, Bound};
 use crate::bytes::Bytes;
-use crate::packet::Packet;
+use crate::framing;
+use crate::latency::Rtt;
+use crate::packet::{Packet, TfhStreamHeader, TFH_STREAM_HEADER_LEN};
+use crate::util::clock;
+use crate::util::json;
 
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Default)]
@@ -38,6 +43,14 @@ impl Sub<usize> for Seq {
     }
 }
 
+impl Seq {
+    /// How far `self` is past `base`, or negative if it's before it.  Unlike comparing the
+    /// numbers, this gives the right answer when the sequence numbers wrap around in between.
+    fn offset_from(self, base: Seq) -> i64 {
+        self.0.wrapping_sub(base.0) as i32 as i64
+    }
+}
+
 impl fmt::Display for Seq {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         <u32 as fmt::Display>::fmt(&self.0, fmt)
@@ -57,8 +70,205 @@ pub struct TfhStream {
     /// Are we in sync with the stream?  If `false`, `next_message` will try some guesswork to find
     /// the start of the next message.
     sync: bool,
+    /// Number of anomalies found while decoding, not yet collected by `take_warnings`.
+    warnings: u64,
+    /// The anomalies themselves, not yet collected by `take_warning_details`, up to
+    /// `MAX_PENDING_WARNINGS`.
+    pending_warnings: Vec<StreamWarning>,
+    /// `index` of the next message.
+    next_index: u64,
+    /// End position and index of each decoded message the other side hasn't acknowledged yet.
+    unacked: VecDeque<(Seq, u64)>,
+    /// Highest acknowledgement the other side has sent for this stream.
+    acked: Seq,
+    /// Replacement bytes for rewritten messages, by starting sequence number.  These are kept
+    /// for a while after the message is decoded, so retransmissions get the same edits.
+    patches: BTreeMap<Seq, Box<[u8]>>,
+    marks: BufferMarks,
+    /// Packets carrying data since the last message was decoded, for spotting stalls.
+    packets_since_message: u32,
+    /// Has the current stall been reported?  Cleared when a message is decoded again.
+    stalled: bool,
+    /// Longest message to accept, including the length field.  A longer length means the stream
+    /// is corrupt or we've lost our place in it.
+    max_message_len: usize,
+    /// Are we skipping bytes to find the next message after an impossible length?  Cleared when
+    /// a message is decoded.
+    resyncing: bool,
+    /// Where the current resync started.
+    resync_from: Seq,
+    /// Where a previous run left off, as the start and `index` of the next message.  See
+    /// `resume_at`.
+    resume: Option<(Seq, u64)>,
+}
+
+/// The most a stream has had buffered at once, for tuning capacity limits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct BufferMarks {
+    /// Bytes received but not yet decoded into messages.
+    pub buf: usize,
+    /// Packets whose data is still in `buf`.
+    pub chunks: usize,
+}
+
+impl BufferMarks {
+    pub fn merge(&mut self, other: BufferMarks) {
+        self.buf = cmp::max(self.buf, other.buf);
+        self.chunks = cmp::max(self.chunks, other.chunks);
+    }
+}
+
+/// Why a stream stopped producing messages while packets kept arriving, as reported to
+/// `StreamHandler::on_stall`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stall {
+    /// Sequence number decoding is stuck at, where the next message starts.
+    pub at: u32,
+    pub reason: StallReason,
+    /// Ranges of sequence numbers buffered from `at` on.
+    pub buffered: Vec<Range<u32>>,
+    /// Packets carrying data since the last message was decoded.
+    pub packets: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StallReason {
+    /// These bytes never arrived, though later ones did, so the next message can't be decoded
+    /// until they're retransmitted.
+    Gap(Range<u32>),
+    /// The next message's length field says it's `len` bytes long, including the field, but only
+    /// `avail` bytes are here.  A huge `len` usually means the stream is corrupt.
+    Incomplete { len: usize, avail: usize },
+    /// Like `Incomplete`, but for a stream we joined partway through, where the bytes read as the
+    /// length probably came from the middle of a message.
+    Desync { len: usize, avail: usize },
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "no messages for {} packets, stuck at {}: ", self.packets, self.at)?;
+        match self.reason {
+            StallReason::Gap(ref r) => write!(fmt, "waiting for {}..{}", r.start, r.end)?,
+            StallReason::Incomplete { len, avail } =>
+                write!(fmt, "{}-byte message, {} bytes received", len, avail)?,
+            StallReason::Desync { len, avail } =>
+                write!(fmt, "lost sync: {}-byte message, {} bytes received", len, avail)?,
+        }
+        write!(fmt, "; buffered")?;
+        for r in self.buffered.iter().take(MAX_STALL_RANGES) {
+            write!(fmt, " {}..{}", r.start, r.end)?;
+        }
+        if self.buffered.len() > MAX_STALL_RANGES {
+            write!(fmt, " and {} more ranges", self.buffered.len() - MAX_STALL_RANGES)?;
+        }
+        Ok(())
+    }
+}
+
+/// A stream that gets this many packets with data without decoding a message is stalled.  This
+/// is far more than any message we've seen needs, so it doesn't catch a long message that's
+/// still arriving.
+const STALL_PACKETS: u32 = 256;
+
+/// Most buffered ranges to show when describing a stall.
+const MAX_STALL_RANGES: usize = 8;
+
+/// Something odd found in a stream, as reported to `StreamHandler::on_warning`.  `at` is the
+/// sequence number where the message starts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StreamWarning {
+    /// The major opcode doesn't fit in a byte.  The message is decoded with the low byte.
+    MajorOutOfRange { at: u32, major: u32 },
+    /// The minor opcode doesn't fit in a byte.  The message is decoded with the low byte.
+    MinorOutOfRange { at: u32, minor: u32 },
+    /// A length field too long or too short for any message, so the stream skips ahead to the
+    /// next plausible message.  See `TfhStream::set_max_message_len`.
+    ImpossibleLength { at: u32, len: u32 },
+    /// The handler's `rewrite` changed the length of a message body, which isn't supported, so
+    /// the edit was dropped, and the message forwarded and passed to `on_message` unchanged.
+    RewriteLength { at: u32, from: usize, to: usize },
+    /// An injected message didn't fit in one packet, so it was dropped.
+    InjectionTooLong { len: usize },
+    /// A message decoded after an `ImpossibleLength`, `skipped` bytes later, so the stream is
+    /// back in sync.
+    Resynced { at: u32, skipped: u32 },
+    /// The packet starting at sequence number `seq` has a wrong UDP checksum.  Only checked with
+    /// `TfhStreamConns::set_check_packets`.
+    BadChecksum { seq: u32 },
+    /// The packet starting at `seq` holds only `len` of the `expected` bytes its UDP header
+    /// claims.  Only checked with `TfhStreamConns::set_check_packets`.
+    Truncated { seq: u32, len: usize, expected: usize },
+}
+
+impl StreamWarning {
+    /// Whether this is an anomaly in the stream itself, rather than a problem with what the
+    /// handler asked for.  Only these are counted by `take_warnings`.  `Resynced` isn't either,
+    /// since it ends an anomaly already counted.
+    pub fn is_decoding(&self) -> bool {
+        match *self {
+            StreamWarning::MajorOutOfRange { .. } |
+            StreamWarning::MinorOutOfRange { .. } |
+            StreamWarning::ImpossibleLength { .. } |
+            StreamWarning::BadChecksum { .. } |
+            StreamWarning::Truncated { .. } => true,
+            StreamWarning::RewriteLength { .. } |
+            StreamWarning::InjectionTooLong { .. } |
+            StreamWarning::Resynced { .. } => false,
+        }
+    }
+
+    /// A short name for the kind of anomaly, for machine-readable output.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            StreamWarning::MajorOutOfRange { .. } => "major_out_of_range",
+            StreamWarning::MinorOutOfRange { .. } => "minor_out_of_range",
+            StreamWarning::ImpossibleLength { .. } => "impossible_length",
+            StreamWarning::RewriteLength { .. } => "rewrite_length",
+            StreamWarning::InjectionTooLong { .. } => "injection_too_long",
+            StreamWarning::Resynced { .. } => "resynced",
+            StreamWarning::BadChecksum { .. } => "bad_checksum",
+            StreamWarning::Truncated { .. } => "truncated",
+        }
+    }
 }
 
+impl fmt::Display for StreamWarning {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamWarning::MajorOutOfRange { at, major } =>
+                write!(fmt, "major opcode out of range at {}: {:x}", at, major),
+            StreamWarning::MinorOutOfRange { at, minor } =>
+                write!(fmt, "minor opcode out of range at {}: {:x}", at, minor),
+            StreamWarning::ImpossibleLength { at, len } =>
+                write!(fmt, "impossible message length at {}: {}, resyncing", at, len),
+            StreamWarning::RewriteLength { at, from, to } =>
+                write!(fmt, "can't change length of message at {} from {} to {}", at, from, to),
+            StreamWarning::InjectionTooLong { len } =>
+                write!(fmt, "injected {}-byte message is too long", len),
+            StreamWarning::Resynced { at, skipped } =>
+                write!(fmt, "back in sync at {} after skipping {} bytes", at, skipped),
+            StreamWarning::BadChecksum { seq } =>
+                write!(fmt, "bad UDP checksum on packet at {}", seq),
+            StreamWarning::Truncated { seq, len, expected } =>
+                write!(fmt, "packet at {} truncated: {} of {} UDP bytes", seq, len, expected),
+        }
+    }
+}
+
+/// Most warnings `TfhStream` keeps for `take_warning_details`.
+const MAX_PENDING_WARNINGS: usize = 1024;
+
+/// Default for `TfhStream::set_max_message_len`.  The longest messages we've seen are a few
+/// kilobytes.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// How far behind the decoding position, in bytes, to keep patches for retransmissions.
+const PATCH_WINDOW: u32 = 64 * 1024;
+
+/// Most messages to remember in `TfhStream::unacked`, in case the other side's acknowledgements
+/// are missing from a capture.
+const MAX_UNACKED: usize = 4096;
+
 impl TfhStream {
     pub fn new() -> TfhStream {
         TfhStream {
@@ -66,23 +276,69 @@ impl TfhStream {
             buf: VecDeque::with_capacity(4096),
             chunks: BTreeMap::new(),
             sync: false,
+            warnings: 0,
+            pending_warnings: Vec::new(),
+            next_index: 0,
+            unacked: VecDeque::new(),
+            acked: Seq(0),
+            patches: BTreeMap::new(),
+            marks: BufferMarks::default(),
+            packets_since_message: 0,
+            stalled: false,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            resyncing: false,
+            resync_from: Seq(0),
+            resume: None,
         }
     }
 
+    /// Pick up the stream where a previous run left off, with the next message starting at `seq`
+    /// and numbered `index`.  This takes effect if the first packet seen doesn't start past
+    /// `seq`; otherwise the bytes in between were missed, and the stream starts out of sync as
+    /// usual.
+    pub fn resume_at(&mut self, seq: u32, index: u64) {
+        self.resume = Some((Seq(seq), index));
+    }
+
+    /// Where to resume decoding in a later run: the start and `index` of the next message, if
+    /// the stream is in sync.
+    pub fn resume_point(&self) -> Option<(u32, u64)> {
+        if self.sync { Some((self.start.0, self.next_index)) } else { None }
+    }
+
+    /// Treat a message longer than `len` bytes, including its length field, as a sign that the
+    /// stream is corrupt, instead of waiting for the rest of it.  The stream then counts as out
+    /// of sync, and decoding skips ahead to the next plausible message header.
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = len;
+    }
+
     pub fn handle_packet(&mut self, p: &Packet) {
         if !p.is_tfh_stream() {
             return;
         }
+        self.handle_data(p.tfh_stream(), p.tfh_stream_payload());
+    }
 
-        let tfh = p.tfh_stream();
-        let data = p.tfh_stream_payload();
-
+    /// Like `handle_packet`, but takes the TFH stream header and payload directly, for callers
+    /// that receive them from a UDP socket rather than as whole IP packets.
+    pub fn handle_data(&mut self, tfh: &TfhStreamHeader, data: &[u8]) {
         let start = Seq(tfh.my_seq());
         if !self.sync && self.buf.len() == 0 {
-            // Let the first packet we see set our current position in the stream.
-            self.start = start;
+            match self.resume.take() {
+                Some((at, index)) if start <= at => {
+                    self.start = at;
+                    self.sync = true;
+                    self.next_index = index;
+                },
+                // Let the first packet we see set our current position in the stream.
+                _ => self.start = start,
+            }
         }
         let end = start + data.len();
+        if data.len() > 0 {
+            self.packets_since_message = self.packets_since_message.saturating_add(1);
+        }
         if end < self.start {
             return;
         }
@@ -111,6 +367,99 @@ impl TfhStream {
                 *ack = cmp::max(*ack, ack_seq);
             },
         }
+        self.marks.merge(BufferMarks { buf: self.buf.len(), chunks: self.chunks.len() });
+    }
+
+    pub fn marks(&self) -> BufferMarks {
+        self.marks
+    }
+
+    /// Return the number of warnings since the last call.
+    pub fn take_warnings(&mut self) -> u64 {
+        mem::replace(&mut self.warnings, 0)
+    }
+
+    /// Return the warnings since the last call.  Only the first `MAX_PENDING_WARNINGS` are kept
+    /// between calls, though `take_warnings` counts them all.
+    pub fn take_warning_details(&mut self) -> Vec<StreamWarning> {
+        mem::take(&mut self.pending_warnings)
+    }
+
+    fn warn(&mut self, w: StreamWarning) {
+        if w.is_decoding() {
+            self.warnings += 1;
+        }
+        if self.pending_warnings.len() < MAX_PENDING_WARNINGS {
+            self.pending_warnings.push(w);
+        }
+    }
+
+    /// Where decoding is up to, and the end of the bytes received without a gap after it.
+    fn position(&self) -> (Seq, Seq) {
+        (self.start, self.start + self.count_avail())
+    }
+
+    /// The reassembly state, for `TfhStreamConns::set_trace`: the decoding position, what's
+    /// buffered, and the first missing byte if there's a gap holding up decoding.
+    fn describe(&self) -> String {
+        let (start, end) = self.position();
+        let mut s = format!("at {}, {} bytes in {} chunks", start, self.buf.len(),
+            self.chunks.len());
+        if self.buf.len() > end - start {
+            s += &format!(", waiting for {}", end);
+        }
+        if !self.sync {
+            s += ", not in sync";
+        }
+        s
+    }
+
+    /// Check whether the stream has stalled, returning the details the first time it's seen.
+    fn check_stall(&mut self) -> Option<Stall> {
+        if self.stalled || self.packets_since_message < STALL_PACKETS || self.buf.len() == 0 {
+            return None;
+        }
+        self.stalled = true;
+
+        // Work in offsets from `start`, since the chunks' sequence numbers may wrap around, and
+        // then the map's order isn't the stream's.
+        let (start, end) = self.position();
+        let mut chunks = self.chunks.iter()
+            .map(|(&at, &(len, _))| {
+                let lo = at.offset_from(start);
+                (cmp::max(lo, 0), lo + len as i64)
+            })
+            .filter(|&(lo, hi)| hi > lo)
+            .collect::<Vec<_>>();
+        chunks.sort();
+        let mut offsets: Vec<Range<i64>> = Vec::new();
+        for (lo, hi) in chunks {
+            match offsets.last_mut() {
+                Some(r) if lo <= r.end => r.end = cmp::max(r.end, hi),
+                _ => offsets.push(lo .. hi),
+            }
+        }
+        let seq = |offset: i64| start.0.wrapping_add(offset as u32);
+        let buffered = offsets.iter().map(|r| seq(r.start) .. seq(r.end)).collect();
+
+        let avail = end.offset_from(start) as usize;
+        let reason = if self.buf.len() > avail {
+            let next = offsets.iter().map(|r| r.start).find(|&x| x > avail as i64)
+                .map_or(end.0, seq);
+            StallReason::Gap(end.0 .. next)
+        } else {
+            let mut raw_header = [0; framing::LEN_PREFIX];
+            let n = cmp::min(raw_header.len(), avail);
+            copy_vec_deque_into_slice(&mut raw_header[..n], &self.buf, 0);
+            let len = framing::Header::parse(&raw_header[..n])
+                .map_or(framing::LEN_PREFIX, |h| h.frame_len());
+            if self.sync {
+                StallReason::Incomplete { len, avail }
+            } else {
+                StallReason::Desync { len, avail }
+            }
+        };
+        Some(Stall { at: start.0, reason, buffered, packets: self.packets_since_message })
     }
 
     fn count_avail(&self) -> usize {
@@ -125,6 +474,52 @@ impl TfhStream {
     }
 
     pub fn next_message(&mut self) -> Option<Message> {
+        self.next_message_at().map(|(_, msg)| msg)
+    }
+
+    /// Like `next_message`, but also returns the sequence number where the message body starts.
+    fn next_message_at(&mut self) -> Option<(Seq, Message)> {
+        let (at, mut msg) = self.decode_message()?;
+        msg.index = self.take_index();
+        self.packets_since_message = 0;
+        self.stalled = false;
+        // Messages already covered by an acknowledgement were acknowledged before we could decode
+        // them, and it's no longer known by which message.
+        if self.start > self.acked {
+            if self.unacked.len() == MAX_UNACKED {
+                self.unacked.pop_front();
+            }
+            self.unacked.push_back((self.start, msg.index));
+        }
+        Some((at, msg))
+    }
+
+    /// Record an acknowledgement of this stream up to `ack`, returning the indices of the
+    /// messages it covers that weren't acknowledged before.
+    fn take_acked(&mut self, ack: Seq) -> Range<u64> {
+        self.acked = cmp::max(self.acked, ack);
+        let first = match self.unacked.front() {
+            Some(&(end, index)) if end <= ack => index,
+            _ => return 0 .. 0,
+        };
+        let mut last = first;
+        while let Some(&(end, index)) = self.unacked.front() {
+            if end > ack {
+                break;
+            }
+            last = index;
+            self.unacked.pop_front();
+        }
+        first .. last + 1
+    }
+
+    /// Allocate an index for a message that's been added to the stream, such as an injected one.
+    fn take_index(&mut self) -> u64 {
+        self.next_index += 1;
+        self.next_index - 1
+    }
+
+    fn decode_message(&mut self) -> Option<(Seq, Message)> {
         let avail = self.count_avail();
 
         // Special case: each side sends one byte before sending actual messages.  We report that
@@ -133,7 +528,7 @@ impl TfhStream {
             self.start += 1;
             let body = vec![self.buf[0]];
             self.buf.drain(..1);
-            return Some(Message {
+            return Some((Seq(0), Message {
                 header: MessageHeader {
                     major: 0,
                     minor: 0,
@@ -142,50 +537,56 @@ impl TfhStream {
                     len: 1,
                 },
                 body: body.into_boxed_slice(),
-            });
-        }
-
-        if avail < 4 {
-            return None;
+                time: 0,
+                index: 0,
+                acks: 0 .. 0,
+            }));
         }
 
-        // Get total message len, and check that we have enough data to read the whole message.
-        let len = {
-            let (a, b) = self.buf.as_slices();
-            if a.len() >= 4 {
-                a.u32_be(0)
-            } else {
-                let mut buf = [0; 4];
-                buf[..a.len()].copy_from_slice(a);
-                buf[a.len()..].copy_from_slice(&b[..4 - a.len()]);
-                buf.u32_be(0)
+        // Parse the header to get the total message len and the major/minor opcode, and check
+        // that we have enough data to read the whole message.  A header that can't be right
+        // means we've lost our place, so skip ahead a byte at a time until one looks right.
+        let mut avail = avail;
+        let header = loop {
+            let mut raw_header = [0; framing::MAX_HEADER_LEN];
+            let raw_header_len = cmp::min(raw_header.len(), avail);
+            copy_vec_deque_into_slice(&mut raw_header[..raw_header_len], &self.buf, 0);
+            let header = framing::Header::parse(&raw_header[..raw_header_len])?;
+            if self.plausible(&header) {
+                break header;
             }
+            if !self.resyncing {
+                self.warn(StreamWarning::ImpossibleLength { at: self.start.0, len: header.len });
+                self.sync = false;
+                self.resyncing = true;
+                self.resync_from = self.start;
+            }
+            self.buf.pop_front();
+            self.start += 1;
+            avail -= 1;
         };
-        let len = len as usize;
-
-        if avail < 4 + len {
+        if avail < header.frame_len() {
             return None;
         }
 
-        let end = self.start + 4 + len;
-
-        // Parse the header to get the major/minor opcode.
-        let mut raw_header = [0; 10];
-        let raw_header_len = cmp::min(raw_header.len(), len);
-        copy_vec_deque_into_slice(&mut raw_header[..raw_header_len], &self.buf, 4);
+        let end = self.start + header.frame_len();
+        if self.resyncing {
+            self.resyncing = false;
+            let skipped = (self.start - self.resync_from) as u32;
+            self.warn(StreamWarning::Resynced { at: self.start.0, skipped });
+        }
 
-        let major = raw_header.u32_be(2);
-        let minor = if major == 0x20 { raw_header.u32_le(6) } else { 0 };
+        let (major, minor) = (header.major, header.minor);
         if major > u8::MAX as u32 {
-            eprintln!("warning: major opcode out of range: {:x}", major);
+            self.warn(StreamWarning::MajorOutOfRange { at: self.start.0, major });
         }
         if minor > u8::MAX as u32 {
-            eprintln!("warning: major opcode out of range: {:x}", minor);
+            self.warn(StreamWarning::MinorOutOfRange { at: self.start.0, minor });
         }
 
         // Extract the message body.
-        let header_len = 10 + if major == 0x20 { 4 } else { 0 };
-        let body_len = (4 + len).saturating_sub(header_len);
+        let header_len = header.header_len();
+        let body_len = header.body_len();
         let mut body = vec![0; body_len];
         copy_vec_deque_into_slice(&mut body, &self.buf, header_len);
 
@@ -205,10 +606,11 @@ impl TfhStream {
             }
         }
 
+        let body_start = self.start + header_len;
         self.buf.drain(.. end - self.start);
         self.start = end;
 
-        Some(Message {
+        Some((body_start, Message {
             header: MessageHeader {
                 major: major as u8,
                 minor: minor as u8,
@@ -217,10 +619,69 @@ impl TfhStream {
                 len: body_len as u32,
             },
             body: body.into_boxed_slice(),
-        })
+            time: 0,
+            index: 0,
+            acks: 0 .. 0,
+        }))
+    }
+
+    /// Could `header` start a message?  Its length must cover its own header and be within
+    /// `max_message_len`.  When resyncing, where many byte offsets would pass that, the opcodes
+    /// must also be in range.
+    fn plausible(&self, header: &framing::Header) -> bool {
+        let len = header.frame_len();
+        if len < header.header_len() || len > self.max_message_len {
+            return false;
+        }
+        !self.resyncing || (header.major <= u8::MAX as u32 && header.minor <= u8::MAX as u32)
+    }
+
+    /// Did the first packet we saw come from partway through the stream, so its start was missed?
+    fn joined_late(&self) -> bool {
+        !self.sync && self.start != Seq(!0)
+    }
+
+    /// Has everything received so far been decoded into whole messages?  If so, a new message
+    /// can be inserted at `start` without splitting one in two.
+    fn at_boundary(&self) -> bool {
+        self.sync && self.buf.len() == 0
+    }
+
+    /// Record that the bytes starting at `at` should be replaced with `data` in any packet that
+    /// carries them.
+    fn patch(&mut self, at: Seq, data: Box<[u8]>) {
+        let oldest = Seq(self.start.0.saturating_sub(PATCH_WINDOW));
+        self.patches = self.patches.split_off(&oldest);
+        self.patches.insert(at, data);
+    }
+
+    /// Apply recorded patches to `data`, a packet payload starting at `start`.  Returns whether
+    /// anything changed.
+    fn apply_patches(&self, start: Seq, data: &mut [u8]) -> bool {
+        let end = start + data.len();
+        let mut changed = false;
+        for (&at, patch) in &self.patches {
+            let patch_end = at + patch.len();
+            if at >= end {
+                break;
+            }
+            if patch_end <= start {
+                continue;
+            }
+            let lo = cmp::max(at, start);
+            let hi = cmp::min(patch_end, end);
+            let dest = &mut data[lo - start .. hi - start];
+            let src = &patch[lo - at .. hi - at];
+            if dest != src {
+                dest.copy_from_slice(src);
+                changed = true;
+            }
+        }
+        changed
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct MessageHeader {
     pub major: u8,
@@ -230,9 +691,64 @@ pub struct MessageHeader {
     pub len: u32,
 }
 
+#[derive(Clone, Debug)]
 pub struct Message {
     pub header: MessageHeader,
     pub body: Box<[u8]>,
+    /// When the message was received, in microseconds since the Unix epoch: the capture time of
+    /// the packet that completed it when replaying, or the wall clock when live.  Zero if
+    /// unknown, as for messages from logs written before timestamps were recorded.
+    pub time: u64,
+    /// Position of the message among those sent in the same direction of the same connection,
+    /// counting from 0 for the preamble.
+    pub index: u64,
+    /// Indices of the messages in the opposite direction that this one acknowledges for the
+    /// first time, found by matching `header.ack` against the opposite stream's message
+    /// boundaries.  This pairs a request with the message sent in reply to it, roughly.  Empty
+    /// if it acknowledges nothing new, or if unknown, as for injected messages and old logs.
+    pub acks: Range<u64>,
+}
+
+impl Message {
+    /// ` acks=#N` or ` acks=#N-M`, describing `acks` in message listings, or nothing if it's
+    /// empty.
+    pub fn acks_label(&self) -> String {
+        match self.acks.end - self.acks.start {
+            0 => String::new(),
+            1 => format!(" acks=#{}", self.acks.start),
+            _ => format!(" acks=#{}-{}", self.acks.start, self.acks.end - 1),
+        }
+    }
+
+    /// Single-line JSON rendering of the message, with the body as a hex string.
+    pub fn to_json(&self, ct: ConnTuple) -> String {
+        let mut obj = json::Object::new();
+        self.write_json(ct, &mut obj);
+        obj.finish()
+    }
+
+    /// Add the fields of `to_json` to `obj`, for embedding them in a larger object.
+    pub fn write_json(&self, ct: ConnTuple, obj: &mut json::Object) {
+        obj.str("conn", &ct.to_string())
+            .num("time", self.time)
+            .num("index", self.index)
+            .num("acks_start", self.acks.start)
+            .num("acks_end", self.acks.end)
+            .num("dir", self.header.dir)
+            .num("major", self.header.major)
+            .num("minor", self.header.minor)
+            .num("ack", self.header.ack)
+            .num("len", self.header.len)
+            .hex("body", &self.body);
+    }
+
+    /// Encode the message as it appears in the stream, as described in `framing`.  This is the
+    /// inverse of `TfhStream::next_message`, except that the unknown bytes aren't preserved by
+    /// decoding, so they're written as zeros.  The one-byte preamble at the start of each stream
+    /// isn't framed this way.
+    pub fn encode(&self) -> Vec<u8> {
+        framing::encode(self.header.major, self.header.minor, &self.body)
+    }
 }
 
 impl MessageHeader {
@@ -245,6 +761,16 @@ impl MessageHeader {
         buf.put_u32_be(8, self.len);
         buf
     }
+
+    pub fn from_bytes(buf: &[u8; 12]) -> MessageHeader {
+        MessageHeader {
+            major: buf.u8_be(0),
+            minor: buf.u8_be(1),
+            dir: buf.u8_be(2),
+            ack: buf.u32_be(4),
+            len: buf.u32_be(8),
+        }
+    }
 }
 
 
@@ -274,78 +800,1045 @@ impl ConnTuple {
             panic!("packet has no ConnTuple")
         }
     }
+
+    /// The client's address.  `TfhStreamConns` puts the client first, as does every tuple built
+    /// with `from_udp_packet` given the right `flip`.
+    pub fn client(&self) -> SocketAddrV4 {
+        match *self {
+            ConnTuple::Ipv4(addr, port, _, _) => SocketAddrV4::new(Ipv4Addr::from(addr), port),
+        }
+    }
+
+    /// The server's address.
+    pub fn server(&self) -> SocketAddrV4 {
+        match *self {
+            ConnTuple::Ipv4(_, _, addr, port) => SocketAddrV4::new(Ipv4Addr::from(addr), port),
+        }
+    }
+
+    /// The same connection with the lower endpoint (by address, then port) first, so packets in
+    /// either direction give the same key even when it isn't known which side is the client.
+    /// The result's `client` and `server` are meaningless.
+    pub fn canonical(&self) -> ConnTuple {
+        match *self {
+            ConnTuple::Ipv4(addr1, port1, addr2, port2) => {
+                if (addr1, port1) <= (addr2, port2) {
+                    *self
+                } else {
+                    ConnTuple::Ipv4(addr2, port2, addr1, port1)
+                }
+            },
+        }
+    }
+
+    /// Big-endian encoding: first address, first port, second address, second port.
+    pub fn as_bytes(&self) -> [u8; 12] {
+        let mut buf = [0; 12];
+        match *self {
+            ConnTuple::Ipv4(addr1, port1, addr2, port2) => {
+                buf.put_u32_be(0, addr1);
+                buf.put_u16_be(4, port1);
+                buf.put_u32_be(6, addr2);
+                buf.put_u16_be(10, port2);
+            },
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; 12]) -> ConnTuple {
+        ConnTuple::Ipv4(buf.u32_be(0), buf.u16_be(4), buf.u32_be(6), buf.u16_be(10))
+    }
+
+    /// Is either end at address `ip` and, if given, `port`?
+    pub fn has_endpoint(&self, ip: u32, port: Option<u16>) -> bool {
+        let matches = |a: SocketAddrV4| {
+            u32::from(*a.ip()) == ip && port.map_or(true, |x| x == a.port())
+        };
+        matches(self.client()) || matches(self.server())
+    }
+}
+
+impl fmt::Display for ConnTuple {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnTuple::Ipv4(addr1, port1, addr2, port2) => write!(
+                fmt, "{}:{} -> {}:{}",
+                Ipv4Addr::from(addr1), port1, Ipv4Addr::from(addr2), port2,
+            ),
+        }
+    }
 }
 
 pub trait StreamHandler {
+    /// Called when the first TFH packet of a new connection is seen.
+    fn on_connect(&mut self, _ct: ConnTuple) {}
     fn on_message(&mut self, ct: ConnTuple, msg: Message) {}
+    /// Called before `on_message` by `TfhStreamConns::handle_mut`, which lets the handler edit
+    /// the message before it's forwarded.  Returns whether the message was changed.  Only the
+    /// body is written back, and only if its length is unchanged.
+    fn rewrite(&mut self, _ct: ConnTuple, _msg: &mut Message) -> bool { false }
+    /// Called by `TfhStreamConns::handle_mut` whenever it could insert a message into the
+    /// server-to-client stream of `ct`.  The message is passed to `on_message` once it's sent.
+    fn next_injection(&mut self, _ct: ConnTuple) -> Option<Message> { None }
     fn on_timeout(&mut self, ct: ConnTuple) {}
+    /// Called when the stream of `ct` in direction `dir` stops producing messages while packets
+    /// keep arriving.  It's called once per stall; decoding a message again ends the stall.
+    fn on_stall(&mut self, _ct: ConnTuple, _dir: u8, _stall: &Stall) {}
+    /// Called for each anomaly in the stream of `ct` in direction `dir`, after it's been logged.
+    fn on_warning(&mut self, _ct: ConnTuple, _dir: u8, _warning: &StreamWarning) {}
+    /// Called when a connection is dropped by `TfhStreamConns::close`, rather than by timing out.
+    fn on_close(&mut self, _ct: ConnTuple) {}
+    /// Called when the first packet of a connection not currently tracked is seen, before
+    /// `on_connect`.  Returning saved state resumes the connection from it, as one that was
+    /// active in a previous run, and `on_connect` isn't called.
+    fn resume(&mut self, _ct: ConnTuple) -> Option<ResumeState> { None }
+    /// Called by `TfhStreamConns::checkpoint` with the state of every connection.
+    fn on_checkpoint(&mut self, _conns: &[(ConnTuple, ResumeState)]) {}
+    /// How `ct` is shown in the reassembler's own log and trace lines.  Handlers that anonymize
+    /// connections return the anonymized one.
+    fn log_conn(&self, ct: ConnTuple) -> ConnTuple { ct }
+    /// Called at the end of each `TfhStreamConns::check_timeout`, with the stream clock, for the
+    /// handler's own periodic checks.
+    fn on_tick(&mut self, _now: u64) {}
+}
+
+/// What a restarted relay needs to pick up a connection's streams where they left off.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResumeState {
+    /// For each direction, the sequence number and `index` of the next message, if the stream
+    /// was in sync.  See `TfhStream::resume_at`.
+    pub next: [Option<(u32, u64)>; 2],
+    /// Messages injected into the server-to-client stream, as the server's sequence number where
+    /// each was inserted and its length.
+    pub injected: Vec<(u32, usize)>,
+    /// Time of the connection's last packet.
+    pub last_packet: u64,
+}
+
+pub enum StreamEvent {
+    Connected(ConnTuple),
+    Message(ConnTuple, Message),
+    Timeout(ConnTuple),
+    Closed(ConnTuple),
+}
+
+/// A `StreamHandler` that just queues up each callback as a `StreamEvent`.  This is for consumers
+/// that prefer to pull events with `TfhStreamConns::poll_event` instead of implementing a handler.
+#[derive(Default)]
+pub struct EventQueue {
+    events: VecDeque<StreamEvent>,
+}
+
+impl EventQueue {
+    /// Take the oldest event.
+    pub fn pop(&mut self) -> Option<StreamEvent> {
+        self.events.pop_front()
+    }
+}
+
+impl StreamHandler for EventQueue {
+    fn on_connect(&mut self, ct: ConnTuple) {
+        self.events.push_back(StreamEvent::Connected(ct));
+    }
+
+    fn on_message(&mut self, ct: ConnTuple, msg: Message) {
+        self.events.push_back(StreamEvent::Message(ct, msg));
+    }
+
+    fn on_timeout(&mut self, ct: ConnTuple) {
+        self.events.push_back(StreamEvent::Timeout(ct));
+    }
+
+    fn on_close(&mut self, ct: ConnTuple) {
+        self.events.push_back(StreamEvent::Closed(ct));
+    }
+}
+
+/// The messages `CollectingHandler` has kept for one connection.
+#[derive(Clone, Debug, Default)]
+pub struct Collected {
+    /// Messages in the order they were decoded, both directions together.
+    pub messages: VecDeque<Message>,
+    /// Older messages dropped to stay within the handler's limit.
+    pub dropped: u64,
+    /// Whether the connection has timed out or been closed.
+    pub ended: bool,
+}
+
+/// A `StreamHandler` that keeps the messages of each connection in memory, for consumers that
+/// just want the decoded messages.  With a limit, only the newest `limit` messages of each
+/// connection are kept.  Ended connections are kept until `take` or `clear`.
+#[derive(Default)]
+pub struct CollectingHandler {
+    conns: HashMap<ConnTuple, Collected>,
+    /// Connections in the order they were first seen.
+    order: Vec<ConnTuple>,
+    limit: Option<usize>,
+}
+
+impl CollectingHandler {
+    pub fn new() -> CollectingHandler {
+        CollectingHandler::default()
+    }
+
+    pub fn with_limit(limit: usize) -> CollectingHandler {
+        CollectingHandler { limit: Some(limit), .. CollectingHandler::default() }
+    }
+
+    /// Connections with collected messages, in the order they were first seen.
+    pub fn conns(&self) -> &[ConnTuple] {
+        &self.order
+    }
+
+    pub fn get(&self, ct: ConnTuple) -> Option<&Collected> {
+        self.conns.get(&ct)
+    }
+
+    /// Messages of connection `ct` in direction `dir` (0 for client to server, 1 for server to
+    /// client).
+    pub fn messages(&self, ct: ConnTuple, dir: u8) -> impl Iterator<Item = &Message> {
+        self.conns.get(&ct).into_iter()
+            .flat_map(|c| c.messages.iter())
+            .filter(move |m| m.header.dir == dir)
+    }
+
+    /// Remove connection `ct` and return what was collected for it.
+    pub fn take(&mut self, ct: ConnTuple) -> Option<Collected> {
+        self.order.retain(|&x| x != ct);
+        self.conns.remove(&ct)
+    }
+
+    pub fn clear(&mut self) {
+        self.conns.clear();
+        self.order.clear();
+    }
+
+    fn entry(&mut self, ct: ConnTuple) -> &mut Collected {
+        let order = &mut self.order;
+        self.conns.entry(ct).or_insert_with(|| {
+            order.push(ct);
+            Collected::default()
+        })
+    }
+}
+
+impl StreamHandler for CollectingHandler {
+    fn on_connect(&mut self, ct: ConnTuple) {
+        // A connection that reconnects after ending starts over.
+        if self.conns.get(&ct).map_or(false, |c| c.ended) {
+            self.take(ct);
+        }
+        self.entry(ct);
+    }
+
+    fn on_message(&mut self, ct: ConnTuple, msg: Message) {
+        let limit = self.limit;
+        let c = self.entry(ct);
+        if limit == Some(0) {
+            c.dropped += 1;
+            return;
+        }
+        if limit == Some(c.messages.len()) {
+            c.messages.pop_front();
+            c.dropped += 1;
+        }
+        c.messages.push_back(msg);
+    }
+
+    fn on_timeout(&mut self, ct: ConnTuple) {
+        self.entry(ct).ended = true;
+    }
+
+    fn on_close(&mut self, ct: ConnTuple) {
+        self.entry(ct).ended = true;
+    }
 }
 
 pub struct TfhStreamConns<H> {
     map: HashMap<ConnTuple, StreamConn>,
     handler: H,
+    /// Latest capture timestamp seen on a packet.  `None` if packets are live.
+    capture_time: Option<u64>,
+    /// Decoding warnings for client-to-server and server-to-client streams, not yet collected by
+    /// `take_warnings`.
+    warnings: [u64; 2],
+    /// Stalls in each direction, not yet collected by `take_stalls`.
+    stalls: [u64; 2],
+    /// Connections that haven't finished the handshake are dropped after this many
+    /// microseconds, instead of `CONN_TIMEOUT`.
+    handshake_timeout: Option<u64>,
+    /// Connections dropped by `handshake_timeout`, not yet collected by
+    /// `take_handshake_timeouts`.
+    handshake_timeouts: u64,
+    /// High-water marks of connections that have been dropped.
+    peak: BufferMarks,
+    /// Round-trip times measured on every connection, not yet collected by `take_rtt`.
+    rtt: Rtt,
+    /// Connections dropped by the last `check_timeout` or closed since, not yet collected by
+    /// `take_ended`.  Cleared by each `check_timeout`, so it can't grow without limit.
+    ended: Vec<ConnTuple>,
+    /// Print a line for each packet of connections with this endpoint.  See `set_trace`.
+    trace: Option<(u32, Option<u16>)>,
+    /// Applied to each stream.  See `TfhStream::set_max_message_len`.
+    max_message_len: usize,
+    /// Check each packet's length and checksum.  See `set_check_packets`.
+    check_packets: bool,
 }
 
-const CONN_TIMEOUT: u64 = 60;
+/// Connections with no packets for this many microseconds are dropped.
+const CONN_TIMEOUT: u64 = 60_000_000;
+
+/// Injected messages are sent again if the client hasn't acknowledged them after this many
+/// microseconds, since the server won't retransmit them.
+const INJECT_RESEND_INTERVAL: u64 = 500_000;
 
 impl<H: StreamHandler> TfhStreamConns<H> {
     pub fn new(handler: H) -> TfhStreamConns<H> {
         TfhStreamConns {
             map: HashMap::new(),
             handler,
+            capture_time: None,
+            warnings: [0; 2],
+            stalls: [0; 2],
+            handshake_timeout: None,
+            handshake_timeouts: 0,
+            peak: BufferMarks::default(),
+            rtt: Rtt::default(),
+            ended: Vec::new(),
+            trace: None,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            check_packets: false,
+        }
+    }
+
+    /// Set the longest message to accept on every connection, as for
+    /// `TfhStream::set_max_message_len`.
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = len;
+        for sc in self.map.values_mut() {
+            sc.ab.set_max_message_len(len);
+            sc.ba.set_max_message_len(len);
         }
     }
 
+    /// Log a line (at `Info`, in the `Stream` subsystem) for each packet of the connections with
+    /// an end at address `ip` (and `port`, if given), showing what the reassembler made of it:
+    /// the packet's sequence range and acknowledgement, what's buffered afterward and any gap
+    /// holding up decoding, the messages it completed, and how many decoded messages the other
+    /// side has yet to acknowledge.  This is for working out why a connection stalls.
+    pub fn set_trace(&mut self, conn: Option<(u32, Option<u16>)>) {
+        self.trace = conn;
+    }
+
+    /// Check that each packet holds all the bytes its UDP header claims, and that its UDP
+    /// checksum is right, reporting `Truncated` and `BadChecksum` warnings for those that don't.
+    /// Off by default, since it means summing every packet.
+    pub fn set_check_packets(&mut self, check: bool) {
+        self.check_packets = check;
+    }
+
+    fn traced(&self, ct: ConnTuple) -> bool {
+        self.trace.map_or(false, |(ip, port)| ct.has_endpoint(ip, port))
+    }
+
+    /// Drop connections that haven't finished the handshake (see `handshaking`) once they're
+    /// `timeout` microseconds old, rather than waiting for them to go quiet.  This keeps a flood
+    /// of half-open connections from holding state for a whole `CONN_TIMEOUT` each.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<u64>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Current time according to the packets, in microseconds since the Unix epoch.  When
+    /// packets carry capture timestamps, as when replaying a pcap, this is the latest of those,
+    /// so connections time out just as they did when the capture was recorded.  Otherwise it's
+    /// `clock::monotonic_us`, so stepping the system clock doesn't stop connections from timing
+    /// out, or time them all out at once.
+    pub fn now(&self) -> u64 {
+        self.capture_time.unwrap_or_else(clock::monotonic_us)
+    }
+
+    /// Move the clock forward to `time`, a capture timestamp.  `handle` does this for each packet
+    /// that has a timestamp, but calling it first lets `check_timeout` expire connections that
+    /// went quiet before this packet arrived.
+    pub fn advance(&mut self, time: u64) {
+        self.capture_time = Some(self.capture_time.map_or(time, |old| cmp::max(old, time)));
+    }
+
     pub fn handle(&mut self, p: &Packet, flip: bool) {
         if !p.is_tfh_stream() {
             return;
         }
-        let ct = ConnTuple::from_udp_packet(&p, flip);
-        let sc = self.map.entry(ct).or_insert_with(StreamConn::new);
-
-        sc.last_packet = Instant::now();
+        let (ct, now, traced) = self.start_packet(p, flip);
+        let sc = self.map.get_mut(&ct).unwrap();
 
         let stream = if !flip { &mut sc.ab } else { &mut sc.ba };
+        let before = stream.position();
         stream.handle_packet(p);
+        sc.track_rtt(p, flip, now, &mut self.rtt);
 
-        while let Some(mut msg) = sc.ab.next_message() {
-            msg.header.dir = 0;
-            self.handler.on_message(ct, msg);
+        let mut emitted = Vec::new();
+        emit_messages(&mut self.handler, sc, ct, now, false, traced, &mut emitted);
+        if traced {
+            log!(Stream, Info, "trace: {}",
+                sc.trace_line(self.handler.log_conn(ct), p, flip, before, &emitted, 0));
         }
-        while let Some(mut msg) = sc.ba.next_message() {
-            msg.header.dir = 1;
-            self.handler.on_message(ct, msg);
+        finish_packet(&mut self.handler, &mut self.stalls, &mut self.warnings, ct, sc, flip);
+    }
+
+    /// The start of handling a packet for `handle` and `handle_mut`: move the clock on, look up
+    /// or add the connection, and check the packet.  Returns the connection, the time, and
+    /// whether the connection is traced.
+    fn start_packet(&mut self, p: &Packet, flip: bool) -> (ConnTuple, u64, bool) {
+        if let Some(t) = p.time() {
+            self.advance(t);
+        }
+        let now = self.now();
+
+        let ct = ConnTuple::from_udp_packet(&p, flip);
+        let traced = self.traced(ct);
+        let sc = get_conn(&mut self.map, &mut self.handler, ct, now, self.max_message_len);
+
+        sc.last_packet = now;
+        if self.check_packets {
+            check_packet(&mut self.handler, &mut self.warnings, ct, p, flip);
         }
+        (ct, now, traced)
+    }
+
+    /// Like `handle`, but lets the handler rewrite each message, and edits `p` to match.  Edits
+    /// can only reach packets that haven't been forwarded yet, so in a message split across
+    /// several packets, only the bytes in the packet that completed it are changed (plus any
+    /// later retransmissions).
+    ///
+    /// This also inserts the handler's `next_injection` messages into the server-to-client
+    /// stream, adjusting sequence numbers in both directions to hide them from the server.
+    /// Returns extra packets carrying injected messages, to be sent after `p`.
+    pub fn handle_mut(&mut self, p: &mut Packet, flip: bool) -> Vec<Packet> {
+        if !p.is_tfh_stream() {
+            return Vec::new();
+        }
+        let (ct, now, traced) = self.start_packet(p, flip);
+        let sc = self.map.get_mut(&ct).unwrap();
+
+        let mut changed = false;
+        if !flip && sc.splice.inserted.len() > 0 {
+            // The client acknowledges server data by its position in the stream it received,
+            // which includes the injected messages.
+            let ack = Seq(p.tfh_stream().your_seq());
+            sc.splice.unacked.retain(|&(_, end, _)| end > ack);
+            let ack = sc.splice.unshift_ack(ack);
+            p.tfh_stream_mut().set_your_seq(ack.0);
+            changed = true;
+        }
+
+        let before = {
+            let stream = if !flip { &mut sc.ab } else { &mut sc.ba };
+            let before = stream.position();
+            stream.handle_packet(p);
+            before
+        };
+        sc.track_rtt(p, flip, now, &mut self.rtt);
+
+        let mut emitted = Vec::new();
+        emit_messages(&mut self.handler, sc, ct, now, true, traced, &mut emitted);
+        finish_packet(&mut self.handler, &mut self.stalls, &mut self.warnings, ct, sc, flip);
+
+        let stream = if !flip { &sc.ab } else { &sc.ba };
+        let start = Seq(p.tfh_stream().my_seq());
+        changed |= stream.apply_patches(start, p.tfh_stream_payload_mut());
+
+        let mut extra = Vec::new();
+        if flip {
+            if sc.splice.inserted.len() > 0 {
+                p.tfh_stream_mut().set_my_seq(sc.splice.shift(start).0);
+                changed = true;
+            }
+
+            for &mut (ref q, _, ref mut sent) in &mut sc.splice.unacked {
+                if now.saturating_sub(*sent) >= INJECT_RESEND_INTERVAL {
+                    extra.push(q.clone());
+                    *sent = now;
+                }
+            }
+
+            while sc.ba.at_boundary() {
+                let mut msg = match self.handler.next_injection(ct) {
+                    Some(x) => x,
+                    None => break,
+                };
+                msg.time = now;
+                let q = match sc.splice.inject(p, sc.ba.start, &msg, now) {
+                    Some(x) => x,
+                    None => {
+                        let w = StreamWarning::InjectionTooLong { len: msg.body.len() };
+                        report_warning(&mut self.handler, ct, 1, &w);
+                        continue;
+                    },
+                };
+                extra.push(q);
+                msg.index = sc.ba.take_index();
+                self.handler.on_message(ct, msg);
+            }
+        }
+
+        if traced {
+            log!(Stream, Info, "trace: {}",
+                sc.trace_line(self.handler.log_conn(ct), p, flip, before, &emitted, extra.len()));
+        }
+        if changed {
+            p.update_udp_checksum();
+        }
+        extra
+    }
+
+    /// Apply edits from `handle_mut` to `p`, a packet that was handled earlier.  Offline tools
+    /// that hold packets back use this to catch up packets carrying the start of a message that
+    /// was only rewritten once a later packet completed it.  Returns whether `p` changed.
+    pub fn apply_patches(&self, p: &mut Packet, flip: bool) -> bool {
+        if !p.is_tfh_stream() {
+            return false;
+        }
+        let ct = ConnTuple::from_udp_packet(&p, flip);
+        let sc = match self.map.get(&ct) {
+            Some(x) => x,
+            None => return false,
+        };
+        let stream = if !flip { &sc.ab } else { &sc.ba };
+        let start = Seq(p.tfh_stream().my_seq());
+        if !stream.apply_patches(start, p.tfh_stream_payload_mut()) {
+            return false;
+        }
+        p.update_udp_checksum();
+        true
+    }
+
+    /// Return the number of decoding warnings in each direction since the last call.
+    pub fn take_warnings(&mut self) -> [u64; 2] {
+        mem::replace(&mut self.warnings, [0; 2])
+    }
+
+    /// Return the number of stalls (see `StreamHandler::on_stall`) in each direction since the
+    /// last call.
+    pub fn take_stalls(&mut self) -> [u64; 2] {
+        mem::replace(&mut self.stalls, [0; 2])
+    }
+
+    /// Number of connections that haven't finished the handshake.
+    pub fn handshaking(&self) -> usize {
+        self.map.values().filter(|sc| !sc.established).count()
+    }
+
+    /// Return the number of connections dropped for not finishing the handshake in time, since
+    /// the last call.
+    pub fn take_handshake_timeouts(&mut self) -> u64 {
+        mem::replace(&mut self.handshake_timeouts, 0)
+    }
+
+    /// High-water marks for connection `ct`, over both directions.
+    pub fn buffer_marks(&self, ct: ConnTuple) -> Option<BufferMarks> {
+        self.map.get(&ct).map(|sc| sc.marks())
+    }
+
+    /// Round-trip times measured on connection `ct` so far.
+    pub fn rtt(&self, ct: ConnTuple) -> Option<&Rtt> {
+        self.map.get(&ct).map(|sc| &sc.rtt)
+    }
+
+    /// Round-trip times of each open connection.
+    pub fn rtts(&self) -> impl Iterator<Item = (ConnTuple, &Rtt)> {
+        self.map.iter().map(|(&ct, sc)| (ct, &sc.rtt))
+    }
+
+    /// Return the round-trip times measured on all connections since the last call.
+    pub fn take_rtt(&mut self) -> Rtt {
+        mem::take(&mut self.rtt)
+    }
+
+    /// Return the connections that timed out in the last `check_timeout`, or were closed since
+    /// then, and haven't been returned before.
+    pub fn take_ended(&mut self) -> Vec<ConnTuple> {
+        mem::take(&mut self.ended)
+    }
+
+    /// High-water marks over every connection seen so far, including ones that were dropped.
+    pub fn peak_buffer_marks(&self) -> BufferMarks {
+        let mut marks = self.peak;
+        for sc in self.map.values() {
+            marks.merge(sc.marks());
+        }
+        marks
+    }
+
+    fn retire(&mut self, orig: ConnTuple, sc: &StreamConn) {
+        let marks = sc.marks();
+        let ct = self.handler.log_conn(orig);
+        log!(Stream, Debug, "{}: buffer high water: {} bytes, {} chunks",
+            ct, marks.buf, marks.chunks);
+        log!(Stream, Debug, "{}: rtt to client: {}", ct, sc.rtt.client.describe());
+        log!(Stream, Debug, "{}: rtt to server: {}", ct, sc.rtt.server.describe());
+        self.peak.merge(marks);
+        self.ended.push(orig);
     }
 
     pub fn check_timeout(&mut self) {
+        let now = self.now();
+        self.ended.clear();
         let mut remove = Vec::new();
         for (k, v) in &mut self.map {
-            if v.last_packet.elapsed().as_secs() as u64 >= CONN_TIMEOUT {
+            let stuck = !v.established &&
+                self.handshake_timeout.map_or(false, |t| now.saturating_sub(v.created) >= t);
+            if stuck || now.saturating_sub(v.last_packet) >= CONN_TIMEOUT {
+                if stuck {
+                    self.handshake_timeouts += 1;
+                }
                 self.handler.on_timeout(*k);
                 remove.push(*k);
             }
         }
 
         for k in remove {
-            self.map.remove(&k);
+            if let Some(sc) = self.map.remove(&k) {
+                self.retire(k, &sc);
+            }
+        }
+        self.handler.on_tick(now);
+    }
+
+    /// Pass the state of every connection to the handler's `on_checkpoint`, so it can be saved
+    /// for resuming them after a restart.
+    pub fn checkpoint(&mut self) {
+        let conns = self.map.iter().map(|(&ct, sc)| (ct, sc.resume_state())).collect::<Vec<_>>();
+        self.handler.on_checkpoint(&conns);
+    }
+
+    /// Drop the state for connection `ct`, if any, notifying the handler.
+    pub fn close(&mut self, ct: ConnTuple) {
+        if let Some(sc) = self.map.remove(&ct) {
+            self.retire(ct, &sc);
+            self.handler.on_close(ct);
+        }
+    }
+
+    /// Drop all connections, such as when the end of a capture file is reached.
+    pub fn close_all(&mut self) {
+        for (k, sc) in mem::replace(&mut self.map, HashMap::new()) {
+            self.retire(k, &sc);
+            self.handler.on_close(k);
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+}
+
+impl TfhStreamConns<EventQueue> {
+    pub fn with_events() -> TfhStreamConns<EventQueue> {
+        TfhStreamConns::new(EventQueue::default())
+    }
+
+    /// Take the oldest event produced by `handle`, `check_timeout`, or `close`.
+    pub fn poll_event(&mut self) -> Option<StreamEvent> {
+        self.handler.pop()
+    }
+}
+
+/// Report `p` if it's truncated or has a bad checksum, as `set_check_packets` asks, adding it
+/// to `counts`.
+fn check_packet<H: StreamHandler>(
+    handler: &mut H,
+    counts: &mut [u64; 2],
+    ct: ConnTuple,
+    p: &Packet,
+    flip: bool,
+) {
+    let seq = p.tfh_stream().my_seq();
+    let payload = p.udp_payload();
+    let expected = (p.udp().len() as usize).saturating_sub(8);
+    let w = if expected > payload.len() {
+        StreamWarning::Truncated { seq, len: payload.len() + 8, expected: expected + 8 }
+    } else {
+        let sum = p.udp().checksum();
+        // Zero means the sender didn't compute one.  Bytes past the UDP length are padding.
+        if sum == 0 || sum == p.compute_udp_checksum(&payload[..expected]) {
+            return;
+        }
+        StreamWarning::BadChecksum { seq }
+    };
+    counts[flip as usize] += 1;
+    report_warning(handler, ct, flip as u8, &w);
+}
+
+fn report_warning<H: StreamHandler>(handler: &mut H, ct: ConnTuple, dir: u8, w: &StreamWarning) {
+    log!(Stream, Warn, "{}: direction {}: {}", handler.log_conn(ct), dir, w);
+    handler.on_warning(ct, dir, w);
+}
+
+/// Report the decoding warnings from both of `sc`'s streams, adding them to `counts`.
+fn report_stream_warnings<H: StreamHandler>(
+    handler: &mut H,
+    counts: &mut [u64; 2],
+    ct: ConnTuple,
+    sc: &mut StreamConn,
+) {
+    for dir in 0 .. 2 {
+        let stream = if dir == 0 { &mut sc.ab } else { &mut sc.ba };
+        for w in stream.take_warning_details() {
+            report_warning(handler, ct, dir as u8, &w);
+        }
+        counts[dir] += stream.take_warnings();
+    }
+}
+
+/// Pass the messages newly decoded in both directions of `sc` to the handler, first letting it
+/// rewrite them if `rewrite` is set.  If `traced`, their headers are added to `emitted`.
+fn emit_messages<H: StreamHandler>(
+    handler: &mut H,
+    sc: &mut StreamConn,
+    ct: ConnTuple,
+    now: u64,
+    rewrite: bool,
+    traced: bool,
+    emitted: &mut Vec<MessageHeader>,
+) {
+    for dir in 0 .. 2 {
+        let (stream, other) = if dir == 0 {
+            (&mut sc.ab, &mut sc.ba)
+        } else {
+            (&mut sc.ba, &mut sc.ab)
+        };
+        while let Some((at, mut msg)) = stream.next_message_at() {
+            msg.header.dir = dir;
+            msg.time = now;
+            msg.acks = other.take_acked(Seq(msg.header.ack));
+            if rewrite {
+                let orig = msg.body.clone();
+                if handler.rewrite(ct, &mut msg) {
+                    if msg.body.len() == orig.len() {
+                        stream.patch(at, msg.body.clone());
+                    } else {
+                        // The packets can't be edited to match, so keep the handler from seeing
+                        // a message that was never sent.
+                        let w = StreamWarning::RewriteLength { at: at.0, from: orig.len(),
+                            to: msg.body.len() };
+                        msg.body = orig;
+                        report_warning(handler, ct, dir, &w);
+                    }
+                }
+            }
+            if traced {
+                emitted.push(msg.header);
+            }
+            handler.on_message(ct, msg);
         }
     }
 }
 
+/// The end of handling a packet that arrived in direction `flip`: check for a stall in that
+/// direction, update the handshake state, and report decoding warnings.
+fn finish_packet<H: StreamHandler>(
+    handler: &mut H,
+    stalls: &mut [u64; 2],
+    warnings: &mut [u64; 2],
+    ct: ConnTuple,
+    sc: &mut StreamConn,
+    flip: bool,
+) {
+    let stream = if !flip { &mut sc.ab } else { &mut sc.ba };
+    if let Some(stall) = stream.check_stall() {
+        report_stall(handler, stalls, ct, flip as u8, &stall);
+    }
+    sc.update_handshake();
+    report_stream_warnings(handler, warnings, ct, sc);
+}
+
+fn report_stall<H: StreamHandler>(
+    handler: &mut H,
+    stalls: &mut [u64; 2],
+    ct: ConnTuple,
+    dir: u8,
+    stall: &Stall,
+) {
+    log!(Stream, Warn, "{}: direction {} stalled: {}", handler.log_conn(ct), dir, stall);
+    stalls[dir as usize] += 1;
+    handler.on_stall(ct, dir, stall);
+}
+
+/// Look up `ct` in `map`, adding it if it's new: resumed from the handler's saved state, if it
+/// has any, or as a new connection.
+fn get_conn<'a, H: StreamHandler>(
+    map: &'a mut HashMap<ConnTuple, StreamConn>,
+    handler: &mut H,
+    ct: ConnTuple,
+    now: u64,
+    max_message_len: usize,
+) -> &'a mut StreamConn {
+    map.entry(ct).or_insert_with(|| match handler.resume(ct) {
+        Some(state) => StreamConn::resumed(now, max_message_len, &state),
+        None => {
+            handler.on_connect(ct);
+            StreamConn::new(now, max_message_len)
+        },
+    })
+}
+
 struct StreamConn {
     ab: TfhStream,
     ba: TfhStream,
-    last_packet: Instant,
+    /// Time of the first packet.
+    created: u64,
+    /// Time of the latest packet, according to `TfhStreamConns::now`.
+    last_packet: u64,
+    /// Has the handshake finished?  See `update_handshake`.
+    established: bool,
+    splice: Splice,
+    /// Data forwarded client-to-server and server-to-client, awaiting acknowledgement.
+    in_flight: [InFlight; 2],
+    rtt: Rtt,
 }
 
 impl StreamConn {
-    pub fn new() -> StreamConn {
+    pub fn new(now: u64, max_message_len: usize) -> StreamConn {
+        let mut ab = TfhStream::new();
+        let mut ba = TfhStream::new();
+        ab.set_max_message_len(max_message_len);
+        ba.set_max_message_len(max_message_len);
         StreamConn {
-            ab: TfhStream::new(),
-            ba: TfhStream::new(),
-            last_packet: Instant::now(),
+            ab,
+            ba,
+            created: now,
+            last_packet: now,
+            established: false,
+            splice: Splice::default(),
+            in_flight: Default::default(),
+            rtt: Rtt::default(),
+        }
+    }
+
+    /// A connection resumed from `state`, saved by a previous run.  It's taken to have finished
+    /// the handshake.
+    fn resumed(now: u64, max_message_len: usize, state: &ResumeState) -> StreamConn {
+        let mut sc = StreamConn::new(now, max_message_len);
+        for (stream, next) in [&mut sc.ab, &mut sc.ba].iter_mut().zip(&state.next) {
+            if let Some((seq, index)) = *next {
+                stream.resume_at(seq, index);
+            }
+        }
+        sc.splice.inserted = state.injected.iter().map(|&(at, len)| (Seq(at), len)).collect();
+        sc.established = true;
+        sc
+    }
+
+    fn resume_state(&self) -> ResumeState {
+        ResumeState {
+            next: [self.ab.resume_point(), self.ba.resume_point()],
+            injected: self.splice.inserted.iter().map(|&(at, len)| (at.0, len)).collect(),
+            last_packet: self.last_packet,
+        }
+    }
+
+    /// Note the data in `p` as in flight, and take a round-trip sample if it acknowledges data
+    /// in flight the other way.  Samples are recorded in `self.rtt` and in `all`.
+    fn track_rtt(&mut self, p: &Packet, flip: bool, now: u64, all: &mut Rtt) {
+        let dir = flip as usize;
+        let start = Seq(p.tfh_stream().my_seq());
+        let len = p.tfh_stream_payload().len();
+        if len > 0 {
+            self.in_flight[dir].send(start, start + len, now);
         }
+        let rtt = match self.in_flight[1 - dir].ack(Seq(p.tfh_stream().your_seq()), now) {
+            Some(x) => x,
+            None => return,
+        };
+        // A packet from the client acknowledges server data, timing the client's leg.
+        if !flip {
+            self.rtt.client.record(rtt);
+            all.client.record(rtt);
+        } else {
+            self.rtt.server.record(rtt);
+            all.server.record(rtt);
+        }
+    }
+
+    /// The handshake is finished once the client has sent its preamble byte and its first
+    /// message (the login), and the server has sent its preamble.  Connections we joined partway
+    /// through, such as after a restart, have missed the handshake, so they count as finished
+    /// once a message has been decoded each way; a flood that only ever goes one way doesn't.
+    fn update_handshake(&mut self) {
+        if !self.established {
+            self.established = if self.ab.joined_late() || self.ba.joined_late() {
+                self.ab.next_index >= 1 && self.ba.next_index >= 1
+            } else {
+                self.ab.next_index >= 2 && self.ba.next_index >= 1
+            };
+        }
+    }
+
+    /// Describe how `p` was handled, for `TfhStreamConns::set_trace`.  `before` is its stream's
+    /// `position` before `p` was added, `emitted` the headers of the messages it completed, and
+    /// `injected` the number of packets of injected messages sent after it.
+    fn trace_line(
+        &self,
+        ct: ConnTuple,
+        p: &Packet,
+        flip: bool,
+        before: (Seq, Seq),
+        emitted: &[MessageHeader],
+        injected: usize,
+    ) -> String {
+        let (stream, other) = if !flip { (&self.ab, &self.ba) } else { (&self.ba, &self.ab) };
+        let start = Seq(p.tfh_stream().my_seq());
+        let end = start + p.tfh_stream_payload().len();
+        let mut s = format!("{} dir {}: seq {}..{} ack {}", ct, flip as u8, start, end,
+            p.tfh_stream().your_seq());
+        // Nothing was buffered before the first packet, so it can't be old or out of order.
+        if before.0 != Seq(!0) {
+            if end <= before.0 && end > start {
+                s += " (already decoded)";
+            } else if start > before.1 {
+                s += &format!(" (out of order, expected {})", before.1);
+            }
+        }
+        s += &format!("; {}", stream.describe());
+        if emitted.len() > 0 {
+            let msgs = emitted.iter()
+                .map(|h| format!("{:02x}:{:02x}", h.major, h.minor))
+                .collect::<Vec<_>>();
+            s += &format!("; decoded {}", msgs.join(" "));
+        }
+        if injected > 0 {
+            s += &format!("; sent {} injected packets", injected);
+        }
+        s += &format!("; unacked messages: {} this way, {} the other way",
+            stream.unacked.len(), other.unacked.len());
+        s
+    }
+
+    fn marks(&self) -> BufferMarks {
+        let mut marks = self.ab.marks();
+        marks.merge(self.ba.marks());
+        marks
+    }
+}
+
+/// Most segments to remember in `InFlight`, in case the other side's acknowledgements are
+/// missing from a capture.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// The data one side has sent that the other hasn't acknowledged, for estimating round-trip
+/// times.  Only the stream positions and times are kept, not the data.
+#[derive(Default)]
+struct InFlight {
+    /// End of each new segment, when it was first seen, and whether it's been retransmitted.
+    segments: VecDeque<(Seq, u64, bool)>,
+    /// End of the furthest data seen.
+    end: Seq,
+}
+
+impl InFlight {
+    fn send(&mut self, start: Seq, end: Seq, now: u64) {
+        if end > self.end {
+            if self.segments.len() == MAX_IN_FLIGHT {
+                self.segments.pop_front();
+            }
+            self.segments.push_back((end, now, false));
+            self.end = end;
+        } else {
+            // An acknowledgement of retransmitted data could be for either copy, so don't take
+            // samples from it (Karn's algorithm).
+            for seg in self.segments.iter_mut().filter(|seg| seg.0 > start && seg.0 <= end) {
+                seg.2 = true;
+            }
+        }
+    }
+
+    /// Drop the segments covered by `ack`, returning the time since the last of them was sent.
+    fn ack(&mut self, ack: Seq, now: u64) -> Option<u64> {
+        let mut newest = None;
+        while let Some(&(end, sent, retransmitted)) = self.segments.front() {
+            if end > ack {
+                break;
+            }
+            self.segments.pop_front();
+            newest = Some((sent, retransmitted));
+        }
+        match newest {
+            Some((sent, false)) => Some(now.saturating_sub(sent)),
+            _ => None,
+        }
+    }
+}
+
+/// Messages injected into a server-to-client stream.  The server doesn't know about them, so
+/// its sequence numbers are shifted past them on the way to the client, and the client's
+/// acknowledgements shifted back.
+#[derive(Default)]
+struct Splice {
+    /// Injected blocks, in order: the server's sequence number where each was inserted, and its
+    /// length.
+    inserted: Vec<(Seq, usize)>,
+    /// Packets of injected data the client hasn't acknowledged yet, with the client's sequence
+    /// number for their end and the time they were last sent.
+    unacked: Vec<(Packet, Seq, u64)>,
+}
+
+impl Splice {
+    /// Convert the server's sequence number `seq` to the one the client sees.
+    fn shift(&self, seq: Seq) -> Seq {
+        let added = self.inserted.iter()
+            .filter(|&&(at, _)| at <= seq)
+            .map(|&(_, len)| len)
+            .sum::<usize>();
+        seq + added
+    }
+
+    /// Convert an acknowledgement from the client to the server's sequence numbers.  Partly
+    /// acknowledging an injected block counts as acknowledging the data before it.
+    fn unshift_ack(&self, ack: Seq) -> Seq {
+        let mut added = 0;
+        for &(at, len) in &self.inserted {
+            let client_at = at + added;
+            if ack <= client_at {
+                break;
+            }
+            if ack < client_at + len {
+                return at;
+            }
+            added += len;
+        }
+        Seq(ack.0 - added as u32)
+    }
+
+    /// Build a packet carrying `msg` at position `at` of the server's stream, copying the
+    /// headers from `template`, a server packet.  Returns `None` if the message doesn't fit in a
+    /// packet.
+    fn inject(&mut self, template: &Packet, at: Seq, msg: &Message, now: u64) -> Option<Packet> {
+        let data = msg.encode();
+        let client_at = self.shift(at);
+
+        let mut payload = template.udp_payload()[..TFH_STREAM_HEADER_LEN].to_owned();
+        payload.extend_from_slice(&data);
+        {
+            let tfh = TfhStreamHeader::new_mut(&mut payload);
+            tfh.set_my_seq(client_at.0);
+            tfh.set_flags(0);
+        }
+        let mut q = template.clone();
+        if !q.set_udp_payload(&payload) {
+            return None;
+        }
+
+        self.inserted.push((at, data.len()));
+        self.unacked.push((q.clone(), client_at + data.len(), now));
+        Some(q)
     }
 }
 
@@ -0,0 +1,481 @@
+//! Reading and writing `.tfhlog` message logs.
+//!
+//! A log starts with a file header: the magic bytes `TFHL`, then a big-endian u32 format version,
+//! then a table of the opcodes that were sampled rather than logged in full: a big-endian u16
+//! count of entries, then for each entry the major and minor opcode, a flags byte whose bit 0
+//! means the entry covers every minor opcode of the major, a zero byte, and a big-endian u32 rate
+//! `N`, meaning one in `N` of those messages was kept.  Each record after that consists of:
+//!
+//!  - timestamp: u64, microseconds since the Unix epoch
+//!  - connection: 12 bytes, as produced by `ConnTuple::as_bytes`
+//!  - index: u64, the message's `Message::index`
+//!  - acks: two u64s, the start and end of the message's `Message::acks`
+//!  - session: u32, the connection's `SessionId`, or zero if unknown
+//!  - message header: 12 bytes, as produced by `MessageHeader::as_bytes`, except that byte 3
+//!    holds flags
+//!  - message body: `len` bytes, where `len` comes from the message header
+//!  - CRC: u32, the CRC-32 of the record's bytes from the timestamp to the end of the body
+//!
+//! Bit 0 of the flags means the body was left out, as for connections past the
+//! `--log-max-bytes` or `--log-max-messages` cap (see `LogLayout`): the record goes straight from
+//! the header to the CRC, and the header's `len` still gives the body's length.  `Reader` returns
+//! such a message with an empty body, and sets `Record::body_omitted`.
+//!
+//! The CRCs mean a record damaged on disk, or half-written when the relay crashed, is reported
+//! as an error rather than read as garbage.  `Reader::position` gives where the bad record
+//! starts, and `tfh log-verify` checks whole logs and can cut off a damaged tail.
+//!
+//! Logs written before the file header was introduced (reported as version 0) have no magic and
+//! contain only the message header and body of each record.  For these, `Reader` numbers the
+//! messages itself, which gives the same indices as long as the log holds every message of each
+//! connection.
+//!
+//! `open` reads a log from a file, including one the disk watchdog has compressed to
+//! `.tfhlog.gz` (see `disk_watchdog`), which goes through `gzip -d`.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use crate::bytes::Bytes;
+use crate::log_layout::{self, OpcodeSpec};
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, Message, MessageHeader};
+use crate::util::crc32;
+
+
+pub const MAGIC: [u8; 4] = *b"TFHL";
+pub const VERSION: u32 = 2;
+
+/// Bytes in a record before the message body.
+pub const RECORD_HEADER_LEN: usize = 60;
+
+/// Bytes in a record after the message body: the CRC.
+pub const RECORD_TRAILER_LEN: usize = 4;
+
+/// How far back from the end of a log `Reader::seek_tail` looks for the start of a record.
+pub const TAIL_LEN: u64 = 1 << 20;
+
+/// Flag in byte 3 of the message header of a record whose body was left out.
+const FLAG_BODY_OMITTED: u8 = 0x01;
+
+/// Opcodes logged one in so many times, as in `LogLayout::sampling`.
+pub type Sampling = [(OpcodeSpec, u32)];
+
+pub struct Record {
+    /// Microseconds since the Unix epoch.  Always zero in version 0 logs.
+    pub time: u64,
+    /// The connection this message belongs to.  Unavailable in version 0 logs.
+    pub conn: Option<ConnTuple>,
+    /// Which connection from `conn` this was.  Unavailable in version 0 logs.
+    pub session: Option<SessionId>,
+    pub msg: Message,
+    /// The body was left out of the log, so `msg.body` is empty.  Never in version 0 logs.
+    pub body_omitted: bool,
+}
+
+pub struct Writer<W> {
+    w: W,
+    /// Bytes written to the log so far, counting those already there when appending.
+    pos: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a new log, writing the file header to `w`.
+    pub fn new(w: W) -> io::Result<Writer<W>> {
+        Writer::with_sampling(w, &[])
+    }
+
+    /// Start a new log whose messages of some opcodes were sampled, recording the rates in the
+    /// file header.
+    pub fn with_sampling(mut w: W, sampling: &Sampling) -> io::Result<Writer<W>> {
+        if sampling.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many sampled opcodes"));
+        }
+        let mut hdr = Vec::with_capacity(10 + 8 * sampling.len());
+        hdr.extend_from_slice(&MAGIC);
+        hdr.extend_from_slice(&VERSION.to_be_bytes());
+        hdr.extend_from_slice(&(sampling.len() as u16).to_be_bytes());
+        for &((major, minor), rate) in sampling {
+            hdr.extend_from_slice(&[major, minor.unwrap_or(0), minor.is_none() as u8, 0]);
+            hdr.extend_from_slice(&rate.to_be_bytes());
+        }
+        w.write_all(&hdr)?;
+        Ok(Writer { w, pos: hdr.len() as u64 })
+    }
+
+    /// Add records to an existing log, `len` bytes long.  `w` must be positioned at its end, as a
+    /// file opened for appending is.
+    pub fn append(w: W, len: u64) -> Writer<W> {
+        Writer { w, pos: len }
+    }
+
+    /// Offset in the file of the next record.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn write(
+        &mut self,
+        time: u64,
+        ct: ConnTuple,
+        session: Option<SessionId>,
+        msg: &Message,
+    ) -> io::Result<()> {
+        self.write_parts(time, ct, session, msg, false)
+    }
+
+    /// Write `msg` without its body, keeping its length in the header.
+    pub fn write_header_only(
+        &mut self,
+        time: u64,
+        ct: ConnTuple,
+        session: Option<SessionId>,
+        msg: &Message,
+    ) -> io::Result<()> {
+        self.write_parts(time, ct, session, msg, true)
+    }
+
+    fn write_parts(
+        &mut self,
+        time: u64,
+        ct: ConnTuple,
+        session: Option<SessionId>,
+        msg: &Message,
+        omit_body: bool,
+    ) -> io::Result<()> {
+        // Build the whole record first so it reaches the file in a single write.
+        let len = RECORD_HEADER_LEN + msg.body.len() + RECORD_TRAILER_LEN;
+        let mut buf = Vec::with_capacity(len);
+        buf.extend_from_slice(&time.to_be_bytes());
+        buf.extend_from_slice(&ct.as_bytes());
+        buf.extend_from_slice(&msg.index.to_be_bytes());
+        buf.extend_from_slice(&msg.acks.start.to_be_bytes());
+        buf.extend_from_slice(&msg.acks.end.to_be_bytes());
+        buf.extend_from_slice(&session.map_or(0, |s| s.0).to_be_bytes());
+        let mut hdr = msg.header.as_bytes();
+        if omit_body {
+            hdr[3] |= FLAG_BODY_OMITTED;
+        }
+        buf.extend_from_slice(&hdr);
+        if !omit_body {
+            buf.extend_from_slice(&msg.body);
+        }
+        let crc = crc32::update(0, &buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        self.w.write_all(&buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    pub fn write_record(&mut self, r: &Record) -> io::Result<()> {
+        let ct = r.conn.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "record has no connection tuple",
+        ))?;
+        self.write_parts(r.time, ct, r.session, &r.msg, r.body_omitted)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+pub struct Reader<R> {
+    r: io::Chain<io::Cursor<Vec<u8>>, R>,
+    version: u32,
+    sampling: Vec<(OpcodeSpec, u32)>,
+    /// Offset in the file of the next record.
+    pos: u64,
+    /// Next index for each connection and direction, for logs that don't record indices.
+    next_index: HashMap<(Option<ConnTuple>, u8), u64>,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut r: R) -> io::Result<Reader<R>> {
+        let mut magic = [0; 4];
+        let n = read_full(&mut r, &mut magic)?;
+        if n == 4 && magic == MAGIC {
+            let mut version = [0; 4];
+            r.read_exact(&mut version)?;
+            let version = u32::from_be_bytes(version);
+            if version != VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported tfhlog version {}", version),
+                ));
+            }
+            let mut sampling = Vec::new();
+            let mut count = [0; 2];
+            r.read_exact(&mut count)?;
+            for _ in 0 .. u16::from_be_bytes(count) {
+                let mut entry = [0; 8];
+                r.read_exact(&mut entry)?;
+                let minor = if entry[2] & 1 != 0 { None } else { Some(entry[1]) };
+                sampling.push(((entry[0], minor), entry.u32_be(4)));
+            }
+            let pos = 10 + 8 * sampling.len() as u64;
+            Ok(Reader {
+                r: io::Cursor::new(Vec::new()).chain(r),
+                version,
+                sampling,
+                pos,
+                next_index: HashMap::new(),
+            })
+        } else {
+            // No header, so these bytes are the start of the first record.
+            Ok(Reader {
+                r: io::Cursor::new(magic[..n].to_owned()).chain(r),
+                version: 0,
+                sampling: Vec::new(),
+                pos: 0,
+                next_index: HashMap::new(),
+            })
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Offset in the file of the next record, or of the end of the last one read.  When `read`
+    /// fails, this is where the bad record starts.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// The opcodes that were sampled when the log was written.
+    pub fn sampling(&self) -> &Sampling {
+        &self.sampling
+    }
+
+    /// How many messages each logged message like `msg` stands for: one unless its opcode was
+    /// sampled.  Multiplying counts by this corrects them for sampling.
+    pub fn sample_rate(&self, msg: &Message) -> u32 {
+        log_layout::sample_rate(&self.sampling, msg)
+    }
+
+    /// Read the next record.  Returns `None` at the end of the log.  A record cut short fails
+    /// with `UnexpectedEof`, and one whose CRC doesn't match with `InvalidData`.
+    pub fn read(&mut self) -> io::Result<Option<Record>> {
+        // The fields before the body.
+        let head_len = if self.version == 0 { 12 } else { RECORD_HEADER_LEN };
+        let mut head = [0; RECORD_HEADER_LEN];
+        let head = &mut head[..head_len];
+        if !read_record_start(&mut self.r, head)? {
+            return Ok(None);
+        }
+
+        let mut time = 0;
+        let mut conn = None;
+        let mut index = None;
+        let mut acks = 0 .. 0;
+        let mut session = None;
+        if self.version >= 2 {
+            time = head.u64_be(0);
+            conn = Some(ConnTuple::from_bytes(head[8 .. 20].try_into().unwrap()));
+            index = Some(head.u64_be(20));
+            acks = head.u64_be(28) .. head.u64_be(36);
+            session = SessionId::from_u32(head.u32_be(44));
+        }
+        let hdr: &[u8; 12] = head[head_len - 12 ..].try_into().unwrap();
+
+        let header = MessageHeader::from_bytes(hdr);
+        let body_omitted = self.version >= 2 && hdr[3] & FLAG_BODY_OMITTED != 0;
+        let body_len = if body_omitted { 0 } else { header.len as u64 };
+        // Read the body bit by bit rather than into a buffer of the full length, since in a
+        // damaged log the length may be garbage.
+        let mut body = Vec::new();
+        (&mut self.r).take(body_len).read_to_end(&mut body)?;
+        if body.len() as u64 != body_len {
+            return Err(truncated());
+        }
+        let mut len = head_len as u64 + body_len;
+        if self.version >= 2 {
+            let mut crc = [0; RECORD_TRAILER_LEN];
+            if read_full(&mut self.r, &mut crc)? < crc.len() {
+                return Err(truncated());
+            }
+            if u32::from_be_bytes(crc) != crc32::update(crc32::update(0, head), &body) {
+                let msg = "tfhlog record CRC mismatch";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            len += RECORD_TRAILER_LEN as u64;
+        }
+        self.pos += len;
+
+        let index = index.unwrap_or_else(|| {
+            let next = self.next_index.entry((conn, header.dir)).or_insert(0);
+            *next += 1;
+            *next - 1
+        });
+        Ok(Some(Record {
+            time,
+            conn,
+            session,
+            msg: Message { header, body: body.into_boxed_slice(), time, index, acks },
+            body_omitted,
+        }))
+    }
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Go to the record starting at `pos`, as given by `position` or a log index (see
+    /// `log_index`).  In version 0 logs, which don't record message indices, the messages
+    /// read after seeking are numbered as if the log started there.
+    pub fn seek(&mut self, pos: u64) -> io::Result<()> {
+        if pos == self.pos {
+            return Ok(());
+        }
+        let (start, r) = self.r.get_mut();
+        start.set_position(start.get_ref().len() as u64);
+        r.seek(SeekFrom::Start(pos))?;
+        self.pos = pos;
+        Ok(())
+    }
+
+    /// Go to a record near the end of the log, which is `len` bytes long, so reading on checks
+    /// only the last records instead of the whole log.  The first place in the last `TAIL_LEN`
+    /// bytes where a whole record with a good CRC starts is taken to be a record.  Logs without
+    /// CRCs, and logs with no such record there, stay where they are.
+    pub fn seek_tail(&mut self, len: u64) -> io::Result<()> {
+        if self.version < 2 || len.saturating_sub(self.pos) <= TAIL_LEN {
+            return Ok(());
+        }
+        let base = len - TAIL_LEN;
+        let mut buf = vec![0; TAIL_LEN as usize];
+        let (start, r) = self.r.get_mut();
+        start.set_position(start.get_ref().len() as u64);
+        r.seek(SeekFrom::Start(base))?;
+        r.read_exact(&mut buf)?;
+        let pos = match (0 .. buf.len()).find(|&i| whole_record(&buf[i..])) {
+            Some(i) => base + i as u64,
+            None => self.pos,
+        };
+        r.seek(SeekFrom::Start(pos))?;
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+/// Whether `buf` starts with a whole record in the current version, with a good CRC.
+fn whole_record(buf: &[u8]) -> bool {
+    if buf.len() < RECORD_HEADER_LEN + RECORD_TRAILER_LEN {
+        return false;
+    }
+    let hdr: &[u8; 12] = buf[RECORD_HEADER_LEN - 12 .. RECORD_HEADER_LEN].try_into().unwrap();
+    // Rule out most places that aren't a record before going to the trouble of the CRC.
+    if hdr[3] & !FLAG_BODY_OMITTED != 0 {
+        return false;
+    }
+    let body_len = if hdr[3] & FLAG_BODY_OMITTED != 0 { 0 } else { hdr.u32_be(8) as usize };
+    let end = match RECORD_HEADER_LEN.checked_add(body_len) {
+        Some(x) if x + RECORD_TRAILER_LEN <= buf.len() => x,
+        _ => return false,
+    };
+    let (head, body) = (&buf[.. RECORD_HEADER_LEN], &buf[RECORD_HEADER_LEN .. end]);
+    buf.u32_be(end) == crc32::update(crc32::update(0, head), body)
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        self.read().transpose()
+    }
+}
+
+/// Open the log at `path` for reading, decompressing it if its name ends in `.gz`.
+pub fn open(path: impl AsRef<Path>) -> io::Result<Reader<Input>> {
+    let path = path.as_ref();
+    if path.extension().map_or(false, |x| x == "gz") {
+        let mut child = Command::new("gzip").arg("-dc").arg("--").arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let out = BufReader::new(child.stdout.take().unwrap());
+        Reader::new(Input::Gzip { child, out })
+    } else {
+        Reader::new(Input::Plain(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// A log file opened by `open`.  Only uncompressed logs can seek.
+pub enum Input {
+    Plain(BufReader<File>),
+    Gzip { child: Child, out: BufReader<ChildStdout> },
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Input::Plain(ref mut r) => r.read(buf),
+            Input::Gzip { ref mut child, ref mut out } => {
+                let n = out.read(buf)?;
+                // A corrupt file makes `gzip` stop early, which would look like the end of the log.
+                if n == 0 && !buf.is_empty() {
+                    let status = child.wait()?;
+                    if !status.success() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("gzip -d failed ({})", status)));
+                    }
+                }
+                Ok(n)
+            },
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            Input::Plain(ref mut r) => r.seek(pos),
+            Input::Gzip { .. } => {
+                Err(io::Error::new(io::ErrorKind::Other, "can't seek in a compressed log"))
+            },
+        }
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        // Don't leave `gzip` behind when the log isn't read to the end.
+        if let Input::Gzip { ref mut child, .. } = *self {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Like `read_exact`, but stops at end of file instead of failing.  Returns the number of bytes
+/// read.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Read the first part of a record into `buf`.  Returns `false` if the log ends cleanly before
+/// the record starts, and fails if it ends partway through.
+fn read_record_start(r: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match read_full(r, buf)? {
+        0 => Ok(false),
+        n if n == buf.len() => Ok(true),
+        _ => Err(truncated()),
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tfhlog record")
+}
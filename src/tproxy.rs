@@ -0,0 +1,123 @@
+//! Socket helpers for transparent proxying with the iptables/nft `TPROXY` target.
+//!
+//! `TPROXY` delivers packets for arbitrary destinations to a local socket without rewriting them.
+//! The socket needs `IP_TRANSPARENT` to accept them, and `IP_RECVORIGDSTADDR` to find out where
+//! each datagram was really going.  Replies are sent from a second transparent socket bound to
+//! that original destination, so the client sees them coming from the server it talked to.
+//! All of this requires `CAP_NET_ADMIN`.
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use libc::{
+    c_int, c_void, sa_family_t, sockaddr, sockaddr_in, socklen_t, iovec, msghdr, cmsghdr,
+    AF_INET, SOCK_DGRAM, SOCK_CLOEXEC, SOL_IP, SOL_SOCKET, SO_REUSEADDR,
+    IP_TRANSPARENT, IP_RECVORIGDSTADDR, IP_ORIGDSTADDR,
+};
+
+
+fn setsockopt_int(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn to_sockaddr_in(addr: SocketAddrV4) -> sockaddr_in {
+    let mut sin: sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = AF_INET as _;
+    sin.sin_port = addr.port().to_be();
+    sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+    sin
+}
+
+fn from_sockaddr_in(sin: &sockaddr_in) -> SocketAddrV4 {
+    let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+    SocketAddrV4::new(ip, u16::from_be(sin.sin_port))
+}
+
+/// Create a UDP socket with `IP_TRANSPARENT` and `SO_REUSEADDR` set and bind it to `addr`, which
+/// need not be a local address.  This is used both for the listening socket and for sending
+/// replies on behalf of the original destination.
+pub fn bind_transparent(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    let fd = unsafe { libc::socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Take ownership right away, so the fd is closed on error.
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+    setsockopt_int(fd, SOL_SOCKET, SO_REUSEADDR, 1)?;
+    setsockopt_int(fd, SOL_IP, IP_TRANSPARENT, 1)?;
+    let sin = to_sockaddr_in(addr);
+    let res = unsafe {
+        libc::bind(
+            fd,
+            &sin as *const sockaddr_in as *const sockaddr,
+            mem::size_of::<sockaddr_in>() as socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sock)
+}
+
+/// Ask for the original destination of each datagram received on `sock`, for `recv_orig_dst`.
+pub fn enable_orig_dst(sock: &UdpSocket) -> io::Result<()> {
+    setsockopt_int(sock.as_raw_fd(), SOL_IP, IP_RECVORIGDSTADDR, 1)
+}
+
+/// Receive a datagram, returning its length, its source, and the destination it was originally
+/// sent to.  The destination is `None` if the kernel didn't report it, which happens if
+/// `enable_orig_dst` wasn't called.
+pub fn recv_orig_dst(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddrV4, Option<SocketAddrV4>)> {
+    let mut src = MaybeUninit::<sockaddr_in>::zeroed();
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    // Room for one `cmsghdr` plus a `sockaddr_in`, with alignment to spare.
+    let mut cmsg_buf = [0u64; 8];
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = src.as_mut_ptr() as *mut c_void;
+    msg.msg_namelen = mem::size_of::<sockaddr_in>() as socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+
+    let len = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let src = unsafe { src.assume_init() };
+    if src.sin_family != AF_INET as sa_family_t {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "datagram is not from IPv4"));
+    }
+
+    let mut orig_dst = None;
+    unsafe {
+        let mut cmsg: *const cmsghdr = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_IP && (*cmsg).cmsg_type == IP_ORIGDSTADDR {
+                let sin = (libc::CMSG_DATA(cmsg) as *const sockaddr_in).read_unaligned();
+                orig_dst = Some(from_sockaddr_in(&sin));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((len as usize, from_sockaddr_in(&src), orig_dst))
+}
@@ -0,0 +1,295 @@
+//! Where the relay's packets come from and go to.  `relay::start` reads each side's packets from
+//! a `PacketSource` and writes to it through a `PacketSink`, so it works the same whether the
+//! packets are on a tun device, in a pcap file, tunnelled over UDP, or in a queue set up by a
+//! test.  Supporting a new transport only needs these two traits.
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::UdpSocket;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::vec;
+use std::thread;
+use std::time::Duration;
+use libc::c_int;
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags};
+use crate::channel::{self, Overflow, Receiver, Sender};
+use crate::packet::{Packet, PACKET_CAP};
+use crate::pcap;
+use crate::util::clock;
+
+
+pub trait PacketSource: Send {
+    /// Wait for the next packet.  Returns `None` at the end of the input, such as the end of a
+    /// pcap file.
+    fn recv(&mut self) -> io::Result<Option<Packet>>;
+}
+
+pub trait PacketSink: Send + Sized {
+    /// Write `p`, returning the number of bytes written.  Fails with `WouldBlock` if the sink is
+    /// full for now, or `Interrupted` if the write should just be retried.
+    fn send(&mut self, p: &Packet) -> io::Result<usize>;
+
+    /// After `send` failed with `WouldBlock`, wait up to `timeout` for room.  Returns whether
+    /// there's room now.
+    fn wait_writable(&mut self, timeout: Duration) -> io::Result<bool> {
+        thread::sleep(timeout);
+        Ok(true)
+    }
+
+    /// Another handle to the same sink, for writing from another thread.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+
+/// A tun device, which passes one IP packet per read or write.
+#[derive(Clone, Copy, Debug)]
+pub struct TunDevice {
+    fd: RawFd,
+}
+
+impl TunDevice {
+    pub fn new(fd: RawFd) -> TunDevice {
+        TunDevice { fd }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Write one packet's bytes.
+    pub fn write(&self, data: &[u8]) -> io::Result<usize> {
+        let ptr = data.as_ptr() as *const libc::c_void;
+        let len = unsafe { libc::write(self.fd, ptr, data.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+impl PacketSource for TunDevice {
+    fn recv(&mut self) -> io::Result<Option<Packet>> {
+        let mut p = Packet::default();
+        let len = unsafe { libc::read(self.fd, p.as_mut_ptr() as *mut libc::c_void, PACKET_CAP) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { p.set_len(len as usize) };
+        Ok(Some(p))
+    }
+}
+
+impl PacketSink for TunDevice {
+    fn send(&mut self, p: &Packet) -> io::Result<usize> {
+        self.write(p.as_slice())
+    }
+
+    fn wait_writable(&mut self, timeout: Duration) -> io::Result<bool> {
+        let mut fds = [PollFd::new(self.fd, PollFlags::POLLOUT)];
+        match nix::poll::poll(&mut fds, timeout.as_millis() as i32) {
+            Ok(n) => Ok(n > 0),
+            Err(nix::Error::Sys(Errno::EINTR)) => Ok(false),
+            Err(nix::Error::Sys(e)) => Err(io::Error::from_raw_os_error(e as i32)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<TunDevice> {
+        Ok(*self)
+    }
+}
+
+
+/// Packets read back from a capture, as by `replay-pcap`.  Records that don't hold an IP packet
+/// are skipped; a capture cut off partway through a record is an error.
+impl<R: Read + Send> PacketSource for pcap::Pcap<R> {
+    fn recv(&mut self) -> io::Result<Option<Packet>> {
+        Ok(self.recv_tagged()?.map(|(p, _)| p))
+    }
+}
+
+/// Packets already in memory, such as ones read by `packet_text::load`.
+impl PacketSource for vec::IntoIter<Packet> {
+    fn recv(&mut self) -> io::Result<Option<Packet>> {
+        Ok(self.next())
+    }
+}
+
+/// Writes packets to a capture.  Packets without a timestamp are recorded with the current
+/// time.
+pub struct PcapSink<W>(Arc<Mutex<pcap::Writer<W>>>);
+
+impl<W: Write> PcapSink<W> {
+    pub fn new(w: pcap::Writer<W>) -> PcapSink<W> {
+        PcapSink(Arc::new(Mutex::new(w)))
+    }
+}
+
+impl<W: Write + Send> PacketSink for PcapSink<W> {
+    fn send(&mut self, p: &Packet) -> io::Result<usize> {
+        let mut w = self.0.lock().unwrap();
+        if p.time().is_some() {
+            w.write_packet(p)?;
+        } else {
+            let mut p = p.clone();
+            p.set_time(Some(clock::now_us()));
+            w.write_packet(&p)?;
+        }
+        w.flush()?;
+        Ok(p.len())
+    }
+
+    fn try_clone(&self) -> io::Result<PcapSink<W>> {
+        Ok(PcapSink(self.0.clone()))
+    }
+}
+
+
+/// A packet socket on one network interface, which sees the IP packets sent and received there,
+/// so the processing pipeline can watch traffic on hosts where tun devices can't be created,
+/// such as containers without `CAP_NET_ADMIN`.  It only needs `CAP_NET_RAW`, which containers
+/// usually keep, but it can only watch: packets can't be held back or changed.
+///
+/// An `AF_INET` raw socket would need the same capability, but only sees packets addressed to
+/// this host, and none that it sends or forwards.  A packet socket in cooked (`SOCK_DGRAM`) mode
+/// sees all of them, with the link-layer header already removed, as on a tun device.
+pub struct RawSocket {
+    fd: RawFd,
+    ifname: String,
+}
+
+impl RawSocket {
+    pub fn open(ifname: &str) -> io::Result<RawSocket> {
+        let c_ifname = CString::new(ifname)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL"))?;
+        let ifindex = unsafe { libc::if_nametoindex(c_ifname.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Only `ETH_P_ALL` sockets see outgoing packets, so this takes every protocol and `recv`
+        // skips the non-IPv4 ones.
+        let proto = (libc::ETH_P_ALL as u16).to_be();
+        let fd = unsafe {
+            libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, proto as c_int)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = RawSocket { fd, ifname: ifname.to_owned() };
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = proto;
+        addr.sll_ifindex = ifindex as c_int;
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sock)
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl PacketSource for RawSocket {
+    fn recv(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let mut p = Packet::default();
+            let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            // With `MSG_TRUNC`, the result is the packet's full length, even if it didn't fit.
+            let len = unsafe {
+                libc::recvfrom(
+                    self.fd,
+                    p.as_mut_ptr() as *mut libc::c_void,
+                    PACKET_CAP,
+                    libc::MSG_TRUNC,
+                    &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                    &mut addr_len,
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if addr.sll_protocol != (libc::ETH_P_IP as u16).to_be() {
+                continue;
+            }
+            let len = len as usize;
+            if len > PACKET_CAP {
+                log!(Relay, Debug, "{}: skipping {}-byte packet", self.ifname, len);
+                continue;
+            }
+            unsafe { p.set_len(len) };
+            return Ok(Some(p));
+        }
+    }
+}
+
+
+/// A connected UDP socket carrying one IP packet per datagram, for tunnelling a side of the
+/// relay to another host.
+impl PacketSource for UdpSocket {
+    fn recv(&mut self) -> io::Result<Option<Packet>> {
+        let mut p = Packet::zeroed(PACKET_CAP);
+        let len = UdpSocket::recv(self, p.as_mut_slice())?;
+        p.truncate(len);
+        Ok(Some(p))
+    }
+}
+
+impl PacketSink for UdpSocket {
+    fn send(&mut self, p: &Packet) -> io::Result<usize> {
+        UdpSocket::send(self, p.as_slice())
+    }
+
+    fn try_clone(&self) -> io::Result<UdpSocket> {
+        UdpSocket::try_clone(self)
+    }
+}
+
+
+/// One end of a `loopback` link: the queue it receives from, and the one it sends to.  The two
+/// halves can be passed straight to `relay::start` as one side's source and sink.
+pub type LoopbackEnd = (Receiver<Packet>, Sender<Packet>);
+
+/// A pair of in-process endpoints connected like a socket pair: packets sent on one end are
+/// received on the other.  This lets a whole relay run without tun devices, as in the
+/// integration tests.  Each direction holds up to `capacity` packets, and a full queue blocks
+/// the sender rather than losing packets.
+pub fn loopback(capacity: usize) -> (LoopbackEnd, LoopbackEnd) {
+    let (send_ab, recv_ab) = channel::bounded(capacity, Overflow::Block);
+    let (send_ba, recv_ba) = channel::bounded(capacity, Overflow::Block);
+    ((recv_ba, send_ab), (recv_ab, send_ba))
+}
+
+/// The receiving end of an in-memory queue.  The input ends once all senders are gone.
+impl PacketSource for Receiver<Packet> {
+    fn recv(&mut self) -> io::Result<Option<Packet>> {
+        Ok(Receiver::recv(self))
+    }
+}
+
+impl PacketSink for Sender<Packet> {
+    fn send(&mut self, p: &Packet) -> io::Result<usize> {
+        match Sender::send(self, p.clone()) {
+            Ok(()) => Ok(p.len()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "queue receiver is gone")),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Sender<Packet>> {
+        Ok(self.clone())
+    }
+}
@@ -0,0 +1,201 @@
+//! Protocol for handing out tun devices over a Unix socket, as `tun-server` does.
+//!
+//! The client sends one request: a big-endian `u16` length followed by that many bytes of
+//! text.  This is either `get <name>`, where an empty name asks for the server's default device,
+//! or `create <name> [multi-queue] [persist]` to open a new device.  The server replies
+//! with a status byte.  `0` means success, and the device's fd arrives with it as `SCM_RIGHTS`
+//! ancillary data.  `1` means failure, and is followed by a `u16` length and an error message.
+//!
+//! A process that can't reach the server's socket by path, such as one in a container, can
+//! instead be started with the devices, or with sockets already connected to the server, as
+//! inherited fds.  `from_fd` accepts either, and `listen_fd` finds fds passed by systemd.
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process;
+use nix::fcntl::{self, FcntlArg, FdFlag};
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags};
+use nix::sys::stat::{self, SFlag};
+use nix::sys::uio::IoVec;
+use crate::{Error, ErrorAt};
+use crate::tuntap::TunOptions;
+
+
+/// Longest request or error message accepted.
+const MAX_LEN: usize = 1024;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/// The first fd passed by systemd (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Request {
+    /// Send the fd of an already-open device, identified by name.  Empty means the default.
+    Get(String),
+    /// Create a device, or attach to an existing one, with `tuntap::open_tun_with`.
+    Create(String, TunOptions),
+}
+
+impl Request {
+    fn parse(s: &str) -> Result<Request, Error> {
+        let mut words = s.split_whitespace();
+        match words.next() {
+            Some("get") => Ok(Request::Get(words.next().unwrap_or("").to_owned())),
+            Some("create") => {
+                let name = words.next().ok_or("create: missing device name")?.to_owned();
+                let mut opts = TunOptions::default();
+                for w in words {
+                    match w {
+                        "multi-queue" => opts.multi_queue = true,
+                        "persist" => opts.persist = true,
+                        _ => return Err(Error(format!("create: unknown flag {:?}", w))),
+                    }
+                }
+                Ok(Request::Create(name, opts))
+            },
+            Some(cmd) => Err(Error(format!("unknown request {:?}", cmd))),
+            None => Err("empty request".into()),
+        }
+    }
+
+    fn to_string(&self) -> String {
+        match *self {
+            Request::Get(ref name) => format!("get {}", name),
+            Request::Create(ref name, opts) => {
+                let mut s = format!("create {}", name);
+                if opts.multi_queue {
+                    s.push_str(" multi-queue");
+                }
+                if opts.persist {
+                    s.push_str(" persist");
+                }
+                s
+            },
+        }
+    }
+}
+
+fn write_framed(w: &mut impl Write, b: &[u8]) -> Result<(), Error> {
+    if b.len() > MAX_LEN {
+        return Err(Error(format!("message too long ({} bytes)", b.len())));
+    }
+    w.write_all(&(b.len() as u16).to_be_bytes())?;
+    w.write_all(b)?;
+    Ok(())
+}
+
+fn read_framed(r: &mut impl Read) -> Result<String, Error> {
+    let mut len = [0; 2];
+    r.read_exact(&mut len)?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len > MAX_LEN {
+        return Err(Error(format!("message too long ({} bytes)", len)));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| "message is not valid UTF-8".into())
+}
+
+/// Server side: read the client's request.
+pub fn read_request(socket: &mut UnixStream) -> Result<Request, Error> {
+    Request::parse(&read_framed(socket)?)
+}
+
+/// Server side: reply with `fd`.
+pub fn send_fd(socket: &UnixStream, fd: RawFd) -> Result<(), Error> {
+    let len = nix::sys::socket::sendmsg(
+        socket.as_raw_fd(),
+        &[IoVec::from_slice(&[STATUS_OK])],
+        &[ControlMessage::ScmRights(&[fd])],
+        MsgFlags::empty(),
+        None,
+    )?;
+    if len != 1 {
+        return Err(Error(format!("sendmsg didn't send data: {} != 1", len)));
+    }
+    Ok(())
+}
+
+/// Server side: reply with an error message.
+pub fn send_error(socket: &mut UnixStream, msg: &str) -> Result<(), Error> {
+    socket.write_all(&[STATUS_ERROR])?;
+    write_framed(socket, msg.as_bytes())
+}
+
+/// Client side: send `req` to the server at `path` and return the fd it replies with.
+pub fn request<P: AsRef<Path>>(path: P, req: &Request) -> Result<RawFd, Error> {
+    let mut socket = UnixStream::connect(path).at("connecting to tun server")?;
+    request_on(&mut socket, req)
+}
+
+/// Client side: send `req` over `socket`, which is already connected to the server, and return
+/// the fd it replies with.
+pub fn request_on(socket: &mut UnixStream, req: &Request) -> Result<RawFd, Error> {
+    write_framed(socket, req.to_string().as_bytes())?;
+
+    let mut data_buf = [0];
+    let mut cmsg_buf = vec![0; 256];
+    let recv_msg = nix::sys::socket::recvmsg(
+        socket.as_raw_fd(),
+        &[IoVec::from_mut_slice(&mut data_buf)],
+        Some(&mut cmsg_buf),
+        MsgFlags::empty(),
+    )?;
+    if recv_msg.bytes != 1 {
+        return Err("tun server closed the connection without replying".into());
+    }
+    let mut fd = None;
+    for cmsg in recv_msg.cmsgs() {
+        match cmsg {
+            ControlMessageOwned::ScmRights(fds) => {
+                if fds.len() != 1 {
+                    return Err(Error(format!("expected exactly 1 fd, but got {}", fds.len())));
+                }
+                fd = Some(fds[0]);
+            },
+            _ => return Err("unexpected control message type".into()),
+        }
+    }
+
+    match data_buf[0] {
+        STATUS_OK => fd.ok_or_else(|| "didn't receive a file descriptor".into()),
+        STATUS_ERROR => Err(Error(format!("tun server: {}", read_framed(socket)?))),
+        s => Err(Error(format!("unknown reply status {}", s))),
+    }
+}
+
+/// Client side: get a tun device from the inherited fd `fd`.  If it's a socket connected to a
+/// tun server, the device named `dev` is requested from it, and the socket is closed.
+/// Otherwise, `fd` is taken to be the device itself, and `dev` must be empty.
+pub fn from_fd(fd: RawFd, dev: &str) -> Result<RawFd, Error> {
+    let at = format!("fd {}", fd);
+    let st = stat::fstat(fd).at(&at)?;
+    if SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFSOCK {
+        let mut socket = unsafe { UnixStream::from_raw_fd(fd) };
+        return request_on(&mut socket, &Request::Get(dev.to_owned())).at(&at);
+    }
+    if dev.len() > 0 {
+        return Err(Error(format!("{}: can't ask for device {:?}: not a socket", at, dev)));
+    }
+    // Inherited fds may not be close-on-exec.
+    fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).at(&at)?;
+    Ok(fd)
+}
+
+/// Find the fd named `name` among those systemd passed to this process, from the fd store or a
+/// socket unit's `FileDescriptorName=`, as described in `sd_listen_fds(3)`.
+pub fn listen_fd(name: &str) -> Result<RawFd, Error> {
+    let pid = env::var("LISTEN_PID").map_err(|_| "no fds were passed in (LISTEN_PID is unset)")?;
+    if pid.parse::<u32>().ok() != Some(process::id()) {
+        return Err("LISTEN_PID is for a different process".into());
+    }
+    let count = env::var("LISTEN_FDS").ok().and_then(|s| s.parse::<usize>().ok())
+        .ok_or("LISTEN_FDS is missing or invalid")?;
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let i = names.split(':').take(count).position(|n| n == name)
+        .ok_or_else(|| Error(format!("no fd named {:?} in LISTEN_FDNAMES", name)))?;
+    Ok(LISTEN_FDS_START + i as RawFd)
+}
@@ -3,17 +3,19 @@ use std::mem::MaybeUninit;
 use std::process;
 use std::os::unix::io::RawFd;
 use nix;
+use nix::errno::Errno;
 use nix::fcntl::OFlag;
 use nix::sys::stat::Mode;
 use libc::{
     c_int, c_short, c_char, c_void, c_ulong, c_uint, c_ushort, c_uchar, sockaddr,
-    IFNAMSIZ, IFF_TUN, IFF_NO_PI,
+    IFNAMSIZ, IFF_TUN, IFF_NO_PI, IFF_MULTI_QUEUE,
 };
-use crate::{Error, ErrorAt};
+use crate::Error;
 
 
 // struct ifreq is not declared in rust's libc bindings
 
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct ifmap {
     pub mem_start: c_ulong,
@@ -24,6 +26,7 @@ pub struct ifmap {
     pub port: c_uchar,
 }
 
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct if_settings {
     pub type_: c_uint,
@@ -60,26 +63,71 @@ pub struct ifreq {
 }
 
 nix::ioctl_write_ptr!(tun_set_iff, b'T', 202, c_int);
+nix::ioctl_write_int!(tun_set_persist, b'T', 203);
 
 
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TunOptions {
+    /// Open one queue of a multi-queue device.  Opening the same name again adds another queue.
+    pub multi_queue: bool,
+    /// Keep the device around after its last fd is closed.
+    pub persist: bool,
+}
+
+/// Describe a failure to set up the tun device `if_name`.  Permission errors from `TUNSETIFF`
+/// nearly always mean the process lacks `CAP_NET_ADMIN` (or the device belongs to another user),
+/// so say that instead of just "Operation not permitted".
+fn tun_error(e: nix::Error, if_name: &str, what: &str) -> Error {
+    let hint = match e.as_errno() {
+        Some(Errno::EPERM) =>
+            ": needs CAP_NET_ADMIN (try setcap or sudo), or the device is owned by another user",
+        Some(Errno::EACCES) => ": no access to /dev/net/tun (check its permissions, or try sudo)",
+        _ => "",
+    };
+    Error(format!("{}: {}: {}{}", if_name, what, e, hint))
+}
+
 pub fn open_tun(if_name: &str) -> Result<RawFd, Error> {
+    open_tun_with(if_name, TunOptions::default())
+}
+
+pub fn open_tun_with(if_name: &str, opts: TunOptions) -> Result<RawFd, Error> {
+    if if_name.len() >= IFNAMSIZ || if_name.contains('\0') {
+        return Err(Error(format!("invalid interface name {:?}", if_name)));
+    }
+
     let fd = nix::fcntl::open(
         "/dev/net/tun",
         OFlag::O_RDWR,
         Mode::empty(),
-    ).at("opening tun device")?;
+    ).map_err(|e| tun_error(e, if_name, "opening /dev/net/tun"))?;
 
     unsafe {
         let mut ifr = MaybeUninit::<ifreq>::zeroed();
         let ifrp = ifr.as_mut_ptr();
-        (*ifrp).ifr_ifru.ifru_flags = (IFF_TUN | IFF_NO_PI) as c_short;
+        let mut flags = IFF_TUN | IFF_NO_PI;
+        if opts.multi_queue {
+            flags |= IFF_MULTI_QUEUE;
+        }
+        (*ifrp).ifr_ifru.ifru_flags = flags as c_short;
         for (i, &b) in if_name.as_bytes().iter().enumerate() {
             (*ifrp).ifr_ifrn.ifrn_name[i] = b as c_char;
         }
         (*ifrp).ifr_ifrn.ifrn_name[if_name.len()] = 0;
         let ifr = ifr.assume_init();
 
-        tun_set_iff(fd, &ifr as *const _ as *const c_int).at("set tun interface name")?;
+        let res = tun_set_iff(fd, &ifr as *const _ as *const c_int)
+            .map_err(|e| tun_error(e, if_name, "set tun interface name"))
+            .and_then(|_| if opts.persist {
+                tun_set_persist(fd, 1)
+                    .map_err(|e| tun_error(e, if_name, "set tun device persistent"))
+            } else {
+                Ok(0)
+            });
+        if let Err(e) = res {
+            let _ = nix::unistd::close(fd);
+            return Err(e);
+        }
     };
 
     Ok(fd)
@@ -0,0 +1,469 @@
+//! Userspace UDP proxy, for using the relay without tun devices or root.
+//!
+//! Players point the game at the proxy's listening socket.  For each client, the proxy opens a
+//! separate socket to the real server and forwards datagrams both ways.  The datagrams are
+//! wrapped in synthetic IPv4 packets and sent through the processing thread as if they'd been
+//! read from tun devices, with the client side as `A` and the server side as `B`, so logging and
+//! everything else work the same as in the normal relay.
+//!
+//! In transparent mode there's no fixed server.  iptables/nft `TPROXY` rules steer the game's
+//! traffic to the listening socket, and each datagram is forwarded to wherever it was originally
+//! headed, as reported by `tproxy::recv_orig_dst`.
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{Error, ErrorAt};
+use crate::affinity::{self, Role};
+use crate::channel::{Sender, Receiver};
+use crate::health;
+use crate::pacing::Pacer;
+use crate::packet::{Packet, PACKET_CAP};
+use crate::process::{self, Input, Output};
+use crate::ratelimit::RateLimiter;
+use crate::sendmmsg;
+use crate::stats::{Counters, RelayStats};
+use crate::supervise::{Restart, Supervisor};
+use crate::tproxy;
+use crate::util::clock;
+
+
+/// Number of times per minute a proxy thread may fail before the relay gives up.
+const MAX_RESTARTS: usize = 5;
+
+/// A client's upstream socket is closed after this many seconds with no traffic either way.
+const IDLE_TIMEOUT: i64 = 120;
+
+/// How often each upstream socket checks whether it's been idle too long.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Client {
+    upstream: UdpSocket,
+    /// In transparent mode, a socket bound to the server's address, for sending replies that
+    /// appear to come from the server.  Otherwise replies go out through the listening socket.
+    reply: Option<UdpSocket>,
+    /// Time of the last datagram in either direction, in seconds.
+    last_active: AtomicI64,
+}
+
+/// Client and server address of one proxied flow.
+type FlowKey = (SocketAddrV4, SocketAddrV4);
+
+struct Proxy {
+    listen: UdpSocket,
+    /// Where to send client traffic.  `None` in transparent mode, where each datagram goes to its
+    /// original destination.
+    server: Option<SocketAddrV4>,
+    clients: Mutex<HashMap<FlowKey, Arc<Client>>>,
+    limiter: Option<Mutex<RateLimiter>>,
+    stats: Arc<RelayStats>,
+}
+
+fn resolve_v4(addr: &str) -> Result<SocketAddrV4, Error> {
+    for a in addr.to_socket_addrs().at(addr)? {
+        if let SocketAddr::V4(a) = a {
+            return Ok(a);
+        }
+    }
+    Err(Error(format!("{}: no IPv4 address", addr)))
+}
+
+fn expect_v4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(a) => Some(a),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+/// Source and destination of a packet built by `Packet::new_udp_ipv4`.
+fn packet_addrs(p: &Packet) -> (SocketAddrV4, SocketAddrV4) {
+    let src = SocketAddrV4::new(Ipv4Addr::from(p.ipv4().source_ip()), p.udp().source_port());
+    let dst = SocketAddrV4::new(Ipv4Addr::from(p.ipv4().dest_ip()), p.udp().dest_port());
+    (src, dst)
+}
+
+/// Apply the rate limits, if any, to a datagram from a client.  Returns whether to forward it.
+fn check_rate(proxy: &Proxy, key: FlowKey) -> bool {
+    let limiter = match proxy.limiter {
+        Some(ref x) => x,
+        None => return true,
+    };
+    if limiter.lock().unwrap().check_datagram(key.0, key.1, clock::monotonic_us()) {
+        return true;
+    }
+    proxy.stats.a_to_b.rate_limited.fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+fn count_send_result(res: io::Result<usize>, counters: &Counters) {
+    match res {
+        Ok(_) => {},
+        Err(e) => {
+            counters.write_failed.fetch_add(1, Ordering::Relaxed);
+            log!(Relay, Warn, "dropping datagram: {}", e);
+        },
+    }
+}
+
+fn send_to_client(proxy: &Proxy, client: &Client, addr: SocketAddrV4, data: &[u8]) {
+    let sock = client.reply.as_ref().unwrap_or(&proxy.listen);
+    count_send_result(sock.send_to(data, addr), &proxy.stats.b_to_a);
+}
+
+/// Get the upstream socket for the flow `key`, creating it and its reader thread if needed.
+fn get_client(
+    proxy: &Arc<Proxy>,
+    key: FlowKey,
+    inp_send: &Sender<Input>,
+) -> io::Result<Arc<Client>> {
+    let mut clients = proxy.clients.lock().unwrap();
+    if let Some(c) = clients.get(&key) {
+        return Ok(c.clone());
+    }
+
+    let (addr, server) = key;
+    let upstream = UdpSocket::bind("0.0.0.0:0")?;
+    upstream.connect(server)?;
+    upstream.set_read_timeout(Some(IDLE_CHECK_INTERVAL))?;
+    let reply = match proxy.server {
+        Some(_) => None,
+        None => {
+            let sock = tproxy::bind_transparent(server)?;
+            sock.connect(addr)?;
+            sock.set_read_timeout(Some(IDLE_CHECK_INTERVAL))?;
+            Some(sock)
+        },
+    };
+    let c = Arc::new(Client {
+        upstream,
+        reply,
+        last_active: AtomicI64::new(clock::now()),
+    });
+    clients.insert(key, c.clone());
+    log!(Relay, Info, "proxy: new client {} for {}", addr, server);
+
+    if c.reply.is_some() {
+        let proxy = proxy.clone();
+        let client = c.clone();
+        let inp_send = inp_send.clone();
+        // Thread names are cut off after 15 bytes, so these use just the client's port.
+        thread::Builder::new().name(format!("down {}", addr.port())).spawn(move || {
+            affinity::try_pin(Role::Reader);
+            run_downstream(&proxy, key, client, inp_send)
+        })?;
+    }
+    let proxy = proxy.clone();
+    let client = c.clone();
+    let inp_send = inp_send.clone();
+    thread::Builder::new().name(format!("up {}", addr.port())).spawn(move || {
+        affinity::try_pin(Role::Reader);
+        run_upstream(&proxy, key, client, inp_send)
+    })?;
+    Ok(c)
+}
+
+/// Receive a datagram from a client, returning its length, source, and the server to forward it
+/// to.
+fn recv_from_client(
+    proxy: &Proxy,
+    buf: &mut [u8],
+) -> io::Result<Option<(usize, SocketAddrV4, SocketAddrV4)>> {
+    match proxy.server {
+        Some(server) => {
+            let (len, from) = proxy.listen.recv_from(buf)?;
+            Ok(expect_v4(from).map(|from| (len, from, server)))
+        },
+        None => {
+            let (len, from, orig_dst) = tproxy::recv_orig_dst(&proxy.listen, buf)?;
+            Ok(orig_dst.map(|dst| (len, from, dst)))
+        },
+    }
+}
+
+/// Forward one datagram from a client to the server.
+fn forward_to_server(
+    proxy: &Proxy,
+    client: &Client,
+    key: FlowKey,
+    data: &[u8],
+    inp_send: &Sender<Input>,
+) -> Result<(), Error> {
+    let p = match Packet::new_udp_ipv4(key.0, key.1, data) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    proxy.stats.a_to_b.count_packet(p.len());
+    client.last_active.store(clock::now(), Ordering::Relaxed);
+    if process::should_process(&p, false) {
+        inp_send.send(Input::FromA(p)).map_err(|_| "processing thread is gone")?;
+    } else {
+        process::note_passthrough(&p, &proxy.stats.a_to_b);
+        count_send_result(client.upstream.send(p.udp_payload()), &proxy.stats.a_to_b);
+    }
+    Ok(())
+}
+
+/// Forward datagrams from clients to the server.
+fn run_listener(proxy: &Arc<Proxy>, inp_send: &Sender<Input>) -> Result<(), Error> {
+    let mut buf = vec![0; PACKET_CAP];
+    loop {
+        let (len, from, server) = match recv_from_client(proxy, &mut buf)? {
+            Some(x) => x,
+            None => continue,
+        };
+        health::beat();
+        // Check before `get_client`, so a flood of new clients doesn't open sockets.
+        if !check_rate(proxy, (from, server)) {
+            continue;
+        }
+        let client = get_client(proxy, (from, server), inp_send)?;
+        forward_to_server(proxy, &client, (from, server), &buf[..len], inp_send)?;
+    }
+}
+
+/// In transparent mode, forward datagrams from one client that arrive on its reply socket.  Once
+/// that socket exists, the kernel delivers the client's traffic to it rather than to the
+/// listening socket, since it's bound to the client's original destination.
+fn run_downstream(proxy: &Proxy, key: FlowKey, client: Arc<Client>, inp_send: Sender<Input>) {
+    let sock = client.reply.as_ref().unwrap();
+    let mut buf = vec![0; PACKET_CAP];
+    loop {
+        let len = match sock.recv(&mut buf) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                    e.kind() == io::ErrorKind::TimedOut => {
+                let idle = clock::now() - client.last_active.load(Ordering::Relaxed);
+                if idle >= IDLE_TIMEOUT {
+                    break;
+                }
+                continue;
+            },
+            Err(e) => {
+                log!(Relay, Warn, "proxy: {}: receive failed: {}", key.0, e);
+                continue;
+            },
+        };
+        if !check_rate(proxy, key) {
+            continue;
+        }
+        if forward_to_server(proxy, &client, key, &buf[..len], &inp_send).is_err() {
+            break;
+        }
+    }
+}
+
+/// Forward datagrams from the server to one client, until the client goes idle.
+fn run_upstream(proxy: &Proxy, key: FlowKey, client: Arc<Client>, inp_send: Sender<Input>) {
+    let (addr, server) = key;
+    let mut buf = vec![0; PACKET_CAP];
+    loop {
+        let len = match client.upstream.recv(&mut buf) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                    e.kind() == io::ErrorKind::TimedOut => {
+                let idle = clock::now() - client.last_active.load(Ordering::Relaxed);
+                if idle >= IDLE_TIMEOUT {
+                    break;
+                }
+                continue;
+            },
+            // Usually `ECONNREFUSED`, left over from an ICMP error for an earlier send.
+            Err(e) => {
+                log!(Relay, Warn, "proxy: {}: receive failed: {}", addr, e);
+                continue;
+            },
+        };
+        let p = match Packet::new_udp_ipv4(server, addr, &buf[..len]) {
+            Some(x) => x,
+            None => continue,
+        };
+        proxy.stats.b_to_a.count_packet(p.len());
+        client.last_active.store(clock::now(), Ordering::Relaxed);
+        if process::should_process(&p, true) {
+            if inp_send.send(Input::FromB(p)).is_err() {
+                break;
+            }
+        } else {
+            process::note_passthrough(&p, &proxy.stats.b_to_a);
+            send_to_client(proxy, &client, addr, p.udp_payload());
+        }
+    }
+    proxy.clients.lock().unwrap().remove(&key);
+    log!(Relay, Info, "proxy: closed client {}", addr);
+}
+
+/// Processed packets waiting to go out together: all in the same direction, for the same flow.
+struct Run {
+    to_server: bool,
+    key: FlowKey,
+    client: Arc<Client>,
+    packets: Vec<Packet>,
+}
+
+impl Run {
+    fn send(self, proxy: &Proxy) {
+        let (addr, _) = self.key;
+        if self.packets.len() == 1 {
+            let data = self.packets[0].udp_payload();
+            if self.to_server {
+                count_send_result(self.client.upstream.send(data), &proxy.stats.a_to_b);
+            } else {
+                send_to_client(proxy, &self.client, addr, data);
+            }
+            return;
+        }
+
+        let (sock, dst, counters) = if self.to_server {
+            (&self.client.upstream, None, &proxy.stats.a_to_b)
+        } else {
+            let sock = self.client.reply.as_ref().unwrap_or(&proxy.listen);
+            (sock, Some(addr), &proxy.stats.b_to_a)
+        };
+
+        let data = self.packets.iter().map(|p| p.udp_payload()).collect::<Vec<_>>();
+        let mut i = 0;
+        while i < data.len() {
+            match sendmmsg::send(sock, dst, &data[i..]) {
+                Ok(n) => {
+                    if n > 1 {
+                        counters.batches.fetch_add(1, Ordering::Relaxed);
+                        counters.batched.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                    i += n;
+                },
+                // Drop the datagram that failed and carry on with the rest.
+                Err(e) => {
+                    count_send_result(Err(e), counters);
+                    i += 1;
+                },
+            }
+        }
+    }
+}
+
+/// Send processed packets on to their destinations, pacing those toward the server with
+/// `pacer` if given.  Runs of up to `batch` packets for the same flow that are ready at once go
+/// out with a single system call.
+fn run_writer(
+    proxy: &Proxy,
+    out_recv: &Receiver<Output>,
+    mut pacer: Option<Pacer>,
+    batch: usize,
+) {
+    let mut run: Option<Run> = None;
+    loop {
+        // Wait only when there's nothing to send.  Otherwise, send what's been collected as soon
+        // as no more packets are ready.
+        let out = if run.is_some() { out_recv.try_recv() } else { out_recv.recv() };
+        let out = match out {
+            Some(x) => x,
+            None => match run.take() {
+                Some(r) => {
+                    r.send(proxy);
+                    continue;
+                },
+                None => break,
+            },
+        };
+        health::beat();
+        let (to_server, p) = match out {
+            Output::ToA(p) => (false, p),
+            Output::ToB(p) => (true, p),
+        };
+        let (src, dst) = packet_addrs(&p);
+        let key = if to_server { (src, dst) } else { (dst, src) };
+        if let Some(ref r) = run {
+            if r.to_server != to_server || r.key != key || r.packets.len() >= batch {
+                run.take().unwrap().send(proxy);
+            }
+        }
+
+        if to_server {
+            // Sockets have no queue to hold packets back in, so wait out the tick.  This holds up
+            // packets to clients too, but only for one tick.
+            if let Some(ref mut pacer) = pacer {
+                if pacer.delay(Instant::now()).is_some() {
+                    if let Some(r) = run.take() {
+                        r.send(proxy);
+                    }
+                    while let Some(delay) = pacer.delay(Instant::now()) {
+                        thread::sleep(delay);
+                    }
+                    proxy.stats.a_to_b.paced.fetch_add(1, Ordering::Relaxed);
+                }
+                pacer.sent();
+            }
+        }
+
+        if run.is_none() {
+            let client = proxy.clients.lock().unwrap().get(&key).cloned();
+            match client {
+                Some(client) => {
+                    run = Some(Run { to_server, key, client, packets: Vec::new() });
+                },
+                // The client went idle while the packet was being processed.
+                None => {
+                    let stats = &proxy.stats;
+                    let counters = if to_server { &stats.a_to_b } else { &stats.b_to_a };
+                    counters.write_failed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                },
+            }
+        }
+        run.as_mut().unwrap().packets.push(p);
+    }
+}
+
+/// Listen for clients on `listen` and proxy them to `server`, running the proxy's threads under
+/// `sup`.  If `server` is `None`, run as a transparent proxy instead.  Datagrams from clients go
+/// through `limiter`, and processed datagrams to the server through `pacer`, if given.  Up to
+/// `batch` processed datagrams for one flow are sent per system call.  `inp_send` and `out_recv`
+/// connect to the processing thread.
+pub fn start(
+    sup: &Supervisor,
+    listen: &str,
+    server: Option<&str>,
+    limiter: Option<RateLimiter>,
+    pacer: Option<Pacer>,
+    batch: usize,
+    stats: Arc<RelayStats>,
+    inp_send: Sender<Input>,
+    out_recv: Receiver<Output>,
+) -> Result<(), Error> {
+    let (listen, server) = match server {
+        Some(server) => {
+            let server = resolve_v4(server)?;
+            let listen = UdpSocket::bind(listen).at(listen)?;
+            log!(Relay, Info, "proxying {} to {}", listen.local_addr()?, server);
+            (listen, Some(server))
+        },
+        None => {
+            let sock = tproxy::bind_transparent(resolve_v4(listen)?).at(listen)?;
+            tproxy::enable_orig_dst(&sock).at(listen)?;
+            log!(Relay, Info, "transparent proxy listening on {}", sock.local_addr()?);
+            (sock, None)
+        },
+    };
+    let proxy = Arc::new(Proxy {
+        listen,
+        server,
+        clients: Mutex::new(HashMap::new()),
+        limiter: limiter.map(Mutex::new),
+        stats,
+    });
+
+    let proxy2 = proxy.clone();
+    sup.spawn("proxy listener", Restart::Limit(MAX_RESTARTS), move || {
+        affinity::pin(Role::Reader)?;
+        run_listener(&proxy2, &inp_send)
+    });
+    sup.spawn("proxy writer", Restart::Limit(MAX_RESTARTS), move || {
+        affinity::pin(Role::Writer)?;
+        run_writer(&proxy, &out_recv, pacer.clone(), batch);
+        Ok(())
+    });
+    Ok(())
+}
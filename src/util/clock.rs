@@ -0,0 +1,56 @@
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+
+/// Seconds since the Unix epoch.
+pub fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// Microseconds since the Unix epoch, for log timestamps.
+pub fn now_us() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_micros() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Microseconds since the Unix epoch, like `now_us`, but steady: the wall clock is read the first
+/// time this is called, and the time is counted on from there with `Instant`.  Stepping the
+/// system clock, as NTP does, doesn't make it jump or go back, so it's the one to time things out
+/// by.
+pub fn monotonic_us() -> u64 {
+    static START: OnceLock<(u64, Instant)> = OnceLock::new();
+    let &(wall, start) = START.get_or_init(|| (now_us(), Instant::now()));
+    wall + start.elapsed().as_micros() as u64
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) date, using the proleptic Gregorian
+/// calendar.  From Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// The UTC date of a Unix time, as `YYYY-MM-DD`.
+pub fn utc_date(secs: i64) -> String {
+    let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// The UTC time of day of a Unix time, as `HH:MM:SS`.
+pub fn utc_time(secs: i64) -> String {
+    let s = secs.rem_euclid(86400);
+    format!("{:02}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
@@ -0,0 +1,21 @@
+//! CRC-32, as used by zlib and gzip, for checking that stored data wasn't damaged.
+
+/// The CRC of each 4-bit value, for the reflected polynomial 0xedb88320.
+const TABLE: [u32; 16] = [
+    0x0000_0000, 0x1db7_1064, 0x3b6e_20c8, 0x26d9_30ac,
+    0x76dc_4190, 0x6b6b_51f4, 0x4db2_6158, 0x5005_713c,
+    0xedb8_8320, 0xf00f_9344, 0xd6d6_a3e8, 0xcb61_b38c,
+    0x9b64_c2b0, 0x86d3_d2d4, 0xa00a_e278, 0xbdbd_f21c,
+];
+
+/// Extend `crc`, the CRC of some bytes, to cover `data` after them.  The CRC of no bytes is zero,
+/// so `update(0, data)` is the CRC of `data`.
+pub fn update(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c ^= b as u32;
+        c = TABLE[(c & 0xf) as usize] ^ (c >> 4);
+        c = TABLE[(c & 0xf) as usize] ^ (c >> 4);
+    }
+    !c
+}
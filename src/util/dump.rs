@@ -0,0 +1,167 @@
+//! Human-readable dumps of raw packet and message bytes.
+use std::fmt::Write as _;
+use std::str;
+
+
+#[derive(Clone, Debug)]
+pub struct DumpOptions {
+    /// Start a new line after this many bytes.  Zero puts everything on a single line.
+    pub bytes_per_line: usize,
+    /// Prefix each line with the offset of its first byte.
+    pub offsets: bool,
+    /// Follow each line with an ASCII rendering of its bytes, like `hexdump -C`.
+    pub ascii: bool,
+    /// Dump at most this many bytes, followed by `...` if anything was left out.
+    pub max_len: Option<usize>,
+    /// Use ANSI escapes to highlight printable bytes and dim zeros.
+    pub color: bool,
+}
+
+impl Default for DumpOptions {
+    fn default() -> DumpOptions {
+        DumpOptions {
+            bytes_per_line: 0,
+            offsets: false,
+            ascii: false,
+            max_len: None,
+            color: false,
+        }
+    }
+}
+
+impl DumpOptions {
+    /// Multi-line layout similar to `hexdump -C`.
+    pub fn lines() -> DumpOptions {
+        DumpOptions {
+            bytes_per_line: 16,
+            offsets: true,
+            ascii: true,
+            .. DumpOptions::default()
+        }
+    }
+}
+
+const COLOR_PRINTABLE: &str = "\x1b[32m";
+const COLOR_ZERO: &str = "\x1b[2m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+fn truncated<'a>(b: &'a [u8], opts: &DumpOptions) -> (&'a [u8], bool) {
+    match opts.max_len {
+        Some(max) if b.len() > max => (&b[..max], true),
+        _ => (b, false),
+    }
+}
+
+fn byte_color(x: u8) -> Option<&'static str> {
+    if is_printable_ascii(x) {
+        Some(COLOR_PRINTABLE)
+    } else if x == 0 {
+        Some(COLOR_ZERO)
+    } else {
+        None
+    }
+}
+
+/// Space-separated hex bytes, e.g. `01 00 0a ff`.
+pub fn hex(b: &[u8]) -> String {
+    hex_with(b, &DumpOptions::default())
+}
+
+pub fn hex_with(b: &[u8], opts: &DumpOptions) -> String {
+    let (b, more) = truncated(b, opts);
+    let per_line = if opts.bytes_per_line == 0 { b.len().max(1) } else { opts.bytes_per_line };
+
+    let mut s = String::with_capacity(b.len() * 3);
+    for (line_idx, line) in b.chunks(per_line).enumerate() {
+        if line_idx > 0 {
+            s.push('\n');
+        }
+        let offset = line_idx * per_line;
+        if opts.offsets {
+            write!(s, "{:08x}  ", offset).unwrap();
+        }
+
+        for (i, &x) in line.iter().enumerate() {
+            if i > 0 {
+                s.push(' ');
+            }
+            match byte_color(x).filter(|_| opts.color) {
+                Some(c) => write!(s, "{}{:02x}{}", c, x, COLOR_RESET).unwrap(),
+                None => write!(s, "{:02x}", x).unwrap(),
+            }
+        }
+
+        if opts.ascii {
+            // Pad short final lines so the ASCII column stays aligned.
+            if opts.bytes_per_line != 0 {
+                for _ in line.len() .. per_line {
+                    s.push_str("   ");
+                }
+            }
+            s.push_str("  |");
+            for &x in line {
+                let c = if is_printable_ascii(x) { x as char } else { '.' };
+                s.push(c);
+            }
+            s.push('|');
+        }
+    }
+
+    if more {
+        s.push_str(if s.is_empty() { "..." } else { " ..." });
+    }
+    s
+}
+
+/// Hex bytes interleaved with quoted runs of printable ASCII, e.g. `0a "name" 00 00`.
+pub fn mixed(b: &[u8]) -> String {
+    mixed_with(b, &DumpOptions::default())
+}
+
+/// Like `mixed`, but honors `max_len` and `color`.  The line layout options don't apply, since
+/// quoted strings have variable width.
+pub fn mixed_with(b: &[u8], opts: &DumpOptions) -> String {
+    let (b, more) = truncated(b, opts);
+
+    let mut out = String::with_capacity(b.len() * 3);
+    let mut i = 0;
+    while i < b.len() {
+        if i > 0 {
+            out.push(' ');
+        }
+        let x = b[i];
+        if is_printable_ascii(x) {
+            let j = b[i..].iter().position(|&x| !is_printable_ascii(x))
+                .map_or(b.len(), |off| i + off);
+            assert!(j > i);
+            if opts.color {
+                out.push_str(COLOR_PRINTABLE);
+            }
+            if j >= i + 2 {
+                let s = str::from_utf8(&b[i..j]).unwrap();
+                write!(out, "{:?}", s).unwrap();
+            } else {
+                write!(out, "{:?}", x as char).unwrap();
+            }
+            if opts.color {
+                out.push_str(COLOR_RESET);
+            }
+            i = j;
+        } else {
+            match byte_color(x).filter(|_| opts.color) {
+                Some(c) => write!(out, "{}{:02x}{}", c, x, COLOR_RESET).unwrap(),
+                None => write!(out, "{:02x}", x).unwrap(),
+            }
+            i += 1;
+        }
+    }
+
+    if more {
+        out.push_str(if out.is_empty() { "..." } else { " ..." });
+    }
+    out
+}
+
+pub fn is_printable_ascii(x: u8) -> bool {
+    x >= 0x20 && x < 0x7f
+}
@@ -0,0 +1,21 @@
+/// Parse a string of hex digits into bytes.  Whitespace between bytes is ignored, so both
+/// `0aff00` and `0a ff 00` are accepted.
+pub fn parse(s: &str) -> Result<Vec<u8>, String> {
+    let digits = s.chars().filter(|c| !c.is_whitespace()).collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        return Err(format!("odd number of hex digits in {:?}", s));
+    }
+    digits.chunks(2).map(|pair| {
+        let hi = pair[0].to_digit(16);
+        let lo = pair[1].to_digit(16);
+        match (hi, lo) {
+            (Some(hi), Some(lo)) => Ok((hi * 16 + lo) as u8),
+            _ => Err(format!("invalid hex byte {}{} in {:?}", pair[0], pair[1], s)),
+        }
+    }).collect()
+}
+
+/// Format bytes as lowercase hex digits with no separators.  This is the inverse of `parse`.
+pub fn encode(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
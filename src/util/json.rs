@@ -0,0 +1,95 @@
+//! Minimal JSON output, enough for exporting messages and events as single-line objects.
+use std::fmt::{self, Write as _};
+
+
+/// Append `s` to `out` as a quoted JSON string.
+pub fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Format `items` as a JSON array of strings.
+pub fn str_array<'a>(items: impl IntoIterator<Item = &'a str>) -> String {
+    let mut out = String::from("[");
+    for (i, s) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_str(&mut out, s);
+    }
+    out.push(']');
+    out
+}
+
+/// Builder for a JSON object.  Keys are written in the order they're added.
+pub struct Object {
+    buf: String,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object { buf: String::from("{") }
+    }
+
+    fn key(&mut self, k: &str) {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        write_str(&mut self.buf, k);
+        self.buf.push(':');
+    }
+
+    pub fn str(&mut self, k: &str, v: &str) -> &mut Object {
+        self.key(k);
+        write_str(&mut self.buf, v);
+        self
+    }
+
+    /// Add a numeric field.  `v` must format as a valid JSON number.
+    pub fn num(&mut self, k: &str, v: impl fmt::Display) -> &mut Object {
+        self.key(k);
+        write!(self.buf, "{}", v).unwrap();
+        self
+    }
+
+    pub fn bool(&mut self, k: &str, v: bool) -> &mut Object {
+        self.key(k);
+        self.buf.push_str(if v { "true" } else { "false" });
+        self
+    }
+
+    /// Add `v` as a string of lowercase hex digits, with no separators.
+    pub fn hex(&mut self, k: &str, v: &[u8]) -> &mut Object {
+        self.key(k);
+        self.buf.push('"');
+        for &b in v {
+            write!(self.buf, "{:02x}", b).unwrap();
+        }
+        self.buf.push('"');
+        self
+    }
+
+    /// Add a field whose value is already encoded as JSON.
+    pub fn raw(&mut self, k: &str, v: &str) -> &mut Object {
+        self.key(k);
+        self.buf.push_str(v);
+        self
+    }
+
+    pub fn finish(&self) -> String {
+        let mut s = self.buf.clone();
+        s.push('}');
+        s
+    }
+}
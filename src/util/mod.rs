@@ -0,0 +1,5 @@
+pub mod clock;
+pub mod crc32;
+pub mod dump;
+pub mod hex;
+pub mod json;
@@ -0,0 +1,284 @@
+//! Live feed of decoded messages over WebSocket, for browser-based protocol inspectors.
+//!
+//! Clients connect to `ws://host:port/` and receive one JSON text frame per message.  The request
+//! path may include a query string to filter the feed:
+//!
+//!  - `major=0a,14`: only these major opcodes (hex)
+//!  - `dir=0` or `dir=1`: only one direction (0 is client to server)
+//!  - `conn=1.2.3.4` or `conn=1.2.3.4:5678`: only connections with a matching endpoint
+//!  - `contains=ff00`: only messages whose body contains these bytes (hex)
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use crate::{Error, ErrorAt};
+use crate::filter::MessageFilter;
+use crate::opcodes::Registry;
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, Message};
+use crate::util::json;
+
+
+/// Number of messages that can be queued for a slow client before further messages are dropped.
+const CLIENT_QUEUE_LEN: usize = 1024;
+
+struct Client {
+    filter: MessageFilter,
+    send: SyncSender<Arc<String>>,
+    /// Set once the client has gone, so the next `publish` drops it whether or not it wants the
+    /// message.
+    closed: Arc<AtomicBool>,
+}
+
+/// Handle for publishing messages to all connected WebSocket clients.
+#[derive(Clone)]
+pub struct Feed {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl Feed {
+    /// Listen on `addr` and accept WebSocket clients in a background thread.
+    pub fn start(addr: &str) -> Result<Feed, Error> {
+        let listener = TcpListener::bind(addr).at("websocket: bind")?;
+        let feed = Feed { clients: Arc::new(Mutex::new(Vec::new())) };
+
+        let feed2 = feed.clone();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let socket = match socket {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log!(Handler, Error, "websocket: accept failed: {}", e);
+                        continue;
+                    },
+                };
+                let feed = feed2.clone();
+                thread::spawn(move || {
+                    let peer = socket.peer_addr().ok();
+                    match feed.serve_client(socket) {
+                        Ok(()) => {},
+                        Err(e) => log!(Handler, Warn, "websocket: client {:?}: {}", peer, e),
+                    }
+                });
+            }
+        });
+
+        Ok(feed)
+    }
+
+    fn serve_client(&self, mut socket: TcpStream) -> Result<(), Error> {
+        let (path, key) = read_handshake(&mut socket)?;
+        let query = path.find('?').map_or("", |i| &path[i + 1 ..]);
+        let filter = match MessageFilter::parse_query(query) {
+            Ok(x) => x,
+            Err(e) => {
+                write!(socket, "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\n\r\n{}",
+                    e.len(), e)?;
+                return Err(Error(e));
+            },
+        };
+
+        write!(
+            socket,
+            "HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key),
+        )?;
+
+        let (send, recv) = mpsc::sync_channel(CLIENT_QUEUE_LEN);
+        let closed = Arc::new(AtomicBool::new(false));
+        self.clients.lock().unwrap().push(Client { filter, send, closed: closed.clone() });
+
+        // Incoming frames (pings, close requests) are ignored, but reading them notices when the
+        // client goes even if nothing has been sent to it lately.
+        let mut incoming = socket.try_clone()?;
+        let closed2 = closed.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok(n) = incoming.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+            closed2.store(true, Ordering::Relaxed);
+        });
+
+        // Once the client is marked closed, the next `publish` removes its `Client` entry, which
+        // ends this loop.
+        for text in recv.iter() {
+            if let Err(e) = write_text_frame(&mut socket, text.as_bytes()) {
+                closed.store(true, Ordering::Relaxed);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `msg` to the clients that want it, with its session, and its name and fields from
+    /// `opcodes`.
+    pub fn publish(
+        &self,
+        ct: ConnTuple,
+        session: SessionId,
+        msg: &Message,
+        opcodes: Option<&Registry>,
+    ) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|c| !c.closed.load(Ordering::Relaxed));
+        if clients.len() == 0 {
+            return;
+        }
+
+        let mut json = None;
+        clients.retain(|c| {
+            if !c.filter.matches(ct, msg) {
+                return true;
+            }
+            let json = json.get_or_insert_with(|| {
+                let mut obj = json::Object::new();
+                obj.str("session", &session.to_string());
+                msg.write_json(ct, &mut obj);
+                if let Some(opcodes) = opcodes {
+                    opcodes.write_json(msg, &mut obj);
+                }
+                Arc::new(obj.finish())
+            }).clone();
+            match c.send.try_send(json) {
+                Ok(()) => true,
+                // The client is falling behind.  Drop this message, but keep the client.
+                Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// Read the client's HTTP upgrade request.  Returns the request path and the value of the
+/// `Sec-WebSocket-Key` header.
+fn read_handshake(socket: &mut TcpStream) -> Result<(String, String), Error> {
+    let mut r = BufReader::new(socket);
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let path = line.split_whitespace().nth(1).ok_or("malformed request line")?.to_owned();
+
+    let mut key = None;
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            return Err("connection closed during handshake".into());
+        }
+        let line = line.trim_end();
+        if line.len() == 0 {
+            break;
+        }
+        if let Some(i) = line.find(':') {
+            if line[..i].eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(line[i + 1 ..].trim().to_owned());
+            }
+        }
+    }
+
+    let key = key.ok_or("missing Sec-WebSocket-Key")?;
+    Ok((path, key))
+}
+
+fn write_text_frame(w: &mut impl Write, data: &[u8]) -> Result<(), Error> {
+    let mut hdr = Vec::with_capacity(10);
+    // FIN + text opcode.  Server-to-client frames are never masked.
+    hdr.push(0x81);
+    if data.len() < 126 {
+        hdr.push(data.len() as u8);
+    } else if data.len() <= u16::MAX as usize {
+        hdr.push(126);
+        hdr.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    } else {
+        hdr.push(127);
+        hdr.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    }
+    w.write_all(&hdr)?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+fn accept_key(key: &str) -> String {
+    const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    let mut input = key.as_bytes().to_owned();
+    input.extend_from_slice(GUID.as_bytes());
+    base64(&sha1(&input))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut msg = data.to_owned();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0 .. 16 {
+            w[i] = u32::from_be_bytes([
+                block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3],
+            ]);
+        }
+        for i in 16 .. 80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for i in 0 .. 80 {
+            let (f, k) = match i {
+                0 ..= 19 => ((b & c) | (!b & d), 0x5a827999),
+                20 ..= 39 => (b ^ c ^ d, 0x6ed9eba1),
+                40 ..= 59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut out = [0; 20];
+    for (i, x) in h.iter().enumerate() {
+        out[i * 4 .. i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0 .. 4 {
+            if i <= chunk.len() {
+                s.push(CHARS[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
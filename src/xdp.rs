@@ -0,0 +1,639 @@
+//! AF_XDP capture and injection, for lobby servers busy enough (hosting a large tournament, say)
+//! that reading and writing a tun device one packet per system call can't keep up.
+//!
+//! An `XdpSocket` is bound to one receive queue of a network interface.  The kernel only gives it
+//! the frames that an XDP program attached to the interface redirects into an `XSKMAP`.  This
+//! module doesn't load programs itself: attach one with the usual tools (`xdp-loader` from
+//! xdp-tools, for instance), pin its map, and pass the map's path so the socket can add itself.
+//! `xdp/redirect.c` is such a program.  Redirect only the lobby's traffic, since anything
+//! redirected bypasses the host's network stack; non-IPv4 frames that arrive anyway, such as
+//! ARP, are dropped, since there's no handing them back to the kernel.
+//!
+//! Frames arrive in a memory area shared with the kernel (the UMEM) and are classified in place.
+//! Only those that `classify` accepts, by default TFH stream packets, are copied out into
+//! `Packet`s.  The rest go straight from the UMEM to the bypass, if one is set, so traffic that
+//! doesn't need processing never gets copied into a `Packet`.
+//!
+//! Outgoing packets need an Ethernet header.  The destination MAC address is the one the
+//! destination IP was last seen sending from, or failing that, the source of the most recent
+//! frame, which for traffic from the internet is the gateway.
+//!
+//! The kernel picks zero-copy or copy mode depending on the driver.  Only copy mode (generic XDP
+//! on a veth pair) has been tried so far.
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use libc::{c_int, c_void, socklen_t};
+use crate::bytes::Bytes;
+use crate::packet::{Ipv4Header, Packet, PACKET_CAP};
+use crate::transport::{PacketSink, PacketSource};
+
+
+const AF_XDP: c_int = 44;
+const SOL_XDP: c_int = 283;
+
+const XDP_MMAP_OFFSETS: c_int = 1;
+const XDP_RX_RING: c_int = 2;
+const XDP_TX_RING: c_int = 3;
+const XDP_UMEM_REG: c_int = 4;
+const XDP_UMEM_FILL_RING: c_int = 5;
+const XDP_UMEM_COMPLETION_RING: c_int = 6;
+
+const XDP_PGOFF_RX_RING: i64 = 0;
+const XDP_PGOFF_TX_RING: i64 = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: i64 = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: i64 = 0x180000000;
+
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1;
+
+const BPF_MAP_UPDATE_ELEM: c_int = 2;
+const BPF_OBJ_GET: c_int = 7;
+
+/// Size of each frame in the UMEM.  The smallest the kernel allows, and still room for a
+/// full-sized Ethernet frame.
+const FRAME_SIZE: usize = 2048;
+/// Frames for receiving, which cycle between the fill and RX rings.
+const RX_FRAMES: usize = 2048;
+/// Frames for sending, which cycle between the TX and completion rings.
+const TX_FRAMES: usize = 2048;
+/// Entries in each ring.  Each ring can hold all of its frames at once.
+const RING_SIZE: u32 = 2048;
+
+/// Most frames to take off the RX ring at once.
+const RX_BATCH: u32 = 64;
+
+/// MAC addresses to remember before starting over.
+const MAX_MACS: usize = 65536;
+
+const ETH_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+struct BpfObjGet {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+struct BpfMapUpdate {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+
+/// A file descriptor, closed on drop.
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// A memory mapping, unmapped on drop.
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(len: usize, fd: RawFd, offset: i64) -> io::Result<Mmap> {
+        let (flags, fd) = if fd < 0 {
+            (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1)
+        } else {
+            (libc::MAP_SHARED | libc::MAP_POPULATE, fd)
+        };
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, prot, flags, fd, offset) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr: ptr as *mut u8, len })
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
+
+/// One of the four rings shared with the kernel.  The fill and TX rings are written here and
+/// read by the kernel, and the RX and completion rings the other way round.
+struct Ring<T> {
+    _map: Mmap,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    entries: *mut T,
+}
+
+impl<T: Copy> Ring<T> {
+    fn map(fd: RawFd, off: &XdpRingOffset, pgoff: i64) -> io::Result<Ring<T>> {
+        let len = off.desc as usize + RING_SIZE as usize * mem::size_of::<T>();
+        let map = Mmap::new(len, fd, pgoff)?;
+        let at = |o: u64| unsafe { map.ptr.add(o as usize) };
+        Ok(Ring {
+            producer: at(off.producer) as *const AtomicU32,
+            consumer: at(off.consumer) as *const AtomicU32,
+            flags: at(off.flags) as *const AtomicU32,
+            entries: at(off.desc) as *mut T,
+            _map: map,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn get(&self, i: u32) -> T {
+        unsafe { *self.entries.add((i & (RING_SIZE - 1)) as usize) }
+    }
+
+    fn set(&self, i: u32, x: T) {
+        unsafe { *self.entries.add((i & (RING_SIZE - 1)) as usize) = x };
+    }
+
+    /// For rings the kernel writes: the index of the first waiting entry, and how many there
+    /// are.
+    fn waiting(&self) -> (u32, u32) {
+        let cons = self.consumer().load(Ordering::Relaxed);
+        (cons, self.producer().load(Ordering::Acquire).wrapping_sub(cons))
+    }
+
+    fn consume(&self, n: u32) {
+        let cons = self.consumer().load(Ordering::Relaxed);
+        self.consumer().store(cons.wrapping_add(n), Ordering::Release);
+    }
+
+    /// For rings written here: the index of the first free entry, and how many are free.
+    fn free(&self) -> (u32, u32) {
+        let prod = self.producer().load(Ordering::Relaxed);
+        let used = prod.wrapping_sub(self.consumer().load(Ordering::Acquire));
+        (prod, RING_SIZE - used)
+    }
+
+    fn produce(&self, n: u32) {
+        let prod = self.producer().load(Ordering::Relaxed);
+        self.producer().store(prod.wrapping_add(n), Ordering::Release);
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        unsafe { &*self.flags }.load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0
+    }
+}
+
+struct Tx {
+    ring: Ring<XdpDesc>,
+    completion: Ring<u64>,
+    /// Frames not currently queued for sending.
+    free: Vec<u64>,
+}
+
+/// MAC addresses to send to.
+#[derive(Default)]
+struct Macs {
+    by_ip: HashMap<u32, [u8; 6]>,
+    /// Source of the most recent frame.
+    last: Option<[u8; 6]>,
+}
+
+struct Inner {
+    ifname: String,
+    umem: Mmap,
+    rx: Mutex<(Ring<XdpDesc>, Ring<u64>)>,
+    tx: Mutex<Tx>,
+    macs: Mutex<Macs>,
+    own_mac: [u8; 6],
+    /// Declared last so it's closed after the rings are unmapped.
+    fd: Fd,
+}
+
+// The raw pointers all point into mappings owned by `Inner`, and the rings are only touched with
+// their locks held.
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+impl Inner {
+    fn frame(&self, addr: u64, len: usize) -> &[u8] {
+        let addr = addr as usize;
+        assert!(addr + len <= self.umem.len);
+        unsafe { std::slice::from_raw_parts(self.umem.ptr.add(addr), len) }
+    }
+
+    /// Move frames the kernel has finished sending back to the free list.
+    fn reap(&self, tx: &mut Tx) {
+        let (start, n) = tx.completion.waiting();
+        for i in 0 .. n {
+            tx.free.push(tx.completion.get(start.wrapping_add(i)));
+        }
+        tx.completion.consume(n);
+    }
+
+    fn kick(&self) {
+        unsafe {
+            libc::sendto(self.fd.0, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0);
+        }
+    }
+
+    fn poll(&self, events: libc::c_short, timeout_ms: c_int) -> io::Result<bool> {
+        let mut pfd = libc::pollfd { fd: self.fd.0, events, revents: 0 };
+        let n = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) };
+        }
+        Ok(n > 0)
+    }
+}
+
+/// What to do with received frames that `classify` rejects.  Gets the IP packet, without the
+/// Ethernet header, still in the UMEM.
+pub type Bypass = Box<dyn FnMut(&[u8]) + Send>;
+
+/// An AF_XDP socket on one queue of a network interface.  Receiving and sending can happen on
+/// different threads, through handles from `try_clone`.
+pub struct XdpSocket {
+    inner: Arc<Inner>,
+    classify: fn(&[u8]) -> bool,
+    bypass: Option<Bypass>,
+    /// Packets taken off the RX ring but not returned by `recv` yet.
+    ready: VecDeque<Packet>,
+}
+
+/// The default `classify`: whether the IP packet `data` is a TFH stream packet, by the same test
+/// as `Packet::is_tfh_stream`.
+pub fn is_tfh_stream(data: &[u8]) -> bool {
+    if data.len() < 20 {
+        return false;
+    }
+    let ip = Ipv4Header::new(&data[.. 20]);
+    if ip.version() != 4 || !ip.is_udp() {
+        return false;
+    }
+    let start = ip.ihl() as usize * 4 + 8;
+    data.len() >= start + 25 && data.u8_be(start) == 1 && data.u32_be(start + 1) == 0
+}
+
+fn setsockopt<T>(fd: RawFd, name: c_int, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const c_void,
+            mem::size_of::<T>() as socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn bpf<T>(cmd: c_int, attr: &mut T) -> io::Result<c_int> {
+    let res = unsafe {
+        libc::syscall(libc::SYS_bpf, cmd, attr as *mut T as *mut c_void, mem::size_of::<T>())
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res as c_int)
+}
+
+/// Add socket `fd` to the `XSKMAP` pinned at `path`, for receive queue `queue`.
+fn register(path: &str, queue: u32, fd: RawFd) -> io::Result<()> {
+    let c_path = CString::new(path)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL"))?;
+    let mut get = BpfObjGet { pathname: c_path.as_ptr() as u64, bpf_fd: 0, file_flags: 0 };
+    let map = Fd(bpf(BPF_OBJ_GET, &mut get)?);
+    let value = fd as u32;
+    let mut update = BpfMapUpdate {
+        map_fd: map.0 as u32,
+        _pad: 0,
+        key: &queue as *const u32 as u64,
+        value: &value as *const u32 as u64,
+        flags: 0,
+    };
+    bpf(BPF_MAP_UPDATE_ELEM, &mut update)?;
+    Ok(())
+}
+
+fn read_mac(ifname: &str) -> io::Result<[u8; 6]> {
+    let path = format!("/sys/class/net/{}/address", ifname);
+    let s = fs::read_to_string(&path)?;
+    let mut mac = [0; 6];
+    let mut parts = s.trim().split(':');
+    for b in mac.iter_mut() {
+        *b = parts.next().and_then(|x| u8::from_str_radix(x, 16).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!(
+                "{}: bad MAC address {:?}", path, s.trim(),
+            )))?;
+    }
+    Ok(mac)
+}
+
+impl XdpSocket {
+    /// Bind to receive queue `queue` of `ifname`, and add the socket to the `XSKMAP` pinned at
+    /// `map_path`.
+    pub fn open(ifname: &str, queue: u32, map_path: &str) -> io::Result<XdpSocket> {
+        let c_ifname = CString::new(ifname)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL"))?;
+        let ifindex = unsafe { libc::if_nametoindex(c_ifname.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let own_mac = read_mac(ifname)?;
+
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = Fd(fd);
+
+        let umem = Mmap::new((RX_FRAMES + TX_FRAMES) * FRAME_SIZE, -1, 0)?;
+        setsockopt(fd.0, XDP_UMEM_REG, &XdpUmemReg {
+            addr: umem.ptr as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE as u32,
+            headroom: 0,
+            flags: 0,
+            tx_metadata_len: 0,
+        })?;
+        for &name in &[XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING, XDP_RX_RING, XDP_TX_RING] {
+            setsockopt(fd.0, name, &(RING_SIZE as c_int))?;
+        }
+
+        let mut off = XdpMmapOffsets::default();
+        let mut len = mem::size_of::<XdpMmapOffsets>() as socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd.0,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut off as *mut XdpMmapOffsets as *mut c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let rx = Ring::<XdpDesc>::map(fd.0, &off.rx, XDP_PGOFF_RX_RING)?;
+        let tx = Ring::<XdpDesc>::map(fd.0, &off.tx, XDP_PGOFF_TX_RING)?;
+        let fill = Ring::<u64>::map(fd.0, &off.fr, XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion = Ring::<u64>::map(fd.0, &off.cr, XDP_UMEM_PGOFF_COMPLETION_RING)?;
+
+        // The first frames are for receiving, and start out given to the kernel to fill.
+        let (start, _) = fill.free();
+        for i in 0 .. RX_FRAMES {
+            fill.set(start.wrapping_add(i as u32), (i * FRAME_SIZE) as u64);
+        }
+        fill.produce(RX_FRAMES as u32);
+        let free = (RX_FRAMES .. RX_FRAMES + TX_FRAMES).map(|i| (i * FRAME_SIZE) as u64).collect();
+
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: XDP_USE_NEED_WAKEUP,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue,
+            sxdp_shared_umem_fd: 0,
+        };
+        let res = unsafe {
+            libc::bind(
+                fd.0,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        register(map_path, queue, fd.0)?;
+
+        let inner = Inner {
+            ifname: ifname.to_owned(),
+            umem,
+            rx: Mutex::new((rx, fill)),
+            tx: Mutex::new(Tx { ring: tx, completion, free }),
+            macs: Mutex::new(Macs::default()),
+            own_mac,
+            fd,
+        };
+        Ok(XdpSocket {
+            inner: Arc::new(inner),
+            classify: is_tfh_stream,
+            bypass: None,
+            ready: VecDeque::new(),
+        })
+    }
+
+    pub fn ifname(&self) -> &str {
+        &self.inner.ifname
+    }
+
+    /// Set which received frames to copy into `Packet`s.  Only takes effect with a bypass.
+    pub fn set_classify(&mut self, classify: fn(&[u8]) -> bool) {
+        self.classify = classify;
+    }
+
+    /// Hand received frames that `classify` rejects to `bypass` instead of returning them from
+    /// `recv`.
+    pub fn set_bypass(&mut self, bypass: Bypass) {
+        self.bypass = Some(bypass);
+    }
+
+    /// Take a batch of frames off the RX ring, and give their memory back to the kernel.
+    fn receive_batch(&mut self) {
+        let inner = self.inner.clone();
+        let rx = inner.rx.lock().unwrap();
+        let (ref ring, ref fill) = *rx;
+        let (start, n) = ring.waiting();
+        let n = n.min(RX_BATCH);
+        let (fill_start, _) = fill.free();
+        for i in 0 .. n {
+            let desc = ring.get(start.wrapping_add(i));
+            let frame = inner.frame(desc.addr, desc.len as usize);
+            if frame.len() > ETH_HEADER_LEN {
+                self.handle_frame(frame);
+            }
+            let base = desc.addr - desc.addr % FRAME_SIZE as u64;
+            fill.set(fill_start.wrapping_add(i), base);
+        }
+        ring.consume(n);
+        fill.produce(n);
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let data = &frame[ETH_HEADER_LEN ..];
+        if frame.u16_be(12) != ETHERTYPE_IPV4 || data.len() < 20 {
+            log!(Relay, Debug, "xdp: {}: dropping non-IPv4 frame", self.inner.ifname);
+            return;
+        }
+        // Scoped so the lock is let go before the bypass, which may send.
+        {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&frame[6 .. 12]);
+            let ip = data.u32_be(12);
+            let mut macs = self.inner.macs.lock().unwrap();
+            if macs.by_ip.get(&ip) != Some(&mac) {
+                if macs.by_ip.len() >= MAX_MACS {
+                    macs.by_ip.clear();
+                }
+                macs.by_ip.insert(ip, mac);
+            }
+            macs.last = Some(mac);
+        }
+
+        if let Some(ref mut bypass) = self.bypass {
+            if !(self.classify)(data) {
+                bypass(data);
+                return;
+            }
+        }
+        if data.len() > PACKET_CAP {
+            log!(Relay, Debug, "xdp: {}: dropping {}-byte frame", self.inner.ifname, frame.len());
+            return;
+        }
+        let mut p = Packet::zeroed(data.len());
+        p.as_mut_slice().copy_from_slice(data);
+        self.ready.push_back(p);
+    }
+}
+
+impl PacketSource for XdpSocket {
+    fn recv(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(p) = self.ready.pop_front() {
+                return Ok(Some(p));
+            }
+            self.receive_batch();
+            if self.ready.is_empty() && self.inner.rx.lock().unwrap().0.waiting().1 == 0 {
+                // Waiting in `poll` also lets the kernel refill the RX ring when it wants a
+                // wakeup.
+                self.inner.poll(libc::POLLIN, -1)?;
+            }
+        }
+    }
+}
+
+impl PacketSink for XdpSocket {
+    fn send(&mut self, p: &Packet) -> io::Result<usize> {
+        let inner = &*self.inner;
+        let len = ETH_HEADER_LEN + p.len();
+        if len > FRAME_SIZE || !p.is_ipv4() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not an IPv4 packet that fits"));
+        }
+        let dest_ip = p.ipv4().dest_ip();
+        let dest_mac = {
+            let macs = inner.macs.lock().unwrap();
+            macs.by_ip.get(&dest_ip).cloned().or(macs.last)
+        };
+        let dest_mac = dest_mac.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "no MAC address to send to yet")
+        })?;
+
+        let mut tx = inner.tx.lock().unwrap();
+        inner.reap(&mut tx);
+        let addr = match tx.free.pop() {
+            Some(x) => x,
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        // The frame came off the free list, so the kernel isn't using it.
+        assert!(addr as usize + len <= inner.umem.len);
+        let frame = unsafe {
+            std::slice::from_raw_parts_mut(inner.umem.ptr.add(addr as usize), len)
+        };
+        frame[0 .. 6].copy_from_slice(&dest_mac);
+        frame[6 .. 12].copy_from_slice(&inner.own_mac);
+        frame[12 .. 14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame[ETH_HEADER_LEN ..].copy_from_slice(p.as_slice());
+
+        // The ring has room for every TX frame, so there's always a free entry.
+        let (start, _) = tx.ring.free();
+        tx.ring.set(start, XdpDesc { addr, len: len as u32, options: 0 });
+        tx.ring.produce(1);
+        if tx.ring.needs_wakeup() {
+            inner.kick();
+        }
+        Ok(p.len())
+    }
+
+    fn wait_writable(&mut self, timeout: Duration) -> io::Result<bool> {
+        let inner = &*self.inner;
+        inner.kick();
+        inner.poll(libc::POLLOUT, timeout.as_millis() as c_int)?;
+        let mut tx = inner.tx.lock().unwrap();
+        inner.reap(&mut tx);
+        Ok(!tx.free.is_empty())
+    }
+
+    fn try_clone(&self) -> io::Result<XdpSocket> {
+        Ok(XdpSocket {
+            inner: self.inner.clone(),
+            classify: self.classify,
+            bypass: None,
+            ready: VecDeque::new(),
+        })
+    }
+}
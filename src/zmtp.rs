@@ -0,0 +1,250 @@
+//! ZeroMQ-compatible publisher of decoded messages.
+//!
+//! This speaks just enough ZMTP 3 (NULL security, PUB socket type) over TCP for ordinary ZeroMQ
+//! SUB sockets to connect and subscribe.  Each message is published as five frames:
+//!
+//!  1. topic: `MM:mm`, the major and minor opcodes in hex.  Subscribe to `0a` for all messages
+//!     with major opcode 0x0a, or to the empty string for everything.
+//!  2. connection: the `ConnTuple` as text, like `1.2.3.4:5678 -> 5.6.7.8:27016`
+//!  3. session: the connection's `SessionId` as 8 hex digits
+//!  4. header: the 12-byte message header, in the same layout as tfhlog records
+//!  5. body
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use crate::{Error, ErrorAt};
+use crate::session::SessionId;
+use crate::tfh_stream::{ConnTuple, Message};
+
+
+/// Number of messages that can be queued for a slow subscriber before further messages are
+/// dropped.
+const CLIENT_QUEUE_LEN: usize = 1024;
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+struct Client {
+    /// Topic prefixes this client has subscribed to.
+    subs: Arc<Mutex<Vec<Vec<u8>>>>,
+    send: SyncSender<Arc<Vec<u8>>>,
+    /// Set once the subscriber disconnects.  Subscribers that never match any messages won't
+    /// see a write error, so this is how their entries get cleaned up.
+    closed: Arc<AtomicBool>,
+}
+
+impl Client {
+    fn wants(&self, topic: &[u8]) -> bool {
+        self.subs.lock().unwrap().iter().any(|s| topic.starts_with(s))
+    }
+}
+
+/// Handle for publishing messages to all connected subscribers.
+#[derive(Clone)]
+pub struct Publisher {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl Publisher {
+    /// Listen on `addr` (`host:port`) and accept subscribers in a background thread.
+    pub fn start(addr: &str) -> Result<Publisher, Error> {
+        let listener = TcpListener::bind(addr).at("zmq: bind")?;
+        let publisher = Publisher { clients: Arc::new(Mutex::new(Vec::new())) };
+
+        let publisher2 = publisher.clone();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let socket = match socket {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log!(Handler, Error, "zmq: accept failed: {}", e);
+                        continue;
+                    },
+                };
+                let publisher = publisher2.clone();
+                thread::spawn(move || {
+                    let peer = socket.peer_addr().ok();
+                    match publisher.serve_client(socket) {
+                        Ok(()) => {},
+                        Err(e) => log!(Handler, Warn, "zmq: subscriber {:?}: {}", peer, e),
+                    }
+                });
+            }
+        });
+
+        Ok(publisher)
+    }
+
+    fn serve_client(&self, mut socket: TcpStream) -> Result<(), Error> {
+        handshake(&mut socket)?;
+
+        let subs = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (send, recv) = mpsc::sync_channel::<Arc<Vec<u8>>>(CLIENT_QUEUE_LEN);
+        self.clients.lock().unwrap().push(Client {
+            subs: subs.clone(),
+            send,
+            closed: closed.clone(),
+        });
+
+        let mut reader = socket.try_clone()?;
+        thread::spawn(move || {
+            // An error here means the subscriber went away.
+            let _ = read_subscriptions(&mut reader, &subs);
+            closed.store(true, Ordering::Relaxed);
+        });
+
+        for frames in recv.iter() {
+            socket.write_all(&frames)?;
+        }
+        Ok(())
+    }
+
+    pub fn publish(&self, ct: ConnTuple, session: SessionId, msg: &Message) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() == 0 {
+            return;
+        }
+
+        let topic = format!("{:02x}:{:02x}", msg.header.major, msg.header.minor);
+        let mut encoded = None;
+        clients.retain(|c| {
+            if c.closed.load(Ordering::Relaxed) {
+                return false;
+            }
+            if !c.wants(topic.as_bytes()) {
+                return true;
+            }
+            let encoded = encoded.get_or_insert_with(|| {
+                let mut buf = Vec::new();
+                write_frame(&mut buf, topic.as_bytes(), true, false);
+                write_frame(&mut buf, ct.to_string().as_bytes(), true, false);
+                write_frame(&mut buf, session.to_string().as_bytes(), true, false);
+                write_frame(&mut buf, &msg.header.as_bytes(), true, false);
+                write_frame(&mut buf, &msg.body, false, false);
+                Arc::new(buf)
+            }).clone();
+            match c.send.try_send(encoded) {
+                Ok(()) => true,
+                // The subscriber is falling behind.  Drop this message, but keep the client.
+                Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+fn write_frame(buf: &mut Vec<u8>, data: &[u8], more: bool, command: bool) {
+    let mut flags = 0;
+    if more {
+        flags |= FLAG_MORE;
+    }
+    if command {
+        flags |= FLAG_COMMAND;
+    }
+    if data.len() > 255 {
+        buf.push(flags | FLAG_LONG);
+        buf.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    } else {
+        buf.push(flags);
+        buf.push(data.len() as u8);
+    }
+    buf.extend_from_slice(data);
+}
+
+/// Read one frame, returning its flags and contents.
+fn read_frame(r: &mut impl Read) -> Result<(u8, Vec<u8>), Error> {
+    let mut flags = [0];
+    r.read_exact(&mut flags)?;
+    let flags = flags[0];
+    let len = if flags & FLAG_LONG != 0 {
+        let mut len = [0; 8];
+        r.read_exact(&mut len)?;
+        u64::from_be_bytes(len) as usize
+    } else {
+        let mut len = [0];
+        r.read_exact(&mut len)?;
+        len[0] as usize
+    };
+    if len > 1 << 20 {
+        return Err(Error(format!("frame too large ({} bytes)", len)));
+    }
+    let mut data = vec![0; len];
+    r.read_exact(&mut data)?;
+    Ok((flags, data))
+}
+
+/// Exchange greetings and READY commands with a newly connected peer.
+fn handshake(socket: &mut TcpStream) -> Result<(), Error> {
+    let mut greeting = [0; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    // Version 3.0.  Peers speaking 3.1 will fall back to 3.0 framing for subscriptions.
+    greeting[10] = 3;
+    greeting[11] = 0;
+    greeting[12 .. 16].copy_from_slice(b"NULL");
+    socket.write_all(&greeting)?;
+
+    let mut peer = [0; 64];
+    socket.read_exact(&mut peer)?;
+    if peer[0] != 0xff || peer[9] & 1 != 1 {
+        return Err("peer is not speaking ZMTP".into());
+    }
+    if peer[10] < 3 {
+        return Err(Error(format!("unsupported ZMTP version {}", peer[10])));
+    }
+    if &peer[12 .. 16] != b"NULL" {
+        return Err("peer requested a security mechanism other than NULL".into());
+    }
+
+    let mut ready = Vec::new();
+    ready.push(5);
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&3_u32.to_be_bytes());
+    ready.extend_from_slice(b"PUB");
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &ready, false, true);
+    socket.write_all(&buf)?;
+
+    let (flags, cmd) = read_frame(socket)?;
+    if flags & FLAG_COMMAND == 0 || !cmd.starts_with(b"\x05READY") {
+        return Err("expected READY command".into());
+    }
+    Ok(())
+}
+
+fn read_subscriptions(r: &mut TcpStream, subs: &Mutex<Vec<Vec<u8>>>) -> Result<(), Error> {
+    loop {
+        let (flags, data) = read_frame(r)?;
+        // ZMTP 3.0 sends subscriptions as messages starting with 1 (subscribe) or 0
+        // (unsubscribe).  3.1 peers may use SUBSCRIBE and CANCEL commands instead.
+        let (subscribe, topic) = if flags & FLAG_COMMAND != 0 {
+            if data.starts_with(b"\x09SUBSCRIBE") {
+                (true, &data[10..])
+            } else if data.starts_with(b"\x06CANCEL") {
+                (false, &data[7..])
+            } else {
+                continue;
+            }
+        } else {
+            match data.split_first() {
+                Some((&1, topic)) => (true, topic),
+                Some((&0, topic)) => (false, topic),
+                _ => continue,
+            }
+        };
+
+        let mut subs = subs.lock().unwrap();
+        if subscribe {
+            subs.push(topic.to_owned());
+        } else if let Some(i) = subs.iter().position(|s| s == topic) {
+            subs.remove(i);
+        }
+    }
+}
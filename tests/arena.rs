@@ -0,0 +1,67 @@
+//! Packets share their frame with their clones and slices until one of them is changed, including
+//! across threads, and freed frames are reused without mixing up anyone's bytes.
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tfh_mitm::packet::Packet;
+
+
+const THREADS: usize = 8;
+
+fn filled(len: usize, byte: u8) -> Packet {
+    let mut p = Packet::zeroed(len);
+    p.as_mut_slice().iter_mut().for_each(|b| *b = byte);
+    p
+}
+
+#[test]
+fn clones_copy_on_write() {
+    let mut a = filled(100, 1);
+    let b = a.clone();
+    let slice = a.slice(10 .. 20);
+    assert_eq!(a.as_ptr(), b.as_ptr(), "a clone should share the frame");
+
+    a.as_mut_slice()[15] = 2;
+    assert_ne!(a.as_ptr(), b.as_ptr(), "writing should move to a frame of its own");
+    assert_eq!(a[15], 2);
+    assert!(b.iter().all(|&x| x == 1), "the clone saw the write");
+    assert!(slice.iter().all(|&x| x == 1), "the slice saw the write");
+
+    // The last holder writes in place.
+    let mut b = b;
+    drop(slice);
+    let ptr = b.as_ptr();
+    b.as_mut_slice()[0] = 3;
+    assert_eq!(b.as_ptr(), ptr);
+}
+
+#[test]
+fn shared_across_threads() {
+    let packets = Arc::new((0 .. THREADS).map(|i| filled(1000, i as u8)).collect::<Vec<_>>());
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let joins = (0 .. THREADS).map(|i| {
+        let packets = packets.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            barrier.wait();
+            for round in 0 .. 1000 {
+                // Each thread writes to its own clones of every packet, and allocates and frees
+                // packets of its own, so frames go through the free list while shared.
+                for (j, p) in packets.iter().enumerate() {
+                    let mut q = p.clone();
+                    q.as_mut_slice()[round % 1000] = 0xff;
+                    assert!(p.iter().all(|&x| x == j as u8), "thread {} saw a write", i);
+                }
+                let mine = filled(500, 0x80 | i as u8);
+                let copy = mine.clone();
+                drop(mine);
+                assert!(copy.iter().all(|&x| x == 0x80 | i as u8), "frame reused while held");
+            }
+        })
+    }).collect::<Vec<_>>();
+    for j in joins {
+        j.join().unwrap();
+    }
+    for (i, p) in packets.iter().enumerate() {
+        assert!(p.iter().all(|&x| x == i as u8), "packet {} changed", i);
+    }
+}
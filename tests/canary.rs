@@ -0,0 +1,81 @@
+//! The canary stays quiet while traffic matches the profile learned from it, and alerts once an
+//! opcode's messages change shape.
+use tfh_mitm::canary::{Canary, Profile};
+use tfh_mitm::opcodes::Registry;
+use tfh_mitm::testing::{self, conn};
+use tfh_mitm::tfh_stream::Message;
+
+
+const SEC: u64 = 1_000_000;
+
+fn match_start(time: u64, match_id: u32, pad: usize) -> Message {
+    let mut body = match_id.to_le_bytes().to_vec();
+    body.resize(4 + pad, 0);
+    let mut msg = testing::message(0x20, 0x05, body);
+    msg.header.dir = 1;
+    msg.time = time;
+    msg
+}
+
+fn learned() -> Profile {
+    let reg = Registry::parse("20:05 match_start\n    0 u32 match_id\n").unwrap();
+    let mut profile = Profile::default();
+    for i in 0 .. 100 {
+        profile.learn(&match_start(i * SEC, 1000 + i as u32, 20), Some(&reg), None);
+    }
+    let text = profile.to_text();
+    assert_eq!(text, "1 20:05 100 24 24\n    0 u32 match_id 1000 1099\n");
+    Profile::parse(&text).unwrap()
+}
+
+#[test]
+fn matching_traffic_is_quiet() {
+    let mut canary = Canary::new(learned());
+    for i in 0 .. 200 {
+        // An occasional odd message stays under the threshold.
+        let pad = if i % 20 == 0 { 28 } else { 20 };
+        assert!(canary.check(conn(), &match_start(i * SEC / 4, 1050, pad)).is_none());
+    }
+}
+
+#[test]
+fn changed_layout_alerts_once() {
+    let mut canary = Canary::new(learned());
+    let mut alerts = Vec::new();
+    for i in 0 .. 100 {
+        // The update added a field before the ID, moving it and growing the body.
+        let mut msg = match_start(i * SEC / 4, 7, 24);
+        msg.body[4 .. 8].copy_from_slice(&1050u32.to_le_bytes());
+        alerts.extend(canary.check(conn(), &msg));
+    }
+    assert_eq!(alerts.len(), 1);
+    let a = &alerts[0];
+    assert_eq!((a.dir, a.major, a.minor, a.checked, a.mismatched), (1, 0x20, 0x05, 20, 20));
+    assert_eq!(a.example, "body is 28 bytes, expected 24..24");
+
+    // An opcode the profile has never seen.
+    let mut alerts = Vec::new();
+    for i in 0 .. 30 {
+        let mut msg = testing::message(0x21, 0x00, vec![0; 4]);
+        msg.time = i * SEC;
+        alerts.extend(canary.check(conn(), &msg));
+    }
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].example, "opcode isn't in the profile");
+}
+
+/// Fields are found with the layout for the client's version.
+#[test]
+fn learned_with_version_layout() {
+    let reg = Registry::parse(concat!(
+        "20:05 match_start\n    0 u32 match_id\n",
+        "version 1200-1299\n20:05 match_start\n    4 u32 match_id\n",
+    )).unwrap();
+    let mut profile = Profile::default();
+    for i in 0 .. 10 {
+        let mut msg = match_start(i * SEC, 0, 20);
+        msg.body[4 .. 8].copy_from_slice(&(1000 + i as u32).to_le_bytes());
+        profile.learn(&msg, Some(&reg), Some(1250));
+    }
+    assert_eq!(profile.to_text(), "1 20:05 10 24 24\n    4 u32 match_id 1000 1009\n");
+}
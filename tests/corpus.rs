@@ -0,0 +1,126 @@
+//! Runs the known-answer fixtures in `tests/corpus`: each capture must decode to the messages
+//! listed beside it.  See `corpus` for the format, and for adding captures.
+use std::env;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use tfh_mitm::corpus::Fixture;
+use tfh_mitm::packet::Packet;
+use tfh_mitm::pcap;
+use tfh_mitm::testing::{self, Delivery, Rng};
+use tfh_mitm::tfh_stream::ConnTuple;
+
+
+#[test]
+fn corpus() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let bless = env::var_os("TFH_CORPUS_BLESS").is_some();
+    let mut names = fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().map_or(false, |x| x == "pcap"))
+        .collect::<Vec<_>>();
+    names.sort();
+    assert!(names.len() > 0, "no fixtures in {}", dir.display());
+
+    let mut failed = Vec::new();
+    for pcap in &names {
+        let txt = pcap.with_extension("txt");
+        let (pcap, txt) = (pcap.to_str().unwrap(), txt.to_str().unwrap());
+        let want = Fixture::load(txt).unwrap();
+        let got = Fixture::decode(pcap, want.server).unwrap();
+        if bless {
+            fs::write(txt, got.to_text()).unwrap();
+        } else if let Err(e) = want.check(&got) {
+            failed.push(format!("{}: {}", pcap, e));
+        }
+    }
+    assert!(failed.is_empty(), "fixtures failed:\n{}", failed.join("\n"));
+}
+
+const SYNTHETIC: &str = "tests/corpus/synthetic-two-conns";
+
+/// The `synthetic-two-conns` fixture: two connections of random messages, each delivered out of
+/// order with duplicates and overlapping retransmissions, with their packets interleaved.
+/// Returns the capture and the fixture's text.
+fn synthetic_two_conns() -> (Vec<u8>, String) {
+    let mut rng = Rng::new(1229);
+    let server = SocketAddrV4::new(Ipv4Addr::new(192, 168, 84, 2), 27016);
+    let mut packets = Vec::new();
+    let mut conns = Vec::new();
+    for (i, &port) in [5001, 5002].iter().enumerate() {
+        let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 5 + i as u8), port);
+        let mut up = testing::random_messages(&mut rng, 25, 200);
+        let mut down = testing::random_messages(&mut rng, 25, 300);
+        let delivery = Delivery { max_chunk: 300, ..Delivery::default() };
+        let mut a = testing::packetize(&mut rng, &up, &delivery, client, server, 0);
+        let mut b = testing::packetize(&mut rng, &down, &delivery, server, client, 0);
+        a.reverse();
+        b.reverse();
+        let mut conn_packets = Vec::new();
+        while a.len() + b.len() > 0 {
+            let to_client = a.is_empty() || (!b.is_empty() && rng.chance(50));
+            conn_packets.push(if to_client { b.pop() } else { a.pop() }.unwrap());
+        }
+        packets.push(conn_packets);
+
+        up.iter_mut().for_each(|m| m.header.dir = 0);
+        down.iter_mut().for_each(|m| m.header.dir = 1);
+        up.extend(down);
+        let ct = ConnTuple::Ipv4(u32::from(*client.ip()), port, u32::from(*server.ip()), 27016);
+        conns.push((ct, up));
+    }
+
+    // The first connection gets a few packets ahead, then the two are interleaved at random.
+    let mut out = Vec::new();
+    let mut w = pcap::Writer::new(&mut out, pcap::LINKTYPE_ETHERNET).unwrap();
+    let mut time = 1_700_000_000_000_000;
+    let mut write = |mut p: Packet| {
+        p.set_time(Some(time));
+        time += 1000;
+        w.write_packet(&p).unwrap();
+    };
+    let mut second = packets.pop().unwrap().into_iter();
+    let mut first = packets.pop().unwrap().into_iter();
+    for _ in 0 .. 3 {
+        write(first.next().unwrap());
+    }
+    loop {
+        let p = if rng.chance(50) {
+            first.next().or_else(|| second.next())
+        } else {
+            second.next().or_else(|| first.next())
+        };
+        match p {
+            Some(p) => write(p),
+            None => break,
+        }
+    }
+    drop(w);
+
+    let fixture = Fixture { server: *server.ip(), conns };
+    let text = format!("# Generated, not captured: two connections with random messages, \
+        delivered\n# out of order with duplicates and overlapping retransmissions.\n{}",
+        fixture.to_text());
+    (out, text)
+}
+
+/// The synthetic fixture is what `synthetic_two_conns` generates.
+#[test]
+fn synthetic_fixture_generated() {
+    let base = Path::new(env!("CARGO_MANIFEST_DIR")).join(SYNTHETIC);
+    let (pcap, txt) = synthetic_two_conns();
+    assert!(fs::read(base.with_extension("pcap")).unwrap() == pcap,
+        "{}.pcap differs from what it's generated from", SYNTHETIC);
+    assert_eq!(fs::read_to_string(base.with_extension("txt")).unwrap(), txt);
+}
+
+/// Rewrite the synthetic fixture, after a change to `testing` alters the random streams:
+/// `cargo test --test corpus -- --ignored`.
+#[test]
+#[ignore]
+fn regenerate_synthetic_fixture() {
+    let base = Path::new(env!("CARGO_MANIFEST_DIR")).join(SYNTHETIC);
+    let (pcap, txt) = synthetic_two_conns();
+    fs::write(base.with_extension("pcap"), pcap).unwrap();
+    fs::write(base.with_extension("txt"), txt).unwrap();
+}
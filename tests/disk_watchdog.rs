@@ -0,0 +1,64 @@
+//! The disk watchdog's arithmetic, and freeing space from the oldest logs that aren't in use,
+//! leaving anything else in the directory alone.
+use std::collections::HashSet;
+use std::fs;
+use std::time::SystemTime;
+use tfh_mitm::disk_watchdog::{self, Budget};
+use tfh_mitm::testing;
+
+
+#[test]
+fn excess() {
+    let budget = Budget { max_bytes: Some(1000), min_free: Some(500), compress: false };
+    assert_eq!(budget.excess(900, 600), 0);
+    assert_eq!(budget.excess(1200, 600), 200);
+    assert_eq!(budget.excess(900, 200), 300);
+    assert_eq!(budget.excess(1200, 200), 300);
+    assert_eq!(Budget::default().excess(u64::MAX, 0), 0);
+}
+
+#[test]
+fn free_up_deletes_oldest_closed_logs() {
+    let dir = testing::temp_dir("disk-test");
+    for name in &["a.tfhlog", "b.tfhlog", "c.tfhlog"] {
+        fs::write(dir.join(name), [0; 100]).unwrap();
+    }
+    // An index goes with its log, and counts toward the bytes freed.
+    fs::write(dir.join("b.tfhlog.idx"), [0; 20]).unwrap();
+    let names = || {
+        let mut v = fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        v.sort();
+        v
+    };
+
+    // Everything was just written, so it may still be open.
+    let none = HashSet::new();
+    assert_eq!(disk_watchdog::free_up(&dir, false, 150, SystemTime::now(), &none).unwrap(), 150);
+    assert_eq!(names(), ["a.tfhlog", "b.tfhlog", "b.tfhlog.idx", "c.tfhlog"]);
+
+    // A log a worker has open stays, however long it's been quiet.
+    let later = SystemTime::now() + disk_watchdog::ACTIVE;
+    let open = [dir.join("a.tfhlog")].iter().cloned().collect();
+    assert_eq!(disk_watchdog::free_up(&dir, false, 150, later, &open).unwrap(), 0);
+    assert_eq!(names(), ["a.tfhlog"]);
+    assert_eq!(disk_watchdog::free_up(&dir, false, 150, later, &open).unwrap(), 150);
+    assert_eq!(disk_watchdog::free_up(&dir, false, 150, later, &none).unwrap(), 50);
+    assert_eq!(names(), Vec::<String>::new());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn free_up_leaves_other_files() {
+    let dir = testing::temp_dir("disk-other-test");
+    for name in &["a.tfhlog", "b.tfhlog.gz", "snapshot", "status.txt"] {
+        fs::write(dir.join(name), [0; 100]).unwrap();
+    }
+    let later = SystemTime::now() + disk_watchdog::ACTIVE;
+    let none = HashSet::new();
+    assert_eq!(disk_watchdog::free_up(&dir, false, 1000, later, &none).unwrap(), 800);
+    assert!(dir.join("snapshot").exists() && dir.join("status.txt").exists());
+    assert!(!dir.join("a.tfhlog").exists() && !dir.join("b.tfhlog.gz").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,71 @@
+//! Failover: the active relay's feed sends its snapshot to standbys, and a standby copies what it
+//! gets, then takes over once the active relay is gone and has let go of the lock.
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use tfh_mitm::failover::{self, Feed, Lock};
+use tfh_mitm::testing;
+
+
+const SNAPSHOT: &str = "conn 10.0.0.5:5001 -> 192.168.84.2:27016\nlast 1700000000000000\n";
+
+/// Read one snapshot from a feed.
+fn read_snapshot(r: &mut impl BufRead) -> String {
+    let mut line = String::new();
+    r.read_line(&mut line).unwrap();
+    let len = line.trim().strip_prefix("snapshot ").unwrap().parse().unwrap();
+    let mut data = vec![0; len];
+    r.read_exact(&mut data).unwrap();
+    String::from_utf8(data).unwrap()
+}
+
+#[test]
+fn feed_pushes_snapshot() {
+    let dir = testing::temp_dir("failover-feed");
+    let path = dir.join("snapshot").to_str().unwrap().to_owned();
+    let feed = Feed::start("127.0.0.1:0", &path).unwrap();
+
+    // A standby gets the snapshot as soon as it connects, empty if there's none yet.
+    let mut r = BufReader::new(TcpStream::connect(feed.local_addr()).unwrap());
+    assert_eq!(read_snapshot(&mut r), "");
+    fs::write(&path, SNAPSHOT).unwrap();
+    feed.push();
+    // The periodic push may have gotten in first.
+    let mut got = read_snapshot(&mut r);
+    if got.is_empty() {
+        got = read_snapshot(&mut r);
+    }
+    assert_eq!(got, SNAPSHOT);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn standby_waits_for_lock() {
+    let dir = testing::temp_dir("failover-standby");
+    let path = dir.join("snapshot").to_str().unwrap().to_owned();
+    let active = Lock::try_acquire(&path).unwrap().unwrap();
+    assert!(Lock::try_acquire(&path).unwrap().is_none(), "the lock was taken twice");
+
+    // An active relay that sends one snapshot, then goes quiet without letting go of the lock.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut s, _) = listener.accept().unwrap();
+        write!(s, "snapshot {}\n{}", SNAPSHOT.len(), SNAPSHOT).unwrap();
+    });
+
+    let (send, recv) = mpsc::channel();
+    let path2 = path.clone();
+    thread::spawn(move || send.send(failover::standby(&addr, &path2)).unwrap());
+    let wait = failover::PUSH_INTERVAL * (failover::MAX_MISSES + 3);
+    assert!(recv.recv_timeout(wait).is_err(), "took over while the lock was held");
+    assert_eq!(fs::read_to_string(&path).unwrap(), SNAPSHOT);
+
+    drop(active);
+    let taken = recv.recv_timeout(failover::PUSH_INTERVAL * 3).expect("didn't take over");
+    let _lock = taken.unwrap();
+    assert!(Lock::try_acquire(&path).unwrap().is_none(), "the standby doesn't hold the lock");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,56 @@
+//! Keepalive spacing: steady keepalives stay quiet, a long gap is reported once as late, and the
+//! next keepalive ends it.
+use tfh_mitm::keepalive::{Event, KeepaliveTracker, MIN_INTERVALS};
+use tfh_mitm::testing::{self, conn};
+
+
+const MS: u64 = 1000;
+
+#[test]
+fn matches_opcodes() {
+    let k = KeepaliveTracker::new(vec![(0x01, Some(0x00)), (0x02, None)]);
+    assert!(k.matches(&testing::message(0x01, 0x00, vec![])));
+    assert!(!k.matches(&testing::message(0x01, 0x01, vec![])));
+    assert!(k.matches(&testing::message(0x02, 0x07, vec![])));
+}
+
+#[test]
+fn late_and_recovered() {
+    let mut k = KeepaliveTracker::new(vec![(0x01, None)]);
+    // Every second, give or take 20 ms.
+    let mut t = 0;
+    assert_eq!(k.record(conn(), 0, t), None);
+    for i in 0 .. 10 {
+        t += 1000 * MS + if i % 2 == 0 { 20 * MS } else { 0 };
+        assert_eq!(k.record(conn(), 0, t), None);
+        assert_eq!(k.check(t + 100 * MS), vec![]);
+    }
+    let s = k.summary(conn(), 0, t).unwrap();
+    assert_eq!(s.count, 11);
+    assert!((s.mean - 1010. * MS as f64).abs() < 1.);
+    assert_eq!(s.max, 1020 * MS);
+
+    // A gap a bit over the usual isn't late, but one of twice the usual is, and it's only
+    // reported once.
+    assert_eq!(k.check(t + 1200 * MS), vec![]);
+    assert_eq!(k.check(t + 2000 * MS), vec![
+        Event::Late { ct: conn(), dir: 0, gap: 2000 * MS, mean: 1010 * MS },
+    ]);
+    assert_eq!(k.check(t + 2500 * MS), vec![]);
+    assert!(k.summary(conn(), 0, t + 2500 * MS).unwrap().deviation > 4.);
+    assert_eq!(k.record(conn(), 0, t + 3000 * MS),
+        Some(Event::Recovered { ct: conn(), dir: 0, gap: 3000 * MS }));
+    assert_eq!(k.summary(conn(), 0, t + 3000 * MS).unwrap().late_count, 1);
+
+    k.close(conn());
+    assert_eq!(k.summary(conn(), 0, t), None);
+}
+
+#[test]
+fn too_few_intervals() {
+    let mut k = KeepaliveTracker::new(vec![(0x01, None)]);
+    for i in 0 .. MIN_INTERVALS as u64 {
+        k.record(conn(), 1, i * 1000 * MS);
+    }
+    assert_eq!(k.check(100_000 * MS), vec![]);
+}
@@ -0,0 +1,49 @@
+//! Version sections in the opcodes file: a connection's client version picks its layouts, and
+//! opcodes a section doesn't list fall back to the general entries.
+use tfh_mitm::opcodes::Registry;
+use tfh_mitm::testing;
+use tfh_mitm::util::json;
+
+
+const OPCODES: &str = "
+0a login
+    12 str:64 name
+20:05 match_start
+    0 u32 match_id
+
+version 1200-1299
+20:05 match_start   # moved when the map field was added
+    0 u32 map
+    4 u32 match_id
+";
+
+fn decode(reg: &Registry, version: Option<u32>) -> String {
+    let mut body = Vec::new();
+    body.extend_from_slice(&7u32.to_le_bytes());
+    body.extend_from_slice(&42u32.to_le_bytes());
+    let mut obj = json::Object::new();
+    reg.for_version(version).write_json(&testing::message(0x20, 0x05, body), &mut obj);
+    obj.finish()
+}
+
+#[test]
+fn version_picks_layout() {
+    let reg = Registry::parse(OPCODES).unwrap();
+    assert_eq!(reg.version_ranges(), [(1200, 1299)]);
+    let general = r#"{"name":"match_start","fields":{"match_id":7}}"#;
+    assert_eq!(decode(&reg, None), general);
+    assert_eq!(decode(&reg, Some(1199)), general);
+    assert_eq!(decode(&reg, Some(1250)),
+        r#"{"name":"match_start","layout":"1200-1299","fields":{"map":7,"match_id":42}}"#);
+
+    // Not listed in the section, so the general entry applies.
+    let login = reg.for_version(Some(1250)).get(0x0a, 0x00).unwrap();
+    assert_eq!(login.fields[0].name, "name");
+}
+
+#[test]
+fn overlapping_sections_rejected() {
+    let s = "version 10-20\n01 a\nversion 15\n01 b\n";
+    let e = Registry::parse(s).unwrap_err();
+    assert_eq!(e.0, "line 3: 15 overlaps versions 10-20");
+}
@@ -0,0 +1,141 @@
+//! Checksums on known-good packets.  The expected values were worked out independently of
+//! `packet.rs`, and the IPv4 header is the example from Wikipedia's "Internet checksum" article.
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tfh_mitm::framing;
+use tfh_mitm::packet::Packet;
+use tfh_mitm::packet_text;
+
+
+/// UDP from a client to the lobby server, with an even-length payload.
+const EVEN: &[u8] = &[
+    0x45, 0x00, 0x00, 0x2b, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x11, 0xff, 0xcc,
+    0x0a, 0x00, 0x00, 0x05, 0xc0, 0xa8, 0x54, 0x02, 0x13, 0x89, 0x69, 0x88,
+    0x00, 0x17, 0xe8, 0xde, 0x01, 0x00, 0x00, 0x00, 0x00, 0x68, 0x65, 0x6c,
+    0x6c, 0x6f, 0x20, 0x74, 0x66, 0x68, 0x21,
+];
+
+/// UDP back from the server, whose payload has an odd length, so the last byte is padded.
+const ODD: &[u8] = &[
+    0x45, 0x00, 0x00, 0x1f, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x11, 0xff, 0xd8,
+    0xc0, 0xa8, 0x54, 0x02, 0x0a, 0x00, 0x00, 0x05, 0x69, 0x88, 0x13, 0x89,
+    0x00, 0x0b, 0x90, 0xb2, 0x6f, 0x64, 0x64,
+];
+
+/// UDP whose checksum works out to zero, which has to be sent as 0xffff.
+const ZERO_SUM: &[u8] = &[
+    0x45, 0x00, 0x00, 0x22, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x11, 0x0a, 0x80,
+    0x0a, 0x00, 0x00, 0x05, 0x0a, 0x00, 0x00, 0x01, 0x9c, 0x40, 0x69, 0x87,
+    0x00, 0x0e, 0xff, 0xff, 0x12, 0x34, 0x56, 0x78, 0x7d, 0x58,
+];
+
+fn packet(data: &[u8]) -> Packet {
+    let mut p = Packet::zeroed(data.len());
+    p.as_mut_slice().copy_from_slice(data);
+    p
+}
+
+/// `data` with both checksums cleared.
+fn cleared(data: &[u8]) -> Packet {
+    let mut p = packet(data);
+    p.ipv4_mut().set_checksum(0);
+    p.udp_mut().set_checksum(0);
+    p
+}
+
+#[test]
+fn ipv4_header_checksum() {
+    let mut p = packet(&[
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00,
+        0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ]);
+    assert_eq!(p.compute_ipv4_checksum(), 0xb861);
+    p.update_ipv4_checksum();
+    assert_eq!(p.ipv4().checksum(), 0xb861);
+    // The old value of the field doesn't matter.
+    p.ipv4_mut().set_checksum(0x1234);
+    p.update_ipv4_checksum();
+    assert_eq!(p.ipv4().checksum(), 0xb861);
+}
+
+#[test]
+fn known_packets() {
+    for &data in &[EVEN, ODD, ZERO_SUM] {
+        let mut p = cleared(data);
+        p.update_ipv4_checksum();
+        p.update_udp_checksum();
+        assert_eq!(p.as_slice(), data);
+    }
+}
+
+#[test]
+fn zero_sum_is_sent_as_ffff() {
+    let mut p = cleared(ZERO_SUM);
+    p.update_udp_checksum();
+    assert_eq!(p.udp().checksum(), 0xffff);
+    // Updating a checksum that's already 0xffff leaves it alone.
+    p.update_udp_checksum();
+    assert_eq!(p.udp().checksum(), 0xffff);
+}
+
+#[test]
+fn recompute_after_edit() {
+    let mut p = packet(EVEN);
+    p.ipv4_mut().set_ttl(63);
+    p.udp_payload_mut()[5] = b'j';
+    p.recompute_checksums();
+    let mut q = cleared(p.as_slice());
+    q.update_ipv4_checksum();
+    q.update_udp_checksum();
+    assert_eq!(p.as_slice(), q.as_slice());
+    assert_ne!(p.ipv4().checksum(), 0xffcc);
+    assert_ne!(p.udp().checksum(), 0xe8de);
+}
+
+#[test]
+fn built_packets() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 84, 2), 27016);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 5), 5001);
+    let mut p = Packet::new_udp_ipv4(src, dst, b"odd").unwrap();
+    p.ipv4_mut().set_ident(0x1c46);
+    p.recompute_checksums();
+    assert_eq!(p.as_slice(), ODD);
+}
+
+#[test]
+fn from_hex() {
+    let p = Packet::from_hex("4500002b 1c464000 4011ffcc\n0a000005 c0a85402 138969880017e8de \
+        010000000068656c6c6f2074666821").unwrap();
+    assert_eq!(p.as_slice(), EVEN);
+    assert!(Packet::from_hex("450").is_err());
+    assert!(Packet::from_hex("45xx").is_err());
+    assert!(Packet::from_hex(&"00".repeat(1501)).is_err());
+}
+
+#[test]
+fn text_format() {
+    let packets = packet_text::parse("
+        # The same packet, whole and built from its parts.
+        ip 4500002b1c4640004011ffcc0a000005c0a85402138969880017e8de010000000068656c6c6f2074666821
+        udp 192.168.84.2:27016 -> 10.0.0.5:5001 6f 6464
+        tfh 10.0.0.5:5001 -> 192.168.84.2:27016 seq=1 ack=7 0a:00 0000 61 20:05
+    ").unwrap();
+    assert_eq!(packets.len(), 3);
+    assert_eq!(packets[0].as_slice(), EVEN);
+
+    let mut p = packets[1].clone();
+    p.ipv4_mut().set_ident(0x1c46);
+    p.recompute_checksums();
+    assert_eq!(p.as_slice(), ODD);
+
+    let p = &packets[2];
+    assert!(p.is_tfh_stream());
+    assert_eq!((p.tfh_stream().my_seq(), p.tfh_stream().your_seq()), (1, 7));
+    let data = p.tfh_stream_payload();
+    let (header, body) = framing::split(data).unwrap();
+    assert_eq!((header.major, header.minor, body), (0x0a, 0, &[0, 0, 0x61][..]));
+    let (header, body) = framing::split(&data[header.frame_len() ..]).unwrap();
+    assert_eq!((header.major, header.minor, body), (0x20, 0x05, &[][..]));
+
+    let err = packet_text::parse("\nudp 10.0.0.5:5001 10.0.0.1:5001 00").err().unwrap();
+    assert!(err.0.starts_with("line 2:"), "{}", err);
+}
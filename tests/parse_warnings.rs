@@ -0,0 +1,57 @@
+//! The parse-warning log: each warning and stall is a line of JSON with the connection, the
+//! warning's own fields, and the last message decoded in its direction.
+use std::fs;
+use tfh_mitm::parse_warnings::{Context, ParseWarningLog};
+use tfh_mitm::session::SessionId;
+use tfh_mitm::testing::{self, conn};
+use tfh_mitm::tfh_stream::{Stall, StallReason, StreamWarning};
+
+
+#[test]
+fn warning_and_stall_lines() {
+    let dir = testing::temp_dir("parse-warnings");
+    let path = dir.join("warnings");
+    let mut log = ParseWarningLog::new(path.to_str().unwrap()).unwrap();
+
+    let mut msg = testing::message(0x20, 0x03, vec![1, 2, 3]);
+    msg.header.dir = 1;
+    msg.index = 41;
+    msg.time = 1_700_000_000_000_000;
+    log.on_message(conn(), &msg);
+
+    let session = SessionId::new(conn(), 0);
+    let player = Some("Velvet Fang");
+    let server = Context { ct: conn(), dir: 1, session: Some(session), player };
+    log.warning(&server, &StreamWarning::MajorOutOfRange { at: 1000, major: 0x1234 }).unwrap();
+    // Nothing was decoded in the other direction yet.
+    let client = Context { ct: conn(), dir: 0, session: None, player: None };
+    let stall = Stall {
+        at: 2000,
+        reason: StallReason::Gap(2000 .. 2100),
+        buffered: vec![2100 .. 2300],
+        packets: 256,
+    };
+    log.stall(&client, &stall).unwrap();
+
+    let text = fs::read_to_string(&path).unwrap();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert_eq!(lines, [
+        format!(concat!(
+            r#"{{"time":1700000000000000,"conn":"10.0.0.5:5001 -> 192.168.84.2:27016","#,
+            r#""session":"{}","player":"Velvet Fang","dir":1,"kind":"major_out_of_range","#,
+            r#""detail":"major opcode out of range at 1000: 1234","last_index":41,"#,
+            r#""last_opcodes":"20:03","at":1000,"major":4660}}"#), session),
+        concat!(
+            r#"{"time":1700000000000000,"conn":"10.0.0.5:5001 -> 192.168.84.2:27016","dir":0,"#,
+            r#""kind":"stall","detail":"no messages for 256 packets, stuck at 2000: waiting "#,
+            r#"for 2000..2100; buffered 2100..2300","at":2000,"packets":256,"reason":"gap","#,
+            r#""gap_start":2000,"gap_end":2100}"#).to_owned(),
+    ]);
+
+    // Once the connection is closed, its last messages are forgotten.
+    log.close(conn());
+    log.warning(&server, &StreamWarning::BadChecksum { seq: 7 }).unwrap();
+    let text = fs::read_to_string(&path).unwrap();
+    assert!(!text.lines().last().unwrap().contains("last_index"));
+    fs::remove_dir_all(&dir).unwrap();
+}
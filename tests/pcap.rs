@@ -0,0 +1,67 @@
+//! Captures written by the relay record each packet's direction in its MAC addresses, and reading
+//! them back recovers it.  Reading skips records without a packet, and rejects truncated ones.
+use std::io::{self, Cursor};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tfh_mitm::packet::Packet;
+use tfh_mitm::pcap::{self, Direction, Pcap};
+
+
+fn packet(to_server: bool, time: u64) -> Packet {
+    let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 5), 5001);
+    let server = SocketAddrV4::new(Ipv4Addr::new(192, 168, 84, 2), 27016);
+    let (src, dst) = if to_server { (client, server) } else { (server, client) };
+    let mut p = Packet::new_udp_ipv4(src, dst, b"hello").unwrap();
+    p.set_time(Some(time));
+    p
+}
+
+#[test]
+fn direction_tags_round_trip() {
+    let mut buf = Vec::new();
+    let mut w = pcap::Writer::new(&mut buf, pcap::LINKTYPE_ETHERNET).unwrap();
+    w.write_tagged_packet(&packet(true, 1_000_000), Direction::AToB).unwrap();
+    w.write_tagged_packet(&packet(false, 2_000_000), Direction::BToA).unwrap();
+    w.write_packet(&packet(true, 3_000_000)).unwrap();
+    w.flush().unwrap();
+    drop(w);
+
+    let mut r = Pcap::new(Cursor::new(buf)).unwrap();
+    let mut got = Vec::new();
+    while let Some((p, dir)) = r.recv_tagged().unwrap() {
+        assert_eq!(p.udp_payload(), b"hello");
+        got.push((p.time(), dir));
+    }
+    assert_eq!(got, [
+        (Some(1_000_000), Some(Direction::AToB)),
+        (Some(2_000_000), Some(Direction::BToA)),
+        (Some(3_000_000), None),
+    ]);
+}
+
+/// Records without an IP packet are skipped rather than ending the capture, and a capture cut
+/// off partway through a record is an error rather than a normal end.
+#[test]
+fn skipped_and_truncated_records() {
+    let mut buf = Vec::new();
+    let mut w = pcap::Writer::new(&mut buf, pcap::LINKTYPE_ETHERNET).unwrap();
+    w.write_packet(&packet(true, 1_000_000)).unwrap();
+    w.write(1_500_000, &[0; 6]).unwrap();
+    w.write_packet(&packet(false, 2_000_000)).unwrap();
+    w.flush().unwrap();
+    drop(w);
+
+    let mut r = Pcap::new(Cursor::new(buf.clone())).unwrap();
+    let mut times = Vec::new();
+    while let Some((p, _)) = r.recv_tagged().unwrap() {
+        times.push(p.time());
+    }
+    assert_eq!(times, [Some(1_000_000), Some(2_000_000)]);
+
+    buf.truncate(buf.len() - 3);
+    let mut r = Pcap::new(Cursor::new(buf)).unwrap();
+    assert!(r.recv_tagged().unwrap().is_some());
+    match r.recv_tagged() {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        Ok(x) => panic!("truncated record read as {:?}", x.map(|(p, _)| p.time())),
+    }
+}
@@ -0,0 +1,122 @@
+//! A whole relay (both readers, the processing thread, and the writer) run over `loopback`
+//! links instead of tun devices.  A session is recorded to a pcap, which is then played into the
+//! relay: every packet must come out the other side unchanged, and the session must be logged.
+use std::env;
+use std::fs::{self, File};
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tfh_mitm::channel::Receiver;
+use tfh_mitm::config::Config;
+use tfh_mitm::packet::Packet;
+use tfh_mitm::pcap::{self, Pcap};
+use tfh_mitm::process as processing;
+use tfh_mitm::relay;
+use tfh_mitm::stats::RelayStats;
+use tfh_mitm::supervise::Supervisor;
+use tfh_mitm::testing::{self, Delivery, Rng};
+use tfh_mitm::tfhlog;
+use tfh_mitm::transport::{self, PacketSource};
+
+
+/// How long to wait for the relay to pass on all the packets.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Record both directions of a session, interleaved, as a pcap file.
+fn record(path: &Path, rng: &mut Rng, to_server: &[Packet], to_client: &[Packet]) {
+    let mut w = pcap::Writer::new(File::create(path).unwrap(), pcap::LINKTYPE_ETHERNET).unwrap();
+    let (mut a, mut b) = (to_server.iter(), to_client.iter());
+    loop {
+        let p = if rng.chance(50) {
+            a.next().or_else(|| b.next())
+        } else {
+            b.next().or_else(|| a.next())
+        };
+        match p {
+            Some(p) => w.write_packet(p).unwrap(),
+            None => break,
+        }
+    }
+    w.flush().unwrap();
+}
+
+fn collect(recv: &Receiver<Packet>, count: usize) -> Vec<Packet> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut out = Vec::new();
+    while out.len() < count {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match recv.recv_timeout(left) {
+            Ok(p) => out.push(p),
+            Err(_) => break,
+        }
+    }
+    out
+}
+
+fn check_same_packets(got: &[Packet], want: &[Packet], dir: &str) {
+    assert_eq!(got.len(), want.len(), "{}: wrong number of packets", dir);
+    for (i, (a, b)) in got.iter().zip(want).enumerate() {
+        assert_eq!(a.as_slice(), b.as_slice(), "{}: packet {} differs", dir, i);
+    }
+}
+
+#[test]
+fn pcap_through_relay() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    // Processing writes its logs to the current directory.
+    let dir = testing::temp_dir("relay-test");
+    env::set_current_dir(&dir).unwrap();
+
+    let mut rng = Rng::new(1);
+    let delivery = Delivery::in_order(1000);
+    let msgs_to_server = testing::random_messages(&mut rng, 40, 500);
+    let msgs_to_client = testing::random_messages(&mut rng, 40, 2000);
+    let to_server =
+        testing::packetize(&mut rng, &msgs_to_server, &delivery, client, server, 0);
+    let to_client =
+        testing::packetize(&mut rng, &msgs_to_client, &delivery, server, client, 0);
+    record(Path::new("session.pcap"), &mut rng, &to_server, &to_client);
+
+    let (cfg, _) = Config::from_args(&[]).unwrap();
+    let sup = Supervisor::new();
+    let stats = Arc::new(RelayStats::default());
+    let (inp_send, out_recv) =
+        processing::start_supervised_processing_thread(&cfg, &sup, stats.clone()).unwrap();
+    // Side A is outside, where the client is, and side B is inside, with the server.
+    let (outside, side_a) = transport::loopback(64);
+    let (inside, side_b) = transport::loopback(64);
+    relay::start(&cfg, &sup, stats, side_a, side_b, inp_send, out_recv).unwrap();
+
+    let mut pcap = Pcap::new(File::open("session.pcap").unwrap()).unwrap();
+    while let Some(p) = pcap.recv().unwrap() {
+        let dest = SocketAddrV4::new(p.ipv4().dest_ip().into(), p.udp().dest_port());
+        if dest == server {
+            outside.1.send(p).ok().unwrap();
+        } else {
+            inside.1.send(p).ok().unwrap();
+        }
+    }
+
+    // Captures are timestamped, so compare the bytes only.
+    check_same_packets(&collect(&inside.0, to_server.len()), &to_server, "to server");
+    check_same_packets(&collect(&outside.0, to_client.len()), &to_client, "to client");
+
+    let logs = fs::read_dir("logs").unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(logs.len(), 1, "expected one session log");
+    let mut r = tfhlog::Reader::new(File::open(logs[0].path()).unwrap()).unwrap();
+    let mut got = [Vec::new(), Vec::new()];
+    while let Some(rec) = r.read().unwrap() {
+        got[rec.msg.header.dir as usize].push(rec.msg);
+    }
+    for (dir, want) in [&msgs_to_server, &msgs_to_client].iter().enumerate() {
+        assert_eq!(got[dir].len(), want.len(), "direction {}: wrong number of messages", dir);
+        for (a, b) in got[dir].iter().zip(want.iter()) {
+            assert!(testing::same_message(a, b), "direction {}: {:?} != {:?}", dir, a, b);
+        }
+    }
+
+    drop(outside);
+    drop(inside);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,61 @@
+//! The roster check, fed by hand: logins and rosters that agree, a restart that missed a login,
+//! and a login the server no longer lists.
+use tfh_mitm::roster::{Finding, RosterCheck, GRACE_US};
+use tfh_mitm::tfh_stream::ConnTuple;
+
+
+const SERVER: u32 = 0xc0a8_5402;
+
+fn conn(i: u32) -> ConnTuple {
+    ConnTuple::Ipv4(0x0a00_0000 + i, 5001, SERVER, 27016)
+}
+
+fn names(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn logins_match_roster() {
+    let mut r = RosterCheck::new();
+    r.seen(conn(1));
+    r.login(0, conn(1), "Velvet");
+    r.seen(conn(2));
+    r.login(0, conn(2), "Mallory");
+    assert_eq!(r.roster(1, &names(&["Mallory", "Velvet"])), vec![]);
+    // Just after a login, the roster may not have caught up yet.
+    r.login(2, conn(3), "Trent");
+    assert_eq!(r.roster(3, &names(&["Mallory", "Velvet"])), vec![]);
+    assert_eq!(r.presence(4, "Trent", true), vec![]);
+}
+
+#[test]
+fn restart_seeds_names() {
+    let mut r = RosterCheck::new();
+    // Two connections already in the lobby when the relay started.
+    r.seen(conn(1));
+    r.seen(conn(2));
+    r.login(0, conn(2), "Mallory");
+    assert_eq!(r.roster(1, &names(&["Velvet", "Mallory", "Trent"])), vec![
+        Finding::Unclaimed { name: "Trent".into() },
+        Finding::Unclaimed { name: "Velvet".into() },
+    ]);
+    // Repeats of the same roster don't repeat the findings.
+    assert_eq!(r.roster(2, &names(&["Velvet", "Mallory", "Trent"])), vec![]);
+    // Once Trent leaves, Velvet is the only name left for the one unnamed connection.
+    assert_eq!(r.presence(3, "Trent", false), vec![
+        Finding::Seeded { ct: conn(1), name: "Velvet".into() },
+    ]);
+    assert_eq!(r.roster(4, &names(&["Velvet", "Mallory"])), vec![]);
+}
+
+#[test]
+fn ghost_login() {
+    let mut r = RosterCheck::new();
+    r.login(0, conn(1), "Velvet");
+    r.login(0, conn(2), "Mallory");
+    assert_eq!(r.roster(GRACE_US, &names(&["Velvet"])), vec![
+        Finding::Ghost { ct: conn(2), name: "Mallory".into() },
+    ]);
+    r.close(conn(2));
+    assert_eq!(r.roster(GRACE_US + 1, &names(&["Velvet"])), vec![]);
+}
@@ -0,0 +1,174 @@
+//! The tfhlog sink: messages land in per-session logs divided by class, rotating starts new files,
+//! a new sink can carry on appending to the files an old one reports, a session past its cap is
+//! logged without bodies, and indexes point at each record.  `Tee` hands everything to each of
+//! its sinks, and `open` finds sinks by name.
+use std::fs::{self, File, OpenOptions};
+use std::io::BufReader;
+use tfh_mitm::log_index;
+use tfh_mitm::log_layout::LogLayout;
+use tfh_mitm::session::SessionId;
+use tfh_mitm::sink::{self, MessageSink, Tee, TfhlogSink};
+use tfh_mitm::testing::{self, conn};
+use tfh_mitm::tfh_stream::Message;
+use tfh_mitm::tfhlog;
+
+
+fn message(major: u8, index: u64) -> Message {
+    let mut msg = testing::message(major, 0x01, vec![index as u8]);
+    msg.time = 1_700_000_000_000_000 + index * 1000;
+    msg.index = index;
+    msg
+}
+
+/// The indices of the messages in the log at `path`.
+fn indices(path: &str) -> Vec<u64> {
+    let mut r = tfhlog::Reader::new(File::open(path).unwrap()).unwrap();
+    let mut out = Vec::new();
+    while let Some(rec) = r.read().unwrap() {
+        out.push(rec.msg.index);
+    }
+    out
+}
+
+#[test]
+fn tfhlog_sink() {
+    let dir = testing::temp_dir("sink-test");
+    let dir = dir.to_str().unwrap().to_owned();
+    let mut layout = LogLayout::default();
+    layout.add_class("pos=20").unwrap();
+    let session = SessionId::new(conn(), 0);
+
+    let mut sink = TfhlogSink::new(&dir, &layout).unwrap();
+    for i in 0 .. 4 {
+        sink.append(conn(), session, &message(if i % 2 == 0 { 0x10 } else { 0x20 }, i)).unwrap();
+    }
+    let locations = sink.locations(conn());
+    assert_eq!(locations.len(), 2);
+    let (main, pos) = (locations[0].1.clone(), locations[1].1.clone());
+    assert_eq!(locations[0].0, None);
+    assert_eq!(locations[1].0.as_deref(), Some("pos"));
+    assert!(pos.ends_with("-pos.tfhlog"), "{}", pos);
+    assert_eq!(indices(&main), [0, 2]);
+    assert_eq!(indices(&pos), [1, 3]);
+
+    // After rotating, the main log continues in a new file.
+    sink.rotate().unwrap();
+    sink.append(conn(), session, &message(0x10, 4)).unwrap();
+    let rotated = sink.locations(conn());
+    assert_eq!(rotated.len(), 1);
+    assert_eq!(rotated[0].1, main.replace(".tfhlog", "-1.tfhlog"));
+    assert_eq!(indices(&rotated[0].1), [4]);
+    assert_eq!(indices(&main), [0, 2]);
+
+    // A fresh sink, as after a restart, appends to the files it's given.
+    let mut sink = sink.fork();
+    sink.reopen(conn(), &locations);
+    sink.append(conn(), session, &message(0x20, 5)).unwrap();
+    sink.append(conn(), session, &message(0x10, 6)).unwrap();
+    assert_eq!(indices(&main), [0, 2, 6]);
+    assert_eq!(indices(&pos), [1, 3, 5]);
+
+    sink.close(conn()).unwrap();
+    assert_eq!(sink.locations(conn()), vec![]);
+
+    // A log sampled differently isn't added to, so the session starts a new one.
+    let mut sampled = layout.clone();
+    sampled.add_sampling("10=2").unwrap();
+    let mut sink = TfhlogSink::new(&dir, &sampled).unwrap();
+    sink.reopen(conn(), &locations);
+    sink.append(conn(), session, &message(0x10, 7)).unwrap();
+    assert_ne!(sink.locations(conn())[0].1, main);
+    assert_eq!(indices(&main), [0, 2, 6]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn capped_session_logs_headers() {
+    let dir = testing::temp_dir("sink-cap-test");
+    let dir = dir.to_str().unwrap().to_owned();
+    let layout = LogLayout { max_messages: Some(3), .. LogLayout::default() };
+    let session = SessionId::new(conn(), 0);
+
+    let mut sink = TfhlogSink::new(&dir, &layout).unwrap();
+    for i in 0 .. 5 {
+        sink.append(conn(), session, &message(0x10, i)).unwrap();
+    }
+    let path = sink.locations(conn())[0].1.clone();
+    let mut r = tfhlog::Reader::new(File::open(&path).unwrap()).unwrap();
+    let mut got = Vec::new();
+    while let Some(rec) = r.read().unwrap() {
+        got.push((rec.msg.index, rec.msg.header.len, rec.msg.body.len(), rec.body_omitted));
+    }
+    assert_eq!(got, [
+        (0, 1, 1, false),
+        (1, 1, 1, false),
+        (2, 1, 1, false),
+        (3, 1, 0, true),
+        (4, 1, 0, true),
+    ]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn indexed_logs() {
+    let dir = testing::temp_dir("sink-index-test");
+    let dir = dir.to_str().unwrap().to_owned();
+    let layout = LogLayout { index: true, .. LogLayout::default() };
+    let session = SessionId::new(conn(), 0);
+
+    let mut sink = TfhlogSink::new(&dir, &layout).unwrap();
+    for i in 0 .. 3 {
+        sink.append(conn(), session, &message(0x10 + i as u8, i)).unwrap();
+    }
+    sink.flush().unwrap();
+    let locations = sink.locations(conn());
+    let path = locations[0].1.clone();
+    let len = fs::metadata(&path).unwrap().len();
+    let entries = log_index::load(&log_index::path(&path), len).unwrap();
+    assert_eq!(entries.iter().map(|e| e.major).collect::<Vec<_>>(), [0x10, 0x11, 0x12]);
+    assert_eq!(entries[2].end(), len);
+
+    // Each entry leads to its record.
+    let mut r = tfhlog::Reader::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+    r.seek(entries[1].offset).unwrap();
+    assert_eq!(r.read().unwrap().unwrap().msg.index, 1);
+
+    // The index fell behind, ending partway through an entry, as when the relay stopped before
+    // flushing it.  A fresh sink indexes the records it's missing, and carries on with the log.
+    let idx = log_index::path(&path);
+    let idx_len = fs::metadata(&idx).unwrap().len();
+    OpenOptions::new().write(true).open(&idx).unwrap().set_len(idx_len - 30).unwrap();
+    let mut sink = sink.fork();
+    sink.reopen(conn(), &locations);
+    sink.append(conn(), session, &message(0x13, 3)).unwrap();
+    sink.close(conn()).unwrap();
+    let len = fs::metadata(&path).unwrap().len();
+    let entries = log_index::load(&log_index::path(&path), len).unwrap();
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[3].end(), len);
+    assert_eq!(log_index::build(&path).unwrap(), 4);
+    assert_eq!(log_index::load(&log_index::path(&path), len).unwrap(), entries);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tee_and_named_sinks() {
+    let dir = testing::temp_dir("sink-tee-test");
+    let dir = dir.to_str().unwrap().to_owned();
+    let layout = LogLayout::default();
+    let session = SessionId::new(conn(), 0);
+
+    let memory = sink::open("memory", &dir, &layout).unwrap();
+    let mut tee = Tee(vec![memory, Box::new(TfhlogSink::new(&dir, &layout).unwrap())]);
+    tee.append(conn(), session, &message(0x10, 0)).unwrap();
+    let locations = tee.locations(conn());
+    assert_eq!(locations.len(), 1);
+    assert_eq!(indices(&locations[0].1), [0]);
+
+    // Forks tee to forks of each.
+    let mut fork = tee.fork();
+    fork.append(conn(), session, &message(0x10, 1)).unwrap();
+    assert_eq!(fork.locations(conn()).len(), 1);
+    assert!(sink::open("sqlite", &dir, &layout).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
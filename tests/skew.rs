@@ -0,0 +1,40 @@
+//! Clock offsets: parsing them, and estimating one from the messages two sources share.
+use std::collections::HashMap;
+use tfh_mitm::skew::{self, EventKey};
+use tfh_mitm::testing::{self, conn};
+
+
+fn key(index: u64, body: u8) -> EventKey {
+    let mut msg = testing::message(0x20, 0x01, vec![body]);
+    msg.index = index;
+    EventKey::new(conn(), &msg)
+}
+
+#[test]
+fn parse_offsets() {
+    assert_eq!(skew::parse_offset("1.5"), Ok(1_500_000));
+    assert_eq!(skew::parse_offset("-2s"), Ok(-2_000_000));
+    assert_eq!(skew::parse_offset("+250ms"), Ok(250_000));
+    assert_eq!(skew::parse_offset("-30us"), Ok(-30));
+    assert!(skew::parse_offset("ms").is_err());
+    assert_eq!(skew::parse_offset(&skew::format_offset(-1_234_500)), Ok(-1_234_500));
+    assert_eq!(skew::apply(10, -20), 0);
+    assert_eq!(skew::apply(10, 5), 15);
+}
+
+#[test]
+fn estimate_from_shared_messages() {
+    // The second source's clock is 2 s behind, give or take some jitter, and one of its
+    // messages has a different body, so it isn't the same message.
+    let reference = (0 .. 5).map(|i| (key(i, 0), 10_000_000 + i * 1000))
+        .collect::<HashMap<_, _>>();
+    let other = vec![
+        (key(0, 0), 8_000_000 - 50),
+        (key(1, 0), 8_001_000 + 20),
+        (key(2, 9), 1),
+        (key(3, 0), 8_003_000),
+        (key(7, 0), 8_007_000),
+    ];
+    assert_eq!(skew::estimate(&reference, &other), Some((2_000_000, 3)));
+    assert_eq!(skew::estimate(&reference, &other[2..3]), None);
+}
@@ -0,0 +1,73 @@
+//! The `--snapshot` file: its text round-trips, and the workers' saves are written out together,
+//! only when every worker has saved and something changed.
+use std::fs;
+use tfh_mitm::session::SessionId;
+use tfh_mitm::snapshot::{self, ConnState, SnapshotFile};
+use tfh_mitm::testing;
+use tfh_mitm::tfh_stream::{ConnTuple, ResumeState};
+
+
+fn state(port: u16, last_packet: u64) -> ConnState {
+    let ct = ConnTuple::Ipv4(0x0a00_0005, port, 0xc0a8_5402, 27016);
+    ConnState {
+        ct,
+        stream: ResumeState {
+            next: [Some((1234, 17)), None],
+            injected: vec![(5000, 40)],
+            last_packet,
+        },
+        name: Some("Velvet Fang".to_owned()),
+        version: Some(1234),
+        session: Some(SessionId::new(ct, 0)),
+        logs: vec![
+            (None, "logs/a b.tfhlog".to_owned()),
+            (Some("chat".to_owned()), "logs/a b-chat.tfhlog".to_owned()),
+        ],
+    }
+}
+
+#[test]
+fn round_trip() {
+    let mut bare = state(5002, 0);
+    bare.stream = ResumeState::default();
+    bare.name = None;
+    bare.version = None;
+    bare.session = None;
+    bare.logs = Vec::new();
+    let conns = vec![state(5001, 1_700_000_000_000_000), bare];
+    let text = snapshot::to_text(&conns);
+    assert_eq!(snapshot::parse(&text).unwrap(), conns);
+    assert!(snapshot::parse("next 0 1 2\n").is_err());
+    assert!(snapshot::parse("conn 10.0.0.5:5001 -> 192.168.84.2:27016\nnext 2 1 2\n").is_err());
+}
+
+#[test]
+fn written_once_all_workers_save() {
+    let dir = testing::temp_dir("snapshot-test");
+    let path = dir.join("snapshot").to_str().unwrap().to_owned();
+    let now = 1_700_000_000_000_000;
+
+    let file = SnapshotFile::open(&path).unwrap();
+    let (a, b) = (file.register(), file.register());
+    file.update(a, now, vec![state(5001, now)]).unwrap();
+    assert!(fs::metadata(&path).is_err(), "written before every worker saved");
+    file.update(b, now, vec![state(5002, now)]).unwrap();
+    let saved = snapshot::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved, [state(5001, now), state(5002, now)]);
+
+    // Saving the same again leaves the file alone.
+    fs::remove_file(&path).unwrap();
+    file.update(b, now, vec![state(5002, now)]).unwrap();
+    file.update(a, now, vec![state(5001, now)]).unwrap();
+    assert!(fs::metadata(&path).is_err(), "rewritten unchanged");
+
+    // A new run picks up where this one left off.
+    file.update(a, now, vec![]).unwrap();
+    file.update(b, now, vec![state(5002, now)]).unwrap();
+    let file = SnapshotFile::open(&path).unwrap();
+    let ct = state(5002, now).ct;
+    assert_eq!(file.take(ct, now + 1), Some(state(5002, now)));
+    assert_eq!(file.take(ct, now + 1), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
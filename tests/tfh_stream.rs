@@ -0,0 +1,467 @@
+//! Property tests for the stream reassembler: however a stream is cut up, duplicated, and
+//! reordered, `TfhStream` gives back the messages it carries.
+use tfh_mitm::framing;
+use tfh_mitm::packet::Packet;
+use tfh_mitm::tfh_stream::{
+    CollectingHandler, ConnTuple, Message, ResumeState, Stall, StallReason, StreamHandler,
+    StreamWarning, TfhStream, TfhStreamConns,
+};
+use tfh_mitm::testing::{self, Delivery, Rng};
+
+
+const SEEDS: u64 = 300;
+
+fn decode(packets: &[Packet]) -> Vec<Message> {
+    let mut stream = TfhStream::new();
+    let mut out = Vec::new();
+    for p in packets {
+        stream.handle_packet(p);
+        while let Some(msg) = stream.next_message() {
+            out.push(msg);
+        }
+    }
+    assert_eq!(stream.take_warnings(), 0);
+    out
+}
+
+fn check_same(seed: u64, got: &[Message], want: &[Message]) {
+    assert_eq!(got.len(), want.len(), "seed {}: wrong number of messages", seed);
+    for (i, (a, b)) in got.iter().zip(want).enumerate() {
+        assert!(testing::same_message(a, b), "seed {}: message {} differs: {:?} != {:?}",
+            seed, i, a, b);
+        assert_eq!(a.index, i as u64, "seed {}: message {} has the wrong index", seed, i);
+    }
+}
+
+fn check_delivery(delivery: &Delivery, max_body: usize) {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    for seed in 0 .. SEEDS {
+        let mut rng = Rng::new(seed);
+        let count = rng.below(50);
+        let msgs = testing::random_messages(&mut rng, count, max_body);
+        let packets = testing::packetize(&mut rng, &msgs, delivery, client, server, 0);
+        check_same(seed, &decode(&packets), &msgs);
+    }
+}
+
+#[test]
+fn in_order() {
+    check_delivery(&Delivery::in_order(1200), 100);
+}
+
+#[test]
+fn tiny_chunks() {
+    check_delivery(&Delivery::in_order(3), 40);
+}
+
+#[test]
+fn messages_larger_than_packets() {
+    check_delivery(&Delivery::in_order(1200), 5000);
+}
+
+#[test]
+fn shuffled() {
+    check_delivery(&Delivery::default(), 2000);
+}
+
+#[test]
+fn shuffled_small_chunks() {
+    let delivery = Delivery { max_chunk: 16, ..Delivery::default() };
+    check_delivery(&delivery, 60);
+}
+
+#[test]
+fn heavy_duplication() {
+    let delivery = Delivery { overlap: 50, duplicate: 50, reorder: 10, ..Delivery::default() };
+    check_delivery(&delivery, 300);
+}
+
+/// Both directions through `TfhStreamConns`, interleaved, each side acknowledging everything the
+/// other has sent.
+#[test]
+fn both_directions() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    for seed in 0 .. SEEDS {
+        let mut rng = Rng::new(seed);
+        let delivery = Delivery { max_chunk: 1 + rng.below(300), ..Delivery::default() };
+        let count = rng.below(30);
+        let to_server = testing::random_messages(&mut rng, count, 400);
+        let count = rng.below(30);
+        let to_client = testing::random_messages(&mut rng, count, 400);
+        let mut a = testing::packetize(&mut rng, &to_server, &delivery, client, server, 0);
+        let mut b = testing::packetize(&mut rng, &to_client, &delivery, server, client, 0);
+        a.reverse();
+        b.reverse();
+
+        let mut conns = TfhStreamConns::new(CollectingHandler::new());
+        let ct = ConnTuple::from_udp_packet(&a[0], false);
+        while a.len() + b.len() > 0 {
+            let flip = a.is_empty() || (!b.is_empty() && rng.chance(50));
+            let p = if flip { b.pop() } else { a.pop() }.unwrap();
+            conns.handle(&p, flip);
+        }
+        assert_eq!(conns.take_warnings(), [0, 0]);
+        assert_eq!(conns.handler().conns(), &[ct]);
+        let got = |dir| conns.handler().messages(ct, dir).cloned().collect::<Vec<_>>();
+        check_same(seed, &got(0), &to_server);
+        check_same(seed, &got(1), &to_client);
+    }
+}
+
+#[derive(Default)]
+struct StallRecorder {
+    messages: usize,
+    stalls: Vec<(u8, Stall)>,
+}
+
+impl StreamHandler for StallRecorder {
+    fn on_message(&mut self, _ct: ConnTuple, _msg: Message) {
+        self.messages += 1;
+    }
+
+    fn on_stall(&mut self, _ct: ConnTuple, dir: u8, stall: &Stall) {
+        self.stalls.push((dir, stall.clone()));
+    }
+}
+
+/// A lost packet holds up decoding until it's retransmitted, which is reported once as a stall.
+#[test]
+fn lost_packet_stalls() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(1);
+    let msgs = testing::random_messages(&mut rng, 300, 40);
+    let mut packets =
+        testing::packetize(&mut rng, &msgs, &Delivery::in_order(16), client, server, 0);
+    let lost = packets.remove(10);
+    let lost_start = lost.tfh_stream().my_seq();
+    let lost_end = lost_start + lost.tfh_stream_payload().len() as u32;
+
+    let mut conns = TfhStreamConns::new(StallRecorder::default());
+    for p in &packets {
+        conns.handle(p, false);
+    }
+    assert_eq!(conns.take_stalls(), [1, 0]);
+    let stalls = &conns.handler().stalls;
+    assert_eq!(stalls.len(), 1);
+    let (dir, ref stall) = stalls[0];
+    assert_eq!(dir, 0);
+    assert_eq!(stall.reason, StallReason::Gap(lost_start .. lost_end));
+    assert!(stall.buffered.iter().all(|r| r.end <= lost_start || r.start >= lost_end));
+    assert!(stall.buffered.iter().any(|r| r.start == lost_end));
+
+    conns.handle(&lost, false);
+    assert_eq!(conns.handler().messages, msgs.len(), "messages missing after retransmission");
+    assert_eq!(conns.take_stalls(), [0, 0]);
+}
+
+/// A length field that's too long or too short for any message makes the stream skip ahead to
+/// the next message, instead of waiting forever or misreading everything after it.
+#[test]
+fn impossible_lengths_resync() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(7);
+    let msgs = testing::random_messages(&mut rng, 20, 100);
+    let mut data = testing::encode_stream(&msgs);
+    // Where each message starts, after the one-byte preamble.
+    let mut starts = vec![0, 1];
+    for m in &msgs[1..] {
+        let len = framing::header_len(m.header.major as u32) + m.body.len();
+        starts.push(starts.last().unwrap() + len);
+    }
+    data[starts[5] .. starts[5] + 4].copy_from_slice(&0xffff_fff0_u32.to_be_bytes());
+    data[starts[12] .. starts[12] + 4].copy_from_slice(&2_u32.to_be_bytes());
+
+    let mut stream = TfhStream::new();
+    let mut got = Vec::new();
+    for (i, chunk) in data.chunks(1200).enumerate() {
+        stream.handle_packet(&testing::packet(client, server, (i * 1200) as u32, 0, chunk));
+        while let Some(msg) = stream.next_message() {
+            got.push(msg);
+        }
+    }
+    assert_eq!(stream.take_warnings(), 2);
+    assert_eq!(stream.take_warning_details(), [
+        StreamWarning::ImpossibleLength { at: starts[5] as u32, len: 0xffff_fff0 },
+        StreamWarning::Resynced {
+            at: starts[6] as u32,
+            skipped: (starts[6] - starts[5]) as u32,
+        },
+        StreamWarning::ImpossibleLength { at: starts[12] as u32, len: 2 },
+        StreamWarning::Resynced {
+            at: starts[13] as u32,
+            skipped: (starts[13] - starts[12]) as u32,
+        },
+    ]);
+    let want = msgs[..5].iter().chain(&msgs[6..12]).chain(&msgs[13..]).collect::<Vec<_>>();
+    assert_eq!(got.len(), want.len(), "wrong number of messages");
+    for (i, (a, b)) in got.iter().zip(want).enumerate() {
+        assert!(testing::same_message(a, b), "message {} differs: {:?} != {:?}", i, a, b);
+    }
+}
+
+#[derive(Default)]
+struct WarningRecorder {
+    warnings: Vec<(u8, StreamWarning)>,
+}
+
+impl StreamHandler for WarningRecorder {
+    fn on_warning(&mut self, _ct: ConnTuple, dir: u8, warning: &StreamWarning) {
+        self.warnings.push((dir, warning.clone()));
+    }
+}
+
+/// Decoding warnings reach the handler's `on_warning`, tagged with the direction they came from.
+#[test]
+fn warnings_reach_handler() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(7);
+    let msgs = testing::random_messages(&mut rng, 10, 100);
+    let mut data = testing::encode_stream(&msgs);
+    let start = 1 + framing::header_len(msgs[1].header.major as u32) + msgs[1].body.len();
+    let next = start + framing::header_len(msgs[2].header.major as u32) + msgs[2].body.len();
+    data[start .. start + 4].copy_from_slice(&2_u32.to_be_bytes());
+
+    let mut conns = TfhStreamConns::new(WarningRecorder::default());
+    conns.handle(&testing::packet(client, server, 0, 0, &[0]), false);
+    conns.handle(&testing::packet(server, client, 0, 1, &data), true);
+    assert_eq!(conns.handler().warnings, [
+        (1, StreamWarning::ImpossibleLength { at: start as u32, len: 2 }),
+        (1, StreamWarning::Resynced { at: next as u32, skipped: (next - start) as u32 }),
+    ]);
+    assert_eq!(conns.take_warnings(), [0, 1]);
+}
+
+/// With `set_check_packets`, packets with a bad checksum or missing bytes are reported.
+#[test]
+fn checked_packets() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(3);
+    let msgs = testing::random_messages(&mut rng, 40, 100);
+    let mut packets =
+        testing::packetize(&mut rng, &msgs, &Delivery::in_order(100), client, server, 0);
+    let sum = packets[5].udp().checksum();
+    packets[5].udp_mut().set_checksum(sum ^ 0x0100);
+    let len = packets[8].len();
+    packets[8].truncate(len - 4);
+
+    let mut conns = TfhStreamConns::new(WarningRecorder::default());
+    conns.set_check_packets(true);
+    for p in &packets {
+        conns.handle(p, false);
+    }
+    let seq = |i: usize| packets[i].tfh_stream().my_seq();
+    let udp_len = packets[8].udp_payload().len() + 8;
+    assert_eq!(conns.handler().warnings, [
+        (0, StreamWarning::BadChecksum { seq: seq(5) }),
+        (0, StreamWarning::Truncated { seq: seq(8), len: udp_len, expected: udp_len + 4 }),
+    ]);
+    assert_eq!(conns.take_warnings(), [2, 0]);
+}
+
+/// Grows every message it's asked to rewrite by a byte, which `handle_mut` can't apply.
+#[derive(Default)]
+struct Grower {
+    messages: Vec<Message>,
+    warnings: Vec<StreamWarning>,
+}
+
+impl StreamHandler for Grower {
+    fn on_message(&mut self, _ct: ConnTuple, msg: Message) {
+        self.messages.push(msg);
+    }
+
+    fn on_warning(&mut self, _ct: ConnTuple, _dir: u8, warning: &StreamWarning) {
+        self.warnings.push(warning.clone());
+    }
+
+    fn rewrite(&mut self, _ct: ConnTuple, msg: &mut Message) -> bool {
+        let mut body = msg.body.to_vec();
+        body.push(0);
+        msg.body = body.into_boxed_slice();
+        true
+    }
+}
+
+/// A rewrite that changes a message's length is dropped: the packets go out as they came in, and
+/// the handler sees the message that was sent.
+#[test]
+fn rewrite_length_dropped() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(9);
+    let msgs = testing::random_messages(&mut rng, 10, 100);
+    let packets =
+        testing::packetize(&mut rng, &msgs, &Delivery::in_order(1200), client, server, 0);
+
+    let mut conns = TfhStreamConns::new(Grower::default());
+    for p in &packets {
+        let mut q = p.clone();
+        assert_eq!(conns.handle_mut(&mut q, false).len(), 0);
+        assert_eq!(q.as_slice(), p.as_slice());
+    }
+    check_same(9, &conns.handler().messages, &msgs);
+    assert_eq!(conns.handler().warnings.len(), msgs.len());
+    assert!(conns.handler().warnings.iter().all(|w| w.kind() == "rewrite_length"));
+}
+
+/// Injects one message into the server's stream at the first chance.
+struct Injecting(Option<Message>);
+
+impl StreamHandler for Injecting {
+    fn next_injection(&mut self, _ct: ConnTuple) -> Option<Message> {
+        self.0.take()
+    }
+}
+
+/// An injected message reaches the client in a well-formed packet, in between the server's
+/// messages, with the server's later packets shifted past it.
+#[test]
+fn injected_message_delivered() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(11);
+    let msgs = testing::random_messages(&mut rng, 30, 100);
+    let data = testing::encode_stream(&msgs);
+    // The first packet ends after the fourth message, so the injection goes in after it.
+    let cut = 1 + msgs[1..4].iter()
+        .map(|m| framing::header_len(m.header.major as u32) + m.body.len())
+        .sum::<usize>();
+    let mut packets = vec![testing::packet(server, client, 0, 0, &data[..cut])];
+    for (i, chunk) in data[cut..].chunks(1000).enumerate() {
+        packets.push(testing::packet(server, client, (cut + i * 1000) as u32, 0, chunk));
+    }
+    let extra = testing::message(0x20, 4, b"injected".to_vec());
+
+    let mut conns = TfhStreamConns::new(Injecting(Some(extra.clone())));
+    let mut sent = Vec::new();
+    let mut injected_at = None;
+    for p in &packets {
+        let mut q = p.clone();
+        let more = conns.handle_mut(&mut q, true);
+        sent.push(q);
+        if more.len() > 0 {
+            injected_at = Some(sent.len());
+        }
+        sent.extend(more);
+    }
+    let injected_at = injected_at.expect("nothing was injected");
+
+    let mut checker = TfhStreamConns::new(WarningRecorder::default());
+    checker.set_check_packets(true);
+    for p in &sent {
+        checker.handle(p, true);
+    }
+    assert_eq!(checker.handler().warnings, []);
+
+    assert_eq!(injected_at, 1);
+    let mut want = msgs.clone();
+    want.insert(4, extra);
+    let got = decode(&sent);
+    assert_eq!(got.len(), want.len(), "wrong number of messages");
+    for (i, (a, b)) in got.iter().zip(&want).enumerate() {
+        assert!(testing::same_message(a, b), "message {} differs: {:?} != {:?}", i, a, b);
+    }
+}
+
+/// A connection joined partway through isn't taken to have finished the handshake until both
+/// sides have sent something, so a one-way flood of mid-stream packets still times out.
+#[test]
+fn late_one_way_flood_dropped() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(5);
+    let msgs = testing::random_messages(&mut rng, 50, 100);
+    let packets =
+        testing::packetize(&mut rng, &msgs, &Delivery::in_order(200), client, server, 0);
+    let timeout = 5_000_000;
+
+    let mut conns = TfhStreamConns::new(CollectingHandler::default());
+    conns.set_handshake_timeout(Some(timeout));
+    conns.advance(1_000_000);
+    for p in &packets[packets.len() / 2 ..] {
+        conns.handle(p, false);
+    }
+    assert_eq!(conns.handshaking(), 1);
+    conns.advance(1_000_000 + timeout);
+    conns.check_timeout();
+    assert_eq!(conns.handshaking(), 0);
+    assert_eq!(conns.take_handshake_timeouts(), 1);
+}
+
+/// Saves the state of its connections on checkpoints, and resumes from state saved before.
+#[derive(Default)]
+struct Resumer {
+    saved: Vec<(ConnTuple, ResumeState)>,
+    connects: usize,
+    messages: Vec<Message>,
+}
+
+impl StreamHandler for Resumer {
+    fn on_connect(&mut self, _ct: ConnTuple) {
+        self.connects += 1;
+    }
+
+    fn on_message(&mut self, _ct: ConnTuple, msg: Message) {
+        self.messages.push(msg);
+    }
+
+    fn resume(&mut self, ct: ConnTuple) -> Option<ResumeState> {
+        self.saved.iter().find(|x| x.0 == ct).map(|x| x.1.clone())
+    }
+
+    fn on_checkpoint(&mut self, conns: &[(ConnTuple, ResumeState)]) {
+        self.saved = conns.to_vec();
+    }
+}
+
+/// A stream saved partway through picks up where it left off in a new `TfhStreamConns`, once
+/// the packet holding its next message arrives, without having to find its place again.
+#[test]
+fn resume_from_checkpoint() {
+    let (client, server) = (testing::conn().client(), testing::conn().server());
+    let mut rng = Rng::new(3);
+    let msgs = testing::random_messages(&mut rng, 100, 300);
+    let packets =
+        testing::packetize(&mut rng, &msgs, &Delivery::in_order(500), client, server, 0);
+    let half = packets.len() / 2;
+
+    let mut conns = TfhStreamConns::new(Resumer::default());
+    for p in &packets[..half] {
+        conns.handle(p, false);
+    }
+    conns.checkpoint();
+    let before = conns.handler().messages.len();
+    let saved = conns.handler().saved.clone();
+    assert_eq!(saved.len(), 1);
+    let (seq, index) = saved[0].1.next[0].expect("stream should be in sync");
+    assert_eq!(index, before as u64);
+
+    // The new run sees the packets again from the one holding the start of the next message, as
+    // the client retransmits what the server never acknowledged.
+    let from = packets.iter().rposition(|p| p.tfh_stream().my_seq() <= seq).unwrap();
+    let mut conns = TfhStreamConns::new(Resumer { saved, ..Resumer::default() });
+    for p in &packets[from..] {
+        conns.handle(p, false);
+    }
+    assert_eq!(conns.handler().connects, 0);
+    assert_eq!(conns.take_warnings(), [0, 0]);
+    let got = &conns.handler().messages;
+    assert_eq!(got.len(), msgs.len() - before, "wrong number of messages");
+    for (i, (a, b)) in got.iter().zip(&msgs[before..]).enumerate() {
+        assert!(testing::same_message(a, b), "message {} differs: {:?} != {:?}", i, a, b);
+        assert_eq!(a.index, (before + i) as u64, "message {} has the wrong index", i);
+    }
+}
+
+#[test]
+fn chunks_cover_stream() {
+    for seed in 0 .. SEEDS {
+        let mut rng = Rng::new(seed);
+        let len = 1 + rng.below(10_000);
+        let chunks = testing::chunks(&mut rng, len, &Delivery::default());
+        assert_eq!(chunks[0].0, 0, "seed {}: first chunk doesn't start the stream", seed);
+        let mut covered = vec![false; len];
+        for &(start, end) in &chunks {
+            assert!(start < end && end <= len, "seed {}: bad chunk {:?}", seed, (start, end));
+            covered[start .. end].iter_mut().for_each(|x| *x = true);
+        }
+        assert!(covered.iter().all(|&x| x), "seed {}: chunks leave a gap", seed);
+    }
+}
@@ -0,0 +1,122 @@
+//! Every record of a tfhlog carries a CRC, so a damaged record or one cut short by a crash is
+//! reported, with where it starts, instead of being read as garbage.  The CRCs also find where a
+//! record starts near the end of a long log.  `open` reads compressed logs too.
+use std::fs;
+use std::io;
+use std::process::Command;
+use tfh_mitm::session::SessionId;
+use tfh_mitm::testing::{self, conn};
+use tfh_mitm::tfhlog;
+
+
+/// A log of three messages, with 10, 20, and 30 byte bodies, and the offset of each record.
+fn log() -> (Vec<u8>, Vec<u64>) {
+    let mut w = tfhlog::Writer::new(Vec::new()).unwrap();
+    let mut offsets = Vec::new();
+    for i in 0 .. 3 {
+        offsets.push(w.position());
+        let mut msg = testing::message(0x20, i as u8, vec![i as u8; 10 * (i + 1)]);
+        msg.index = i as u64;
+        w.write(1_700_000_000_000_000, conn(), Some(SessionId::new(conn(), 0)), &msg).unwrap();
+    }
+    (w.into_inner(), offsets)
+}
+
+/// The number of records read before the first error, the error, and where the bad record starts.
+fn read_all(data: &[u8]) -> (usize, Option<io::Error>, u64) {
+    let mut r = tfhlog::Reader::new(data).unwrap();
+    let mut n = 0;
+    loop {
+        match r.read() {
+            Ok(Some(_)) => n += 1,
+            Ok(None) => return (n, None, r.position()),
+            Err(e) => return (n, Some(e), r.position()),
+        }
+    }
+}
+
+#[test]
+fn intact_log_reads_back() {
+    let (data, offsets) = log();
+    let rec_len = |body| (tfhlog::RECORD_HEADER_LEN + body + tfhlog::RECORD_TRAILER_LEN) as u64;
+    assert_eq!(offsets[1] - offsets[0], rec_len(10));
+    assert_eq!(offsets[2] - offsets[1], rec_len(20));
+
+    let (n, err, pos) = read_all(&data);
+    assert_eq!(n, 3);
+    assert!(err.is_none());
+    assert_eq!(pos, data.len() as u64);
+}
+
+#[test]
+fn damage_is_reported() {
+    let (data, offsets) = log();
+
+    // Cut short partway through the last record's body, as a crash leaves it.
+    let (n, err, pos) = read_all(&data[.. data.len() - 12]);
+    assert_eq!(n, 2);
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(pos, offsets[2]);
+
+    // Cut short within the CRC.
+    let (n, err, _) = read_all(&data[.. data.len() - 1]);
+    assert_eq!(n, 2);
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+    // A flipped bit in the second record's body.
+    let mut bad = data.clone();
+    bad[offsets[1] as usize + tfhlog::RECORD_HEADER_LEN + 5] ^= 0x10;
+    let (n, err, pos) = read_all(&bad);
+    assert_eq!(n, 1);
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(pos, offsets[1]);
+
+    // A garbage length is found out without trying to read gigabytes.
+    let mut bad = data.clone();
+    bad[offsets[2] as usize + tfhlog::RECORD_HEADER_LEN - 4] = 0xff;
+    let (n, err, _) = read_all(&bad);
+    assert_eq!(n, 2);
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn compressed_log_reads_back() {
+    let dir = testing::temp_dir("tfhlog-gz");
+    let path = dir.join("a.tfhlog");
+    fs::write(&path, log().0).unwrap();
+    assert!(Command::new("gzip").arg("-f").arg(&path).status().unwrap().success());
+    let mut gz = path.into_os_string();
+    gz.push(".gz");
+
+    let r = tfhlog::open(&gz).unwrap();
+    let lens = r.map(|rec| rec.unwrap().msg.body.len()).collect::<Vec<_>>();
+    assert_eq!(lens, [10, 20, 30]);
+    let mut r = tfhlog::open(&gz).unwrap();
+    assert!(r.seek(0).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn seek_tail_finds_a_record() {
+    let mut w = tfhlog::Writer::new(Vec::new()).unwrap();
+    let count = 2 * tfhlog::TAIL_LEN / 1000;
+    for i in 0 .. count {
+        let mut msg = testing::message(0x20, 0x01, vec![i as u8; 1000 - 64]);
+        msg.index = i;
+        w.write(1_700_000_000_000_000, conn(), None, &msg).unwrap();
+    }
+    let data = w.into_inner();
+    let len = data.len() as u64;
+    let mut r = tfhlog::Reader::new(io::Cursor::new(&data)).unwrap();
+    r.seek_tail(len).unwrap();
+    assert!(r.position() >= len - tfhlog::TAIL_LEN);
+    let indices = r.map(|rec| rec.unwrap().msg.index).collect::<Vec<_>>();
+    assert_eq!(indices.last(), Some(&(count - 1)));
+    assert!(indices.windows(2).all(|w| w[1] == w[0] + 1));
+
+    // A short log stays at its first record.
+    let (data, offsets) = log();
+    let mut r = tfhlog::Reader::new(io::Cursor::new(&data)).unwrap();
+    r.seek_tail(data.len() as u64).unwrap();
+    assert_eq!(r.position(), offsets[0]);
+}
@@ -0,0 +1,63 @@
+-- Wireshark dissector for message-level captures written by `tfhlog-filter --format pcap`.
+--
+-- Each record is a 12-byte connection tuple, the 12-byte message header, and the body.  Install
+-- by copying this file into Wireshark's personal plugins directory.
+
+local tfh = Proto("tfhmsg", "TFH message")
+
+local dirs = { [0] = "client to server", [1] = "server to client" }
+
+local f = tfh.fields
+f.client = ProtoField.ipv4("tfhmsg.client", "Client address")
+f.client_port = ProtoField.uint16("tfhmsg.client_port", "Client port")
+f.server = ProtoField.ipv4("tfhmsg.server", "Server address")
+f.server_port = ProtoField.uint16("tfhmsg.server_port", "Server port")
+f.major = ProtoField.uint8("tfhmsg.major", "Major opcode", base.HEX)
+f.minor = ProtoField.uint8("tfhmsg.minor", "Minor opcode", base.HEX)
+f.dir = ProtoField.uint8("tfhmsg.dir", "Direction", base.DEC, dirs)
+f.ack = ProtoField.uint32("tfhmsg.ack", "Ack")
+f.len = ProtoField.uint32("tfhmsg.len", "Body length")
+f.body = ProtoField.bytes("tfhmsg.body", "Body")
+
+function tfh.dissector(buf, pinfo, tree)
+    if buf:len() < 24 then
+        return 0
+    end
+
+    pinfo.cols.protocol = "TFH"
+    local t = tree:add(tfh, buf(), "TFH message")
+
+    t:add(f.client, buf(0, 4))
+    t:add(f.client_port, buf(4, 2))
+    t:add(f.server, buf(6, 4))
+    t:add(f.server_port, buf(10, 2))
+
+    local major = buf(12, 1):uint()
+    local minor = buf(13, 1):uint()
+    local dir = buf(14, 1):uint()
+
+    -- The tuple always lists the client first, so swap for server-to-client messages.
+    local from, to = 0, 6
+    if dir == 1 then
+        from, to = 6, 0
+    end
+    pinfo.src = buf(from, 4):ipv4()
+    pinfo.src_port = buf(from + 4, 2):uint()
+    pinfo.dst = buf(to, 4):ipv4()
+    pinfo.dst_port = buf(to + 4, 2):uint()
+
+    t:add(f.major, buf(12, 1))
+    t:add(f.minor, buf(13, 1))
+    t:add(f.dir, buf(14, 1))
+    t:add(f.ack, buf(16, 4))
+    t:add(f.len, buf(20, 4))
+    if buf:len() > 24 then
+        t:add(f.body, buf(24))
+    end
+
+    pinfo.cols.info = string.format("%s %02x:%02x, %d bytes",
+        dir == 0 and "C->S" or "S->C", major, minor, buf:len() - 24)
+    return buf:len()
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, tfh)
@@ -0,0 +1,58 @@
+// XDP program for `--xdp-map`: redirects the lobby server's UDP traffic into the relay's AF_XDP
+// sockets, and passes everything else to the kernel as usual, so ARP, SSH, and the rest of the
+// host's traffic keep working.
+//
+// Build and attach with:
+//
+//     clang -O2 -g -target bpf -DLOBBY_PORT=27016 -c redirect.c -o redirect.o
+//     xdp-loader load -p /sys/fs/bpf eth0 redirect.o
+//
+// which pins the socket map at /sys/fs/bpf/tfh_xsks.
+
+#include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/in.h>
+#include <linux/ip.h>
+#include <linux/udp.h>
+#include <bpf/bpf_endian.h>
+#include <bpf/bpf_helpers.h>
+
+#ifndef LOBBY_PORT
+#define LOBBY_PORT 27016
+#endif
+
+struct {
+    __uint(type, BPF_MAP_TYPE_XSKMAP);
+    __uint(max_entries, 64);
+    __type(key, __u32);
+    __type(value, __u32);
+    __uint(pinning, LIBBPF_PIN_BY_NAME);
+} tfh_xsks SEC(".maps");
+
+SEC("xdp")
+int tfh_redirect(struct xdp_md *ctx) {
+    void *data = (void *)(long)ctx->data;
+    void *data_end = (void *)(long)ctx->data_end;
+
+    // Only IPv4 goes to the relay, which can't hand frames back to the kernel.
+    struct ethhdr *eth = data;
+    if ((void *)(eth + 1) > data_end || eth->h_proto != bpf_htons(ETH_P_IP)) {
+        return XDP_PASS;
+    }
+    struct iphdr *ip = (void *)(eth + 1);
+    if ((void *)(ip + 1) > data_end || ip->protocol != IPPROTO_UDP || ip->ihl < 5) {
+        return XDP_PASS;
+    }
+    struct udphdr *udp = (void *)ip + ip->ihl * 4;
+    if ((void *)(udp + 1) > data_end) {
+        return XDP_PASS;
+    }
+    if (udp->dest != bpf_htons(LOBBY_PORT) && udp->source != bpf_htons(LOBBY_PORT)) {
+        return XDP_PASS;
+    }
+
+    // Frames for a queue with no socket are passed too.
+    return bpf_redirect_map(&tfh_xsks, ctx->rx_queue_index, XDP_PASS);
+}
+
+char _license[] SEC("license") = "GPL";